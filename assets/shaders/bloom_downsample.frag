@@ -0,0 +1,18 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 f_uv;
+
+layout(set = 0, binding = 0) uniform sampler tex_sampler;
+layout(set = 0, binding = 1) uniform texture2D source_tex;
+
+layout(location = 0) out vec4 color;
+
+void main() {
+    vec2 texel = 1.0 / vec2(textureSize(sampler2D(source_tex, tex_sampler), 0));
+    vec3 c = texture(sampler2D(source_tex, tex_sampler), f_uv + texel * vec2(-1.0, -1.0)).rgb;
+    c += texture(sampler2D(source_tex, tex_sampler), f_uv + texel * vec2(1.0, -1.0)).rgb;
+    c += texture(sampler2D(source_tex, tex_sampler), f_uv + texel * vec2(-1.0, 1.0)).rgb;
+    c += texture(sampler2D(source_tex, tex_sampler), f_uv + texel * vec2(1.0, 1.0)).rgb;
+    color = vec4(c * 0.25, 1.0);
+}
@@ -0,0 +1,33 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 f_uv;
+
+layout(set = 0, binding = 0) uniform sampler tex_sampler;
+layout(set = 0, binding = 1) uniform texture2D hdr_tex;
+
+layout(push_constant) uniform BloomArgs {
+    float threshold;
+    float radius;
+};
+
+layout(location = 0) out vec4 color;
+
+void main() {
+    // Four bilinear taps average 4x4 source texels, which avoids aliasing when halving
+    vec2 texel = 1.0 / vec2(textureSize(sampler2D(hdr_tex, tex_sampler), 0));
+    vec3 c = texture(sampler2D(hdr_tex, tex_sampler), f_uv + texel * vec2(-1.0, -1.0)).rgb;
+    c += texture(sampler2D(hdr_tex, tex_sampler), f_uv + texel * vec2(1.0, -1.0)).rgb;
+    c += texture(sampler2D(hdr_tex, tex_sampler), f_uv + texel * vec2(-1.0, 1.0)).rgb;
+    c += texture(sampler2D(hdr_tex, tex_sampler), f_uv + texel * vec2(1.0, 1.0)).rgb;
+    c *= 0.25;
+
+    // Quadratic soft knee below the threshold so that bloom fades in instead of popping
+    float brightness = max(c.r, max(c.g, c.b));
+    float knee = threshold * 0.5 + 0.00001;
+    float soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+    soft = soft * soft / (4.0 * knee);
+    float contribution = max(soft, brightness - threshold) / max(brightness, 0.00001);
+
+    color = vec4(c * contribution, 1.0);
+}
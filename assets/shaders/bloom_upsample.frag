@@ -0,0 +1,35 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 f_uv;
+
+layout(set = 0, binding = 0) uniform sampler tex_sampler;
+// The blurred result of the next smaller level
+layout(set = 0, binding = 1) uniform texture2D blurred_tex;
+// The downsampled image of this level
+layout(set = 0, binding = 2) uniform texture2D level_tex;
+
+layout(push_constant) uniform BloomArgs {
+    float threshold;
+    float radius;
+};
+
+layout(location = 0) out vec4 color;
+
+void main() {
+    // 3x3 tent filter, spread out by the radius
+    vec2 d = radius / vec2(textureSize(sampler2D(blurred_tex, tex_sampler), 0));
+    vec3 c = texture(sampler2D(blurred_tex, tex_sampler), f_uv).rgb * 4.0;
+    c += texture(sampler2D(blurred_tex, tex_sampler), f_uv + vec2(-d.x, 0.0)).rgb * 2.0;
+    c += texture(sampler2D(blurred_tex, tex_sampler), f_uv + vec2(d.x, 0.0)).rgb * 2.0;
+    c += texture(sampler2D(blurred_tex, tex_sampler), f_uv + vec2(0.0, -d.y)).rgb * 2.0;
+    c += texture(sampler2D(blurred_tex, tex_sampler), f_uv + vec2(0.0, d.y)).rgb * 2.0;
+    c += texture(sampler2D(blurred_tex, tex_sampler), f_uv + vec2(-d.x, -d.y)).rgb;
+    c += texture(sampler2D(blurred_tex, tex_sampler), f_uv + vec2(d.x, -d.y)).rgb;
+    c += texture(sampler2D(blurred_tex, tex_sampler), f_uv + vec2(-d.x, d.y)).rgb;
+    c += texture(sampler2D(blurred_tex, tex_sampler), f_uv + vec2(d.x, d.y)).rgb;
+    c /= 16.0;
+
+    c += texture(sampler2D(level_tex, tex_sampler), f_uv).rgb;
+    color = vec4(c, 1.0);
+}
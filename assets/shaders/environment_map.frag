@@ -2,10 +2,13 @@
 #extension GL_ARB_separate_shader_objects : enable
 
 layout(location = 0) in vec3 f_pos;
+layout(location = 1) in vec4 f_clip_pos;
+layout(location = 2) in vec4 f_prev_clip_pos;
 
 layout(std140, set = 0, binding = 0) uniform UniformArgs {
     mat4 proj;
     mat4 view;
+    mat4 prev_view;
     float roughness;
 };
 
@@ -13,8 +16,10 @@ layout(set = 1, binding = 0) uniform sampler cube_sampler;
 layout(set = 1, binding = 1) uniform textureCube cube_map;
 
 layout(location = 0) out vec4 color;
+layout(location = 1) out vec2 velocity;
 
 void main() {
     vec3 col = textureLod(samplerCube(cube_map, cube_sampler), f_pos, roughness).rgb;
     color = vec4(col, 1.0);
+    velocity = (f_clip_pos.xy / f_clip_pos.w - f_prev_clip_pos.xy / f_prev_clip_pos.w) * 0.5;
 }
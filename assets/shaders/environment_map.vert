@@ -6,11 +6,16 @@ layout(location = 0) in vec3 a_pos;
 layout(std140, set = 0, binding = 0) uniform UniformArgs {
     mat4 proj;
     mat4 view;
+    mat4 prev_view;
 };
 
 layout(location = 0) out vec3 f_pos;
+layout(location = 1) out vec4 f_clip_pos;
+layout(location = 2) out vec4 f_prev_clip_pos;
 
 void main() {
     f_pos = a_pos;
     gl_Position = proj * view * vec4(vec3(100.0) * f_pos, 1.0);
+    f_clip_pos = gl_Position;
+    f_prev_clip_pos = proj * prev_view * vec4(vec3(100.0) * f_pos, 1.0);
 }
\ No newline at end of file
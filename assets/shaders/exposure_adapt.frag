@@ -0,0 +1,60 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 f_uv;
+
+layout(set = 0, binding = 0) uniform sampler tex_sampler;
+layout(set = 0, binding = 1) uniform texture2D luminance_tex;
+layout(set = 0, binding = 2) uniform texture2D history_tex;
+
+layout(push_constant) uniform Args {
+    float min_ev;
+    float max_ev;
+    float adaptation_speed;
+    float time_step;
+};
+
+layout(location = 0) out float adapted_luminance;
+
+const int BINS = 64;
+// Only the part of the histogram between these fractions of the pixels is averaged, so that
+// small dark areas and bright highlights don't change the exposure much
+const float LOW_PERCENTILE = 0.5;
+const float HIGH_PERCENTILE = 0.95;
+
+void main() {
+    float histogram[BINS];
+    for (int i = 0; i < BINS; ++i) {
+        histogram[i] = 0.0;
+    }
+
+    ivec2 size = textureSize(sampler2D(luminance_tex, tex_sampler), 0);
+    float range = max(max_ev - min_ev, 0.0001);
+    for (int y = 0; y < size.y; ++y) {
+        for (int x = 0; x < size.x; ++x) {
+            float l = texelFetch(sampler2D(luminance_tex, tex_sampler), ivec2(x, y), 0).r;
+            int bin = int(clamp((l - min_ev) / range, 0.0, 1.0) * float(BINS - 1) + 0.5);
+            histogram[bin] += 1.0;
+        }
+    }
+
+    float total = float(size.x * size.y);
+    float low = total * LOW_PERCENTILE;
+    float high = total * HIGH_PERCENTILE;
+    float count = 0.0;
+    float sum = 0.0;
+    float weight = 0.0;
+    for (int i = 0; i < BINS; ++i) {
+        float in_range = max(min(count + histogram[i], high) - max(count, low), 0.0);
+        sum += in_range * (min_ev + float(i) / float(BINS - 1) * range);
+        weight += in_range;
+        count += histogram[i];
+    }
+
+    float previous = texelFetch(sampler2D(history_tex, tex_sampler), ivec2(0), 0).r;
+    float target = weight > 0.0 ? sum / weight : previous;
+
+    // Exponential smoothing, independent of the frame rate
+    float blend = 1.0 - exp(-time_step * adaptation_speed);
+    adapted_luminance = clamp(mix(previous, target, blend), min_ev, max_ev);
+}
@@ -0,0 +1,38 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 f_uv;
+
+layout(set = 0, binding = 0) uniform sampler tex_sampler;
+layout(set = 0, binding = 1) uniform texture2D hdr_tex;
+
+layout(location = 0) out float log_luminance;
+
+// Must match LUMINANCE_RES in exposure.rs
+const int LUMINANCE_RES = 64;
+
+float luminance(const vec3 c) {
+    return dot(c, vec3(0.2126, 0.7152, 0.0722));
+}
+
+void main() {
+    // Each output pixel covers a block of the HDR image, which is averaged in log space so
+    // that a few very bright pixels don't dominate the result
+    ivec2 size = textureSize(sampler2D(hdr_tex, tex_sampler), 0);
+    ivec2 out_pixel = ivec2(gl_FragCoord.xy);
+    ivec2 out_size = min(size, ivec2(LUMINANCE_RES));
+    ivec2 block = (size + out_size - 1) / out_size;
+    ivec2 start = out_pixel * block;
+
+    float sum = 0.0;
+    int count = 0;
+    for (int y = start.y; y < min(start.y + block.y, size.y); ++y) {
+        for (int x = start.x; x < min(start.x + block.x, size.x); ++x) {
+            vec3 c = texelFetch(sampler2D(hdr_tex, tex_sampler), ivec2(x, y), 0).rgb;
+            sum += log2(max(luminance(c), 0.00001));
+            count += 1;
+        }
+    }
+
+    log_luminance = count > 0 ? sum / float(count) : 0.0;
+}
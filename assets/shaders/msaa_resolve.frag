@@ -0,0 +1,41 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 f_uv;
+
+layout(set = 0, binding = 0) uniform sampler tex_sampler;
+layout(set = 0, binding = 1) uniform texture2DMS hdr_tex;
+layout(set = 0, binding = 2) uniform texture2DMS velocity_tex;
+
+layout(push_constant) uniform Args {
+    int samples;
+};
+
+layout(location = 0) out vec4 color;
+layout(location = 1) out vec2 velocity;
+
+float luminance(const vec3 c) {
+    return dot(c, vec3(0.2126, 0.7152, 0.0722));
+}
+
+void main() {
+    ivec2 size = textureSize(sampler2DMS(hdr_tex, tex_sampler));
+    ivec2 pixel = min(ivec2(f_uv * vec2(size)), size - 1);
+
+    // Weighting each sample by its inverse brightness keeps edges against very bright
+    // areas from resolving to a solid bright pixel, which would undo the anti-aliasing
+    // once the image is tonemapped
+    vec3 color_sum = vec3(0.0);
+    float weight_sum = 0.0;
+    vec2 velocity_sum = vec2(0.0);
+    for (int i = 0; i < samples; ++i) {
+        vec3 c = texelFetch(sampler2DMS(hdr_tex, tex_sampler), pixel, i).rgb;
+        float weight = 1.0 / (1.0 + luminance(c));
+        color_sum += c * weight;
+        weight_sum += weight;
+        velocity_sum += texelFetch(sampler2DMS(velocity_tex, tex_sampler), pixel, i).xy;
+    }
+
+    color = vec4(color_sum / weight_sum, 1.0);
+    velocity = velocity_sum / float(samples);
+}
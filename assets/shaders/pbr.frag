@@ -1,31 +1,49 @@
 #version 450
 #extension GL_ARB_separate_shader_objects : enable
 
-layout(early_fragment_tests) in;
-
 layout(location = 0) in vec4 f_world_pos;
 layout(location = 1) in vec3 f_norm;
 layout(location = 2) in vec3 f_tang;
 layout(location = 3) flat in float f_tbn_handedness;
 layout(location = 4) in vec2 f_uv;
+layout(location = 5) in vec4 f_clip_pos;
+layout(location = 6) in vec4 f_prev_clip_pos;
+layout(location = 7) in vec2 f_uv1;
+
+const int LIGHT_POINT = 0;
+const int LIGHT_DIRECTIONAL = 1;
+const int LIGHT_SPOT = 2;
+
+const int MAX_SHADOW_MAPS = 8;
+const int NUM_CASCADES = 3;
 
 layout(std140) struct Light {
     vec3 pos;
     float intensity;
     vec3 color;
+    int kind;
+    vec3 direction;
+    float spot_cos_inner;
+    float spot_cos_outer;
+    int shadow_index;
 };
 
 layout(set = 0, binding = 0) uniform sampler tex_sampler;
 layout(set = 0, binding = 1) uniform textureCube spec_cube_map;
 layout(set = 0, binding = 2) uniform textureCube irradiance_cube_map;
 layout(set = 0, binding = 3) uniform texture2D spec_brdf_map;
+layout(set = 0, binding = 4) uniform samplerShadow shadow_sampler;
+layout(set = 0, binding = 5) uniform texture2D shadow_maps[MAX_SHADOW_MAPS];
 
 layout(std140, set = 1, binding = 0) uniform Args {
     layout(offset = 0) mat4 proj;
     layout(offset = 64) mat4 view;
-    layout(offset = 128) vec3 camera_pos;
-    layout(offset = 140) int lights_count;
-    layout(offset = 144) Light lights[32];
+    layout(offset = 128) mat4 prev_view;
+    layout(offset = 192) vec3 camera_pos;
+    layout(offset = 204) int lights_count;
+    layout(offset = 208) Light lights[32];
+    layout(offset = 2256) mat4 shadow_matrices[MAX_SHADOW_MAPS];
+    layout(offset = 2768) vec4 cascade_splits;
 };
 
 layout(set = 2, binding = 0) uniform texture2D albedo_map;
@@ -33,11 +51,44 @@ layout(set = 2, binding = 1) uniform texture2D normal_map;
 layout(set = 2, binding = 2) uniform texture2D metallic_roughness_map;
 layout(set = 2, binding = 3) uniform texture2D ao_map;
 layout(set = 2, binding = 4) uniform texture2D emissive_map;
+layout(set = 2, binding = 6) uniform sampler albedo_sampler;
+layout(set = 2, binding = 7) uniform sampler normal_sampler;
+layout(set = 2, binding = 8) uniform sampler metallic_roughness_sampler;
+layout(set = 2, binding = 9) uniform sampler ao_sampler;
+layout(set = 2, binding = 10) uniform sampler emissive_sampler;
+// Maps the vertex texture coordinates to the coordinates a texture is sampled at
+struct TextureTransform {
+    vec3 u;
+    int tex_coord;
+    vec3 v;
+};
+
+const int TEXTURE_ALBEDO = 0;
+const int TEXTURE_NORMAL = 1;
+const int TEXTURE_METALLIC_ROUGHNESS = 2;
+const int TEXTURE_AO = 3;
+const int TEXTURE_EMISSIVE = 4;
+
+const int ALPHA_OPAQUE = 0;
+const int ALPHA_MASK = 1;
+const int ALPHA_BLEND = 2;
+
 layout(std140, set = 2, binding = 5) uniform MatData {
+    vec4 base_color_factor;
     vec3 emissive_factor;
+    float metallic_factor;
+    float roughness_factor;
+    float normal_scale;
+    float occlusion_strength;
+    float emissive_strength;
+    TextureTransform texture_transforms[5];
+    float alpha_cutoff;
+    int alpha_mode;
+    int double_sided;
 };
 
 layout(location = 0) out vec4 color;
+layout(location = 1) out vec2 velocity;
 
 const float MAX_SPEC_LOD = 4.0;
 
@@ -72,16 +123,75 @@ float saturate(float v) {
     return clamp(v, 0.0, 1.0);
 }
 
+// Fraction of the light reaching the fragment, filtered over 3x3 texels
+float shadow_pcf(const int map, const vec3 N, const vec3 L) {
+    float NdotL = saturate(dot(N, L));
+    // Offset along the normal and bias the depth more at grazing angles to avoid acne
+    vec3 pos = f_world_pos.xyz + N * 0.02 * (1.0 - NdotL);
+    vec4 light_clip = shadow_matrices[map] * vec4(pos, 1.0);
+    vec3 ndc = light_clip.xyz / light_clip.w;
+    if (any(greaterThan(abs(ndc.xy), vec2(1.0))) || ndc.z > 1.0) {
+        return 1.0;
+    }
+    vec2 uv = ndc.xy * 0.5 + 0.5;
+    float depth = ndc.z - max(0.002 * (1.0 - NdotL), 0.0005);
+
+    vec2 texel = 1.0 / vec2(textureSize(sampler2DShadow(shadow_maps[map], shadow_sampler), 0));
+    float lit = 0.0;
+    for (int x = -1; x <= 1; ++x) {
+        for (int y = -1; y <= 1; ++y) {
+            lit += texture(sampler2DShadow(shadow_maps[map], shadow_sampler), vec3(uv + vec2(x, y) * texel, depth));
+        }
+    }
+    return lit / 9.0;
+}
+
+float shadow(const Light light, const vec3 N, const vec3 L) {
+    if (light.shadow_index < 0) {
+        return 1.0;
+    }
+    if (light.kind != LIGHT_DIRECTIONAL) {
+        return shadow_pcf(light.shadow_index, N, L);
+    }
+
+    float view_depth = -(view * f_world_pos).z;
+    int cascade = 0;
+    for (int i = 0; i < NUM_CASCADES; ++i) {
+        if (view_depth > cascade_splits[i]) {
+            cascade = i + 1;
+        }
+    }
+    if (cascade >= NUM_CASCADES) {
+        return 1.0;
+    }
+    return shadow_pcf(light.shadow_index + cascade, N, L);
+}
+
+vec2 texture_uv(const int texture) {
+    TextureTransform transform = texture_transforms[texture];
+    vec3 uv = vec3(transform.tex_coord == 1 ? f_uv1 : f_uv, 1.0);
+    return vec2(dot(transform.u, uv), dot(transform.v, uv));
+}
+
 void main() {
-    vec3 albedo = texture(sampler2D(albedo_map, tex_sampler), f_uv).rgb;
-    vec3 normal = texture(sampler2D(normal_map, tex_sampler), f_uv).rgb;
-    vec2 metallic_roughness = texture(sampler2D(metallic_roughness_map, tex_sampler), f_uv).bg;
-    float metallic = metallic_roughness.x;
-    float roughness = metallic_roughness.y;
-    float ao = texture(sampler2D(ao_map, tex_sampler), f_uv).r;
-    vec3 emissive = texture(sampler2D(emissive_map, tex_sampler), f_uv).rgb;
+    // Blended materials are drawn without culling so that they can be sorted together
+    if (!gl_FrontFacing && double_sided == 0) {
+        discard;
+    }
+    vec4 base_color = texture(sampler2D(albedo_map, albedo_sampler), texture_uv(TEXTURE_ALBEDO)) * base_color_factor;
+    if (alpha_mode == ALPHA_MASK && base_color.a < alpha_cutoff) {
+        discard;
+    }
+    vec3 albedo = base_color.rgb;
+    vec3 normal = texture(sampler2D(normal_map, normal_sampler), texture_uv(TEXTURE_NORMAL)).rgb;
+    vec2 metallic_roughness = texture(sampler2D(metallic_roughness_map, metallic_roughness_sampler), texture_uv(TEXTURE_METALLIC_ROUGHNESS)).bg;
+    float metallic = metallic_roughness.x * metallic_factor;
+    float roughness = metallic_roughness.y * roughness_factor;
+    float ao = texture(sampler2D(ao_map, ao_sampler), texture_uv(TEXTURE_AO)).r;
+    ao = 1.0 + occlusion_strength * (ao - 1.0);
+    vec3 emissive = texture(sampler2D(emissive_map, emissive_sampler), texture_uv(TEXTURE_EMISSIVE)).rgb;
 
-    normal = normal * 2.0 - 1.0;
+    normal = (normal * 2.0 - 1.0) * vec3(normal_scale, normal_scale, 1.0);
 
     vec3 V = normalize(camera_pos - f_world_pos.xyz);
 
@@ -91,6 +201,9 @@ void main() {
     mat3 TBN = mat3(T, B, N);
 
     N = normalize(TBN * normal);
+    if (!gl_FrontFacing) {
+        N = -N;
+    }
     vec3 R = reflect(-V, N);
 
     float NdotV = abs(dot(N, V)) + 0.00001;
@@ -111,11 +224,24 @@ void main() {
     float a = roughness * roughness;
     vec3 acc = vec3(0.0);
     for (int i = 0; i < lights_count; ++i) {
-        vec3 L = lights[i].pos - f_world_pos.xyz;
-        float d2 = dot(L, L);
-        L = normalize(L);
+        vec3 L;
+        float attenuation;
+        if (lights[i].kind == LIGHT_DIRECTIONAL) {
+            L = -lights[i].direction;
+            attenuation = 1.0;
+        } else {
+            L = lights[i].pos - f_world_pos.xyz;
+            float d2 = dot(L, L);
+            L = normalize(L);
+            attenuation = 1.0 / d2;
+            if (lights[i].kind == LIGHT_SPOT) {
+                float cos_angle = dot(-L, lights[i].direction);
+                attenuation *= smoothstep(lights[i].spot_cos_outer, lights[i].spot_cos_inner, cos_angle);
+            }
+        }
         vec3 H = normalize(V + L);
-        vec3 l_contrib = lights[i].color * lights[i].intensity / d2;
+        attenuation *= shadow(lights[i], N, L);
+        vec3 l_contrib = lights[i].color * lights[i].intensity * attenuation;
 
         float NdotL = saturate(dot(N, L));
         float NdotH = saturate(dot(N, H));
@@ -132,6 +258,7 @@ void main() {
         acc += (diffuse + specular) * NdotL * l_contrib;
     }
 
-    vec3 final = ambient * ao + acc + emissive * emissive_factor;
-    color = vec4(final, 1.0);
+    vec3 final = ambient * ao + acc + emissive * emissive_factor * emissive_strength;
+    color = vec4(final, alpha_mode == ALPHA_BLEND ? base_color.a : 1.0);
+    velocity = (f_clip_pos.xy / f_clip_pos.w - f_prev_clip_pos.xy / f_prev_clip_pos.w) * 0.5;
 }
\ No newline at end of file
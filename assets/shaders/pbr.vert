@@ -5,27 +5,108 @@ layout(location = 0) in vec3 a_pos;
 layout(location = 1) in vec3 a_norm;
 layout(location = 2) in vec4 a_tang;
 layout(location = 3) in vec2 a_uv;
+layout(location = 4) in uvec4 a_joints;
+layout(location = 5) in vec4 a_weights;
 // vec4[4] is used instead of mat4 due to spirv-cross bug for dx12 backend
-layout(location = 4) in vec4 model[4]; // per-instance.
+layout(location = 6) in vec4 model[4]; // per-instance.
+// per-instance, the first joint matrix and morph target weight of the instance, -1 if unused.
+layout(location = 10) in ivec2 deformation_offsets;
+layout(location = 11) in vec4 prev_model[4]; // per-instance, from the previous frame.
+layout(location = 15) in vec2 a_uv1;
 
 layout(std140, set = 1, binding = 0) uniform Args {
     mat4 proj;
     mat4 view;
+    mat4 prev_view;
     vec3 camera_pos;
 };
 
+layout(std430, set = 1, binding = 1) readonly buffer Joints {
+    mat4 joints[];
+};
+
+layout(std430, set = 1, binding = 2) readonly buffer PreviousJoints {
+    mat4 prev_joints[];
+};
+
+struct MorphDelta {
+    vec4 position;
+    vec4 normal;
+    vec4 tangent;
+};
+
+layout(std430, set = 1, binding = 3) readonly buffer MorphDeltas {
+    MorphDelta morph_deltas[];
+};
+
+layout(std430, set = 1, binding = 4) readonly buffer MorphWeights {
+    float morph_weights[];
+};
+
+layout(std430, set = 1, binding = 5) readonly buffer PreviousMorphWeights {
+    float prev_morph_weights[];
+};
+
+// The deltas of each target of the primitive follow each other, one per vertex
+layout(push_constant) uniform MorphTargets {
+    int first_morph_delta;
+    int morph_target_count;
+    int morph_vertex_count;
+};
+
 layout(location = 0) out vec4 frag_world_pos;
 layout(location = 1) out vec3 frag_norm;
 layout(location = 2) out vec3 frag_tang;
 layout(location = 3) flat out float frag_tbn_handedness;
 layout(location = 4) out vec2 frag_uv;
+layout(location = 5) out vec4 frag_clip_pos;
+layout(location = 6) out vec4 frag_prev_clip_pos;
+layout(location = 7) out vec2 frag_uv1;
 
 void main() {
+    vec3 pos = a_pos;
+    vec3 prev_pos = a_pos;
+    vec3 norm = a_norm;
+    vec3 tang = a_tang.xyz;
+    int weight_offset = deformation_offsets.y;
+    if (weight_offset >= 0) {
+        for (int i = 0; i < morph_target_count; i++) {
+            MorphDelta delta =
+                morph_deltas[first_morph_delta + i * morph_vertex_count + gl_VertexIndex];
+            float weight = morph_weights[weight_offset + i];
+            pos += weight * delta.position.xyz;
+            prev_pos += prev_morph_weights[weight_offset + i] * delta.position.xyz;
+            norm += weight * delta.normal.xyz;
+            tang += weight * delta.tangent.xyz;
+        }
+    }
+
     mat4 model_mat = mat4(model[0], model[1], model[2], model[3]);
+    mat4 prev_model_mat = mat4(prev_model[0], prev_model[1], prev_model[2], prev_model[3]);
+    // Skinned vertices are moved by their joints alone, which already include the transform
+    // of the instance
+    int joint_offset = deformation_offsets.x;
+    if (joint_offset >= 0) {
+        ivec4 j = joint_offset + ivec4(a_joints);
+        model_mat = a_weights.x * joints[j.x] + a_weights.y * joints[j.y]
+            + a_weights.z * joints[j.z] + a_weights.w * joints[j.w];
+        prev_model_mat = a_weights.x * prev_joints[j.x] + a_weights.y * prev_joints[j.y]
+            + a_weights.z * prev_joints[j.z] + a_weights.w * prev_joints[j.w];
+    }
+
     frag_uv = a_uv;
-    frag_norm = normalize((model_mat * vec4(a_norm, 0.0)).xyz);
-    frag_tang = normalize((model_mat * vec4(a_tang.xyz, 0.0)).xyz);
-    frag_tbn_handedness = a_tang.w;
-    frag_world_pos = model_mat * vec4(a_pos, 1.0);
+    frag_uv1 = a_uv1;
+    // Normals are transformed by the inverse transpose to stay perpendicular to the surface
+    // under non-uniform scale, and mirroring flips the handedness of the tangent frame
+    mat3 linear = mat3(model_mat);
+    frag_norm = normalize(transpose(inverse(linear)) * norm);
+    frag_tang = normalize(linear * tang);
+    frag_tbn_handedness = determinant(linear) < 0.0 ? -a_tang.w : a_tang.w;
+    frag_world_pos = model_mat * vec4(pos, 1.0);
     gl_Position = proj * view * frag_world_pos;
+    // Only used by point primitives
+    gl_PointSize = 1.0;
+    // The jitter is the same for both positions, so it cancels out of the motion vector
+    frag_clip_pos = gl_Position;
+    frag_prev_clip_pos = proj * prev_view * prev_model_mat * vec4(prev_pos, 1.0);
 }
\ No newline at end of file
@@ -0,0 +1,47 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 f_uv;
+layout(location = 1) in vec2 f_uv1;
+
+layout(set = 1, binding = 0) uniform texture2D albedo_map;
+layout(set = 1, binding = 1) uniform sampler albedo_sampler;
+
+// See pbr.frag
+struct TextureTransform {
+    vec3 u;
+    int tex_coord;
+    vec3 v;
+};
+
+const int TEXTURE_ALBEDO = 0;
+
+const int ALPHA_MASK = 1;
+
+layout(std140, set = 1, binding = 2) uniform MatData {
+    vec4 base_color_factor;
+    vec3 emissive_factor;
+    float metallic_factor;
+    float roughness_factor;
+    float normal_scale;
+    float occlusion_strength;
+    float emissive_strength;
+    TextureTransform texture_transforms[5];
+    float alpha_cutoff;
+    int alpha_mode;
+    int double_sided;
+};
+
+// Depth only, masked materials only cast shadows where they are drawn
+void main() {
+    if (alpha_mode != ALPHA_MASK) {
+        return;
+    }
+    TextureTransform transform = texture_transforms[TEXTURE_ALBEDO];
+    vec3 uv = vec3(transform.tex_coord == 1 ? f_uv1 : f_uv, 1.0);
+    vec2 albedo_uv = vec2(dot(transform.u, uv), dot(transform.v, uv));
+    float alpha = texture(sampler2D(albedo_map, albedo_sampler), albedo_uv).a * base_color_factor.a;
+    if (alpha < alpha_cutoff) {
+        discard;
+    }
+}
@@ -0,0 +1,63 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec3 a_pos;
+layout(location = 3) in vec2 a_uv;
+layout(location = 4) in uvec4 a_joints;
+layout(location = 5) in vec4 a_weights;
+// vec4[4] is used instead of mat4 due to spirv-cross bug for dx12 backend
+layout(location = 6) in vec4 model[4]; // per-instance.
+// per-instance, the first joint matrix and morph target weight of the instance, -1 if unused.
+layout(location = 10) in ivec2 deformation_offsets;
+layout(location = 11) in vec2 a_uv1;
+
+layout(std430, set = 0, binding = 0) readonly buffer Joints {
+    mat4 joints[];
+};
+
+struct MorphDelta {
+    vec4 position;
+    vec4 normal;
+    vec4 tangent;
+};
+
+layout(std430, set = 0, binding = 1) readonly buffer MorphDeltas {
+    MorphDelta morph_deltas[];
+};
+
+layout(std430, set = 0, binding = 2) readonly buffer MorphWeights {
+    float morph_weights[];
+};
+
+layout(push_constant) uniform LightArgs {
+    mat4 view_proj;
+    // The morph targets of the primitive, see pbr.vert
+    int first_morph_delta;
+    int morph_target_count;
+    int morph_vertex_count;
+};
+
+layout(location = 0) out vec2 frag_uv;
+layout(location = 1) out vec2 frag_uv1;
+
+void main() {
+    vec3 pos = a_pos;
+    int weight_offset = deformation_offsets.y;
+    if (weight_offset >= 0) {
+        for (int i = 0; i < morph_target_count; i++) {
+            int delta = first_morph_delta + i * morph_vertex_count + gl_VertexIndex;
+            pos += morph_weights[weight_offset + i] * morph_deltas[delta].position.xyz;
+        }
+    }
+
+    mat4 model_mat = mat4(model[0], model[1], model[2], model[3]);
+    int joint_offset = deformation_offsets.x;
+    if (joint_offset >= 0) {
+        ivec4 j = joint_offset + ivec4(a_joints);
+        model_mat = a_weights.x * joints[j.x] + a_weights.y * joints[j.y]
+            + a_weights.z * joints[j.z] + a_weights.w * joints[j.w];
+    }
+    gl_Position = view_proj * model_mat * vec4(pos, 1.0);
+    frag_uv = a_uv;
+    frag_uv1 = a_uv1;
+}
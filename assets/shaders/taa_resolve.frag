@@ -0,0 +1,60 @@
+#version 450
+#extension GL_ARB_separate_shader_objects : enable
+
+layout(location = 0) in vec2 f_uv;
+
+layout(set = 0, binding = 0) uniform sampler tex_sampler;
+layout(set = 0, binding = 1) uniform texture2D hdr_tex;
+layout(set = 0, binding = 2) uniform texture2D velocity_tex;
+layout(set = 0, binding = 3) uniform texture2D history_tex;
+
+layout(push_constant) uniform Args {
+    // Weight of the history, 0 when temporal anti-aliasing is disabled
+    float feedback;
+};
+
+layout(location = 0) out vec4 color;
+
+float luminance(const vec3 c) {
+    return dot(c, vec3(0.2126, 0.7152, 0.0722));
+}
+
+// Blending in a compressed range keeps single very bright samples from dominating the
+// result, which would otherwise flicker as the jitter moves over them
+vec3 compress(const vec3 c) {
+    return c / (1.0 + luminance(c));
+}
+
+vec3 uncompress(const vec3 c) {
+    return c / max(1.0 - luminance(c), 0.0001);
+}
+
+void main() {
+    ivec2 size = textureSize(sampler2D(hdr_tex, tex_sampler), 0);
+    ivec2 pixel = min(ivec2(f_uv * vec2(size)), size - 1);
+    vec3 current = texelFetch(sampler2D(hdr_tex, tex_sampler), pixel, 0).rgb;
+
+    vec2 velocity = texelFetch(sampler2D(velocity_tex, tex_sampler), pixel, 0).xy;
+    vec2 history_uv = f_uv - velocity;
+    if (feedback <= 0.0 || any(lessThan(history_uv, vec2(0.0))) || any(greaterThan(history_uv, vec2(1.0)))) {
+        color = vec4(current, 1.0);
+        return;
+    }
+
+    current = compress(current);
+    vec3 neighborhood_min = current;
+    vec3 neighborhood_max = current;
+    for (int x = -1; x <= 1; ++x) {
+        for (int y = -1; y <= 1; ++y) {
+            ivec2 neighbor = clamp(pixel + ivec2(x, y), ivec2(0), size - 1);
+            vec3 c = compress(texelFetch(sampler2D(hdr_tex, tex_sampler), neighbor, 0).rgb);
+            neighborhood_min = min(neighborhood_min, c);
+            neighborhood_max = max(neighborhood_max, c);
+        }
+    }
+
+    vec3 history = compress(texture(sampler2D(history_tex, tex_sampler), history_uv).rgb);
+    history = clamp(history, neighborhood_min, neighborhood_max);
+
+    color = vec4(uncompress(mix(current, history, feedback)), 1.0);
+}
@@ -8,28 +8,74 @@ layout(set = 0, binding = 1) uniform texture2D hdr_tex;
 
 layout(std140, set = 0, binding = 2) uniform Args {
     float exposure;
-    int curve;
+    int tonemap_operator;
+    int comparison_operator;
     float comparison_factor;
+    float reinhard_white_point;
+    // Hable curve
+    float shoulder_strength;
+    float linear_strength;
+    float linear_angle;
+    float toe_strength;
+    float toe_numerator;
+    float toe_denominator;
+    float hable_white_point;
+    float bloom_intensity;
+    // Color grading
+    vec3 lift;
+    float temperature;
+    vec3 gamma;
+    float tint;
+    vec3 gain;
+    float contrast;
+    float saturation;
+    float lut_contribution;
+    vec3 lut_domain_min;
+    vec3 lut_domain_max;
+    // Automatic exposure
+    int auto_exposure;
+    float exposure_compensation;
 };
+layout(set = 0, binding = 3) uniform texture2D bloom_tex;
+layout(set = 0, binding = 4) uniform texture3D lut_tex;
+layout(set = 0, binding = 5) uniform sampler lut_sampler;
+layout(set = 0, binding = 6) uniform texture2D adapted_luminance_tex;
 
 layout(location = 0) out vec4 color;
 
-vec3 uncharted2Tonemap(const vec3 x) {
-	const float A = 0.15;
-	const float B = 0.50;
-	const float C = 0.10;
-	const float D = 0.20;
-	const float E = 0.02;
-	const float F = 0.30;
-	return ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F;
+// Operator indices, matching TonemapOperator
+const int REINHARD = 0;
+const int REINHARD_EXTENDED = 1;
+const int HABLE = 2;
+const int ACES_FITTED = 3;
+const int AGX = 4;
+const int PBR_NEUTRAL = 5;
+
+vec3 reinhard(const vec3 color) {
+    return color / (1.0 + color);
+}
+
+// Reinhard with the luminance mapped to white given by the white point
+vec3 reinhard_extended(const vec3 color) {
+    vec3 numerator = color * (1.0 + color / (reinhard_white_point * reinhard_white_point));
+    return numerator / (1.0 + color);
+}
+
+vec3 hable_partial(const vec3 x) {
+    float A = shoulder_strength;
+    float B = linear_strength;
+    float C = linear_angle;
+    float D = toe_strength;
+    float E = toe_numerator;
+    float F = toe_denominator;
+    return ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F;
 }
 
 // http://filmicworlds.com/blog/filmic-tonemapping-operators/
 // outputs LINEAR tonemapped data, so should still be used with an SRGB render target
-vec3 tonemapUncharted2(const vec3 color) {
-	const float W = 11.2; // Hardcoded white point
-	vec3 curr = uncharted2Tonemap(color);
-	vec3 whiteScale = 1.0 / uncharted2Tonemap(vec3(W));
+vec3 hable(const vec3 color) {
+	vec3 curr = hable_partial(color);
+	vec3 whiteScale = 1.0 / hable_partial(vec3(hable_white_point));
 	return curr * whiteScale;
 }
 
@@ -72,24 +118,164 @@ vec3 aces_fitted(vec3 color)
     return color;
 }
 
+// Minimal AgX fit by Benjamin Wrensch
+// https://iolite-engine.com/blog_posts/minimal_agx_implementation
+vec3 agx_contrast_approx(vec3 x) {
+    vec3 x2 = x * x;
+    vec3 x4 = x2 * x2;
+    return 15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2
+        + 0.1191 * x - 0.00232;
+}
+
+vec3 agx(vec3 color) {
+    const mat3 AgXInset = mat3(
+        0.842479062253094, 0.0423282422610123, 0.0423756549057051,
+        0.0784335999999992, 0.878468636469772, 0.0784336,
+        0.0792237451477643, 0.0791661274605434, 0.879142973793104
+    );
+    const mat3 AgXOutset = mat3(
+        1.19687900512017, -0.0528968517574562, -0.0529716355144438,
+        -0.0980208811401368, 1.15190312990417, -0.0980434501171241,
+        -0.0990297440797205, -0.0989611768448433, 1.15107367264116
+    );
+    const float min_ev = -12.47393;
+    const float max_ev = 4.026069;
+
+    color = AgXInset * color;
+    color = clamp(log2(max(color, 1e-10)), min_ev, max_ev);
+    color = (color - min_ev) / (max_ev - min_ev);
+    color = agx_contrast_approx(color);
+    color = AgXOutset * color;
+
+    // The curve outputs display encoded values, decode them to linear
+    return pow(clamp(color, 0.0, 1.0), vec3(2.2));
+}
+
+// https://github.com/KhronosGroup/ToneMapping/tree/main/PBR_Neutral
+vec3 pbr_neutral(vec3 color) {
+    const float start_compression = 0.8 - 0.04;
+    const float desaturation = 0.15;
+
+    float x = min(color.r, min(color.g, color.b));
+    float offset = x < 0.08 ? x - 6.25 * x * x : 0.04;
+    color -= offset;
+
+    float peak = max(color.r, max(color.g, color.b));
+    if (peak < start_compression) {
+        return color;
+    }
+
+    const float d = 1.0 - start_compression;
+    float new_peak = 1.0 - d * d / (peak + d - start_compression);
+    color *= new_peak / peak;
+
+    float g = 1.0 - 1.0 / (desaturation * (peak - new_peak) + 1.0);
+    return mix(color, vec3(new_peak), g);
+}
+
+vec3 tonemap(int operator_index, vec3 color) {
+    switch (operator_index) {
+        case REINHARD:
+            return reinhard(color);
+        case REINHARD_EXTENDED:
+            return reinhard_extended(color);
+        case HABLE:
+            return hable(color);
+        case ACES_FITTED:
+            return aces_fitted(color);
+        case AGX:
+            return agx(color);
+        case PBR_NEUTRAL:
+            return pbr_neutral(color);
+        default:
+            return color;
+    }
+}
+
+// White balance adapted from Unity's post processing stack. The shift is applied in LMS
+// space, moving the white point from D65 to the given temperature and tint.
+const mat3 LinearToLMS = mat3(
+    3.90405e-1, 5.49941e-1, 8.92632e-3,
+    7.08416e-2, 9.63172e-1, 1.35775e-3,
+    2.31082e-2, 1.28021e-1, 9.36245e-1
+);
+
+const mat3 LMSToLinear = mat3(
+    2.85847e+0, -1.62879e+0, -2.48910e-2,
+    -2.10182e-1, 1.15820e+0, 3.24281e-4,
+    -4.18120e-2, -1.18169e-1, 1.06867e+0
+);
+
+vec3 white_balance(vec3 color) {
+    float t1 = temperature * 10.0 / 6.0;
+    float t2 = tint * 10.0 / 6.0;
+
+    // CIE xy chromaticity of the target white point
+    float x = 0.31271 - t1 * (t1 < 0.0 ? 0.1 : 0.05);
+    float standard_illuminant_y = 2.87 * x - 3.0 * x * x - 0.27509507;
+    float y = standard_illuminant_y + t2 * 0.05;
+
+    // xy to LMS, with Y = 1
+    vec3 xyz = vec3(x / y, 1.0, (1.0 - x - y) / y);
+    vec3 lms = vec3(
+        0.7328 * xyz.x + 0.4296 * xyz.y - 0.1624 * xyz.z,
+        -0.7036 * xyz.x + 1.6975 * xyz.y + 0.0061 * xyz.z,
+        0.0030 * xyz.x + 0.0136 * xyz.y + 0.9834 * xyz.z
+    );
+    const vec3 d65_lms = vec3(0.949237, 1.03542, 1.08728);
+
+    // The matrices are written row by row, so vectors are multiplied from the left
+    vec3 balanced = (color * LinearToLMS) * (d65_lms / lms);
+    return balanced * LMSToLinear;
+}
+
+vec3 linear_to_srgb(vec3 color) {
+    return mix(color * 12.92, 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055, step(0.0031308, color));
+}
+
+vec3 srgb_to_linear(vec3 color) {
+    return mix(color / 12.92, pow((color + 0.055) / 1.055, vec3(2.4)), step(0.04045, color));
+}
+
+// Grades the tonemapped color in display space, where lookup tables are usually authored
+vec3 color_grade(vec3 color) {
+    vec3 display = linear_to_srgb(clamp(color, 0.0, 1.0));
+
+    display = gain * (display + lift * (1.0 - display));
+    display = pow(max(display, 0.0), 1.0 / max(gamma, vec3(0.0001)));
+    display = (display - 0.5) * contrast + 0.5;
+    float luma = dot(display, vec3(0.2126, 0.7152, 0.0722));
+    display = clamp(mix(vec3(luma), display, saturation), 0.0, 1.0);
+
+    // Sample at texel centers so that the domain bounds map exactly onto the outer entries
+    float lut_size = float(textureSize(sampler3D(lut_tex, lut_sampler), 0).x);
+    vec3 lut_uv = clamp((display - lut_domain_min) / (lut_domain_max - lut_domain_min), 0.0, 1.0);
+    lut_uv = lut_uv * ((lut_size - 1.0) / lut_size) + 0.5 / lut_size;
+    vec3 graded = texture(sampler3D(lut_tex, lut_sampler), lut_uv).rgb;
+    display = mix(display, graded, lut_contribution);
+
+    return srgb_to_linear(clamp(display, 0.0, 1.0));
+}
+
 void main() {
     vec2 uv = f_uv;
     uv.y = 1.0 - uv.y;
     vec3 hdrColor = texture(sampler2D(hdr_tex, tex_sampler), uv).rgb;
+    vec3 bloom = texture(sampler2D(bloom_tex, tex_sampler), uv).rgb;
+    hdrColor += bloom * bloom_intensity;
 
-    hdrColor *= exposure; // exposure
-
-    float factor;
-
-    if (curve == 0) {
-        factor = 0.0;
-    } else if (curve == 1) {
-        factor = 1.0;
-    } else if (curve == 2) {
-        factor = comparison_factor;
+    if (auto_exposure != 0) {
+        // Maps the adapted luminance to middle grey
+        float adapted = texelFetch(sampler2D(adapted_luminance_tex, tex_sampler), ivec2(0), 0).r;
+        hdrColor *= 0.18 / exp2(adapted) * exp2(exposure_compensation);
+    } else {
+        hdrColor *= exposure; // exposure
     }
+    hdrColor = max(white_balance(hdrColor), 0.0);
 
-    vec3 mapped = mix(aces_fitted(hdrColor), tonemapUncharted2(hdrColor), step(uv.x, factor));
+    // The comparison operator is shown left of the split
+    int operator_index = uv.x < comparison_factor ? comparison_operator : tonemap_operator;
+    vec3 mapped = tonemap(operator_index, hdrColor);
 
-    color = vec4(mapped, 1.0);
+    color = vec4(color_grade(mapped), 1.0);
 }
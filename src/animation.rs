@@ -0,0 +1,407 @@
+//! Playback of glTF animations. Each channel of a clip animates the translation, rotation,
+//! scale or morph target weights of the entities created from its target node, and the clips
+//! loop independently.
+use nalgebra::{Quaternion, Translation3, UnitQuaternion, Vector3};
+use specs::prelude::*;
+
+use std::{collections::HashMap, time::Instant};
+
+use crate::{asset, components};
+
+/// Entities created from each node of a glTF file, by node index
+pub type NodeEntities = HashMap<usize, Vec<Entity>>;
+
+/// The transform component or morph target weights a channel animates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Property {
+    Translation,
+    Rotation,
+    Scale,
+    Weights,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    Linear,
+    Step,
+    /// Each keyframe has an in-tangent, a value and an out-tangent
+    CubicSpline,
+}
+
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub targets: Vec<Entity>,
+    pub property: Property,
+    pub interpolation: Interpolation,
+    /// Keyframe times in seconds, in increasing order
+    pub times: Vec<f32>,
+    /// Number of floats in each value: 3 for translations and scales, 4 for rotations as
+    /// `[x, y, z, w]` and one per morph target for weights
+    pub width: usize,
+    /// Keyframe values, `width` floats each. Cubic spline channels have three values per
+    /// keyframe.
+    pub values: Vec<f32>,
+}
+
+impl Channel {
+    /// The value of the channel at `time`, holding the first and last keyframes outside of
+    /// the keyframe times
+    pub fn sample(&self, time: f32) -> Vec<f32> {
+        let value = |i: usize| {
+            let i = match self.interpolation {
+                Interpolation::CubicSpline => i * 3 + 1,
+                _ => i,
+            };
+            &self.values[i * self.width..(i + 1) * self.width]
+        };
+
+        let last = self.times.len() - 1;
+        if time <= self.times[0] {
+            return value(0).to_vec();
+        }
+        if time >= self.times[last] {
+            return value(last).to_vec();
+        }
+
+        // The keyframe at or before `time`
+        let i = match self
+            .times
+            .binary_search_by(|t| t.partial_cmp(&time).unwrap())
+        {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let delta = self.times[i + 1] - self.times[i];
+        let s = (time - self.times[i]) / delta;
+
+        match self.interpolation {
+            Interpolation::Step => value(i).to_vec(),
+            Interpolation::Linear => {
+                let (start, end) = (value(i), value(i + 1));
+                // Take the shorter way around between the two rotations
+                let dot = start.iter().zip(end).map(|(a, b)| a * b).sum::<f32>();
+                let end_sign = if self.property == Property::Rotation && dot < 0.0 {
+                    -1.0
+                } else {
+                    1.0
+                };
+                start
+                    .iter()
+                    .zip(end)
+                    .map(|(start, end)| start * (1.0 - s) + end * end_sign * s)
+                    .collect()
+            }
+            Interpolation::CubicSpline => {
+                let tangent = |i: usize| &self.values[i * self.width..(i + 1) * self.width];
+                let start = value(i);
+                let start_out_tangent = tangent(i * 3 + 2);
+                let end = value(i + 1);
+                let end_in_tangent = tangent((i + 1) * 3);
+                let s2 = s * s;
+                let s3 = s2 * s;
+                (0..self.width)
+                    .map(|c| {
+                        start[c] * (2.0 * s3 - 3.0 * s2 + 1.0)
+                            + start_out_tangent[c] * delta * (s3 - 2.0 * s2 + s)
+                            + end[c] * (-2.0 * s3 + 3.0 * s2)
+                            + end_in_tangent[c] * delta * (s3 - s2)
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Applies a value returned by `sample` to the transform or morph weights of a target
+    fn apply(
+        &self,
+        value: &[f32],
+        transform: Option<&mut components::Transform>,
+        morph_weights: Option<&mut components::MorphWeights>,
+    ) {
+        match (self.property, transform, morph_weights) {
+            (Property::Translation, Some(transform), _) => {
+                transform.isometry.translation = Translation3::new(value[0], value[1], value[2]);
+            }
+            (Property::Rotation, Some(transform), _) => {
+                transform.isometry.rotation = UnitQuaternion::from_quaternion(Quaternion::new(
+                    value[3], value[0], value[1], value[2],
+                ));
+            }
+            (Property::Scale, Some(transform), _) => {
+                transform.scale = Vector3::new(value[0], value[1], value[2]);
+            }
+            (Property::Weights, _, Some(morph_weights)) => {
+                for (weight, value) in morph_weights.weights.iter_mut().zip(value) {
+                    *weight = *value;
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    pub name: Option<String>,
+    pub channels: Vec<Channel>,
+    /// Time of the last keyframe of any channel, after which the clip loops
+    pub duration: f32,
+}
+
+impl AnimationClip {
+    /// Reads `animation`, targeting the entities in `node_entities`. Channels targeting nodes
+    /// without entities are skipped.
+    pub fn load(
+        animation: &gltf::Animation<'_>,
+        buffers: &asset::GltfBuffers,
+        node_entities: &NodeEntities,
+    ) -> Result<Self, failure::Error> {
+        use gltf::animation::util::ReadOutputs;
+
+        let mut channels = Vec::new();
+        for channel in animation.channels() {
+            let targets = match node_entities.get(&channel.target().node().index()) {
+                Some(targets) => targets.clone(),
+                None => continue,
+            };
+
+            let reader = channel.reader(|buffer| buffers.buffer(&buffer));
+            let times = reader
+                .read_inputs()
+                .ok_or(failure::format_err!(
+                    "Animation channel has no keyframe times"
+                ))?
+                .collect::<Vec<_>>();
+            // Sampling searches the times and divides by the difference between them
+            if times.iter().any(|time| !time.is_finite()) {
+                failure::bail!("Animation channel has a keyframe time which is not finite");
+            }
+            if times.windows(2).any(|pair| pair[0] >= pair[1]) {
+                failure::bail!("Animation channel keyframe times are not increasing");
+            }
+            let (property, values) = match reader.read_outputs().ok_or(failure::format_err!(
+                "Animation channel has no keyframe values"
+            ))? {
+                ReadOutputs::Translations(translations) => (
+                    Property::Translation,
+                    translations
+                        .flat_map(|value| value.to_vec())
+                        .collect::<Vec<_>>(),
+                ),
+                ReadOutputs::Rotations(rotations) => (
+                    Property::Rotation,
+                    rotations
+                        .into_f32()
+                        .flat_map(|value| value.to_vec())
+                        .collect::<Vec<_>>(),
+                ),
+                ReadOutputs::Scales(scales) => (
+                    Property::Scale,
+                    scales.flat_map(|value| value.to_vec()).collect::<Vec<_>>(),
+                ),
+                ReadOutputs::MorphTargetWeights(weights) => {
+                    (Property::Weights, weights.into_f32().collect::<Vec<_>>())
+                }
+            };
+
+            let interpolation = match channel.sampler().interpolation() {
+                gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                gltf::animation::Interpolation::Step => Interpolation::Step,
+                gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+            };
+            let values_per_keyframe = match interpolation {
+                Interpolation::CubicSpline => 3,
+                _ => 1,
+            };
+            let width = match property {
+                Property::Translation | Property::Scale => 3,
+                Property::Rotation => 4,
+                // The outputs hold a weight for every target of every keyframe
+                Property::Weights => values.len() / (times.len() * values_per_keyframe).max(1),
+            };
+            if times.is_empty()
+                || width == 0
+                || values.len() != times.len() * values_per_keyframe * width
+            {
+                failure::bail!(
+                    "Animation channel has {} keyframe times but {} values",
+                    times.len(),
+                    values.len() / width.max(1)
+                );
+            }
+
+            channels.push(Channel {
+                targets,
+                property,
+                interpolation,
+                times,
+                width,
+                values,
+            });
+        }
+
+        let duration = channels
+            .iter()
+            .map(|channel| channel.times[channel.times.len() - 1])
+            .fold(0.0, f32::max);
+
+        Ok(AnimationClip {
+            name: animation.name().map(String::from),
+            channels,
+            duration,
+        })
+    }
+}
+
+/// The animation clips playing in the scene
+#[derive(Debug, Default)]
+pub struct Animations {
+    pub clips: Vec<AnimationClip>,
+    /// Time since the clips started playing, in seconds
+    pub time: f32,
+}
+
+/// Advances the playing animations and writes the animated values to the `Transform`s of the
+/// target entities
+#[derive(Default)]
+pub struct AnimationSystem {
+    last_frame: Option<Instant>,
+}
+
+impl<'a> System<'a> for AnimationSystem {
+    type SystemData = (
+        Write<'a, Animations>,
+        WriteStorage<'a, components::Transform>,
+        WriteStorage<'a, components::MorphWeights>,
+    );
+
+    fn run(&mut self, (mut animations, mut transforms, mut morph_weights): Self::SystemData) {
+        let now = Instant::now();
+        if let Some(last_frame) = self.last_frame {
+            let elapsed = now - last_frame;
+            animations.time +=
+                elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1_000_000_000.0;
+        }
+        self.last_frame = Some(now);
+
+        let time = animations.time;
+        for clip in animations.clips.iter() {
+            let clip_time = if clip.duration > 0.0 {
+                time % clip.duration
+            } else {
+                0.0
+            };
+            for channel in clip.channels.iter() {
+                let value = channel.sample(clip_time);
+                for target in channel.targets.iter() {
+                    channel.apply(
+                        &value,
+                        transforms.get_mut(*target),
+                        morph_weights.get_mut(*target),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_channel(
+        property: Property,
+        interpolation: Interpolation,
+        times: Vec<f32>,
+        width: usize,
+        values: Vec<f32>,
+    ) -> Channel {
+        Channel {
+            targets: Vec::new(),
+            property,
+            interpolation,
+            times,
+            width,
+            values,
+        }
+    }
+
+    #[test]
+    fn step_holds_previous_keyframe() {
+        let channel = new_channel(
+            Property::Weights,
+            Interpolation::Step,
+            vec![0.0, 1.0, 2.0],
+            1,
+            vec![0.0, 10.0, 20.0],
+        );
+        assert_eq!(channel.sample(0.99), vec![0.0]);
+        assert_eq!(channel.sample(1.0), vec![10.0]);
+        assert_eq!(channel.sample(1.5), vec![10.0]);
+    }
+
+    #[test]
+    fn linear_interpolates_componentwise() {
+        let channel = new_channel(
+            Property::Translation,
+            Interpolation::Linear,
+            vec![0.0, 2.0],
+            3,
+            vec![1.0, 0.0, 2.0, -1.0, 4.0, 2.0],
+        );
+        assert_eq!(channel.sample(0.5), vec![0.5, 1.0, 2.0]);
+        assert_eq!(channel.sample(1.0), vec![0.0, 2.0, 2.0]);
+    }
+
+    #[test]
+    fn linear_rotation_takes_shorter_way() {
+        // The same rotation with opposite signs, so there is nothing to interpolate
+        let channel = new_channel(
+            Property::Rotation,
+            Interpolation::Linear,
+            vec![0.0, 1.0],
+            4,
+            vec![0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, -1.0],
+        );
+        assert_eq!(channel.sample(0.5), vec![0.0, 0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn cubic_spline_uses_tangents_scaled_by_keyframe_interval() {
+        // In-tangent, value and out-tangent of each keyframe
+        let channel = new_channel(
+            Property::Weights,
+            Interpolation::CubicSpline,
+            vec![0.0, 2.0],
+            1,
+            vec![0.0, 0.0, 2.0, 0.0, 4.0, 0.0],
+        );
+        assert_eq!(channel.sample(0.0), vec![0.0]);
+        // Halfway, the values contribute 0.5 each and the out-tangent 0.125 per unit of time
+        assert_eq!(channel.sample(1.0), vec![2.5]);
+        assert_eq!(channel.sample(2.0), vec![4.0]);
+    }
+
+    #[test]
+    fn clamps_outside_keyframe_times() {
+        let channel = new_channel(
+            Property::Weights,
+            Interpolation::CubicSpline,
+            vec![1.0, 2.0],
+            1,
+            vec![5.0, 1.0, 5.0, 5.0, 3.0, 5.0],
+        );
+        assert_eq!(channel.sample(0.0), vec![1.0]);
+        assert_eq!(channel.sample(3.0), vec![3.0]);
+
+        let channel = new_channel(
+            Property::Weights,
+            Interpolation::Linear,
+            vec![1.0],
+            1,
+            vec![7.0],
+        );
+        assert_eq!(channel.sample(0.0), vec![7.0]);
+        assert_eq!(channel.sample(2.0), vec![7.0]);
+    }
+}
@@ -6,23 +6,108 @@ use rendy::{
     factory::{BufferState, Factory, ImageState},
     memory::MemoryUsageValue,
     mesh::PosNormTangTex,
-    resource::{Buffer, BufferInfo, Escape},
+    resource::{Buffer, BufferInfo, Escape, Filter, SamplerDesc, WrapMode},
     texture::{
         image::{ImageTextureConfig, Repr},
         Texture, TextureBuilder,
     },
 };
 
-use std::{collections::HashMap, fs::File, io::Read, path::Path};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::{components, geometry, gltf_extensions, Backend};
+
+/// Maps the texture coordinates of a vertex to those a texture is sampled at, as the rows of
+/// a 2x3 matrix. Laid out as the std140 `TextureTransform` struct of `pbr.frag`.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct TextureTransform {
+    pub u: [f32; 3],
+    /// The texture coordinate set, 0 or 1
+    pub tex_coord: i32,
+    pub v: [f32; 3],
+    _pad: f32,
+}
+
+impl TextureTransform {
+    /// The transform of a texture using coordinate set `tex_coord`, with an optional
+    /// `KHR_texture_transform` applied
+    pub fn new(tex_coord: u32, transform: Option<gltf_extensions::KhrTextureTransform>) -> Self {
+        let transform = transform.unwrap_or_default();
+        let tex_coord = transform.tex_coord.unwrap_or(tex_coord);
+        if tex_coord > 1 {
+            log::warn!(
+                "Texture coordinate set {} is not supported, using set 0",
+                tex_coord
+            );
+        }
+        // translation * rotation * scale
+        let (sin, cos) = transform.rotation.sin_cos();
+        let [sx, sy] = transform.scale;
+        let [ox, oy] = transform.offset;
+        TextureTransform {
+            u: [cos * sx, sin * sy, ox],
+            tex_coord: if tex_coord == 1 { 1 } else { 0 },
+            v: [-sin * sx, cos * sy, oy],
+            _pad: 0.0,
+        }
+    }
+}
 
-use crate::Backend;
+impl Default for TextureTransform {
+    fn default() -> Self {
+        TextureTransform::new(0, None)
+    }
+}
 
+/// The factors of a material, multiplied with its textures. Laid out as the std140 `MatData`
+/// uniform of `pbr.frag`.
 #[derive(Clone, Copy, Default)]
 #[repr(C, align(16))]
 pub struct MaterialFactors {
     pub albedo: [f32; 4],
+    pub emissive: [f32; 3],
     pub metallic: f32,
     pub roughness: f32,
+    pub normal_scale: f32,
+    pub occlusion_strength: f32,
+    pub emissive_strength: f32,
+    /// Indexed by the `TEXTURE_*` constants
+    pub texture_transforms: [TextureTransform; 5],
+    /// Fragments of `ALPHA_MASK` materials with a lower base color alpha are discarded
+    pub alpha_cutoff: f32,
+    /// One of the `ALPHA_*` constants
+    pub alpha_mode: i32,
+    /// Set if back faces are drawn too, with their normals reversed
+    pub double_sided: i32,
+}
+
+impl MaterialFactors {
+    pub const ALPHA_OPAQUE: i32 = 0;
+    pub const ALPHA_MASK: i32 = 1;
+    pub const ALPHA_BLEND: i32 = 2;
+
+    pub const TEXTURE_ALBEDO: usize = 0;
+    pub const TEXTURE_NORMAL: usize = 1;
+    pub const TEXTURE_METALLIC_ROUGHNESS: usize = 2;
+    pub const TEXTURE_AO: usize = 3;
+    pub const TEXTURE_EMISSIVE: usize = 4;
+
+    /// Whether the material is drawn in the transparent pass
+    #[inline]
+    pub fn is_transparent(&self) -> bool {
+        self.alpha_mode == Self::ALPHA_BLEND
+    }
+
+    #[inline]
+    pub fn is_double_sided(&self) -> bool {
+        self.double_sided != 0
+    }
 }
 
 pub struct MaterialData<B: hal::Backend> {
@@ -32,19 +117,106 @@ pub struct MaterialData<B: hal::Backend> {
     pub metallic_roughness: Texture<B>,
     pub ao: Texture<B>,
     pub emissive: Texture<B>,
-    pub emissive_factor_buffer: Escape<Buffer<B>>,
+    /// `factors`, bound as a uniform buffer
+    pub factors_buffer: Escape<Buffer<B>>,
 }
 
-#[derive(Default)]
+#[derive(Derivative)]
+#[derivative(Default(bound = ""))]
 pub struct MaterialStorage<B: hal::Backend>(pub Vec<MaterialData<B>>);
 pub type MaterialHandle = usize;
 
+/// The joints influencing a vertex and their weights, read from JOINTS_0 and WEIGHTS_0.
+/// Vertices of primitives without a skin have zero weights.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct SkinVertex {
+    pub joints: [u16; 4],
+    pub weights: [f32; 4],
+}
+
+impl SkinVertex {
+    /// The vertex buffer layout, with the joints and weights in consecutive locations
+    pub fn vertex_input_desc() -> (
+        Vec<hal::pso::Element<hal::format::Format>>,
+        hal::pso::ElemStride,
+        hal::pso::VertexInputRate,
+    ) {
+        (
+            vec![
+                hal::pso::Element {
+                    format: hal::format::Format::Rgba16Uint,
+                    offset: 0,
+                },
+                hal::pso::Element {
+                    format: hal::format::Format::Rgba32Sfloat,
+                    offset: std::mem::size_of::<[u16; 4]>() as u32,
+                },
+            ],
+            std::mem::size_of::<SkinVertex>() as hal::pso::ElemStride,
+            hal::pso::VertexInputRate::Vertex,
+        )
+    }
+}
+
+/// The displacement of one vertex by one morph target. Vectors are padded to a vec4 so the
+/// deltas can be read from a std430 storage buffer.
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct MorphDelta {
+    pub position: [f32; 4],
+    pub normal: [f32; 4],
+    pub tangent: [f32; 4],
+}
+
+/// The morph target deltas of every primitive, uploaded to a storage buffer by the mesh
+/// pipelines
+#[derive(Default)]
+pub struct MorphTargetStorage(pub Vec<MorphDelta>);
+
+/// The range of a primitive's deltas in `MorphTargetStorage`. The deltas of each target are
+/// stored one after the other, with one delta per vertex.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MorphTargets {
+    pub first_delta: u32,
+    /// The number of targets, 0 if the primitive has none
+    pub count: u32,
+    pub vertex_count: u32,
+}
+
 pub struct Primitive<B: hal::Backend> {
     pub mesh_data: rendy::mesh::Mesh<B>,
+    /// A `SkinVertex` for every vertex of `mesh_data`
+    pub skin_vertices: Escape<Buffer<B>>,
+    /// The second texture coordinate set of every vertex, a copy of the first if the
+    /// primitive has only one
+    pub tex_coords_1: Escape<Buffer<B>>,
+    pub morph_targets: MorphTargets,
+    pub topology: Topology,
     pub mesh_handle: MeshHandle,
     pub mat: MaterialHandle,
 }
 
+/// How the indices of a primitive are assembled. Strips, fans and loops are converted to lists
+/// when they are loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Topology {
+    Triangles,
+    Lines,
+    Points,
+}
+
+impl Topology {
+    /// The primitive type of pipelines drawing this topology
+    pub fn primitive(self) -> hal::Primitive {
+        match self {
+            Topology::Triangles => hal::Primitive::TriangleList,
+            Topology::Lines => hal::Primitive::LineList,
+            Topology::Points => hal::Primitive::PointList,
+        }
+    }
+}
+
 #[derive(Derivative)]
 #[derivative(Default(bound = ""))]
 pub struct PrimitiveStorage<B: hal::Backend>(pub Vec<Primitive<B>>);
@@ -54,6 +226,8 @@ pub type PrimitiveHandle = usize;
 pub struct Mesh {
     pub primitives: Vec<PrimitiveHandle>,
     pub max_instances: u16,
+    /// The weight of each morph target of the primitives, empty if they have none
+    pub default_weights: Vec<f32>,
 }
 
 #[derive(Default)]
@@ -66,28 +240,42 @@ pub struct MeshHandleMap(pub HashMap<String, MeshHandle>);
 pub struct GltfBuffers(pub Vec<Vec<u8>>);
 
 impl GltfBuffers {
+    /// Loads the buffers of `gltf` from files relative to `base_path`, from base64 data URIs,
+    /// or from the binary chunk of a GLB file
     pub fn load_from_gltf<P: AsRef<Path>>(
         base_path: P,
         gltf: &gltf::Gltf,
     ) -> Result<Self, failure::Error> {
         use gltf::buffer::Source;
         let mut buffers = vec![];
-        for (_index, buffer) in gltf.buffers().enumerate() {
+        for buffer in gltf.buffers() {
             let data = match buffer.source() {
                 Source::Uri(uri) => {
                     if uri.starts_with("data:") {
-                        unimplemented!();
+                        decode_data_uri(uri)?
                     } else {
-                        let mut file = File::open(base_path.as_ref().join(uri))?;
+                        let path = base_path.as_ref().join(uri);
+                        let mut file = File::open(&path).map_err(|e| {
+                            format_err!("Failed to open glTF buffer {:?}: {}", path, e)
+                        })?;
                         let mut data: Vec<u8> = Vec::with_capacity(file.metadata()?.len() as usize);
                         file.read_to_end(&mut data)?;
                         data
                     }
                 }
-                Source::Bin => unimplemented!(),
+                Source::Bin => gltf.blob.clone().ok_or(format_err!(
+                    "glTF buffer refers to a missing GLB binary chunk"
+                ))?,
             };
 
-            assert!(data.len() >= buffer.length());
+            if data.len() < buffer.length() {
+                failure::bail!(
+                    "glTF buffer {} is {} bytes long but only {} bytes could be loaded",
+                    buffer.index(),
+                    buffer.length(),
+                    data.len()
+                );
+            }
             buffers.push(data);
         }
         Ok(GltfBuffers(buffers))
@@ -98,14 +286,27 @@ impl GltfBuffers {
         self.0.get(buffer.index()).map(Vec::as_slice)
     }
 
-    /// Obtain the contents of a loaded buffer view.
-    #[allow(unused)]
-    pub fn view(&self, view: &gltf::buffer::View<'_>) -> Option<&[u8]> {
-        self.buffer(&view.buffer()).map(|data| {
-            let begin = view.offset();
-            let end = begin + view.length();
-            &data[begin..end]
-        })
+    /// Obtain the contents of a loaded buffer view, failing if its buffer was not loaded or
+    /// the view runs past the end of it.
+    pub fn view(&self, view: &gltf::buffer::View<'_>) -> Result<&[u8], failure::Error> {
+        let data = self.buffer(&view.buffer()).ok_or(format_err!(
+            "Buffer view {} refers to buffer {} which was not loaded",
+            view.index(),
+            view.buffer().index()
+        ))?;
+        let begin = view.offset();
+        begin
+            .checked_add(view.length())
+            .and_then(|end| data.get(begin..end))
+            .ok_or(format_err!(
+                "Buffer view {} at offset {} with length {} runs past the end of buffer {}, \
+                 which is {} bytes long",
+                view.index(),
+                begin,
+                view.length(),
+                view.buffer().index(),
+                data.len()
+            ))
     }
 }
 
@@ -113,52 +314,189 @@ pub fn load_gltf_mesh<P: AsRef<Path>, B: hal::Backend>(
     mesh: &gltf::Mesh<'_>,
     max_instances: u16,
     generate_mips: bool,
+    max_anisotropy: u8,
+    smooth_normals: bool,
     base_dir: P,
     buffers: &GltfBuffers,
+    extensions: &gltf_extensions::GltfExtensions,
     base_mesh_index: usize,
     base_material_index: usize,
+    default_material: &mut Option<MaterialHandle>,
     material_storage: &mut Vec<Option<MaterialData<B>>>,
     primitive_storage: &mut Vec<Option<Primitive<B>>>,
     mesh_storage: &mut Vec<Option<Mesh>>,
+    morph_target_storage: &mut Vec<MorphDelta>,
     factory: &mut Factory<B>,
     queue: QueueId,
 ) -> Result<MeshHandle, failure::Error> {
+    use gltf::mesh::Mode;
+
     let mesh_idx = base_mesh_index + mesh.index();
     if let Some(_) = mesh_storage[mesh_idx] {
         Ok(mesh_idx as MeshHandle)
     } else {
         let mut primitives = Vec::new();
+        let mut num_morph_targets = None;
 
         for primitive in mesh.primitives() {
             let reader = primitive.reader(|buf_id| buffers.buffer(&buf_id));
 
-            let indices = reader
-                .read_indices()
-                .ok_or(format_err!("Mesh primitive does not contain indices"))?
-                .into_u32()
-                .collect::<Vec<u32>>();
-
             let positions = reader
                 .read_positions()
-                .ok_or(format_err!("Primitive does not have positions"))?;
-            let normals = reader
-                .read_normals()
-                .ok_or(format_err!("Primitive does not have normals"))?;
-            let tangents = reader
-                .read_tangents()
-                .ok_or(format_err!("Primitive does not have tangents"))?;
-            let uvs = reader
-                .read_tex_coords(0)
-                .ok_or(format_err!("Primitive does not have tex coords"))?
-                .into_f32();
+                .ok_or(format_err!("Primitive does not have positions"))?
+                .collect::<Vec<_>>();
+            let vertex_count = positions.len();
+
+            // Primitives without indices draw their vertices in order
+            let indices = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect::<Vec<u32>>(),
+                None => (0..vertex_count as u32).collect(),
+            };
+            let (topology, mut indices) = match primitive.mode() {
+                Mode::Points => (Topology::Points, indices),
+                Mode::Lines => (Topology::Lines, indices),
+                Mode::LineLoop => (
+                    Topology::Lines,
+                    geometry::line_strip_to_list(&indices, true),
+                ),
+                Mode::LineStrip => (
+                    Topology::Lines,
+                    geometry::line_strip_to_list(&indices, false),
+                ),
+                Mode::Triangles => (Topology::Triangles, indices),
+                Mode::TriangleStrip => (
+                    Topology::Triangles,
+                    geometry::triangle_strip_to_list(&indices),
+                ),
+                Mode::TriangleFan => (
+                    Topology::Triangles,
+                    geometry::triangle_fan_to_list(&indices),
+                ),
+            };
+            if let Some(index) = indices
+                .iter()
+                .find(|index| **index as usize >= vertex_count)
+            {
+                failure::bail!(
+                    "Primitive index {} is out of range of its {} vertices",
+                    index,
+                    vertex_count
+                );
+            }
+
+            let material = primitive.material();
+            // Only normal mapping needs tangents to follow the texture coordinates
+            let normal_texture = match material.normal_texture() {
+                Some(info)
+                    if reader.read_tangents().is_none() && topology == Topology::Triangles =>
+                {
+                    Some(info)
+                }
+                _ => None,
+            };
+            // Smooth normals are averaged over the triangles sharing each vertex, so they are
+            // generated before the vertices are split below
+            let normals = match reader.read_normals() {
+                Some(normals) => Some(normals.collect::<Vec<_>>()),
+                // Points and lines have no faces, so they are lit as if they faced +z
+                None if topology != Topology::Triangles => {
+                    Some(vec![[0.0, 0.0, 1.0]; vertex_count])
+                }
+                None if smooth_normals => Some(geometry::smooth_normals(&positions, &indices)),
+                None => None,
+            };
+
+            // Flat normals differ between triangles sharing a vertex, and MikkTSpace splits the
+            // tangents of vertices on UV seams and mirrored UVs, so primitives with either
+            // generated get separate vertices for each triangle first
+            let split_vertices = if normals.is_none() || normal_texture.is_some() {
+                Some(geometry::unweld(&mut indices))
+            } else {
+                None
+            };
+            let source_vertices = split_vertices.as_ref().map(Vec::as_slice);
+
+            let positions = geometry::remap(positions, source_vertices);
+            let tex_coords = match reader.read_tex_coords(0) {
+                Some(tex_coords) => tex_coords.into_f32().collect(),
+                // Untextured materials never sample at the coordinates
+                None if !has_textures(&material) => vec![[0.0, 0.0]; vertex_count],
+                None => failure::bail!("Primitive has a textured material but no tex coords"),
+            };
+            let tex_coords =
+                vertex_attribute(tex_coords, "tex coords", vertex_count, source_vertices)?;
+            let normals = match normals {
+                Some(normals) => {
+                    vertex_attribute(normals, "normals", vertex_count, source_vertices)?
+                }
+                None => geometry::flat_normals(&positions, &indices),
+            };
+            let tex_coords_1 = match reader.read_tex_coords(1) {
+                Some(tex_coords) => Some(vertex_attribute(
+                    tex_coords.into_f32().collect(),
+                    "tex coords",
+                    vertex_count,
+                    source_vertices,
+                )?),
+                None => None,
+            };
+
+            let tangents = match (reader.read_tangents(), normal_texture) {
+                (Some(tangents), _) => vertex_attribute(
+                    tangents.collect(),
+                    "tangents",
+                    vertex_count,
+                    source_vertices,
+                )?,
+                (None, Some(info)) => {
+                    let normal_tex_coords = match (info.tex_coord(), &tex_coords_1) {
+                        (1, Some(tex_coords_1)) => tex_coords_1,
+                        _ => &tex_coords,
+                    };
+                    geometry::mikktspace_tangents(&positions, &normals, normal_tex_coords, &indices)
+                        .unwrap_or_else(|| {
+                            log::warn!(
+                                "Failed to generate tangents for a primitive of mesh {}",
+                                mesh.index()
+                            );
+                            geometry::orthogonal_tangents(&normals)
+                        })
+                }
+                (None, None) => geometry::orthogonal_tangents(&normals),
+            };
+
+            // Weld the split vertices back together wherever the generated attributes agree
+            let (source_vertices, positions, normals, tangents, tex_coords, tex_coords_1) =
+                match split_vertices {
+                    Some(split_vertices) => {
+                        let welded = geometry::weld(&mut indices, |vertex| {
+                            (
+                                split_vertices[vertex],
+                                geometry::frame_bits(&normals[vertex], &tangents[vertex]),
+                            )
+                        });
+                        let welded = Some(welded.as_slice());
+                        (
+                            Some(geometry::remap(split_vertices, welded)),
+                            geometry::remap(positions, welded),
+                            geometry::remap(normals, welded),
+                            geometry::remap(tangents, welded),
+                            geometry::remap(tex_coords, welded),
+                            tex_coords_1.map(|tex_coords| geometry::remap(tex_coords, welded)),
+                        )
+                    }
+                    None => (None, positions, normals, tangents, tex_coords, tex_coords_1),
+                };
+            let source_vertices = source_vertices.as_ref().map(Vec::as_slice);
 
             let vertices = positions
-                .zip(normals.zip(tangents.zip(uvs)))
+                .iter()
+                .zip(normals.iter().zip(tangents.iter().zip(tex_coords.iter())))
                 .map(|(pos, (norm, (tang, uv)))| PosNormTangTex {
-                    position: pos.into(),
-                    normal: norm.into(),
-                    tangent: tang.into(),
-                    tex_coord: uv.into(),
+                    position: (*pos).into(),
+                    normal: (*norm).into(),
+                    tangent: (*tang).into(),
+                    tex_coord: (*uv).into(),
                 })
                 .collect::<Vec<_>>();
 
@@ -167,121 +505,59 @@ pub fn load_gltf_mesh<P: AsRef<Path>, B: hal::Backend>(
                 .with_vertices(&vertices[..])
                 .build(queue, factory)?;
 
-            let material = primitive.material();
-            let mat_idx = base_material_index
-                + material
-                    .index()
-                    .ok_or(format_err!("Default material unimplemented"))?;
-
-            if let None = material_storage[mat_idx] {
-                let pbr_met_rough = material.pbr_metallic_roughness();
-
-                let factors = MaterialFactors {
-                    albedo: pbr_met_rough.base_color_factor(),
-                    metallic: pbr_met_rough.metallic_factor(),
-                    roughness: pbr_met_rough.roughness_factor(),
-                };
-
-                let state = ImageState {
-                    queue,
-                    stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
-                    access: hal::image::Access::SHADER_READ,
-                    layout: hal::image::Layout::ShaderReadOnlyOptimal,
-                };
+            let skin_vertices = match (reader.read_joints(0), reader.read_weights(0)) {
+                (Some(joints), Some(weights)) => vertex_attribute(
+                    joints
+                        .into_u16()
+                        .zip(weights.into_f32())
+                        .map(|(joints, weights)| SkinVertex { joints, weights })
+                        .collect(),
+                    "joints",
+                    vertex_count,
+                    source_vertices,
+                )?,
+                _ => vec![SkinVertex::default(); vertices.len()],
+            };
+            let skin_vertices = create_vertex_buffer(factory, queue, &skin_vertices)?;
 
-                let albedo = load_gltf_texture(
-                    &base_dir,
-                    pbr_met_rough
-                        .base_color_texture()
-                        .ok_or(format_err!("Material has no base color texture"))?
-                        .texture(),
-                    true,
-                    generate_mips,
-                )?
-                .build(state, factory)?;
+            let tex_coords_1 =
+                create_vertex_buffer(factory, queue, tex_coords_1.as_ref().unwrap_or(&tex_coords))?;
 
-                let metallic_roughness = load_gltf_texture(
-                    &base_dir,
-                    pbr_met_rough
-                        .metallic_roughness_texture()
-                        .ok_or(format_err!("Material has no metallic_roughness texture"))?
-                        .texture(),
-                    false,
-                    generate_mips,
-                )?
-                .build(state, factory)?;
+            let morph_targets =
+                load_morph_targets(&reader, vertex_count, source_vertices, morph_target_storage)?;
+            if *num_morph_targets.get_or_insert(morph_targets.count) != morph_targets.count {
+                failure::bail!("Primitives of a mesh have different numbers of morph targets");
+            }
 
-                let normal = load_gltf_texture(
-                    &base_dir,
-                    material
-                        .normal_texture()
-                        .ok_or(format_err!("Material has no normal texture"))?
-                        .texture(),
-                    false,
-                    generate_mips,
-                )?
-                .build(state, factory)?;
+            // Primitives without a material use the default material, which is only loaded
+            // once per file
+            let mat_idx = match material.index() {
+                Some(index) => base_material_index + index,
+                None => *default_material.get_or_insert_with(|| {
+                    material_storage.push(None);
+                    material_storage.len() - 1
+                }),
+            };
 
-                let ao = load_gltf_texture(
+            if let None = material_storage[mat_idx] {
+                material_storage[mat_idx] = Some(load_gltf_material(
+                    &material,
+                    extensions.material(material.index()),
                     &base_dir,
-                    material
-                        .occlusion_texture()
-                        .ok_or(format_err!("Material has no occlusion texture"))?
-                        .texture(),
-                    false,
+                    buffers,
                     generate_mips,
-                )?
-                .build(state, factory)?;
-
-                let emissive = if let Some(emissive_info) = material.emissive_texture() {
-                    load_gltf_texture(&base_dir, emissive_info.texture(), true, generate_mips)?
-                        .build(state, factory)?
-                } else {
-                    rendy::texture::TextureBuilder::new()
-                        .with_data(vec![rendy::texture::pixel::Rgb8Srgb { repr: [0, 0, 0] }])
-                        .with_data_width(1)
-                        .with_data_height(1)
-                        .with_kind(hal::image::Kind::D2(1, 1, 1, 1))
-                        .with_view_kind(hal::image::ViewKind::D2)
-                        .build(state, factory)?
-                };
-
-                let emissive_factor = material.emissive_factor();
-                let emissive_factor_buffer = factory.create_buffer(
-                    BufferInfo {
-                        size: std::mem::size_of::<[f32; 3]>() as u64,
-                        usage: hal::buffer::Usage::UNIFORM | hal::buffer::Usage::TRANSFER_DST,
-                    },
-                    MemoryUsageValue::Data,
-                )?;
-
-                unsafe {
-                    factory.upload_buffer(
-                        &emissive_factor_buffer,
-                        0,
-                        &emissive_factor,
-                        None,
-                        BufferState {
-                            queue,
-                            stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
-                            access: hal::buffer::Access::SHADER_READ,
-                        },
-                    )?;
-                }
-
-                material_storage[mat_idx] = Some(MaterialData {
-                    factors,
-                    albedo,
-                    metallic_roughness,
-                    normal,
-                    ao,
-                    emissive,
-                    emissive_factor_buffer,
-                });
+                    max_anisotropy,
+                    factory,
+                    queue,
+                )?);
             }
 
             primitive_storage.push(Some(Primitive {
                 mesh_data: prim_mesh,
+                skin_vertices,
+                tex_coords_1,
+                morph_targets,
+                topology,
                 mesh_handle: mesh_idx,
                 mat: mat_idx as MaterialHandle,
             }));
@@ -289,41 +565,679 @@ pub fn load_gltf_mesh<P: AsRef<Path>, B: hal::Backend>(
             primitives.push(primitive_storage.len() - 1);
         }
 
+        // Targets without a default weight start out with no influence
+        let mut default_weights = vec![0.0; num_morph_targets.unwrap_or(0) as usize];
+        if let Some(weights) = mesh.weights() {
+            for (default_weight, weight) in default_weights.iter_mut().zip(weights) {
+                *default_weight = *weight;
+            }
+        }
+
         mesh_storage[mesh_idx] = Some(Mesh {
             primitives,
             max_instances,
+            default_weights,
         });
 
         Ok(mesh_idx as MeshHandle)
     }
 }
 
+/// Loads the textures and factors of `material`. Textures the material doesn't have are
+/// replaced by 1x1 textures which leave the factors unchanged.
+fn load_gltf_material<P: AsRef<Path>, B: hal::Backend>(
+    material: &gltf::Material<'_>,
+    extensions: gltf_extensions::MaterialExtensions,
+    base_dir: P,
+    buffers: &GltfBuffers,
+    generate_mips: bool,
+    max_anisotropy: u8,
+    factory: &mut Factory<B>,
+    queue: QueueId,
+) -> Result<MaterialData<B>, failure::Error> {
+    let pbr_met_rough = material.pbr_metallic_roughness();
+
+    let mut texture_transforms = [TextureTransform::default(); 5];
+    if let Some(info) = pbr_met_rough.base_color_texture() {
+        texture_transforms[MaterialFactors::TEXTURE_ALBEDO] =
+            TextureTransform::new(info.tex_coord(), extensions.base_color_transform);
+    }
+    if let Some(info) = material.normal_texture() {
+        texture_transforms[MaterialFactors::TEXTURE_NORMAL] =
+            TextureTransform::new(info.tex_coord(), extensions.normal_transform);
+    }
+    if let Some(info) = pbr_met_rough.metallic_roughness_texture() {
+        texture_transforms[MaterialFactors::TEXTURE_METALLIC_ROUGHNESS] =
+            TextureTransform::new(info.tex_coord(), extensions.metallic_roughness_transform);
+    }
+    if let Some(info) = material.occlusion_texture() {
+        texture_transforms[MaterialFactors::TEXTURE_AO] =
+            TextureTransform::new(info.tex_coord(), extensions.occlusion_transform);
+    }
+    if let Some(info) = material.emissive_texture() {
+        texture_transforms[MaterialFactors::TEXTURE_EMISSIVE] =
+            TextureTransform::new(info.tex_coord(), extensions.emissive_transform);
+    }
+
+    let factors = MaterialFactors {
+        albedo: pbr_met_rough.base_color_factor(),
+        emissive: material.emissive_factor(),
+        metallic: pbr_met_rough.metallic_factor(),
+        roughness: pbr_met_rough.roughness_factor(),
+        normal_scale: material.normal_texture().map_or(1.0, |info| info.scale()),
+        occlusion_strength: material
+            .occlusion_texture()
+            .map_or(1.0, |info| info.strength()),
+        emissive_strength: extensions.emissive_strength,
+        texture_transforms,
+        alpha_cutoff: material.alpha_cutoff(),
+        alpha_mode: match material.alpha_mode() {
+            gltf::material::AlphaMode::Opaque => MaterialFactors::ALPHA_OPAQUE,
+            gltf::material::AlphaMode::Mask => MaterialFactors::ALPHA_MASK,
+            gltf::material::AlphaMode::Blend => MaterialFactors::ALPHA_BLEND,
+        },
+        double_sided: material.double_sided() as i32,
+    };
+
+    let state = ImageState {
+        queue,
+        stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+        access: hal::image::Access::SHADER_READ,
+        layout: hal::image::Layout::ShaderReadOnlyOptimal,
+    };
+
+    let mut load_texture = |texture: Option<gltf::Texture>,
+                            srgb: bool,
+                            fallback: [u8; 4]|
+     -> Result<Texture<B>, failure::Error> {
+        let builder = match texture {
+            Some(texture) => load_gltf_texture(
+                &base_dir,
+                buffers,
+                texture,
+                srgb,
+                generate_mips,
+                max_anisotropy,
+            )?,
+            None => solid_texture(fallback, srgb),
+        };
+        Ok(builder.build(state, factory)?)
+    };
+
+    let albedo = load_texture(
+        pbr_met_rough
+            .base_color_texture()
+            .map(|info| info.texture()),
+        true,
+        [255, 255, 255, 255],
+    )?;
+    let metallic_roughness = load_texture(
+        pbr_met_rough
+            .metallic_roughness_texture()
+            .map(|info| info.texture()),
+        false,
+        [255, 255, 255, 255],
+    )?;
+    // Pointing straight out of the surface
+    let normal = load_texture(
+        material.normal_texture().map(|info| info.texture()),
+        false,
+        [128, 128, 255, 255],
+    )?;
+    let ao = load_texture(
+        material.occlusion_texture().map(|info| info.texture()),
+        false,
+        [255, 255, 255, 255],
+    )?;
+    let emissive = load_texture(
+        material.emissive_texture().map(|info| info.texture()),
+        true,
+        [255, 255, 255, 255],
+    )?;
+
+    let factors_buffer = factory.create_buffer(
+        BufferInfo {
+            size: std::mem::size_of::<MaterialFactors>() as u64,
+            usage: hal::buffer::Usage::UNIFORM | hal::buffer::Usage::TRANSFER_DST,
+        },
+        MemoryUsageValue::Data,
+    )?;
+
+    unsafe {
+        factory.upload_buffer(
+            &factors_buffer,
+            0,
+            &[factors],
+            None,
+            BufferState {
+                queue,
+                stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+                access: hal::buffer::Access::SHADER_READ,
+            },
+        )?;
+    }
+
+    Ok(MaterialData {
+        factors,
+        albedo,
+        metallic_roughness,
+        normal,
+        ao,
+        emissive,
+        factors_buffer,
+    })
+}
+
+/// A 1x1 texture of a single color
+fn solid_texture(color: [u8; 4], srgb: bool) -> TextureBuilder<'static> {
+    let builder = if srgb {
+        TextureBuilder::new().with_data(vec![rendy::texture::pixel::Rgba8Srgb { repr: color }])
+    } else {
+        TextureBuilder::new().with_data(vec![rendy::texture::pixel::Rgba8Unorm { repr: color }])
+    };
+    builder
+        .with_data_width(1)
+        .with_data_height(1)
+        .with_kind(hal::image::Kind::D2(1, 1, 1, 1))
+        .with_view_kind(hal::image::ViewKind::D2)
+}
+
+/// Appends the deltas of the morph targets of a primitive with `vertex_count` vertices to
+/// `morph_target_storage`. Attributes which a target doesn't displace get zero deltas.
+fn load_morph_targets<'a, 's, F>(
+    reader: &gltf::mesh::Reader<'a, 's, F>,
+    vertex_count: usize,
+    source_vertices: Option<&[u32]>,
+    morph_target_storage: &mut Vec<MorphDelta>,
+) -> Result<MorphTargets, failure::Error>
+where
+    F: Clone + Fn(gltf::Buffer<'a>) -> Option<&'s [u8]>,
+{
+    let first_delta = morph_target_storage.len();
+    let mut count = 0;
+    for (positions, normals, tangents) in reader.read_morph_targets() {
+        let mut deltas = vec![MorphDelta::default(); vertex_count];
+        write_morph_deltas(&mut deltas, positions, |delta| &mut delta.position)?;
+        write_morph_deltas(&mut deltas, normals, |delta| &mut delta.normal)?;
+        write_morph_deltas(&mut deltas, tangents, |delta| &mut delta.tangent)?;
+        morph_target_storage.extend(geometry::remap(deltas, source_vertices));
+        count += 1;
+    }
+
+    Ok(MorphTargets {
+        first_delta: first_delta as u32,
+        count,
+        vertex_count: source_vertices.map_or(vertex_count, <[u32]>::len) as u32,
+    })
+}
+
+/// Whether a material samples any texture, and so needs texture coordinates
+fn has_textures(material: &gltf::Material<'_>) -> bool {
+    let pbr_met_rough = material.pbr_metallic_roughness();
+    pbr_met_rough.base_color_texture().is_some()
+        || pbr_met_rough.metallic_roughness_texture().is_some()
+        || material.normal_texture().is_some()
+        || material.occlusion_texture().is_some()
+        || material.emissive_texture().is_some()
+}
+
+/// Checks that a vertex attribute has a value for each of the primitive's `vertex_count`
+/// positions, and copies the values to the unwelded vertices if there are any
+fn vertex_attribute<T: Copy>(
+    values: Vec<T>,
+    name: &str,
+    vertex_count: usize,
+    source_vertices: Option<&[u32]>,
+) -> Result<Vec<T>, failure::Error> {
+    if values.len() != vertex_count {
+        failure::bail!(
+            "Primitive has {} {} but {} positions",
+            values.len(),
+            name,
+            vertex_count
+        );
+    }
+    Ok(geometry::remap(values, source_vertices))
+}
+
+/// Writes one attribute of a target's deltas, if the target displaces it
+fn write_morph_deltas<I: Iterator<Item = [f32; 3]>>(
+    deltas: &mut [MorphDelta],
+    values: Option<I>,
+    field: fn(&mut MorphDelta) -> &mut [f32; 4],
+) -> Result<(), failure::Error> {
+    if let Some(values) = values {
+        let mut written = 0;
+        for (delta, [x, y, z]) in deltas.iter_mut().zip(values) {
+            *field(delta) = [x, y, z, 0.0];
+            written += 1;
+        }
+        if written != deltas.len() {
+            failure::bail!(
+                "Morph target has {} deltas but the primitive has {} vertices",
+                written,
+                deltas.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Creates a device local vertex buffer holding `vertices`
+fn create_vertex_buffer<B: hal::Backend, T: Copy>(
+    factory: &mut Factory<B>,
+    queue: QueueId,
+    vertices: &[T],
+) -> Result<Escape<Buffer<B>>, failure::Error> {
+    let buffer = factory.create_buffer(
+        BufferInfo {
+            size: (std::mem::size_of::<T>() * vertices.len()).max(1) as u64,
+            usage: hal::buffer::Usage::VERTEX | hal::buffer::Usage::TRANSFER_DST,
+        },
+        MemoryUsageValue::Data,
+    )?;
+
+    if !vertices.is_empty() {
+        unsafe {
+            factory.upload_buffer(
+                &buffer,
+                0,
+                vertices,
+                None,
+                BufferState {
+                    queue,
+                    stage: hal::pso::PipelineStage::VERTEX_INPUT,
+                    access: hal::buffer::Access::VERTEX_BUFFER_READ,
+                },
+            )?;
+        }
+    }
+
+    Ok(buffer)
+}
+
+/// Decodes the contents of a base64 `data:` URI, as used for embedded buffers and images
+fn decode_data_uri(uri: &str) -> Result<Vec<u8>, failure::Error> {
+    let comma = uri.find(',').ok_or(format_err!(
+        "Malformed data URI, expected a ',' before the data"
+    ))?;
+    let (header, data) = (&uri[..comma], &uri[comma + 1..]);
+    if !header.ends_with(";base64") {
+        failure::bail!(
+            "Unsupported data URI {:?}, only base64 data is supported",
+            header
+        );
+    }
+    base64::decode(data).map_err(|e| format_err!("Invalid base64 in data URI: {}", e))
+}
+
+/// The filters and wrap modes of a glTF sampler, with linear filtering where it leaves a filter
+/// unspecified. Samplers which filter linearly in every direction are anisotropic up to
+/// `max_anisotropy`.
+fn gltf_sampler_desc(sampler: &gltf::texture::Sampler<'_>, max_anisotropy: u8) -> SamplerDesc {
+    use gltf::texture::{MagFilter, MinFilter, WrappingMode};
+
+    let wrap_mode = |mode| match mode {
+        WrappingMode::ClampToEdge => WrapMode::Clamp,
+        WrappingMode::MirroredRepeat => WrapMode::Mirror,
+        WrappingMode::Repeat => WrapMode::Tile,
+    };
+    let mag_filter = match sampler.mag_filter() {
+        Some(MagFilter::Nearest) => Filter::Nearest,
+        Some(MagFilter::Linear) | None => Filter::Linear,
+    };
+    // Minification without mipmapping uses the nearest mip level, which is the only level
+    // unless mipmaps are generated
+    let (min_filter, mip_filter) = match sampler.min_filter() {
+        Some(MinFilter::Nearest) | Some(MinFilter::NearestMipmapNearest) => {
+            (Filter::Nearest, Filter::Nearest)
+        }
+        Some(MinFilter::Linear) | Some(MinFilter::LinearMipmapNearest) => {
+            (Filter::Linear, Filter::Nearest)
+        }
+        Some(MinFilter::NearestMipmapLinear) => (Filter::Nearest, Filter::Linear),
+        Some(MinFilter::LinearMipmapLinear) | None => (Filter::Linear, Filter::Linear),
+    };
+
+    let mut desc = SamplerDesc::new(Filter::Linear, WrapMode::Tile);
+    desc.min_filter = min_filter;
+    desc.mag_filter = mag_filter;
+    desc.mip_filter = mip_filter;
+    desc.wrap_mode = (
+        wrap_mode(sampler.wrap_s()),
+        wrap_mode(sampler.wrap_t()),
+        WrapMode::Tile,
+    );
+    let linear = [min_filter, mag_filter, mip_filter]
+        .iter()
+        .all(|filter| *filter == Filter::Linear);
+    if linear && max_anisotropy > 1 {
+        desc.anisotropic = hal::image::Anisotropic::On(max_anisotropy);
+    }
+    desc
+}
+
+/// Highest anisotropic filtering level that may be requested in the scene file or on the
+/// command line
+pub const MAX_ANISOTROPY: u8 = 16;
+
+/// Checks that `requested` is between 1 and `MAX_ANISOTROPY`. If the device doesn't support
+/// that level of anisotropic filtering, warns and falls back to the highest level it does.
+pub fn supported_anisotropy<B: hal::Backend>(
+    factory: &Factory<B>,
+    requested: u8,
+) -> Result<u8, failure::Error> {
+    if requested < 1 || requested > MAX_ANISOTROPY {
+        failure::bail!(
+            "Invalid anisotropy {}, must be between 1 and {}",
+            requested,
+            MAX_ANISOTROPY
+        );
+    }
+
+    let features = hal::adapter::PhysicalDevice::features(factory.physical());
+    let limits = hal::adapter::PhysicalDevice::limits(factory.physical());
+    let anisotropy = if features.contains(hal::Features::SAMPLER_ANISOTROPY) {
+        requested.min(limits.max_sampler_anisotropy as u8).max(1)
+    } else {
+        1
+    };
+    if anisotropy != requested {
+        log::warn!(
+            "{}x anisotropic filtering is not supported by the device, using {}x instead",
+            requested,
+            anisotropy
+        );
+    }
+    Ok(anisotropy)
+}
+
 fn load_gltf_texture<P>(
     base_dir: P,
+    buffers: &GltfBuffers,
     texture: gltf::Texture<'_>,
     srgb: bool,
     generate_mips: bool,
+    max_anisotropy: u8,
 ) -> Result<TextureBuilder<'static>, failure::Error>
 where
     P: AsRef<Path>,
 {
-    match texture.source().source() {
-        gltf::image::Source::View { .. } => unimplemented!(),
+    let config = ImageTextureConfig {
+        repr: match srgb {
+            true => Repr::Srgb,
+            false => Repr::Unorm,
+        },
+        // The factory caches samplers, so textures with equal samplers share one
+        sampler_info: gltf_sampler_desc(&texture.sampler(), max_anisotropy),
+        generate_mips,
+        ..Default::default()
+    };
+    let image = texture.source();
+    let result = match image.source() {
+        gltf::image::Source::View { view, .. } => {
+            log::info!(
+                "Loading image {} from buffer view {}",
+                image.index(),
+                view.index()
+            );
+            let data = buffers.view(&view)?;
+            rendy::texture::image::load_from_image(Cursor::new(data), config)
+        }
+        gltf::image::Source::Uri { uri, .. } if uri.starts_with("data:") => {
+            log::info!("Loading image {} from a data URI", image.index());
+            rendy::texture::image::load_from_image(Cursor::new(decode_data_uri(uri)?), config)
+        }
         gltf::image::Source::Uri { uri, .. } => {
             let path = base_dir.as_ref().join(uri);
             log::info!("Loading image: {:#?}", path);
-            rendy::texture::image::load_from_image(
-                std::io::BufReader::new(File::open(path)?),
-                ImageTextureConfig {
-                    repr: match srgb {
-                        true => Repr::Srgb,
-                        false => Repr::Unorm,
-                    },
-                    generate_mips,
-                    ..Default::default()
-                },
+            let file = File::open(&path)
+                .map_err(|e| format_err!("Failed to open image {:?}: {}", path, e))?;
+            rendy::texture::image::load_from_image(std::io::BufReader::new(file), config)
+        }
+    };
+    result.map_err(|e| format_err!("Failed to load image {}: {}", image.index(), e))
+}
+
+/// Loads the materials of a glTF file which no primitive uses, so every material of the file
+/// has a slot at `base_material_index` plus its index once its meshes are loaded.
+pub fn load_unused_gltf_materials<P: AsRef<Path>, B: hal::Backend>(
+    gltf: &gltf::Gltf,
+    base_dir: P,
+    buffers: &GltfBuffers,
+    extensions: &gltf_extensions::GltfExtensions,
+    generate_mips: bool,
+    max_anisotropy: u8,
+    base_material_index: usize,
+    material_storage: &mut Vec<Option<MaterialData<B>>>,
+    factory: &mut Factory<B>,
+    queue: QueueId,
+) -> Result<(), failure::Error> {
+    for material in gltf.materials() {
+        let index = material.index().unwrap();
+        if let None = material_storage[base_material_index + index] {
+            log::info!("Loading material {}, which no mesh uses", index);
+            material_storage[base_material_index + index] = Some(load_gltf_material(
+                &material,
+                extensions.material(material.index()),
+                &base_dir,
+                buffers,
+                generate_mips,
+                max_anisotropy,
+                factory,
+                queue,
+            )?);
+        }
+    }
+    Ok(())
+}
+
+/// Loads every mesh of a glTF file, appending the meshes and their primitives and materials
+/// to the storages in `world`. Returns the handles of the new meshes in the order they appear
+/// in the file. The render graph picks up the grown storages on the next frame.
+pub fn load_gltf_into_world<P: AsRef<Path>, B: hal::Backend>(
+    base_path: P,
+    filename: &str,
+    max_instances: u16,
+    generate_mips: bool,
+    max_anisotropy: u8,
+    smooth_normals: bool,
+    world: &specs::World,
+    factory: &mut Factory<B>,
+    queue: QueueId,
+) -> Result<Vec<MeshHandle>, failure::Error> {
+    let base_path = base_path.as_ref();
+    let (gltf, extensions) = gltf_extensions::read_gltf(&base_path.join(filename))?;
+    let gltf_buffers = GltfBuffers::load_from_gltf(base_path, &gltf)?;
+
+    let mut material_storage = world.write_resource::<MaterialStorage<B>>();
+    let mut primitive_storage = world.write_resource::<PrimitiveStorage<B>>();
+    let mut mesh_storage = world.write_resource::<MeshStorage>();
+    let mut morph_target_storage = world.write_resource::<MorphTargetStorage>();
+
+    let base_mesh_index = mesh_storage.0.len();
+    let base_material_index = material_storage.0.len();
+    let base_primitive_index = primitive_storage.0.len();
+
+    // `load_gltf_mesh` indexes by the final handles, so the new assets are loaded into
+    // scratch storages which are padded up to the existing ones
+    let mut new_meshes = (0..base_mesh_index + gltf.meshes().len())
+        .map(|_| None)
+        .collect::<Vec<_>>();
+    let mut new_materials = (0..base_material_index + gltf.materials().len())
+        .map(|_| None)
+        .collect::<Vec<_>>();
+    let mut new_primitives = (0..base_primitive_index).map(|_| None).collect::<Vec<_>>();
+
+    // Morph targets are appended, so they can be loaded into the storage directly
+    let mut default_material = None;
+    let mesh_handles = gltf
+        .meshes()
+        .map(|mesh| {
+            load_gltf_mesh(
+                &mesh,
+                max_instances,
+                generate_mips,
+                max_anisotropy,
+                smooth_normals,
+                base_path,
+                &gltf_buffers,
+                &extensions,
+                base_mesh_index,
+                base_material_index,
+                &mut default_material,
+                &mut new_materials,
+                &mut new_primitives,
+                &mut new_meshes,
+                &mut morph_target_storage.0,
+                factory,
+                queue,
             )
-            .map_err(|e| e.into())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    load_unused_gltf_materials(
+        &gltf,
+        base_path,
+        &gltf_buffers,
+        &extensions,
+        generate_mips,
+        max_anisotropy,
+        base_material_index,
+        &mut new_materials,
+        factory,
+        queue,
+    )?;
+
+    material_storage.0.extend(
+        new_materials
+            .into_iter()
+            .skip(base_material_index)
+            .map(Option::unwrap),
+    );
+    primitive_storage.0.extend(
+        new_primitives
+            .into_iter()
+            .skip(base_primitive_index)
+            .map(Option::unwrap),
+    );
+    mesh_storage.0.extend(
+        new_meshes
+            .into_iter()
+            .skip(base_mesh_index)
+            .map(Option::unwrap),
+    );
+
+    Ok(mesh_handles)
+}
+
+/// The glTF files waiting to be loaded by the `GltfLoader`, such as those dropped onto the
+/// window
+#[derive(Debug, Default)]
+pub struct GltfLoadRequests(pub Vec<PathBuf>);
+
+/// Loads the glTF files requested through `GltfLoadRequests` between frames, adding an
+/// entity at the origin for each of their meshes. Meshes are shown in their rest pose, since
+/// the nodes, skins and animations of the file aren't loaded.
+pub struct GltfLoader {
+    queue: QueueId,
+    max_instances: u16,
+    generate_mips: bool,
+    max_anisotropy: u8,
+    smooth_normals: bool,
+}
+
+impl GltfLoader {
+    pub fn new(
+        queue: QueueId,
+        max_instances: u16,
+        generate_mips: bool,
+        max_anisotropy: u8,
+        smooth_normals: bool,
+    ) -> Self {
+        GltfLoader {
+            queue,
+            max_instances,
+            generate_mips,
+            max_anisotropy,
+            smooth_normals,
         }
     }
+
+    /// Loads the requested files. Must be called between frames.
+    pub fn maintain<B: hal::Backend>(
+        &mut self,
+        factory: &mut Factory<B>,
+        world: &mut specs::World,
+    ) {
+        use specs::Builder;
+
+        let requests = std::mem::replace(
+            &mut world.write_resource::<GltfLoadRequests>().0,
+            Vec::new(),
+        );
+        for path in requests {
+            let loaded = match (
+                path.parent(),
+                path.file_name().and_then(|name| name.to_str()),
+            ) {
+                (Some(base_path), Some(filename)) => load_gltf_into_world(
+                    base_path,
+                    filename,
+                    self.max_instances,
+                    self.generate_mips,
+                    self.max_anisotropy,
+                    self.smooth_normals,
+                    world,
+                    factory,
+                    self.queue,
+                ),
+                _ => Err(format_err!("Not a path to a file")),
+            };
+            match loaded {
+                Ok(mesh_handles) => {
+                    log::info!("Loaded {} meshes from {:?}", mesh_handles.len(), path);
+                    for mesh_handle in mesh_handles {
+                        world
+                            .create_entity()
+                            .with(components::Transform::new(
+                                nalgebra::Translation3::identity(),
+                                nalgebra::UnitQuaternion::identity(),
+                                nalgebra::Vector3::repeat(1.0),
+                            ))
+                            .with(components::Mesh(mesh_handle))
+                            .build();
+                    }
+                }
+                Err(e) => log::error!("Failed to load {:?}: {}", path, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_data_uri;
+
+    #[test]
+    fn decodes_base64_data_uri() {
+        let uri = "data:application/octet-stream;base64,AAECAw==";
+        assert_eq!(decode_data_uri(uri).unwrap(), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn decodes_empty_data_uri() {
+        let uri = "data:application/octet-stream;base64,";
+        assert!(decode_data_uri(uri).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_data_uri_without_data() {
+        assert!(decode_data_uri("data:application/octet-stream;base64").is_err());
+    }
+
+    #[test]
+    fn rejects_data_uri_which_is_not_base64() {
+        assert!(decode_data_uri("data:text/plain,hello").is_err());
+        assert!(decode_data_uri("data:application/octet-stream;base64,!!!!").is_err());
+    }
 }
@@ -3,6 +3,8 @@ use crate::asset;
 use serde::Deserialize;
 use specs::prelude::*;
 
+use std::convert::TryFrom;
+
 pub use crate::transform::components::*;
 
 #[derive(Debug, Clone, Copy)]
@@ -11,17 +13,144 @@ pub struct Camera {
     pub pitch: f32,
     pub dist: f32,
     pub focus: nalgebra::Point3<f32>,
-    pub proj: nalgebra::Perspective3<f32>,
+    pub proj: Projection,
 }
 
 impl Component for Camera {
     type Storage = FlaggedStorage<Self, HashMapStorage<Self>>;
 }
 
+/// The projection of a camera, looking down its -Z axis
+#[derive(Debug, Clone, Copy)]
+pub enum Projection {
+    Perspective(nalgebra::Perspective3<f32>),
+    Orthographic(nalgebra::Orthographic3<f32>),
+}
+
+impl Projection {
+    pub fn to_homogeneous(&self) -> nalgebra::Matrix4<f32> {
+        match self {
+            Projection::Perspective(proj) => proj.to_homogeneous(),
+            Projection::Orthographic(proj) => proj.to_homogeneous(),
+        }
+    }
+
+    pub fn znear(&self) -> f32 {
+        match self {
+            Projection::Perspective(proj) => proj.znear(),
+            Projection::Orthographic(proj) => proj.znear(),
+        }
+    }
+
+    pub fn zfar(&self) -> f32 {
+        match self {
+            Projection::Perspective(proj) => proj.zfar(),
+            Projection::Orthographic(proj) => proj.zfar(),
+        }
+    }
+
+    /// The corners of the view frustum at `depth` in front of the camera, in view space
+    pub fn frustum_corners(&self, depth: f32) -> [nalgebra::Point3<f32>; 4] {
+        let (left, right, bottom, top) = match self {
+            Projection::Perspective(proj) => {
+                let tan_y = (proj.fovy() / 2.0).tan();
+                let tan_x = tan_y * proj.aspect();
+                (-tan_x * depth, tan_x * depth, -tan_y * depth, tan_y * depth)
+            }
+            Projection::Orthographic(proj) => {
+                (proj.left(), proj.right(), proj.bottom(), proj.top())
+            }
+        };
+        [
+            nalgebra::Point3::new(left, bottom, -depth),
+            nalgebra::Point3::new(right, bottom, -depth),
+            nalgebra::Point3::new(left, top, -depth),
+            nalgebra::Point3::new(right, top, -depth),
+        ]
+    }
+}
+
 #[derive(Debug, Clone, Copy, Deserialize)]
 pub struct Light {
     pub intensity: f32,
     pub color: [f32; 3],
+    #[serde(default)]
+    pub kind: LightKind,
+    /// Whether the light is occluded by meshes. Only directional and spot lights cast shadows.
+    #[serde(default)]
+    pub casts_shadows: bool,
+    /// Width and height of the light's shadow map, or of each cascade for directional lights
+    #[serde(default = "default_shadow_resolution")]
+    pub shadow_resolution: u32,
+}
+
+pub fn default_shadow_resolution() -> u32 {
+    1024
+}
+
+/// How a light emits. Directional and spot lights point down the -Z axis of their transform.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "LightKindSource")]
+pub enum LightKind {
+    /// Emits in all directions from its position, falling off with the inverse square of
+    /// the distance
+    Point,
+    /// Emits parallel rays with no falloff, like the sun. The position is ignored.
+    Directional,
+    /// A point light limited to a cone. The angles are measured from the cone axis in
+    /// radians; the light fades out between `inner_angle` and `outer_angle`, with
+    /// `0 <= inner_angle < outer_angle <= π/2`.
+    Spot { inner_angle: f32, outer_angle: f32 },
+}
+
+impl LightKind {
+    /// A spot light, failing if its angles are out of range, see `LightKind::Spot`
+    pub fn spot(inner_angle: f32, outer_angle: f32) -> Result<Self, failure::Error> {
+        if !(0.0 <= inner_angle
+            && inner_angle < outer_angle
+            && outer_angle <= std::f32::consts::FRAC_PI_2)
+        {
+            failure::bail!(
+                "Spot light angles must satisfy 0 <= inner_angle < outer_angle <= π/2, \
+                 but inner_angle is {} and outer_angle is {}",
+                inner_angle,
+                outer_angle
+            );
+        }
+        Ok(LightKind::Spot {
+            inner_angle,
+            outer_angle,
+        })
+    }
+}
+
+impl Default for LightKind {
+    fn default() -> Self {
+        LightKind::Point
+    }
+}
+
+/// A `LightKind` as written in a scene file, before its spot angles are validated
+#[derive(Deserialize)]
+enum LightKindSource {
+    Point,
+    Directional,
+    Spot { inner_angle: f32, outer_angle: f32 },
+}
+
+impl TryFrom<LightKindSource> for LightKind {
+    type Error = failure::Error;
+
+    fn try_from(source: LightKindSource) -> Result<Self, failure::Error> {
+        match source {
+            LightKindSource::Point => Ok(LightKind::Point),
+            LightKindSource::Directional => Ok(LightKind::Directional),
+            LightKindSource::Spot {
+                inner_angle,
+                outer_angle,
+            } => LightKind::spot(inner_angle, outer_angle),
+        }
+    }
 }
 
 impl Component for Light {
@@ -34,6 +163,34 @@ impl Component for Mesh {
     type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
 }
 
+/// The joints deforming the mesh of a skinned entity. The vertices are transformed by the
+/// joints alone, so the entity's own transform doesn't move the mesh.
+#[derive(Debug, Clone)]
+pub struct Skin {
+    pub joints: Vec<Entity>,
+    /// Transforms the mesh from its bind pose into the local space of each joint
+    pub inverse_bind_matrices: Vec<nalgebra::Matrix4<f32>>,
+    /// Index of the matrix of the first joint in `systems::JointMatrices`
+    pub joint_offset: usize,
+}
+
+impl Component for Skin {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// The weights of the morph targets of an entity's mesh, starting out as the mesh's
+/// default weights
+#[derive(Debug, Clone)]
+pub struct MorphWeights {
+    pub weights: Vec<f32>,
+    /// Index of the first weight in `systems::InstanceMorphWeights`
+    pub weight_offset: usize,
+}
+
+impl Component for MorphWeights {
+    type Storage = HashMapStorage<Self>;
+}
+
 /// Indicates that an entity is the active camera.
 #[derive(Debug, Default)]
 pub struct ActiveCamera;
@@ -0,0 +1,216 @@
+//! Command line options and the application configuration resolved from them.
+use crate::{headless, scene::Quality};
+
+use std::{
+    convert::TryFrom,
+    path::{Path, PathBuf},
+};
+
+use rendy::core::{Backend, EnabledBackend};
+
+use structopt::StructOpt;
+
+/// Command line options. See `AppConfig` for the validated configuration built from them.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "rendy-pbr",
+    about = "A small realtime physically based renderer"
+)]
+pub struct Opt {
+    /// Scene description file to load, relative to the application root
+    #[structopt(
+        short = "s",
+        long = "scene",
+        default_value = "assets/scene.ron",
+        parse(from_os_str)
+    )]
+    pub scene: PathBuf,
+
+    /// Width of the window or headless render target in pixels
+    #[structopt(long = "width", default_value = "1280")]
+    pub width: u32,
+
+    /// Height of the window or headless render target in pixels
+    #[structopt(long = "height", default_value = "960")]
+    pub height: u32,
+
+    /// Equirectangular HDR environment map to use instead of the one in the scene file
+    #[structopt(short = "e", long = "environment", parse(from_os_str))]
+    pub environment: Option<PathBuf>,
+
+    /// Environment filter quality to use instead of the one in the scene file (low, medium, high)
+    #[structopt(short = "q", long = "quality")]
+    pub quality: Option<Quality>,
+
+    /// MSAA samples per pixel to use instead of the count in the scene file (1, 2, 4, 8)
+    #[structopt(long = "msaa")]
+    pub msaa: Option<u8>,
+
+    /// Anisotropic filtering level of model textures to use instead of the one in the scene
+    /// file (1 to 16)
+    #[structopt(long = "anisotropy")]
+    pub anisotropy: Option<u8>,
+
+    /// Graphics backend to use (dx12, metal, vulkan, empty). Defaults to the first enabled
+    /// backend which initializes successfully
+    #[structopt(short = "b", long = "backend")]
+    pub backend: Option<String>,
+
+    /// Always preprocess the environment map, without reading or writing the environment cache
+    #[structopt(long = "no-environment-cache")]
+    pub no_environment_cache: bool,
+
+    /// Number of frames the CPU may record ahead of the GPU
+    #[structopt(long = "frames-in-flight", default_value = "3")]
+    pub frames_in_flight: u32,
+
+    /// Render without a window and write the final frame to this .png or .exr file
+    #[structopt(long = "headless", parse(from_os_str))]
+    pub headless: Option<PathBuf>,
+
+    /// Number of frames to render before writing the output in headless mode
+    #[structopt(long = "frames", default_value = "3")]
+    pub frames: u32,
+
+    /// Log level for rendy-pbr (off, error, warn, info, debug, trace)
+    #[structopt(long = "log-level", default_value = "info")]
+    pub log_level: log::LevelFilter,
+}
+
+/// Resolutions and mip counts of the textures produced by environment preprocessing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct EnvironmentConfig {
+    pub env_cubemap_res: u32,
+    pub env_cubemap_mip_levels: u8,
+    pub irradiance_cubemap_res: u32,
+    pub spec_cubemap_res: u32,
+    pub spec_cubemap_mip_levels: u8,
+    pub spec_brdf_map_res: u32,
+}
+
+impl Default for EnvironmentConfig {
+    fn default() -> Self {
+        EnvironmentConfig {
+            env_cubemap_res: 512,
+            env_cubemap_mip_levels: 6,
+            irradiance_cubemap_res: 64,
+            spec_cubemap_res: 128,
+            spec_cubemap_mip_levels: 6,
+            spec_brdf_map_res: 256,
+        }
+    }
+}
+
+/// The validated application configuration
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    pub scene: PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub environment_override: Option<String>,
+    pub quality_override: Option<Quality>,
+    pub msaa_override: Option<u8>,
+    pub anisotropy_override: Option<u8>,
+    pub backend: Option<EnabledBackend>,
+    pub frames_in_flight: u32,
+    pub headless: Option<headless::HeadlessConfig>,
+    pub log_level: log::LevelFilter,
+    pub environment: EnvironmentConfig,
+    pub environment_cache: bool,
+}
+
+const MAX_DIMENSION: u32 = 16384;
+const MAX_FRAMES_IN_FLIGHT: u32 = 8;
+
+impl AppConfig {
+    /// Validate the parsed command line options
+    pub fn from_opt(opt: Opt) -> Result<Self, failure::Error> {
+        if opt.width == 0
+            || opt.height == 0
+            || opt.width > MAX_DIMENSION
+            || opt.height > MAX_DIMENSION
+        {
+            failure::bail!(
+                "Invalid resolution {}x{}, width and height must be between 1 and {}",
+                opt.width,
+                opt.height,
+                MAX_DIMENSION
+            );
+        }
+
+        if opt.frames_in_flight == 0 || opt.frames_in_flight > MAX_FRAMES_IN_FLIGHT {
+            failure::bail!(
+                "Invalid frames in flight {}, must be between 1 and {}",
+                opt.frames_in_flight,
+                MAX_FRAMES_IN_FLIGHT
+            );
+        }
+
+        check_exists(&opt.scene, "Scene file")?;
+
+        let environment_override = match opt.environment {
+            Some(path) => {
+                check_exists(&path, "Environment map")?;
+                Some(
+                    path.to_str()
+                        .ok_or(failure::format_err!(
+                            "Environment map path {:?} is not valid unicode",
+                            path
+                        ))?
+                        .to_owned(),
+                )
+            }
+            None => None,
+        };
+
+        let backend = match opt.backend {
+            Some(name) => {
+                let backend = name.parse::<Backend>().map_err(|_| {
+                    failure::format_err!(
+                        "Unknown backend {:?}, expected one of dx12, metal, vulkan, empty",
+                        name
+                    )
+                })?;
+                Some(EnabledBackend::try_from(backend).map_err(|_| {
+                    failure::format_err!(
+                        "Backend {:?} is not enabled, rebuild with `--features {}`",
+                        name,
+                        name
+                    )
+                })?)
+            }
+            None => None,
+        };
+
+        let headless = match opt.headless {
+            Some(output) => Some(headless::HeadlessConfig::new(output, opt.frames)?),
+            None => None,
+        };
+
+        Ok(AppConfig {
+            scene: opt.scene,
+            width: opt.width,
+            height: opt.height,
+            environment_override,
+            quality_override: opt.quality,
+            msaa_override: opt.msaa,
+            anisotropy_override: opt.anisotropy,
+            backend,
+            frames_in_flight: opt.frames_in_flight,
+            headless,
+            log_level: opt.log_level,
+            environment: EnvironmentConfig::default(),
+            environment_cache: !opt.no_environment_cache,
+        })
+    }
+}
+
+/// Paths given on the command line are relative to the application root,
+/// like the paths inside the scene file.
+fn check_exists(path: &Path, what: &str) -> Result<(), failure::Error> {
+    let full_path = Path::new(&crate::application_root_dir()).join(path);
+    if !full_path.is_file() {
+        failure::bail!("{} {:?} does not exist", what, full_path);
+    }
+    Ok(())
+}
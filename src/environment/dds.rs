@@ -0,0 +1,245 @@
+//! Minimal reader and writer for uncompressed float DDS files with a DX10 header,
+//! used to cache preprocessed environment textures between runs.
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 4] = b"DDS ";
+const HEADER_SIZE: u32 = 124;
+const PIXEL_FORMAT_SIZE: u32 = 32;
+
+const DDSD_CAPS: u32 = 0x1;
+const DDSD_HEIGHT: u32 = 0x2;
+const DDSD_WIDTH: u32 = 0x4;
+const DDSD_PITCH: u32 = 0x8;
+const DDSD_PIXELFORMAT: u32 = 0x1000;
+const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+
+const DDPF_FOURCC: u32 = 0x4;
+
+const DDSCAPS_COMPLEX: u32 = 0x8;
+const DDSCAPS_TEXTURE: u32 = 0x1000;
+const DDSCAPS_MIPMAP: u32 = 0x40_0000;
+
+const DDSCAPS2_CUBEMAP_ALL_FACES: u32 = 0x200 | 0xFC00;
+
+const D3D10_RESOURCE_DIMENSION_TEXTURE2D: u32 = 3;
+const D3D10_RESOURCE_MISC_TEXTURECUBE: u32 = 0x4;
+
+/// The subset of DXGI formats used by the environment textures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Rgba32Float,
+    Rg32Float,
+}
+
+impl Format {
+    fn dxgi(self) -> u32 {
+        match self {
+            Format::Rgba32Float => 2,
+            Format::Rg32Float => 16,
+        }
+    }
+
+    fn from_dxgi(dxgi: u32) -> Result<Self, failure::Error> {
+        match dxgi {
+            2 => Ok(Format::Rgba32Float),
+            16 => Ok(Format::Rg32Float),
+            _ => Err(failure::format_err!(
+                "Unsupported DXGI format {} in DDS file",
+                dxgi
+            )),
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> u32 {
+        match self {
+            Format::Rgba32Float => 16,
+            Format::Rg32Float => 8,
+        }
+    }
+}
+
+/// An uncompressed 2D or cube texture with its full mip chain.
+#[derive(Debug)]
+pub struct DdsImage {
+    pub format: Format,
+    pub width: u32,
+    pub height: u32,
+    pub cubemap: bool,
+    /// Tightly packed data for each mip level, starting at level 0. Within a level the
+    /// faces of a cubemap follow each other in +X, -X, +Y, -Y, +Z, -Z order.
+    pub levels: Vec<Vec<u8>>,
+}
+
+impl DdsImage {
+    pub fn layers(&self) -> u32 {
+        if self.cubemap {
+            6
+        } else {
+            1
+        }
+    }
+
+    /// The size in bytes of one face of the given mip level
+    pub fn layer_size(&self, level: u32) -> u64 {
+        let width = (self.width >> level).max(1) as u64;
+        let height = (self.height >> level).max(1) as u64;
+        width * height * self.format.bytes_per_pixel() as u64
+    }
+
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), failure::Error> {
+        let mip_levels = self.levels.len() as u32;
+        for (level, data) in self.levels.iter().enumerate() {
+            if data.len() as u64 != self.layer_size(level as u32) * self.layers() as u64 {
+                failure::bail!("Mip level {} has the wrong amount of data", level);
+            }
+        }
+
+        let mut header = Vec::with_capacity(4 + HEADER_SIZE as usize + 20);
+        let mut push = |value: u32| header.extend_from_slice(&value.to_le_bytes());
+
+        push(u32::from_le_bytes(*MAGIC));
+        push(HEADER_SIZE);
+        push(
+            DDSD_CAPS | DDSD_HEIGHT | DDSD_WIDTH | DDSD_PITCH | DDSD_PIXELFORMAT | DDSD_MIPMAPCOUNT,
+        );
+        push(self.height);
+        push(self.width);
+        push(self.width * self.format.bytes_per_pixel());
+        // depth
+        push(0);
+        push(mip_levels);
+        for _ in 0..11 {
+            push(0);
+        }
+
+        // Pixel format, only pointing at the DX10 header
+        push(PIXEL_FORMAT_SIZE);
+        push(DDPF_FOURCC);
+        push(u32::from_le_bytes(*b"DX10"));
+        for _ in 0..5 {
+            push(0);
+        }
+
+        let mut caps = DDSCAPS_TEXTURE;
+        if mip_levels > 1 {
+            caps |= DDSCAPS_COMPLEX | DDSCAPS_MIPMAP;
+        }
+        if self.cubemap {
+            caps |= DDSCAPS_COMPLEX;
+        }
+        push(caps);
+        push(if self.cubemap {
+            DDSCAPS2_CUBEMAP_ALL_FACES
+        } else {
+            0
+        });
+        // caps3, caps4, reserved
+        push(0);
+        push(0);
+        push(0);
+
+        // DX10 header
+        push(self.format.dxgi());
+        push(D3D10_RESOURCE_DIMENSION_TEXTURE2D);
+        push(if self.cubemap {
+            D3D10_RESOURCE_MISC_TEXTURECUBE
+        } else {
+            0
+        });
+        // array size, counted in cubes for cubemaps
+        push(1);
+        push(0);
+
+        writer.write_all(&header)?;
+
+        // DDS stores each face with its whole mip chain before moving on to the next face
+        for layer in 0..self.layers() as usize {
+            for (level, data) in self.levels.iter().enumerate() {
+                let layer_size = self.layer_size(level as u32) as usize;
+                writer.write_all(&data[layer * layer_size..(layer + 1) * layer_size])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads an image written by `write`. A corrupt file results in an error rather than a
+    /// panic or an allocation larger than the file.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self, failure::Error> {
+        let mut header = [0u8; 4 + HEADER_SIZE as usize + 20];
+        reader.read_exact(&mut header)?;
+        let field = |index: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&header[index * 4..index * 4 + 4]);
+            u32::from_le_bytes(bytes)
+        };
+
+        if field(0) != u32::from_le_bytes(*MAGIC) || field(1) != HEADER_SIZE {
+            failure::bail!("Not a DDS file");
+        }
+        // Pixel format fourCC lives at byte 84 of the file
+        if field(21) != u32::from_le_bytes(*b"DX10") {
+            failure::bail!("Only DDS files with a DX10 header are supported");
+        }
+
+        let height = field(3);
+        let width = field(4);
+        if width == 0 || height == 0 {
+            failure::bail!("DDS texture is empty");
+        }
+        // The header may be corrupt, so the mip levels are bounded by those of a full chain
+        let mip_levels = field(7).max(1);
+        let max_mip_levels = 32 - width.max(height).leading_zeros();
+        if mip_levels > max_mip_levels {
+            failure::bail!(
+                "DDS texture of {}x{} has {} mip levels, at most {} are possible",
+                width,
+                height,
+                mip_levels,
+                max_mip_levels
+            );
+        }
+        let format = Format::from_dxgi(field(32))?;
+        let cubemap = field(34) & D3D10_RESOURCE_MISC_TEXTURECUBE != 0;
+        if field(35) != 1 {
+            failure::bail!("DDS texture arrays are not supported");
+        }
+
+        let mut image = DdsImage {
+            format,
+            width,
+            height,
+            cubemap,
+            levels: Vec::new(),
+        };
+        let layer_sizes = (0..mip_levels)
+            .map(|level| image.layer_size(level))
+            .collect::<Vec<_>>();
+        let size = layer_sizes.iter().sum::<u64>() * image.layers() as u64;
+
+        // Only as much data as the file actually holds is read, and no more than expected
+        let mut data = Vec::new();
+        reader.take(size + 1).read_to_end(&mut data)?;
+        if data.len() as u64 != size {
+            failure::bail!(
+                "DDS file holds {} bytes of texture data, but its header describes {}",
+                data.len(),
+                size
+            );
+        }
+
+        image.levels = layer_sizes
+            .iter()
+            .map(|layer_size| Vec::with_capacity(*layer_size as usize * image.layers() as usize))
+            .collect();
+        let mut offset = 0;
+        for _ in 0..image.layers() {
+            for (level, layer_size) in image.levels.iter_mut().zip(layer_sizes.iter()) {
+                let layer_size = *layer_size as usize;
+                level.extend_from_slice(&data[offset..offset + layer_size]);
+                offset += layer_size;
+            }
+        }
+
+        Ok(image)
+    }
+}
@@ -0,0 +1,1174 @@
+//! Loads the environment map used for image based lighting. Converting and filtering the
+//! environment is expensive, so the results are cached on disk as DDS files and reused
+//! as long as the environment map, filter quality and texture resolutions are unchanged.
+use crate::{
+    config::EnvironmentConfig,
+    node::{self, pbr::EnvironmentStorage, readback::ReadbackStorage},
+    scene::{Quality, SceneConfig},
+};
+
+use rendy::{
+    command::{Families, Fence, QueueId, Submission},
+    factory::{Factory, ImageState},
+    graph::{render::*, Graph, GraphBuilder, ImageId, NodeId},
+    texture::{MipLevels, Texture, TextureBuilder},
+};
+
+use rendy::hal;
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver, TryRecvError},
+};
+
+pub mod dds;
+
+/// Directory, relative to the application root, in which preprocessed environments are cached
+pub const CACHE_DIR: &str = "cache/environment";
+/// Bump whenever the output of preprocessing changes to invalidate existing caches
+const CACHE_VERSION: u32 = 1;
+
+const ENVIRONMENT: &str = "environment";
+const IRRADIANCE: &str = "irradiance";
+const SPECULAR: &str = "specular";
+const SPEC_BRDF: &str = "spec_brdf";
+
+/// The preprocessed environment textures as read back from the gpu
+pub struct PreprocessedEnvironment {
+    environment: dds::DdsImage,
+    irradiance: dds::DdsImage,
+    specular: dds::DdsImage,
+    spec_brdf: dds::DdsImage,
+}
+
+/// The CPU side of loading an environment. Creating it doesn't touch the device,
+/// so it can happen on a background thread.
+pub enum EnvironmentData {
+    /// Textures preprocessed by an earlier run, read from the cache
+    Cached(PreprocessedEnvironment),
+    /// The decoded equirectangular environment map, which still needs preprocessing
+    Equirectangular {
+        image: TextureBuilder<'static>,
+        cache_dir: Option<PathBuf>,
+    },
+}
+
+impl EnvironmentData {
+    /// Reads an environment map, or its preprocessed textures if they are in the cache
+    /// and `use_cache` is set.
+    pub fn read(
+        environment_map: &str,
+        quality: Quality,
+        config: &EnvironmentConfig,
+        use_cache: bool,
+    ) -> Result<Self, failure::Error> {
+        let path = Path::new(&crate::application_root_dir()).join(environment_map);
+        let contents = std::fs::read(&path).map_err(|e| {
+            failure::format_err!("Failed to read environment map {:?}: {}", path, e)
+        })?;
+
+        let cache_dir = if use_cache {
+            Some(cache_dir(&contents, quality, config))
+        } else {
+            None
+        };
+
+        if let Some(cache_dir) = &cache_dir {
+            if cache_dir.is_dir() {
+                match read_cached(cache_dir, config) {
+                    Ok(cached) => {
+                        log::info!("Loaded preprocessed environment from {:?}", cache_dir);
+                        return Ok(EnvironmentData::Cached(cached));
+                    }
+                    Err(e) => log::warn!(
+                        "Failed to load preprocessed environment from {:?}, preprocessing again: {}",
+                        cache_dir,
+                        e
+                    ),
+                }
+            }
+        }
+
+        let image = rendy::texture::image::load_from_image(
+            std::io::Cursor::new(contents),
+            rendy::texture::image::ImageTextureConfig {
+                repr: rendy::texture::image::Repr::Float,
+                ..Default::default()
+            },
+        )?;
+
+        Ok(EnvironmentData::Equirectangular { image, cache_dir })
+    }
+
+    /// Creates the environment textures, running the preprocessing graph and
+    /// caching its results if they weren't cached already.
+    pub fn upload<B: hal::Backend>(
+        self,
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        queue: QueueId,
+        align: u64,
+        quality: Quality,
+        config: &EnvironmentConfig,
+    ) -> Result<EnvironmentStorage<B>, failure::Error> {
+        match self {
+            EnvironmentData::Cached(cached) => upload_cached(factory, queue, &cached, config),
+            EnvironmentData::Equirectangular { image, cache_dir } => {
+                let mut preprocess = Preprocess::start(
+                    factory, families, queue, align, image, cache_dir, quality, config,
+                )?;
+                preprocess.wait(factory)?;
+                let (storage, preprocessed) = preprocess.finish(factory)?;
+                if let Some((cache_dir, preprocessed)) = preprocessed {
+                    write_cache_logged(&cache_dir, &preprocessed);
+                }
+                Ok(storage)
+            }
+        }
+    }
+}
+
+/// Loads the environment map of the scene into an `EnvironmentStorage`, either from the
+/// cache or by running the preprocessing graph, caching its results if `use_cache` is set.
+pub fn load_environment<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    align: u64,
+    scene_config: &SceneConfig,
+    config: &EnvironmentConfig,
+    use_cache: bool,
+) -> Result<EnvironmentStorage<B>, failure::Error> {
+    let quality = scene_config.environment_filter_quality;
+    EnvironmentData::read(&scene_config.environment_map, quality, config, use_cache)?
+        .upload(factory, families, queue, align, quality, config)
+}
+
+/// The environment maps which can be switched between at runtime. The first map in the
+/// list is the one loaded at startup. Switches are requested by index; the
+/// `EnvironmentLoader` picks requests up and swaps the new environment in once it's ready.
+#[derive(Debug, Default)]
+pub struct EnvironmentList {
+    maps: Vec<String>,
+    current: usize,
+    requested: Option<usize>,
+}
+
+impl EnvironmentList {
+    pub fn new(maps: Vec<String>) -> Self {
+        EnvironmentList {
+            maps,
+            current: 0,
+            requested: None,
+        }
+    }
+
+    pub fn maps(&self) -> &[String] {
+        &self.maps
+    }
+
+    /// The index of the environment map currently in use
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Adds an environment map to the list if it isn't already in it, returning its index
+    pub fn add(&mut self, environment_map: &str) -> usize {
+        match self.maps.iter().position(|map| map == environment_map) {
+            Some(index) => index,
+            None => {
+                self.maps.push(String::from(environment_map));
+                self.maps.len() - 1
+            }
+        }
+    }
+
+    /// Request switching to the environment map at `index`
+    pub fn request(&mut self, index: usize) -> Result<(), failure::Error> {
+        if index >= self.maps.len() {
+            failure::bail!(
+                "No environment map with index {}, there are {}",
+                index,
+                self.maps.len()
+            );
+        }
+        self.requested = Some(index);
+        Ok(())
+    }
+
+    /// Request switching to the environment map after the current or last requested one
+    pub fn request_next(&mut self) {
+        if !self.maps.is_empty() {
+            let index = self.requested.unwrap_or(self.current);
+            self.requested = Some((index + 1) % self.maps.len());
+        }
+    }
+
+    /// Request switching to the environment map before the current or last requested one
+    pub fn request_previous(&mut self) {
+        if !self.maps.is_empty() {
+            let index = self.requested.unwrap_or(self.current);
+            self.requested = Some((index + self.maps.len() - 1) % self.maps.len());
+        }
+    }
+}
+
+/// Loads environments requested through the `EnvironmentList` on a background thread,
+/// then preprocesses them on the device while rendering continues, and swaps them into the
+/// `EnvironmentStorage` once they are ready.
+pub struct EnvironmentLoader<B: hal::Backend> {
+    queue: QueueId,
+    align: u64,
+    quality: Quality,
+    config: EnvironmentConfig,
+    use_cache: bool,
+    /// The index of the environment being loaded, and how far along it is
+    pending: Option<(usize, PendingEnvironment<B>)>,
+}
+
+enum PendingEnvironment<B: hal::Backend> {
+    /// The environment map is read, or its preprocessed textures loaded from the cache
+    Reading(Receiver<Result<EnvironmentData, failure::Error>>),
+    Preprocessing(Preprocess<B>),
+}
+
+impl<B: hal::Backend> EnvironmentLoader<B> {
+    pub fn new(
+        queue: QueueId,
+        align: u64,
+        quality: Quality,
+        config: EnvironmentConfig,
+        use_cache: bool,
+    ) -> Self {
+        EnvironmentLoader {
+            queue,
+            align,
+            quality,
+            config,
+            use_cache,
+            pending: None,
+        }
+    }
+
+    /// Advances the environment being loaded, swapping it in once it's ready, or starts
+    /// loading a newly requested one. Must be called between frames.
+    pub fn maintain(
+        &mut self,
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        world: &specs::World,
+    ) {
+        if let Some((index, pending)) = self.pending.take() {
+            match self.advance(factory, families, index, pending) {
+                Ok(Some(storage)) => {
+                    self.swap(world, storage);
+                    let mut list = world.write_resource::<EnvironmentList>();
+                    list.current = index;
+                    log::info!("Switched to environment map {}", list.maps[index]);
+                }
+                Ok(None) => (),
+                Err(e) => log::error!(
+                    "Failed to switch to environment map {}: {}",
+                    world.read_resource::<EnvironmentList>().maps[index],
+                    e
+                ),
+            }
+        }
+        // Once advanced, a still pending environment is put back
+        if self.pending.is_some() {
+            return;
+        }
+
+        let mut list = world.write_resource::<EnvironmentList>();
+        if let Some(index) = list.requested.take() {
+            if index != list.current {
+                let environment_map = list.maps[index].clone();
+                log::info!("Loading environment map {}", environment_map);
+
+                let (sender, receiver) = channel();
+                let (quality, config, use_cache) = (self.quality, self.config, self.use_cache);
+                std::thread::spawn(move || {
+                    // The receiver is gone if the app exited in the meantime
+                    let _ = sender.send(EnvironmentData::read(
+                        &environment_map,
+                        quality,
+                        &config,
+                        use_cache,
+                    ));
+                });
+                self.pending = Some((index, PendingEnvironment::Reading(receiver)));
+            }
+        }
+    }
+
+    /// Moves the environment being loaded on to its next step, returning it once it's ready.
+    /// An environment which is still pending is put back into `self.pending`.
+    fn advance(
+        &mut self,
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        index: usize,
+        pending: PendingEnvironment<B>,
+    ) -> Result<Option<EnvironmentStorage<B>>, failure::Error> {
+        match pending {
+            PendingEnvironment::Reading(receiver) => match receiver.try_recv() {
+                Ok(Ok(EnvironmentData::Cached(cached))) => {
+                    upload_cached(factory, self.queue, &cached, &self.config).map(Some)
+                }
+                Ok(Ok(EnvironmentData::Equirectangular { image, cache_dir })) => {
+                    let preprocess = Preprocess::start(
+                        factory,
+                        families,
+                        self.queue,
+                        self.align,
+                        image,
+                        cache_dir,
+                        self.quality,
+                        &self.config,
+                    )?;
+                    self.pending = Some((index, PendingEnvironment::Preprocessing(preprocess)));
+                    Ok(None)
+                }
+                Ok(Err(e)) => Err(e),
+                Err(TryRecvError::Empty) => {
+                    self.pending = Some((index, PendingEnvironment::Reading(receiver)));
+                    Ok(None)
+                }
+                Err(TryRecvError::Disconnected) => {
+                    Err(failure::format_err!("Environment loading thread panicked"))
+                }
+            },
+            PendingEnvironment::Preprocessing(mut preprocess) => {
+                match preprocess.is_complete(factory) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        self.pending = Some((index, PendingEnvironment::Preprocessing(preprocess)));
+                        return Ok(None);
+                    }
+                    Err(e) => {
+                        preprocess.cancel(factory);
+                        return Err(e);
+                    }
+                }
+                let (storage, preprocessed) = preprocess.finish(factory)?;
+                if let Some((cache_dir, preprocessed)) = preprocessed {
+                    // Writing the cache takes a while, so it happens in the background
+                    std::thread::spawn(move || write_cache_logged(&cache_dir, &preprocessed));
+                }
+                Ok(Some(storage))
+            }
+        }
+    }
+
+    /// Replaces the current environment. Frames in flight may still be sampling it, but
+    /// the factory only destroys its textures once they are done, and the passes only
+    /// rewrite the descriptor sets of frames which are no longer in flight.
+    fn swap(&self, world: &specs::World, mut storage: EnvironmentStorage<B>) {
+        let mut current = world.write_resource::<EnvironmentStorage<B>>();
+        storage.generation = current.generation + 1;
+        *current = storage;
+    }
+
+    /// Waits for any environment being preprocessed and disposes of it
+    pub fn dispose(&mut self, factory: &mut Factory<B>) {
+        if let Some((_, PendingEnvironment::Preprocessing(preprocess))) = self.pending.take() {
+            preprocess.cancel(factory);
+        }
+    }
+}
+
+/// The cache directory for an environment, named after a hash of everything
+/// which affects the result of preprocessing.
+fn cache_dir(environment_map: &[u8], quality: Quality, config: &EnvironmentConfig) -> PathBuf {
+    let mut hash = Fnv1a::new();
+    hash.write(environment_map);
+    hash.write(&CACHE_VERSION.to_le_bytes());
+    hash.write(&[quality as u8]);
+    for value in &[
+        config.env_cubemap_res,
+        config.env_cubemap_mip_levels as u32,
+        config.irradiance_cubemap_res,
+        config.spec_cubemap_res,
+        config.spec_cubemap_mip_levels as u32,
+        config.spec_brdf_map_res,
+    ] {
+        hash.write(&value.to_le_bytes());
+    }
+
+    Path::new(&crate::application_root_dir())
+        .join(CACHE_DIR)
+        .join(format!("{:016x}", hash.0))
+}
+
+/// 64 bit FNV-1a, used for cache keys since it is stable across builds
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+fn write_cache(
+    cache_dir: &Path,
+    preprocessed: &PreprocessedEnvironment,
+) -> Result<(), failure::Error> {
+    // Write into a temporary directory first so that an interrupted write never
+    // leaves behind a cache entry which looks complete
+    let tmp_dir = cache_dir.with_extension("tmp");
+    if tmp_dir.exists() {
+        std::fs::remove_dir_all(&tmp_dir)?;
+    }
+    std::fs::create_dir_all(&tmp_dir)?;
+
+    for (name, image) in &[
+        (ENVIRONMENT, &preprocessed.environment),
+        (IRRADIANCE, &preprocessed.irradiance),
+        (SPECULAR, &preprocessed.specular),
+        (SPEC_BRDF, &preprocessed.spec_brdf),
+    ] {
+        let mut writer = BufWriter::new(File::create(tmp_dir.join(format!("{}.dds", name)))?);
+        image.write(&mut writer)?;
+    }
+
+    if cache_dir.exists() {
+        std::fs::remove_dir_all(cache_dir)?;
+    }
+    std::fs::rename(&tmp_dir, cache_dir)?;
+    Ok(())
+}
+
+/// Writes the preprocessed textures to the cache, only logging a failure since the
+/// environment can always be preprocessed again
+fn write_cache_logged(cache_dir: &Path, preprocessed: &PreprocessedEnvironment) {
+    match write_cache(cache_dir, preprocessed) {
+        Ok(()) => log::info!("Cached preprocessed environment in {:?}", cache_dir),
+        Err(e) => log::warn!(
+            "Failed to cache preprocessed environment in {:?}: {}",
+            cache_dir,
+            e
+        ),
+    }
+}
+
+fn read_cached_image(
+    cache_dir: &Path,
+    name: &str,
+    format: dds::Format,
+    res: u32,
+    mip_levels: usize,
+) -> Result<dds::DdsImage, failure::Error> {
+    let path = cache_dir.join(format!("{}.dds", name));
+    let image = dds::DdsImage::read(&mut BufReader::new(File::open(&path)?))?;
+    if image.format != format
+        || image.width != res
+        || image.height != res
+        || image.levels.len() != mip_levels
+    {
+        failure::bail!(
+            "Cached texture {:?} does not match the environment config",
+            path
+        );
+    }
+    Ok(image)
+}
+
+fn read_cached(
+    cache_dir: &Path,
+    config: &EnvironmentConfig,
+) -> Result<PreprocessedEnvironment, failure::Error> {
+    use dds::Format::{Rg32Float, Rgba32Float};
+
+    let environment = read_cached_image(
+        cache_dir,
+        ENVIRONMENT,
+        Rgba32Float,
+        config.env_cubemap_res,
+        1,
+    )?;
+    let irradiance = read_cached_image(
+        cache_dir,
+        IRRADIANCE,
+        Rgba32Float,
+        config.irradiance_cubemap_res,
+        1,
+    )?;
+    let specular = read_cached_image(
+        cache_dir,
+        SPECULAR,
+        Rgba32Float,
+        config.spec_cubemap_res,
+        config.spec_cubemap_mip_levels as usize,
+    )?;
+    let spec_brdf =
+        read_cached_image(cache_dir, SPEC_BRDF, Rg32Float, config.spec_brdf_map_res, 1)?;
+
+    Ok(PreprocessedEnvironment {
+        environment,
+        irradiance,
+        specular,
+        spec_brdf,
+    })
+}
+
+fn upload_cached<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    queue: QueueId,
+    cached: &PreprocessedEnvironment,
+    config: &EnvironmentConfig,
+) -> Result<EnvironmentStorage<B>, failure::Error> {
+    Ok(EnvironmentStorage {
+        // Only the base level of the environment is cached, the rest are regenerated
+        env_cube: Some(build_texture(
+            factory,
+            queue,
+            &cached.environment,
+            MipLevels::GenerateLevels(
+                std::num::NonZeroU8::new(config.env_cubemap_mip_levels).unwrap(),
+            ),
+        )?),
+        irradiance_cube: Some(build_texture(
+            factory,
+            queue,
+            &cached.irradiance,
+            MipLevels::Levels(std::num::NonZeroU8::new(1).unwrap()),
+        )?),
+        spec_cube: Some(build_texture(
+            factory,
+            queue,
+            &cached.specular,
+            MipLevels::Levels(std::num::NonZeroU8::new(config.spec_cubemap_mip_levels).unwrap()),
+        )?),
+        spec_brdf_map: Some(build_texture(
+            factory,
+            queue,
+            &cached.spec_brdf,
+            MipLevels::Levels(std::num::NonZeroU8::new(1).unwrap()),
+        )?),
+        generation: 0,
+    })
+}
+
+/// Creates a texture from a cached image, uploading any mip levels beyond the first
+/// which are stored in the image.
+fn build_texture<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    queue: QueueId,
+    image: &dds::DdsImage,
+    mip_levels: MipLevels,
+) -> Result<Texture<B>, failure::Error> {
+    let state = ImageState {
+        queue,
+        stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+        access: hal::image::Access::SHADER_READ,
+        layout: hal::image::Layout::ShaderReadOnlyOptimal,
+    };
+
+    let format = match image.format {
+        dds::Format::Rgba32Float => hal::format::Format::Rgba32Sfloat,
+        dds::Format::Rg32Float => hal::format::Format::Rg32Sfloat,
+    };
+
+    let texture = TextureBuilder::new()
+        .with_kind(rendy::resource::Kind::D2(
+            image.width,
+            image.height,
+            image.layers() as u16,
+            1,
+        ))
+        .with_mip_levels(mip_levels)
+        .with_view_kind(if image.cubemap {
+            rendy::resource::ViewKind::Cube
+        } else {
+            rendy::resource::ViewKind::D2
+        })
+        .with_data_width(image.width)
+        .with_data_height(image.height)
+        .with_raw_data(&image.levels[0][..], format)
+        .build(state, factory)?;
+
+    for (level, data) in image.levels.iter().enumerate().skip(1) {
+        let width = (image.width >> level).max(1);
+        let height = (image.height >> level).max(1);
+        unsafe {
+            factory.upload_image(
+                texture.image().clone(),
+                width,
+                height,
+                hal::image::SubresourceLayers {
+                    aspects: hal::format::Aspects::COLOR,
+                    level: level as u8,
+                    layers: 0..image.layers() as u16,
+                },
+                hal::image::Offset::ZERO,
+                hal::image::Extent {
+                    width,
+                    height,
+                    depth: 1,
+                },
+                &data[..],
+                hal::image::Layout::Undefined,
+                state,
+            )
+        }
+        .map_err(|e| failure::format_err!("Failed to upload mip level {}: {:?}", level, e))?;
+    }
+
+    Ok(texture)
+}
+
+fn specular_readback_name(mip_level: usize) -> String {
+    format!("{}_{}", SPECULAR, mip_level)
+}
+
+/// Adds a node copying the faces rendered into `image` by `pass` to a readback buffer
+fn add_readback<B: hal::Backend>(
+    builder: &mut GraphBuilder<B, node::env_preprocess::Aux<B>>,
+    readback: &mut ReadbackStorage<B>,
+    factory: &Factory<B>,
+    image: ImageId,
+    pass: NodeId,
+    name: &str,
+    size: u32,
+) -> Result<(), failure::Error> {
+    readback.add_buffer(factory, name, size as u64)?;
+    builder.add_node(node::readback::Readback::<B>::builder(image, name).with_dependency(pass));
+    Ok(())
+}
+
+/// Preprocessing of an environment map submitted to the device. It runs alongside the
+/// frames rendered in the meantime, and its results are read back once it completes.
+pub struct Preprocess<B: hal::Backend> {
+    graph: Graph<B, node::env_preprocess::Aux<B>>,
+    aux: node::env_preprocess::Aux<B>,
+    /// One for each queue, signaled once the queue has finished the work submitted before it
+    fences: Vec<Fence<B>>,
+    cache_dir: Option<PathBuf>,
+}
+
+impl<B: hal::Backend> Preprocess<B> {
+    /// Converts the equirectangular environment map to a cubemap and filters it for image
+    /// based lighting, without waiting for the device to finish. The results are read back
+    /// so they can be cached in `cache_dir`.
+    pub fn start(
+        factory: &mut Factory<B>,
+        families: &mut Families<B>,
+        queue: QueueId,
+        align: u64,
+        equirect_image: TextureBuilder<'static>,
+        cache_dir: Option<PathBuf>,
+        quality: Quality,
+        config: &EnvironmentConfig,
+    ) -> Result<Self, failure::Error> {
+        let (graph, aux) = build_preprocess_graph(
+            factory,
+            families,
+            queue,
+            align,
+            equirect_image,
+            quality,
+            config,
+        )?;
+        let mut preprocess = Preprocess {
+            graph,
+            aux,
+            fences: Vec::new(),
+            cache_dir,
+        };
+
+        factory.maintain(families);
+        preprocess.graph.run(factory, families, &mut preprocess.aux);
+
+        // The graph may have submitted to any queue, and each executes its submissions in
+        // order, so a fence submitted to every queue after it signals when all of it is done
+        for family in families.as_slice_mut() {
+            for queue in family.as_slice_mut() {
+                let mut fence = factory
+                    .create_fence(false)
+                    .map_err(|e| failure::format_err!("Failed to create fence: {:?}", e))?;
+                unsafe {
+                    queue.submit(Some(Submission::new()), Some(&mut fence));
+                }
+                preprocess.fences.push(fence);
+            }
+        }
+        Ok(preprocess)
+    }
+
+    /// Whether the device has finished preprocessing
+    pub fn is_complete(&mut self, factory: &Factory<B>) -> Result<bool, failure::Error> {
+        for fence in self.fences.iter_mut() {
+            if !fence.is_signaled()
+                && fence
+                    .check_signaled(factory.device())
+                    .map_err(|e| failure::format_err!("Failed to check fence: {:?}", e))?
+                    .is_none()
+            {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Blocks until the device has finished preprocessing
+    pub fn wait(&mut self, factory: &Factory<B>) -> Result<(), failure::Error> {
+        for fence in self.fences.iter_mut() {
+            if !fence.is_signaled() {
+                fence
+                    .wait_signaled(factory.device(), !0)
+                    .map_err(|e| failure::format_err!("Failed to wait for fence: {:?}", e))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads back the results of preprocessing, which must be complete. Also returns the
+    /// preprocessed textures along with the directory to cache them in, if caching.
+    pub fn finish(
+        self,
+        factory: &mut Factory<B>,
+    ) -> Result<
+        (
+            EnvironmentStorage<B>,
+            Option<(PathBuf, PreprocessedEnvironment)>,
+        ),
+        failure::Error,
+    > {
+        let (mut aux, cache_dir) = self.dispose(factory);
+        let config = aux.environment_config;
+
+        let mut read_cube = |name: &str, res: u32| -> Result<dds::DdsImage, failure::Error> {
+            Ok(dds::DdsImage {
+                format: dds::Format::Rgba32Float,
+                width: res,
+                height: res,
+                cubemap: true,
+                levels: vec![aux.readback.read(factory, name)?],
+            })
+        };
+
+        let environment = read_cube(ENVIRONMENT, config.env_cubemap_res)?;
+        let irradiance = read_cube(IRRADIANCE, config.irradiance_cubemap_res)?;
+        let mut specular = read_cube(&specular_readback_name(0), config.spec_cubemap_res)?;
+        for mip_level in 1..config.spec_cubemap_mip_levels as usize {
+            specular.levels.push(
+                aux.readback
+                    .read(factory, &specular_readback_name(mip_level))?,
+            );
+        }
+        let spec_brdf = dds::DdsImage {
+            format: dds::Format::Rg32Float,
+            width: config.spec_brdf_map_res,
+            height: config.spec_brdf_map_res,
+            cubemap: false,
+            levels: vec![aux.readback.read(factory, SPEC_BRDF)?],
+        };
+
+        let storage = EnvironmentStorage {
+            env_cube: aux.environment_cubemap.take(),
+            irradiance_cube: aux.irradiance_cubemap.take(),
+            spec_cube: aux.spec_cubemap.take(),
+            spec_brdf_map: aux.spec_brdf_map.take(),
+            generation: 0,
+        };
+
+        let preprocessed = PreprocessedEnvironment {
+            environment,
+            irradiance,
+            specular,
+            spec_brdf,
+        };
+        Ok((
+            storage,
+            cache_dir.map(|cache_dir| (cache_dir, preprocessed)),
+        ))
+    }
+
+    /// Waits for preprocessing to complete and disposes of it without reading back results
+    pub fn cancel(mut self, factory: &mut Factory<B>) {
+        if let Err(e) = self.wait(factory) {
+            log::error!("Failed to wait for environment preprocessing: {}", e);
+        }
+        self.dispose(factory);
+    }
+
+    /// Disposes of the graph and fences once preprocessing is complete, returning what is
+    /// needed to read back the results
+    fn dispose(self, factory: &mut Factory<B>) -> (node::env_preprocess::Aux<B>, Option<PathBuf>) {
+        let Preprocess {
+            graph,
+            mut aux,
+            fences,
+            cache_dir,
+        } = self;
+        for fence in fences {
+            factory.destroy_fence(fence);
+        }
+        graph.dispose(factory, &mut aux);
+        (aux, cache_dir)
+    }
+}
+
+/// Builds the graph converting the equirectangular environment map to a cubemap and
+/// filtering it for image based lighting, along with the textures it renders into
+fn build_preprocess_graph<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    queue: QueueId,
+    align: u64,
+    equirect_image: TextureBuilder<'static>,
+    quality: Quality,
+    config: &EnvironmentConfig,
+) -> Result<
+    (
+        Graph<B, node::env_preprocess::Aux<B>>,
+        node::env_preprocess::Aux<B>,
+    ),
+    failure::Error,
+> {
+    let mut env_preprocess_graph_builder = GraphBuilder::<B, node::env_preprocess::Aux<B>>::new();
+    let mut readback = ReadbackStorage::new();
+
+    // Equirectangular env map to environment cube map
+
+    let env_cube_faces_img = env_preprocess_graph_builder.create_image(
+        hal::image::Kind::D2(config.env_cubemap_res, config.env_cubemap_res * 6, 1, 1),
+        1,
+        hal::format::Format::Rgba32Sfloat,
+        Some(hal::command::ClearValue {
+            color: hal::command::ClearColor {
+                float32: [0.0, 0.0, 0.0, 1.0],
+            },
+        }),
+    );
+
+    let equirect_to_faces_pass = env_preprocess_graph_builder.add_node(
+        node::env_preprocess::equirectangular_to_cube_faces::Pipeline::<B>::builder()
+            .into_subpass()
+            .with_color(env_cube_faces_img)
+            .into_pass(),
+    );
+
+    add_readback(
+        &mut env_preprocess_graph_builder,
+        &mut readback,
+        factory,
+        env_cube_faces_img,
+        equirect_to_faces_pass,
+        ENVIRONMENT,
+        config.env_cubemap_res * config.env_cubemap_res * 6 * 16,
+    )?;
+
+    let faces_to_env_pass = env_preprocess_graph_builder.add_node(
+        node::env_preprocess::faces_to_cubemap::FacesToCubemap::<B>::builder(
+            vec![env_cube_faces_img],
+            "environment",
+            node::env_preprocess::faces_to_cubemap::CopyMips::GenerateMips,
+        )
+        .with_dependency(equirect_to_faces_pass),
+    );
+
+    // Environment cube map to convolved irradiance cube map
+
+    let irradiance_cube_faces_img = env_preprocess_graph_builder.create_image(
+        hal::image::Kind::D2(
+            config.irradiance_cubemap_res,
+            config.irradiance_cubemap_res * 6,
+            1,
+            1,
+        ),
+        1,
+        hal::format::Format::Rgba32Sfloat,
+        Some(hal::command::ClearValue {
+            color: hal::command::ClearColor {
+                float32: [0.0, 0.0, 0.0, 1.0],
+            },
+        }),
+    );
+
+    let env_to_irradiance_faces_pass = env_preprocess_graph_builder.add_node(
+        node::env_preprocess::env_to_irradiance::Pipeline::<B>::builder()
+            .with_dependency(faces_to_env_pass)
+            .into_subpass()
+            .with_color(irradiance_cube_faces_img)
+            .into_pass(),
+    );
+
+    add_readback(
+        &mut env_preprocess_graph_builder,
+        &mut readback,
+        factory,
+        irradiance_cube_faces_img,
+        env_to_irradiance_faces_pass,
+        IRRADIANCE,
+        config.irradiance_cubemap_res * config.irradiance_cubemap_res * 6 * 16,
+    )?;
+
+    let _irradiance_to_cube_pass = env_preprocess_graph_builder.add_node(
+        node::env_preprocess::faces_to_cubemap::FacesToCubemap::<B>::builder(
+            vec![irradiance_cube_faces_img],
+            "irradiance",
+            node::env_preprocess::faces_to_cubemap::CopyMips::CopyMips(1),
+        )
+        .with_dependency(env_to_irradiance_faces_pass),
+    );
+
+    // Environment cube map to convolved specular cube map with different roughnesses
+    // stored in mip levels
+
+    let mut env_to_spec_faces_subpasses = Vec::new();
+    let mut spec_cube_faces_images = Vec::new();
+
+    for mip_level in 0..config.spec_cubemap_mip_levels {
+        let res = config.spec_cubemap_res / 2u32.pow(mip_level as u32);
+        let mut subpass = node::env_preprocess::env_to_specular::Pipeline::<B>::builder()
+            .with_dependency(faces_to_env_pass)
+            .into_subpass();
+        let image = env_preprocess_graph_builder.create_image(
+            hal::image::Kind::D2(res, res * 6, 1, 1),
+            1,
+            hal::format::Format::Rgba32Sfloat,
+            Some(hal::command::ClearValue {
+                color: hal::command::ClearColor {
+                    float32: [0.0, 0.0, 0.0, 1.0],
+                },
+            }),
+        );
+        subpass.add_color(image);
+        spec_cube_faces_images.push(image);
+        env_to_spec_faces_subpasses.push(subpass);
+    }
+
+    let mut env_to_spec_faces_passes = Vec::new();
+    while !env_to_spec_faces_subpasses.is_empty() {
+        env_to_spec_faces_passes.push(
+            env_preprocess_graph_builder
+                .add_node(env_to_spec_faces_subpasses.pop().unwrap().into_pass()),
+        );
+    }
+
+    let mut builder = node::env_preprocess::faces_to_cubemap::FacesToCubemap::<B>::builder(
+        spec_cube_faces_images.clone(),
+        "specular",
+        node::env_preprocess::faces_to_cubemap::CopyMips::CopyMips(config.spec_cubemap_mip_levels),
+    );
+
+    for &pass in &env_to_spec_faces_passes {
+        builder.add_dependency(pass);
+    }
+
+    // Passes were added starting from the smallest mip level
+    for (mip_level, (&image, &pass)) in spec_cube_faces_images
+        .iter()
+        .zip(env_to_spec_faces_passes.iter().rev())
+        .enumerate()
+    {
+        let res = config.spec_cubemap_res / 2u32.pow(mip_level as u32);
+        add_readback(
+            &mut env_preprocess_graph_builder,
+            &mut readback,
+            factory,
+            image,
+            pass,
+            &specular_readback_name(mip_level),
+            res * res * 6 * 16,
+        )?;
+    }
+
+    let _spec_to_cube_pass = env_preprocess_graph_builder.add_node(builder);
+
+    let spec_brdf_map = env_preprocess_graph_builder.create_image(
+        hal::image::Kind::D2(config.spec_brdf_map_res, config.spec_brdf_map_res, 1, 1),
+        1,
+        hal::format::Format::Rg32Sfloat,
+        Some(hal::command::ClearValue {
+            color: hal::command::ClearColor {
+                float32: [0.0, 0.0, 0.0, 1.0],
+            },
+        }),
+    );
+
+    let brdf_integration_pass = env_preprocess_graph_builder.add_node(
+        node::env_preprocess::integrate_spec_brdf::Pipeline::builder()
+            .into_subpass()
+            .with_color(spec_brdf_map)
+            .into_pass(),
+    );
+
+    add_readback(
+        &mut env_preprocess_graph_builder,
+        &mut readback,
+        factory,
+        spec_brdf_map,
+        brdf_integration_pass,
+        SPEC_BRDF,
+        config.spec_brdf_map_res * config.spec_brdf_map_res * 8,
+    )?;
+
+    let _brdf_to_texture = env_preprocess_graph_builder.add_node(
+        node::env_preprocess::copy_to_texture::CopyToTexture::<B>::builder(
+            spec_brdf_map,
+            "spec_brdf",
+        )
+        .with_dependency(brdf_integration_pass),
+    );
+
+    let equirect_tex = equirect_image.build(
+        ImageState {
+            queue,
+            stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+            access: hal::image::Access::SHADER_READ,
+            layout: hal::image::Layout::ShaderReadOnlyOptimal,
+        },
+        factory,
+    )?;
+
+    let env_cubemap_tex = rendy::texture::TextureBuilder::new()
+        .with_kind(rendy::resource::Kind::D2(
+            config.env_cubemap_res,
+            config.env_cubemap_res,
+            6,
+            1,
+        ))
+        .with_mip_levels(rendy::texture::MipLevels::Levels(
+            std::num::NonZeroU8::new(config.env_cubemap_mip_levels).unwrap(),
+        ))
+        .with_view_kind(rendy::resource::ViewKind::Cube)
+        .with_data_width(config.env_cubemap_res)
+        .with_data_height(config.env_cubemap_res)
+        .with_data(vec![
+            rendy::texture::pixel::Rgba32Sfloat {
+                repr: [0.0, 0.0, 0.0, 1.0]
+            };
+            (config.env_cubemap_res * config.env_cubemap_res * 6)
+                as usize
+        ])
+        .build(
+            ImageState {
+                queue,
+                stage: hal::pso::PipelineStage::TRANSFER,
+                access: hal::image::Access::TRANSFER_WRITE,
+                layout: hal::image::Layout::TransferDstOptimal,
+            },
+            factory,
+        )?;
+
+    let irradiance_cubemap_tex = rendy::texture::TextureBuilder::new()
+        .with_kind(rendy::resource::Kind::D2(
+            config.irradiance_cubemap_res,
+            config.irradiance_cubemap_res,
+            6,
+            1,
+        ))
+        .with_view_kind(rendy::resource::ViewKind::Cube)
+        .with_data_width(config.irradiance_cubemap_res)
+        .with_data_height(config.irradiance_cubemap_res)
+        .with_data(vec![
+            rendy::texture::pixel::Rgba32Sfloat {
+                repr: [0.0, 0.0, 0.0, 1.0]
+            };
+            (config.irradiance_cubemap_res * config.irradiance_cubemap_res * 6)
+                as usize
+        ])
+        .build(
+            ImageState {
+                queue,
+                stage: hal::pso::PipelineStage::TRANSFER,
+                access: hal::image::Access::TRANSFER_WRITE,
+                layout: hal::image::Layout::TransferDstOptimal,
+            },
+            factory,
+        )?;
+
+    let spec_cubemap_tex = rendy::texture::TextureBuilder::new()
+        .with_kind(rendy::resource::Kind::D2(
+            config.spec_cubemap_res,
+            config.spec_cubemap_res,
+            6,
+            1,
+        ))
+        .with_mip_levels(rendy::texture::MipLevels::Levels(
+            std::num::NonZeroU8::new(config.spec_cubemap_mip_levels).unwrap(),
+        ))
+        .with_view_kind(rendy::resource::ViewKind::Cube)
+        .with_data_width(config.spec_cubemap_res)
+        .with_data_height(config.spec_cubemap_res)
+        .with_data(vec![
+            rendy::texture::pixel::Rgba32Sfloat {
+                repr: [0.0, 0.0, 0.0, 1.0]
+            };
+            (config.spec_cubemap_res * config.spec_cubemap_res * 6)
+                as usize
+        ])
+        .build(
+            ImageState {
+                queue,
+                stage: hal::pso::PipelineStage::TRANSFER,
+                access: hal::image::Access::TRANSFER_WRITE,
+                layout: hal::image::Layout::TransferDstOptimal,
+            },
+            factory,
+        )?;
+
+    let spec_brdf_tex = rendy::texture::TextureBuilder::new()
+        .with_kind(rendy::resource::Kind::D2(
+            config.spec_brdf_map_res,
+            config.spec_brdf_map_res,
+            1,
+            1,
+        ))
+        .with_view_kind(rendy::resource::ViewKind::D2)
+        .with_data_width(config.spec_brdf_map_res)
+        .with_data_height(config.spec_brdf_map_res)
+        .with_data(vec![
+            rendy::texture::pixel::Rg32Sfloat { repr: [0.0, 0.0] };
+            (config.spec_brdf_map_res * config.spec_brdf_map_res)
+                as usize
+        ])
+        .build(
+            ImageState {
+                queue,
+                stage: hal::pso::PipelineStage::TRANSFER,
+                access: hal::image::Access::TRANSFER_WRITE,
+                layout: hal::image::Layout::TransferDstOptimal,
+            },
+            factory,
+        )?;
+
+    let mut env_preprocess_aux = node::env_preprocess::Aux {
+        align,
+        environment_config: *config,
+        irradiance_theta_samples: match quality {
+            Quality::High => 720,
+            Quality::Medium => 512,
+            Quality::Low => 256,
+        },
+        spec_samples: match quality {
+            Quality::High => 8192,
+            Quality::Medium => 4096,
+            Quality::Low => 1024,
+        },
+        equirectangular_texture: equirect_tex,
+        environment_cubemap: Some(env_cubemap_tex),
+        irradiance_cubemap: Some(irradiance_cubemap_tex),
+        spec_cubemap: Some(spec_cubemap_tex),
+        spec_brdf_map: Some(spec_brdf_tex),
+        queue,
+        mip_level: std::sync::atomic::AtomicUsize::new(0),
+        readback,
+    };
+
+    let env_preprocess_graph =
+        env_preprocess_graph_builder.build(factory, families, &mut env_preprocess_aux)?;
+
+    Ok((env_preprocess_graph, env_preprocess_aux))
+}
@@ -0,0 +1,284 @@
+//! Vertex attributes generated on import for glTF primitives which leave them out, and
+//! conversion of their indices to lists. Attributes are only generated for triangle lists.
+use nalgebra::Vector3;
+
+use std::{collections::HashMap, hash::Hash};
+
+/// Converts triangle strip indices to triangle list indices. Every other triangle of a strip
+/// is flipped, so that all of them wind like the first.
+pub fn triangle_strip_to_list(indices: &[u32]) -> Vec<u32> {
+    indices
+        .windows(3)
+        .enumerate()
+        .flat_map(|(i, triangle)| {
+            if i % 2 == 0 {
+                vec![triangle[0], triangle[1], triangle[2]]
+            } else {
+                vec![triangle[1], triangle[0], triangle[2]]
+            }
+        })
+        .collect()
+}
+
+/// Converts triangle fan indices to triangle list indices
+pub fn triangle_fan_to_list(indices: &[u32]) -> Vec<u32> {
+    indices
+        .windows(2)
+        .skip(1)
+        .flat_map(|edge| vec![edge[0], edge[1], indices[0]])
+        .collect()
+}
+
+/// Converts line strip indices to line list indices, adding a line from the last vertex back
+/// to the first if `closed` is set
+pub fn line_strip_to_list(indices: &[u32], closed: bool) -> Vec<u32> {
+    let mut list = indices
+        .windows(2)
+        .flat_map(|line| vec![line[0], line[1]])
+        .collect::<Vec<_>>();
+    if closed && indices.len() > 2 {
+        list.push(indices[indices.len() - 1]);
+        list.push(indices[0]);
+    }
+    list
+}
+
+/// Gives every triangle corner its own vertex, rewriting `indices` to `0..indices.len()`.
+/// Returns the vertex each new vertex is copied from, for `remap`.
+pub fn unweld(indices: &mut Vec<u32>) -> Vec<u32> {
+    let source_vertices = std::mem::replace(indices, Vec::new());
+    indices.extend(0..source_vertices.len() as u32);
+    source_vertices
+}
+
+/// Merges the vertices for which `key` is equal, rewriting `indices` to the merged vertices.
+/// Returns the first vertex merged into each, for `remap`.
+pub fn weld<K: Eq + Hash>(indices: &mut [u32], key: impl Fn(usize) -> K) -> Vec<u32> {
+    let mut merged = HashMap::new();
+    let mut kept = Vec::new();
+    for index in indices.iter_mut() {
+        let vertex = *index;
+        *index = *merged.entry(key(vertex as usize)).or_insert_with(|| {
+            kept.push(vertex);
+            kept.len() as u32 - 1
+        });
+    }
+    kept
+}
+
+/// The bits of a normal and tangent, for comparing generated tangent frames in `weld`
+pub fn frame_bits(normal: &[f32; 3], tangent: &[f32; 4]) -> [u32; 7] {
+    let mut bits = [0; 7];
+    for (bits, value) in bits.iter_mut().zip(normal.iter().chain(tangent.iter())) {
+        *bits = value.to_bits();
+    }
+    bits
+}
+
+/// Copies the attributes of the source vertices returned by `unweld` or `weld`, or returns
+/// `values` unchanged if the primitive wasn't unwelded
+pub fn remap<T: Copy>(values: Vec<T>, source_vertices: Option<&[u32]>) -> Vec<T> {
+    match source_vertices {
+        Some(source_vertices) => source_vertices
+            .iter()
+            .map(|vertex| values[*vertex as usize])
+            .collect(),
+        None => values,
+    }
+}
+
+/// The normal of each triangle, weighted by its area
+fn triangle_normals<'a>(
+    positions: &'a [[f32; 3]],
+    indices: &'a [u32],
+) -> impl Iterator<Item = ([usize; 3], Vector3<f32>)> + 'a {
+    indices.chunks_exact(3).map(move |triangle| {
+        let corners = [
+            triangle[0] as usize,
+            triangle[1] as usize,
+            triangle[2] as usize,
+        ];
+        let [a, b, c] = corners;
+        let a_pos = Vector3::from(positions[a]);
+        let normal = (Vector3::from(positions[b]) - a_pos)
+            .cross(&(Vector3::from(positions[c]) - a_pos))
+            * 0.5;
+        (corners, normal)
+    })
+}
+
+/// Normalizes each normal, pointing degenerate ones along +z
+fn normalize_all(normals: Vec<Vector3<f32>>) -> Vec<[f32; 3]> {
+    normals
+        .into_iter()
+        .map(|normal| {
+            normal
+                .try_normalize(std::f32::EPSILON)
+                .unwrap_or(Vector3::z())
+                .into()
+        })
+        .collect()
+}
+
+/// Gives the vertices of each triangle its normal. The triangles must not share vertices, see
+/// `unweld`.
+pub fn flat_normals(positions: &[[f32; 3]], indices: &[u32]) -> Vec<[f32; 3]> {
+    let mut normals = vec![Vector3::zeros(); positions.len()];
+    for (corners, normal) in triangle_normals(positions, indices) {
+        for vertex in corners.iter() {
+            normals[*vertex] = normal;
+        }
+    }
+    normalize_all(normals)
+}
+
+/// Averages the normals of the triangles sharing each vertex, weighted by their areas.
+/// Triangles are only smoothed across vertices they share by index.
+pub fn smooth_normals(positions: &[[f32; 3]], indices: &[u32]) -> Vec<[f32; 3]> {
+    let mut normals = vec![Vector3::zeros(); positions.len()];
+    for (corners, normal) in triangle_normals(positions, indices) {
+        for vertex in corners.iter() {
+            normals[*vertex] += normal;
+        }
+    }
+    normalize_all(normals)
+}
+
+/// Any tangent perpendicular to each normal, for primitives without a normal map, which only
+/// need the tangent frame to be valid
+pub fn orthogonal_tangents(normals: &[[f32; 3]]) -> Vec<[f32; 4]> {
+    normals
+        .iter()
+        .map(|normal| {
+            let normal = Vector3::from(*normal);
+            // The axis least aligned with the normal
+            let axis = if normal.x.abs() < 0.9 {
+                Vector3::x()
+            } else {
+                Vector3::y()
+            };
+            let tangent = (axis - normal * normal.dot(&axis)).normalize();
+            [tangent.x, tangent.y, tangent.z, 1.0]
+        })
+        .collect()
+}
+
+/// MikkTSpace tangents, as the glTF spec requires for normal mapped primitives without
+/// tangents. The triangles must not share vertices, see `unweld`, since MikkTSpace gives the
+/// corners of a vertex different tangents on UV seams. Returns `None` if they couldn't be
+/// generated.
+pub fn mikktspace_tangents(
+    positions: &[[f32; 3]],
+    normals: &[[f32; 3]],
+    tex_coords: &[[f32; 2]],
+    indices: &[u32],
+) -> Option<Vec<[f32; 4]>> {
+    let mut geometry = MikktGeometry {
+        positions,
+        normals,
+        tex_coords,
+        indices,
+        tangents: vec![[1.0, 0.0, 0.0, 1.0]; positions.len()],
+    };
+    if mikktspace::generate_tangents(&mut geometry) {
+        Some(geometry.tangents)
+    } else {
+        None
+    }
+}
+
+struct MikktGeometry<'a> {
+    positions: &'a [[f32; 3]],
+    normals: &'a [[f32; 3]],
+    tex_coords: &'a [[f32; 2]],
+    indices: &'a [u32],
+    tangents: Vec<[f32; 4]>,
+}
+
+impl<'a> MikktGeometry<'a> {
+    fn vertex(&self, face: usize, vert: usize) -> usize {
+        self.indices[face * 3 + vert] as usize
+    }
+}
+
+impl<'a> mikktspace::Geometry for MikktGeometry<'a> {
+    fn num_faces(&self) -> usize {
+        self.indices.len() / 3
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.positions[self.vertex(face, vert)]
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        self.normals[self.vertex(face, vert)]
+    }
+
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        // MikkTSpace expects v to point up, glTF's points down
+        let [u, v] = self.tex_coords[self.vertex(face, vert)];
+        [u, 1.0 - v]
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        let vertex = self.vertex(face, vert);
+        self.tangents[vertex] = tangent;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triangle_strip_keeps_winding() {
+        assert_eq!(
+            triangle_strip_to_list(&[0, 1, 2, 3, 4]),
+            vec![0, 1, 2, 2, 1, 3, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn triangle_fan_shares_first_vertex() {
+        assert_eq!(
+            triangle_fan_to_list(&[0, 1, 2, 3, 4]),
+            vec![1, 2, 0, 2, 3, 0, 3, 4, 0]
+        );
+    }
+
+    #[test]
+    fn line_strip_closes_loops() {
+        assert_eq!(line_strip_to_list(&[0, 1, 2], false), vec![0, 1, 1, 2]);
+        assert_eq!(line_strip_to_list(&[0, 1, 2], true), vec![0, 1, 1, 2, 2, 0]);
+        // A loop of two vertices is a single line
+        assert_eq!(line_strip_to_list(&[0, 1], true), vec![0, 1]);
+    }
+
+    #[test]
+    fn too_few_indices_give_empty_lists() {
+        assert!(triangle_strip_to_list(&[0, 1]).is_empty());
+        assert!(triangle_fan_to_list(&[0, 1]).is_empty());
+        assert!(line_strip_to_list(&[0], true).is_empty());
+        assert!(line_strip_to_list(&[], false).is_empty());
+    }
+
+    #[test]
+    fn weld_merges_unwelded_vertices_with_equal_keys() {
+        let keys = [10, 20, 10];
+        let mut indices = vec![0, 1, 2, 2, 1, 0];
+        let source_vertices = unweld(&mut indices);
+        assert_eq!(indices, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(source_vertices, vec![0, 1, 2, 2, 1, 0]);
+
+        let kept = weld(&mut indices, |vertex| {
+            keys[source_vertices[vertex] as usize]
+        });
+        assert_eq!(indices, vec![0, 1, 0, 0, 1, 0]);
+        let kept = remap(source_vertices, Some(&kept));
+        assert_eq!(remap(keys.to_vec(), Some(&kept)), vec![10, 20]);
+    }
+}
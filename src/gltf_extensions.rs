@@ -0,0 +1,355 @@
+//! glTF extensions which the `gltf` crate doesn't read, parsed from the JSON of the file
+//! alongside it.
+use crate::components;
+
+use serde::Deserialize;
+
+use std::path::Path;
+
+/// The extension data of a glTF file
+#[derive(Debug, Default)]
+pub struct GltfExtensions {
+    materials: Vec<MaterialExtensions>,
+    /// `KHR_lights_punctual` lights
+    lights: Vec<components::Light>,
+    /// The index of the light attached to each node
+    node_lights: Vec<Option<usize>>,
+}
+
+/// `KHR_texture_transform`, mapping texture coordinates to the coordinates sampled
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct KhrTextureTransform {
+    pub offset: [f32; 2],
+    /// Counter-clockwise rotation in radians
+    pub rotation: f32,
+    pub scale: [f32; 2],
+    /// Overrides the texture coordinate set of the texture info
+    pub tex_coord: Option<u32>,
+}
+
+impl Default for KhrTextureTransform {
+    fn default() -> Self {
+        KhrTextureTransform {
+            offset: [0.0; 2],
+            rotation: 0.0,
+            scale: [1.0; 2],
+            tex_coord: None,
+        }
+    }
+}
+
+/// The extensions of a material and of its texture infos
+#[derive(Debug, Clone, Copy)]
+pub struct MaterialExtensions {
+    /// `KHR_materials_emissive_strength`, scaling the emissive factor
+    pub emissive_strength: f32,
+    pub base_color_transform: Option<KhrTextureTransform>,
+    pub metallic_roughness_transform: Option<KhrTextureTransform>,
+    pub normal_transform: Option<KhrTextureTransform>,
+    pub occlusion_transform: Option<KhrTextureTransform>,
+    pub emissive_transform: Option<KhrTextureTransform>,
+}
+
+impl Default for MaterialExtensions {
+    fn default() -> Self {
+        MaterialExtensions {
+            emissive_strength: 1.0,
+            base_color_transform: None,
+            metallic_roughness_transform: None,
+            normal_transform: None,
+            occlusion_transform: None,
+            emissive_transform: None,
+        }
+    }
+}
+
+impl GltfExtensions {
+    /// The extensions of the material at `index`, or of the default material if `None`
+    pub fn material(&self, index: Option<usize>) -> MaterialExtensions {
+        index
+            .and_then(|index| self.materials.get(index).cloned())
+            .unwrap_or_default()
+    }
+
+    /// The `KHR_lights_punctual` light attached to the node at `index`, if it has one
+    pub fn node_light(&self, index: usize) -> Option<components::Light> {
+        self.node_lights
+            .get(index)
+            .and_then(|light| *light)
+            .map(|light| self.lights[light])
+    }
+
+    fn from_json(json: &[u8]) -> Result<Self, failure::Error> {
+        let root: json::Root = serde_json::from_slice(json)?;
+        let transform = |info: Option<json::TextureInfo>| {
+            info.and_then(|info| info.extensions.khr_texture_transform)
+        };
+        let materials = root
+            .materials
+            .into_iter()
+            .map(|material| MaterialExtensions {
+                emissive_strength: material
+                    .extensions
+                    .khr_materials_emissive_strength
+                    .map_or(1.0, |extension| extension.emissive_strength),
+                base_color_transform: transform(material.pbr_metallic_roughness.base_color_texture),
+                metallic_roughness_transform: transform(
+                    material.pbr_metallic_roughness.metallic_roughness_texture,
+                ),
+                normal_transform: transform(material.normal_texture),
+                occlusion_transform: transform(material.occlusion_texture),
+                emissive_transform: transform(material.emissive_texture),
+            })
+            .collect();
+
+        let lights = root
+            .extensions
+            .khr_lights_punctual
+            .map_or(Vec::new(), |extension| extension.lights)
+            .into_iter()
+            .enumerate()
+            .map(|(index, light)| {
+                Ok(components::Light {
+                    // Candela for point and spot lights and lux for directional lights, which
+                    // match the falloff of the light kinds here
+                    intensity: light.intensity,
+                    color: light.color,
+                    kind: match light.kind {
+                        json::LightType::Point => components::LightKind::Point,
+                        json::LightType::Directional => components::LightKind::Directional,
+                        json::LightType::Spot => {
+                            let spot = light.spot.unwrap_or_default();
+                            components::LightKind::spot(
+                                spot.inner_cone_angle,
+                                spot.outer_cone_angle,
+                            )
+                            .map_err(|e| failure::format_err!("Light {}: {}", index, e))?
+                        }
+                    },
+                    casts_shadows: false,
+                    shadow_resolution: components::default_shadow_resolution(),
+                })
+            })
+            .collect::<Result<Vec<_>, failure::Error>>()?;
+        let node_lights = root
+            .nodes
+            .into_iter()
+            .map(|node| node.extensions.khr_lights_punctual.map(|light| light.light))
+            .collect::<Vec<_>>();
+        if let Some(light) = node_lights
+            .iter()
+            .flatten()
+            .find(|light| **light >= lights.len())
+        {
+            failure::bail!("Node refers to light {} which does not exist", light);
+        }
+
+        Ok(GltfExtensions {
+            materials,
+            lights,
+            node_lights,
+        })
+    }
+}
+
+/// Reads a `.gltf` or `.glb` file, along with the extensions the `gltf` crate skips
+pub fn read_gltf(path: &Path) -> Result<(gltf::Gltf, GltfExtensions), failure::Error> {
+    let data = std::fs::read(path)
+        .map_err(|e| failure::format_err!("Failed to open glTF file {:?}: {}", path, e))?;
+    let gltf = gltf::Gltf::from_slice(&data)
+        .map_err(|e| failure::format_err!("Failed to read glTF file {:?}: {}", path, e))?;
+    let extensions = GltfExtensions::from_json(json_chunk(&data)?)
+        .map_err(|e| failure::format_err!("Invalid extensions in {:?}: {}", path, e))?;
+    Ok((gltf, extensions))
+}
+
+/// The JSON of a glTF file, which is the first chunk of a GLB file and the whole of any
+/// other file
+fn json_chunk(data: &[u8]) -> Result<&[u8], failure::Error> {
+    if !data.starts_with(b"glTF") {
+        return Ok(data);
+    }
+    // 12 byte header, then the length and type of the JSON chunk
+    let read_u32 = |offset: usize| {
+        data.get(offset..offset + 4)
+            .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    };
+    let length = read_u32(12).ok_or(failure::format_err!("GLB file is truncated"))?;
+    data.get(20..20 + length)
+        .ok_or(failure::format_err!("GLB JSON chunk is truncated"))
+}
+
+/// The subset of the glTF JSON schema holding the extensions read here
+mod json {
+    use super::KhrTextureTransform;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Default)]
+    #[serde(default)]
+    pub struct Root {
+        pub materials: Vec<Material>,
+        pub nodes: Vec<Node>,
+        pub extensions: RootExtensions,
+    }
+
+    #[derive(Deserialize, Default)]
+    #[serde(default)]
+    pub struct RootExtensions {
+        #[serde(rename = "KHR_lights_punctual")]
+        pub khr_lights_punctual: Option<LightsPunctual>,
+    }
+
+    #[derive(Deserialize, Default)]
+    #[serde(default)]
+    pub struct LightsPunctual {
+        pub lights: Vec<Light>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Light {
+        #[serde(rename = "type")]
+        pub kind: LightType,
+        #[serde(default = "default_light_color")]
+        pub color: [f32; 3],
+        #[serde(default = "default_light_intensity")]
+        pub intensity: f32,
+        #[serde(default)]
+        pub spot: Option<Spot>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum LightType {
+        Point,
+        Spot,
+        Directional,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(default, rename_all = "camelCase")]
+    pub struct Spot {
+        pub inner_cone_angle: f32,
+        pub outer_cone_angle: f32,
+    }
+
+    impl Default for Spot {
+        fn default() -> Self {
+            Spot {
+                inner_cone_angle: 0.0,
+                outer_cone_angle: std::f32::consts::FRAC_PI_4,
+            }
+        }
+    }
+
+    fn default_light_color() -> [f32; 3] {
+        [1.0; 3]
+    }
+
+    fn default_light_intensity() -> f32 {
+        1.0
+    }
+
+    #[derive(Deserialize, Default)]
+    #[serde(default)]
+    pub struct Node {
+        pub extensions: NodeExtensions,
+    }
+
+    #[derive(Deserialize, Default)]
+    #[serde(default)]
+    pub struct NodeExtensions {
+        #[serde(rename = "KHR_lights_punctual")]
+        pub khr_lights_punctual: Option<NodeLight>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct NodeLight {
+        pub light: usize,
+    }
+
+    #[derive(Deserialize, Default)]
+    #[serde(default, rename_all = "camelCase")]
+    pub struct Material {
+        pub pbr_metallic_roughness: PbrMetallicRoughness,
+        pub normal_texture: Option<TextureInfo>,
+        pub occlusion_texture: Option<TextureInfo>,
+        pub emissive_texture: Option<TextureInfo>,
+        pub extensions: MaterialExtensions,
+    }
+
+    #[derive(Deserialize, Default)]
+    #[serde(default, rename_all = "camelCase")]
+    pub struct PbrMetallicRoughness {
+        pub base_color_texture: Option<TextureInfo>,
+        pub metallic_roughness_texture: Option<TextureInfo>,
+    }
+
+    #[derive(Deserialize, Default)]
+    #[serde(default)]
+    pub struct TextureInfo {
+        pub extensions: TextureInfoExtensions,
+    }
+
+    #[derive(Deserialize, Default)]
+    #[serde(default)]
+    pub struct TextureInfoExtensions {
+        #[serde(rename = "KHR_texture_transform")]
+        pub khr_texture_transform: Option<KhrTextureTransform>,
+    }
+
+    #[derive(Deserialize, Default)]
+    #[serde(default)]
+    pub struct MaterialExtensions {
+        #[serde(rename = "KHR_materials_emissive_strength")]
+        pub khr_materials_emissive_strength: Option<EmissiveStrength>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct EmissiveStrength {
+        #[serde(default = "default_emissive_strength")]
+        pub emissive_strength: f32,
+    }
+
+    fn default_emissive_strength() -> f32 {
+        1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::json_chunk;
+
+    fn glb(json: &[u8], chunk_length: u32) -> Vec<u8> {
+        let mut data = b"glTF".to_vec();
+        data.extend_from_slice(&2u32.to_le_bytes());
+        data.extend_from_slice(&(20 + json.len() as u32).to_le_bytes());
+        data.extend_from_slice(&chunk_length.to_le_bytes());
+        data.extend_from_slice(b"JSON");
+        data.extend_from_slice(json);
+        data
+    }
+
+    #[test]
+    fn gltf_is_all_json() {
+        let data = br#"{"asset":{"version":"2.0"}}"#;
+        assert_eq!(json_chunk(data).unwrap(), &data[..]);
+    }
+
+    #[test]
+    fn glb_json_is_first_chunk() {
+        let json = br#"{"asset":{"version":"2.0"}}"#;
+        let mut data = glb(json, json.len() as u32);
+        // A binary chunk after the JSON one is not included
+        data.extend_from_slice(&[4, 0, 0, 0, b'B', b'I', b'N', 0, 1, 2, 3, 4]);
+        assert_eq!(json_chunk(&data).unwrap(), &json[..]);
+    }
+
+    #[test]
+    fn rejects_truncated_glb() {
+        assert!(json_chunk(b"glTF\x02\x00\x00\x00").is_err());
+        let json = br#"{"asset":{"version":"2.0"}}"#;
+        assert!(json_chunk(&glb(json, json.len() as u32 + 1)).is_err());
+    }
+}
@@ -0,0 +1,204 @@
+//! Support for rendering the pbr graph without a window, writing the final frame to disk.
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Name of the readback buffer holding the tonemapped, display-referred color image
+pub const COLOR_READBACK: &str = "color";
+/// Name of the readback buffer holding the scene-referred HDR image
+pub const HDR_READBACK: &str = "hdr";
+
+/// The file format of a headless render, determined by the output file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// 8-bit sRGB PNG of the tonemapped frame
+    Png,
+    /// 32-bit float OpenEXR of the HDR frame, before exposure and tonemapping
+    Exr,
+}
+
+impl OutputFormat {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, failure::Error> {
+        let extension = path
+            .as_ref()
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+        match extension.as_ref().map(String::as_str) {
+            Some("png") => Ok(OutputFormat::Png),
+            Some("exr") => Ok(OutputFormat::Exr),
+            _ => Err(failure::format_err!(
+                "Unsupported headless output file {:?}, expected a .png or .exr extension",
+                path.as_ref()
+            )),
+        }
+    }
+
+    /// The name of the readback buffer which holds the image for this format
+    pub fn readback_name(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => COLOR_READBACK,
+            OutputFormat::Exr => HDR_READBACK,
+        }
+    }
+
+    /// The size in bytes of a single pixel of the image read back for this format
+    pub fn bytes_per_pixel(&self) -> u64 {
+        match self {
+            OutputFormat::Png => 4,
+            OutputFormat::Exr => 16,
+        }
+    }
+}
+
+/// Settings for a headless render
+#[derive(Debug, Clone)]
+pub struct HeadlessConfig {
+    pub output: PathBuf,
+    pub format: OutputFormat,
+    /// How many frames to render before reading back the result
+    pub frames: u32,
+}
+
+impl HeadlessConfig {
+    pub fn new(output: PathBuf, frames: u32) -> Result<Self, failure::Error> {
+        if frames == 0 {
+            failure::bail!("Headless rendering must render at least one frame");
+        }
+        let format = OutputFormat::from_path(&output)?;
+        Ok(HeadlessConfig {
+            output,
+            format,
+            frames,
+        })
+    }
+
+    /// Write the read back pixels of a `width` x `height` frame to the output file
+    pub fn write_output(&self, width: u32, height: u32, data: &[u8]) -> Result<(), failure::Error> {
+        match self.format {
+            OutputFormat::Png => write_png(&self.output, width, height, data),
+            OutputFormat::Exr => write_exr(&self.output, width, height, data),
+        }
+    }
+}
+
+/// Write tightly packed Rgba8 pixels to a PNG file
+pub fn write_png<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    data: &[u8],
+) -> Result<(), failure::Error> {
+    image::save_buffer(
+        path.as_ref(),
+        data,
+        width,
+        height,
+        image::ColorType::RGBA(8),
+    )?;
+    Ok(())
+}
+
+/// Write tightly packed Rgba32Sfloat pixels to an uncompressed scanline OpenEXR file.
+///
+/// The HDR target is rendered upside down relative to the presented image (the tonemap
+/// pass flips it back), so rows are flipped here to match what would be on screen.
+pub fn write_exr<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    data: &[u8],
+) -> Result<(), failure::Error> {
+    let row_bytes = width as usize * 16;
+    if data.len() < row_bytes * height as usize {
+        failure::bail!("Not enough pixel data for a {}x{} EXR image", width, height);
+    }
+
+    let mut header = Vec::new();
+    // Magic number and version 2, single part scanline file
+    header.extend_from_slice(&20000630i32.to_le_bytes());
+    header.extend_from_slice(&2i32.to_le_bytes());
+
+    // Channels must be listed in alphabetical order
+    let mut channels = Vec::new();
+    for name in &["A", "B", "G", "R"] {
+        channels.extend_from_slice(name.as_bytes());
+        channels.push(0);
+        // FLOAT pixel type
+        channels.extend_from_slice(&2i32.to_le_bytes());
+        // pLinear + reserved
+        channels.extend_from_slice(&[0, 0, 0, 0]);
+        // x and y sampling
+        channels.extend_from_slice(&1i32.to_le_bytes());
+        channels.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channels.push(0);
+    write_exr_attribute(&mut header, "channels", "chlist", &channels);
+    write_exr_attribute(&mut header, "compression", "compression", &[0]);
+
+    let mut window = Vec::new();
+    for v in &[0i32, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+    write_exr_attribute(&mut header, "dataWindow", "box2i", &window);
+    write_exr_attribute(&mut header, "displayWindow", "box2i", &window);
+    write_exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    write_exr_attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1.0f32.to_bits().to_le_bytes(),
+    );
+    let mut center = Vec::new();
+    center.extend_from_slice(&0.0f32.to_bits().to_le_bytes());
+    center.extend_from_slice(&0.0f32.to_bits().to_le_bytes());
+    write_exr_attribute(&mut header, "screenWindowCenter", "v2f", &center);
+    write_exr_attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1.0f32.to_bits().to_le_bytes(),
+    );
+    header.push(0);
+
+    // One scanline per block: 4 byte y coordinate, 4 byte size, then the channel data
+    let block_data_size = width as usize * 4 * 4;
+    let block_size = 8 + block_data_size;
+    let offset_table_size = height as usize * 8;
+    let first_block = header.len() + offset_table_size;
+
+    let mut writer = BufWriter::new(File::create(path.as_ref())?);
+    writer.write_all(&header)?;
+    for y in 0..height as usize {
+        writer.write_all(&((first_block + y * block_size) as u64).to_le_bytes())?;
+    }
+
+    let mut line = Vec::with_capacity(block_data_size);
+    for y in 0..height as usize {
+        let src_row = &data[(height as usize - 1 - y) * row_bytes..][..row_bytes];
+        line.clear();
+        // A, B, G, R channel order, matching the channel list
+        for &channel in &[3usize, 2, 1, 0] {
+            for x in 0..width as usize {
+                let offset = x * 16 + channel * 4;
+                line.extend_from_slice(&src_row[offset..offset + 4]);
+            }
+        }
+        writer.write_all(&(y as i32).to_le_bytes())?;
+        writer.write_all(&(block_data_size as i32).to_le_bytes())?;
+        writer.write_all(&line)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_exr_attribute(header: &mut Vec<u8>, name: &str, ty: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(ty.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
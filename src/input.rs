@@ -26,6 +26,12 @@ pub const ZOOM_MOUSE_SENSITIVITY: f32 = 0.0125;
 pub const ZOOM_SCROLL_SENSITIVITY: f32 = 0.25;
 pub const EXPOSURE_ADJUST_SENSITIVITY: f32 = 0.1;
 pub const CUBE_ROUGHNESS_SENSITIVITY: f32 = 0.1;
+pub const BLOOM_INTENSITY_SENSITIVITY: f32 = 0.01;
+pub const BLOOM_THRESHOLD_SENSITIVITY: f32 = 0.1;
+pub const BLOOM_RADIUS_SENSITIVITY: f32 = 0.1;
+pub const COLOR_TEMPERATURE_SENSITIVITY: f32 = 0.05;
+pub const CONTRAST_SENSITIVITY: f32 = 0.05;
+pub const SATURATION_SENSITIVITY: f32 = 0.05;
 
 #[derive(Derivative, Debug, Clone, Copy)]
 #[derivative(Default)]
@@ -1,12 +1,17 @@
 #![cfg_attr(
-    not(any(feature = "dx12", feature = "metal", feature = "vulkan")),
+    not(any(
+        feature = "dx12",
+        feature = "metal",
+        feature = "vulkan",
+        feature = "empty"
+    )),
     allow(unused)
 )]
 
 use rendy::{
-    command::{Families, Graphics, Supports},
-    factory::{Config, Factory, ImageState},
-    graph::{present::PresentNode, render::*, GraphBuilder},
+    command::{Families, Graphics, QueueId, Supports},
+    factory::{Config, Factory},
+    graph::{present::PresentNode, render::*, Graph, GraphBuilder, ImageId},
     init::winit::{
         self,
         event::{Event, WindowEvent},
@@ -21,22 +26,26 @@ use rendy::hal;
 
 use specs::prelude::*;
 
+use structopt::StructOpt;
+
+mod animation;
 mod asset;
 mod components;
+mod config;
+mod environment;
+mod geometry;
+mod gltf_extensions;
+mod headless;
 mod input;
 mod node;
 mod scene;
 mod systems;
 mod transform;
 
-pub const ENV_CUBEMAP_RES: u32 = 512;
-pub const ENV_CUBEMAP_MIP_LEVELS: u8 = 6;
-pub const IRRADIANCE_CUBEMAP_RES: u32 = 64;
-pub const SPEC_CUBEMAP_RES: u32 = 128;
-pub const SPEC_CUBEMAP_MIP_LEVELS: u8 = 6;
-pub const SPEC_BRDF_MAP_RES: u32 = 256;
+/// Must match the size of the lights array declared in `pbr.frag`
 pub const MAX_LIGHTS: usize = 32;
-pub const FRAMES_IN_FLIGHT: u32 = 3;
+/// Must match the size of the shadow map array declared in `pbr.frag`
+pub const MAX_SHADOW_MAPS: usize = 8;
 
 #[cfg(feature = "dx12")]
 pub type Backend = rendy::dx12::Backend;
@@ -50,14 +59,21 @@ pub type Backend = rendy::vulkan::Backend;
 #[cfg(feature = "empty")]
 pub type Backend = rendy::empty::Backend;
 
-#[cfg(any(feature = "dx12", feature = "metal", feature = "vulkan"))]
+#[cfg(any(
+    feature = "dx12",
+    feature = "metal",
+    feature = "vulkan",
+    feature = "empty"
+))]
 fn main() {
+    let opt = config::Opt::from_args();
+
     env_logger::Builder::from_default_env()
         .filter_level(log::LevelFilter::Warn)
-        .filter_module("rendy_pbr", log::LevelFilter::Info)
+        .filter_module("rendy_pbr", opt.log_level)
         .init();
 
-    match err_main() {
+    match config::AppConfig::from_opt(opt).and_then(err_main) {
         Err(e) => {
             if let Some(name) = e.name() {
                 log::error!("Exit with {}: {}\n\nBACKTRACE:\n{}", name, e, e.backtrace());
@@ -94,63 +110,81 @@ pub fn application_root_dir() -> String {
     }
 }
 
+#[cfg(any(
+    feature = "dx12",
+    feature = "metal",
+    feature = "vulkan",
+    feature = "empty"
+))]
+fn err_main(app_config: config::AppConfig) -> Result<(), failure::Error> {
+    let config: Config = Default::default();
+
+    if app_config.headless.is_some() {
+        let rendy = match app_config.backend {
+            Some(backend) => rendy::init::AnyRendy::init(backend, &config),
+            None => rendy::init::AnyRendy::init_auto(&config),
+        }
+        .map_err(|e| failure::format_err!("Failed to initialize rendy: {:?}", e))?;
+        return rendy::with_any_rendy!((rendy)
+            (factory, families) => {
+                run_headless(factory, families, &app_config)
+            }
+        );
+    }
+
+    err_main_windowed(config, app_config)
+}
+
+#[cfg(not(any(feature = "dx12", feature = "metal", feature = "vulkan")))]
+fn err_main_windowed(
+    _config: Config,
+    _app_config: config::AppConfig,
+) -> Result<(), failure::Error> {
+    failure::bail!(
+        "The empty backend can only be used for headless rendering, pass --headless <output>"
+    )
+}
+
 #[cfg(any(feature = "dx12", feature = "metal", feature = "vulkan"))]
-fn err_main() -> Result<(), failure::Error> {
+fn err_main_windowed(config: Config, app_config: config::AppConfig) -> Result<(), failure::Error> {
     #[cfg(feature = "rd")]
     let mut rd: renderdoc::RenderDoc<renderdoc::V120> =
         renderdoc::RenderDoc::new().expect("Failed to init renderdoc");
     #[cfg(feature = "rd")]
     use renderdoc::prelude::*;
 
-    let config: Config = Default::default();
-
     let event_loop = EventLoop::new();
 
     let window = WindowBuilder::new()
         .with_title("rendy-pbr")
-        .with_inner_size(winit::dpi::LogicalSize::new(1280.0, 960.0));
-
-    let rendy = rendy::init::AnyWindowedRendy::init_auto(&config, window, &event_loop).unwrap();
+        .with_inner_size(winit::dpi::LogicalSize::new(
+            app_config.width as f64,
+            app_config.height as f64,
+        ));
+
+    let rendy = match app_config.backend {
+        Some(backend) => rendy::init::AnyWindowedRendy::init(backend, &config, window, &event_loop),
+        None => rendy::init::AnyWindowedRendy::init_auto(&config, window, &event_loop),
+    }
+    .map_err(|e| failure::format_err!("Failed to initialize rendy: {:?}", e))?;
     rendy::with_any_windowed_rendy!((rendy)
         (factory, families, surface, window) => {
-            run(event_loop, surface, window, factory, families)
+            run(event_loop, surface, window, factory, families, &app_config)
         }
     )
 }
 
-fn run<B: hal::Backend>(
-    event_loop: rendy::init::winit::event_loop::EventLoop<()>,
-    surface: rendy::wsi::Surface<B>,
-    window: Window,
-    mut factory: Factory<B>,
-    mut families: Families<B>,
-) -> Result<(), failure::Error> {
-    // Initialize specs and register components
-    let mut world = specs::World::new();
-
-    world.register::<components::Transform>();
-    world.register::<components::GlobalTransform>();
-    world.register::<components::Parent>();
-    world.register::<components::Mesh>();
-    world.register::<components::Camera>();
-    world.register::<components::ActiveCamera>();
-    world.register::<components::Light>();
-
-    let scene_config = scene::SceneConfig::from_path("assets/scene.ron")?;
-
-    let input = input::InputState::new(window.inner_size());
-    let event_bucket = input::EventBucket(Vec::new());
-
-    #[cfg(feature = "rd")]
-    rd.start_frame_capture(std::ptr::null(), std::ptr::null());
-
-    let size = window.inner_size().to_physical(window.hidpi_factor());
-    let aspect = (size.width / size.height) as f32;
-
-    let align = hal::adapter::PhysicalDevice::limits(factory.physical())
-        .min_uniform_buffer_offset_alignment;
+/// The final destination of frames rendered by the pbr graph
+pub enum FrameTarget<B: hal::Backend> {
+    /// Present to a window surface
+    Surface(rendy::wsi::Surface<B>),
+    /// Render offscreen, copying the image needed for the given output format
+    /// into a readback buffer after every frame
+    Offscreen(headless::OutputFormat),
+}
 
-    let queue = families
+fn graphics_queue<B: hal::Backend>(families: &Families<B>) -> QueueId {
+    families
         .as_slice()
         .iter()
         .find(|family| {
@@ -162,368 +196,107 @@ fn run<B: hal::Backend>(
         })
         .unwrap()
         .as_slice()[0]
-        .id();
-
-    // Preprocess steps to load environment map, convert it to a cubemap,
-    // and filter it for use later
-
-    // Equirectangular env map to environment cube map
-
-    let mut preprocessed_environment_data = {
-        let mut env_preprocess_graph_builder =
-            GraphBuilder::<B, node::env_preprocess::Aux<B>>::new();
-
-        let env_cube_faces_img = env_preprocess_graph_builder.create_image(
-            hal::image::Kind::D2(ENV_CUBEMAP_RES, ENV_CUBEMAP_RES * 6, 1, 1),
-            1,
-            hal::format::Format::Rgba32Sfloat,
-            Some(hal::command::ClearValue {
-                color: hal::command::ClearColor {
-                    float32: [0.0, 0.0, 0.0, 1.0],
-                },
-            }),
-        );
-
-        let equirect_to_faces_pass = env_preprocess_graph_builder.add_node(
-            node::env_preprocess::equirectangular_to_cube_faces::Pipeline::<B>::builder()
-                .into_subpass()
-                .with_color(env_cube_faces_img)
-                .into_pass(),
-        );
-
-        let faces_to_env_pass = env_preprocess_graph_builder.add_node(
-            node::env_preprocess::faces_to_cubemap::FacesToCubemap::<Backend>::builder(
-                vec![env_cube_faces_img],
-                "environment",
-                node::env_preprocess::faces_to_cubemap::CopyMips::GenerateMips,
-            )
-            .with_dependency(equirect_to_faces_pass),
-        );
-
-        // Environment cube map to convolved irradiance cube map
-
-        let irradiance_cube_faces_img = env_preprocess_graph_builder.create_image(
-            hal::image::Kind::D2(IRRADIANCE_CUBEMAP_RES, IRRADIANCE_CUBEMAP_RES * 6, 1, 1),
-            1,
-            hal::format::Format::Rgba32Sfloat,
-            Some(hal::command::ClearValue {
-                color: hal::command::ClearColor {
-                    float32: [0.0, 0.0, 0.0, 1.0],
-                },
-            }),
-        );
-
-        let env_to_irradiance_faces_pass = env_preprocess_graph_builder.add_node(
-            node::env_preprocess::env_to_irradiance::Pipeline::<B>::builder()
-                .with_dependency(faces_to_env_pass)
-                .into_subpass()
-                .with_color(irradiance_cube_faces_img)
-                .into_pass(),
-        );
-
-        let _irradiance_to_cube_pass = env_preprocess_graph_builder.add_node(
-            node::env_preprocess::faces_to_cubemap::FacesToCubemap::<B>::builder(
-                vec![irradiance_cube_faces_img],
-                "irradiance",
-                node::env_preprocess::faces_to_cubemap::CopyMips::CopyMips(1),
-            )
-            .with_dependency(env_to_irradiance_faces_pass),
-        );
-
-        // Environment cube map to convolved specular cube map with different roughnesses stored in mip levels
-
-        let mut env_to_spec_faces_subpasses = Vec::new();
-        let mut spec_cube_faces_images = Vec::new();
-
-        for mip_level in 0..SPEC_CUBEMAP_MIP_LEVELS {
-            let res = SPEC_CUBEMAP_RES / 2u32.pow(mip_level as u32);
-            let mut subpass = node::env_preprocess::env_to_specular::Pipeline::<B>::builder()
-                .with_dependency(faces_to_env_pass)
-                .into_subpass();
-            let image = env_preprocess_graph_builder.create_image(
-                hal::image::Kind::D2(res, res * 6, 1, 1),
-                1,
-                hal::format::Format::Rgba32Sfloat,
-                Some(hal::command::ClearValue {
-                    color: hal::command::ClearColor {
-                        float32: [0.0, 0.0, 0.0, 1.0],
-                    },
-                }),
-            );
-            subpass.add_color(image);
-            spec_cube_faces_images.push(image);
-            env_to_spec_faces_subpasses.push(subpass);
-        }
-
-        let mut env_to_spec_faces_passes = Vec::new();
-        while !env_to_spec_faces_subpasses.is_empty() {
-            env_to_spec_faces_passes.push(
-                env_preprocess_graph_builder
-                    .add_node(env_to_spec_faces_subpasses.pop().unwrap().into_pass()),
-            );
-        }
-
-        let mut builder = node::env_preprocess::faces_to_cubemap::FacesToCubemap::<B>::builder(
-            spec_cube_faces_images.clone(),
-            "specular",
-            node::env_preprocess::faces_to_cubemap::CopyMips::CopyMips(SPEC_CUBEMAP_MIP_LEVELS),
-        );
-
-        for pass in env_to_spec_faces_passes {
-            builder.add_dependency(pass);
-        }
-
-        let _spec_to_cube_pass = env_preprocess_graph_builder.add_node(builder);
-
-        let spec_brdf_map = env_preprocess_graph_builder.create_image(
-            hal::image::Kind::D2(SPEC_BRDF_MAP_RES, SPEC_BRDF_MAP_RES, 1, 1),
-            1,
-            hal::format::Format::Rg32Sfloat,
-            Some(hal::command::ClearValue {
-                color: hal::command::ClearColor {
-                    float32: [0.0, 0.0, 0.0, 1.0],
-                },
-            }),
-        );
+        .id()
+}
 
-        let brdf_integration_pass = env_preprocess_graph_builder.add_node(
-            node::env_preprocess::integrate_spec_brdf::Pipeline::builder()
-                .into_subpass()
-                .with_color(spec_brdf_map)
-                .into_pass(),
-        );
+/// Registers components, preprocesses the environment map, loads the scene and builds
+/// the dispatcher. The dispatcher is run once so that all state needed by the pbr graph
+/// exists before it is built. Also returns the loaders used to switch environment maps
+/// and load glTF files at runtime.
+fn init_world<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    config: &config::AppConfig,
+) -> Result<
+    (
+        specs::World,
+        specs::Dispatcher<'static, 'static>,
+        environment::EnvironmentLoader<B>,
+        asset::GltfLoader,
+    ),
+    failure::Error,
+> {
+    // Initialize specs and register components
+    let mut world = specs::World::new();
 
-        let _brdf_to_texture = env_preprocess_graph_builder.add_node(
-            node::env_preprocess::copy_to_texture::CopyToTexture::<Backend>::builder(
-                spec_brdf_map,
-                "spec_brdf",
-            )
-            .with_dependency(brdf_integration_pass),
-        );
+    world.register::<components::Transform>();
+    world.register::<components::GlobalTransform>();
+    world.register::<components::PreviousGlobalTransform>();
+    world.register::<components::Parent>();
+    world.register::<components::Mesh>();
+    world.register::<components::Camera>();
+    world.register::<components::ActiveCamera>();
+    world.register::<components::Light>();
+    world.register::<components::Skin>();
+    world.register::<components::MorphWeights>();
 
-        let equirect_file = std::fs::File::open(
-            &std::path::Path::new(&application_root_dir())
-                .join(scene_config.environment_map.clone()),
-        )?;
+    let mut scene_config = scene::SceneConfig::from_path(&config.scene)?;
+    if let Some(environment_map) = &config.environment_override {
+        scene_config.environment_map = environment_map.clone();
+    }
+    if let Some(quality) = config.quality_override {
+        scene_config.environment_filter_quality = quality;
+    }
+    if let Some(samples) = config.msaa_override {
+        scene_config.msaa_samples = samples;
+    }
+    if let Some(anisotropy) = config.anisotropy_override {
+        scene_config.texture_anisotropy = anisotropy;
+    }
 
-        let equirect_tex = rendy::texture::image::load_from_image(
-            std::io::BufReader::new(equirect_file),
-            rendy::texture::image::ImageTextureConfig {
-                repr: rendy::texture::image::Repr::Float,
-                ..Default::default()
-            },
-        )?
-        .build(
-            ImageState {
-                queue,
-                stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
-                access: hal::image::Access::SHADER_READ,
-                layout: hal::image::Layout::ShaderReadOnlyOptimal,
-            },
-            &mut factory,
-        )?;
-
-        let env_cubemap_tex = rendy::texture::TextureBuilder::new()
-            .with_kind(rendy::resource::Kind::D2(
-                ENV_CUBEMAP_RES,
-                ENV_CUBEMAP_RES,
-                6,
-                1,
-            ))
-            .with_mip_levels(rendy::texture::MipLevels::Levels(
-                std::num::NonZeroU8::new(ENV_CUBEMAP_MIP_LEVELS).unwrap(),
-            ))
-            .with_view_kind(rendy::resource::ViewKind::Cube)
-            .with_data_width(ENV_CUBEMAP_RES)
-            .with_data_height(ENV_CUBEMAP_RES)
-            .with_data(vec![
-                rendy::texture::pixel::Rgba32Sfloat {
-                    repr: [0.0, 0.0, 0.0, 1.0]
-                };
-                (ENV_CUBEMAP_RES * ENV_CUBEMAP_RES * 6) as usize
-            ])
-            .build(
-                ImageState {
-                    queue,
-                    stage: hal::pso::PipelineStage::TRANSFER,
-                    access: hal::image::Access::TRANSFER_WRITE,
-                    layout: hal::image::Layout::TransferDstOptimal,
-                },
-                &mut factory,
-            )?;
-
-        let irradiance_cubemap_tex = rendy::texture::TextureBuilder::new()
-            .with_kind(rendy::resource::Kind::D2(
-                IRRADIANCE_CUBEMAP_RES,
-                IRRADIANCE_CUBEMAP_RES,
-                6,
-                1,
-            ))
-            .with_view_kind(rendy::resource::ViewKind::Cube)
-            .with_data_width(IRRADIANCE_CUBEMAP_RES)
-            .with_data_height(IRRADIANCE_CUBEMAP_RES)
-            .with_data(vec![
-                rendy::texture::pixel::Rgba32Sfloat {
-                    repr: [0.0, 0.0, 0.0, 1.0]
-                };
-                (IRRADIANCE_CUBEMAP_RES * IRRADIANCE_CUBEMAP_RES * 6)
-                    as usize
-            ])
-            .build(
-                ImageState {
-                    queue,
-                    stage: hal::pso::PipelineStage::TRANSFER,
-                    access: hal::image::Access::TRANSFER_WRITE,
-                    layout: hal::image::Layout::TransferDstOptimal,
-                },
-                &mut factory,
-            )?;
-
-        let spec_cubemap_tex = rendy::texture::TextureBuilder::new()
-            .with_kind(rendy::resource::Kind::D2(
-                SPEC_CUBEMAP_RES,
-                SPEC_CUBEMAP_RES,
-                6,
-                1,
-            ))
-            .with_mip_levels(rendy::texture::MipLevels::Levels(
-                std::num::NonZeroU8::new(SPEC_CUBEMAP_MIP_LEVELS).unwrap(),
-            ))
-            .with_view_kind(rendy::resource::ViewKind::Cube)
-            .with_data_width(SPEC_CUBEMAP_RES)
-            .with_data_height(SPEC_CUBEMAP_RES)
-            .with_data(vec![
-                rendy::texture::pixel::Rgba32Sfloat {
-                    repr: [0.0, 0.0, 0.0, 1.0]
-                };
-                (SPEC_CUBEMAP_RES * SPEC_CUBEMAP_RES * 6) as usize
-            ])
-            .build(
-                ImageState {
-                    queue,
-                    stage: hal::pso::PipelineStage::TRANSFER,
-                    access: hal::image::Access::TRANSFER_WRITE,
-                    layout: hal::image::Layout::TransferDstOptimal,
-                },
-                &mut factory,
-            )?;
-
-        let spec_brdf_tex = rendy::texture::TextureBuilder::new()
-            .with_kind(rendy::resource::Kind::D2(
-                SPEC_BRDF_MAP_RES,
-                SPEC_BRDF_MAP_RES,
-                1,
-                1,
-            ))
-            .with_view_kind(rendy::resource::ViewKind::D2)
-            .with_data_width(SPEC_BRDF_MAP_RES)
-            .with_data_height(SPEC_BRDF_MAP_RES)
-            .with_data(vec![
-                rendy::texture::pixel::Rg32Sfloat { repr: [0.0, 0.0] };
-                (SPEC_BRDF_MAP_RES * SPEC_BRDF_MAP_RES) as usize
-            ])
-            .build(
-                ImageState {
-                    queue,
-                    stage: hal::pso::PipelineStage::TRANSFER,
-                    access: hal::image::Access::TRANSFER_WRITE,
-                    layout: hal::image::Layout::TransferDstOptimal,
-                },
-                &mut factory,
-            )?;
-
-        use scene::Quality;
-        let mut env_preprocess_aux = node::env_preprocess::Aux {
-            align,
-            irradiance_theta_samples: match scene_config.environment_filter_quality {
-                Quality::High => 720,
-                Quality::Medium => 512,
-                Quality::Low => 256,
-            },
-            spec_samples: match scene_config.environment_filter_quality {
-                Quality::High => 8192,
-                Quality::Medium => 4096,
-                Quality::Low => 1024,
-            },
-            equirectangular_texture: equirect_tex,
-            environment_cubemap: Some(env_cubemap_tex),
-            irradiance_cubemap: Some(irradiance_cubemap_tex),
-            spec_cubemap: Some(spec_cubemap_tex),
-            spec_brdf_map: Some(spec_brdf_tex),
-            queue,
-            mip_level: std::sync::atomic::AtomicUsize::new(0),
-        };
-
-        let mut env_preprocess_graph = env_preprocess_graph_builder.build(
-            &mut factory,
-            &mut families,
-            &mut env_preprocess_aux,
-        )?;
+    let input = input::InputState::new(winit::dpi::LogicalSize::new(
+        config.width as f64,
+        config.height as f64,
+    ));
+    let event_bucket = input::EventBucket(Vec::new());
 
-        factory.maintain(&mut families);
-        env_preprocess_graph.run(&mut factory, &mut families, &mut env_preprocess_aux);
-        env_preprocess_graph.dispose(&mut factory, &mut env_preprocess_aux);
+    let aspect = config.width as f32 / config.height as f32;
 
-        env_preprocess_aux
-    };
+    let align = hal::adapter::PhysicalDevice::limits(factory.physical())
+        .min_uniform_buffer_offset_alignment;
 
-    let mut pbr_graph_builder = GraphBuilder::<B, specs::World>::new();
+    let msaa_samples = node::pbr::msaa::supported_samples(factory, scene_config.msaa_samples)?;
+    scene_config.texture_anisotropy =
+        asset::supported_anisotropy(factory, scene_config.texture_anisotropy)?;
 
-    let hdr = pbr_graph_builder.create_image(
-        hal::image::Kind::D2(size.width as u32, size.height as u32, 1, 1),
-        1,
-        hal::format::Format::Rgba32Sfloat,
-        Some(hal::command::ClearValue {
-            color: hal::command::ClearColor {
-                float32: [0.1, 0.3, 0.4, 1.0],
-            },
-        }),
-    );
+    let queue = graphics_queue(families);
 
-    let color = pbr_graph_builder.create_image(
-        hal::image::Kind::D2(size.width as u32, size.height as u32, 1, 1),
-        1,
-        factory.get_surface_format(&surface),
-        Some(hal::command::ClearValue {
-            color: hal::command::ClearColor {
-                float32: [0.1, 0.3, 0.4, 1.0],
-            },
-        }),
-    );
+    let color_lut = node::pbr::color_grading::ColorLut::load(
+        factory,
+        queue,
+        scene_config.color_lut.as_ref().map(String::as_str),
+    )?;
 
-    let depth = pbr_graph_builder.create_image(
-        hal::image::Kind::D2(size.width as u32, size.height as u32, 1, 1),
-        1,
-        hal::format::Format::D32Sfloat,
-        Some(hal::command::ClearValue {
-            depth_stencil: hal::command::ClearDepthStencil {
-                depth: 1.0,
-                stencil: 0,
-            },
-        }),
+    let environment_storage = environment::load_environment(
+        factory,
+        families,
+        queue,
+        align,
+        &scene_config,
+        &config.environment,
+        config.environment_cache,
+    )?;
+
+    let mut environment_list =
+        environment::EnvironmentList::new(vec![scene_config.environment_map.clone()]);
+    for environment_map in scene_config.environment_maps.iter() {
+        environment_list.add(environment_map);
+    }
+    let environment_loader = environment::EnvironmentLoader::new(
+        queue,
+        align,
+        scene_config.environment_filter_quality,
+        config.environment,
+        config.environment_cache,
     );
-
-    let mesh_pass = pbr_graph_builder.add_node(
-        node::pbr::environment_map::Pipeline::builder()
-            .into_subpass()
-            .with_group(node::pbr::mesh::Pipeline::builder())
-            .with_color(hdr)
-            .with_depth_stencil(depth)
-            .into_pass(),
+    let gltf_loader = asset::GltfLoader::new(
+        queue,
+        256,
+        scene_config.mipmap_model_textures,
+        scene_config.texture_anisotropy,
+        scene_config.smooth_generated_normals,
     );
 
-    let tonemap_pass = pbr_graph_builder.add_node(
-        node::pbr::tonemap::Pipeline::builder()
-            .with_image(hdr)
-            .into_subpass()
-            .with_dependency(mesh_pass)
-            .with_color(color)
-            .into_pass(),
-    );
-
-    pbr_graph_builder
-        .add_node(PresentNode::builder(&factory, surface, color).with_dependency(tonemap_pass));
-
     // Hierarchy system must be added before loading scene
     let mut hierarchy_system = specs_hierarchy::HierarchySystem::<components::Parent>::new();
     specs::System::setup(&mut hierarchy_system, &mut world.res);
@@ -531,20 +304,46 @@ fn run<B: hal::Backend>(
     specs::System::setup(&mut transform_system, &mut world.res);
 
     // Load scene from config file
-    let (material_storage, primitive_storage, mesh_storage, _scene_entities) =
-        scene_config.load(aspect, &mut factory, queue, &mut world)?;
+    let (
+        material_storage,
+        primitive_storage,
+        mesh_storage,
+        morph_target_storage,
+        animations,
+        _scene_entities,
+    ) = scene_config.load(aspect, factory, queue, &mut world)?;
+    let num_joints = world
+        .read_storage::<components::Skin>()
+        .join()
+        .map(|skin| skin.joints.len())
+        .sum::<usize>();
+    let num_morph_weights = world
+        .read_storage::<components::MorphWeights>()
+        .join()
+        .map(|weights| weights.weights.len())
+        .sum::<usize>();
 
     let num_meshes = mesh_storage.0.len();
     let num_materials = material_storage.0.len();
 
     let pbr_aux = node::pbr::Aux {
-        frames: FRAMES_IN_FLIGHT as _,
+        frames: config.frames_in_flight as _,
         align,
-        tonemapper_args: node::pbr::tonemap::TonemapperArgs {
-            exposure: 1.7,
-            curve: 0,
-            comparison_factor: 0.5,
+        spec_cubemap_mip_levels: config.environment.spec_cubemap_mip_levels,
+        tonemapper_args: scene_config.tonemapper,
+        auto_exposure: scene_config.auto_exposure,
+        bloom_args: node::pbr::bloom::BloomArgs {
+            intensity: 0.05,
+            threshold: 1.0,
+            radius: 1.0,
+        },
+        color_grading: scene_config.color_grading,
+        color_lut_enabled: scene_config.color_lut.is_some(),
+        taa_args: node::pbr::taa::TaaArgs {
+            enabled: true,
+            feedback: 0.9,
         },
+        msaa_samples,
         cube_display: node::pbr::environment_map::CubeDisplay::Environment,
         cube_roughness: 1.0,
     };
@@ -556,19 +355,24 @@ fn run<B: hal::Backend>(
     world.add_resource(material_storage);
     world.add_resource(primitive_storage);
     world.add_resource(mesh_storage);
-    world.add_resource(node::pbr::EnvironmentStorage {
-        env_cube: preprocessed_environment_data.environment_cubemap.take(),
-        irradiance_cube: preprocessed_environment_data.irradiance_cubemap.take(),
-        spec_cube: preprocessed_environment_data.spec_cubemap.take(),
-        spec_brdf_map: preprocessed_environment_data.spec_brdf_map.take(),
-    });
-    std::mem::drop(preprocessed_environment_data);
+    world.add_resource(morph_target_storage);
+    world.add_resource(animations);
+    world.add_resource(systems::JointMatrices::new(num_joints));
+    world.add_resource(systems::InstanceMorphWeights::new(num_morph_weights));
+    world.add_resource(environment_storage);
+    world.add_resource(environment_list);
+    world.add_resource(color_lut);
+    world.add_resource(asset::GltfLoadRequests::default());
+    let shadow_maps = node::pbr::shadow::ShadowMaps::allocate(&world);
+    world.add_resource(shadow_maps);
+    // Sized to the render target once the graph is built
+    world.add_resource(node::pbr::taa::Jitter::default());
     world.add_resource(systems::HelmetArraySize { x: 0, y: 0, z: 0 });
     world.add_resource(systems::HelmetArrayEntities(Vec::new()));
     world.add_resource(systems::MeshInstanceStorage(Default::default()));
     world.add_resource(systems::InstanceCache {
-        dirty_entities: vec![specs::BitSet::new(); FRAMES_IN_FLIGHT as _],
-        dirty_mesh_indirects: vec![HashSet::new(); FRAMES_IN_FLIGHT as _],
+        dirty_entities: vec![specs::BitSet::new(); config.frames_in_flight as _],
+        dirty_mesh_indirects: vec![HashSet::new(); config.frames_in_flight as _],
         mesh_instance_counts: vec![0; num_meshes],
         material_bitsets: vec![specs::BitSet::new(); num_materials],
     });
@@ -577,11 +381,14 @@ fn run<B: hal::Backend>(
         let mut mesh_storage = world.write_storage::<components::Mesh>();
 
         systems::InstanceCacheUpdateSystem {
-            frames_in_flight: FRAMES_IN_FLIGHT as usize,
-            previous_frame: FRAMES_IN_FLIGHT as usize - 1,
+            frames_in_flight: config.frames_in_flight as usize,
+            previous_frame: config.frames_in_flight as usize - 1,
             transform_reader_id: world
                 .write_storage::<components::GlobalTransform>()
                 .register_reader(),
+            previous_transform_reader_id: world
+                .write_storage::<components::PreviousGlobalTransform>()
+                .register_reader(),
             mesh_reader_id: mesh_storage.register_reader(),
             dirty_entities_scratch: specs::BitSet::new(),
             dirty_mesh_indirects_scratch: HashSet::new(),
@@ -589,12 +396,18 @@ fn run<B: hal::Backend>(
             mesh_deleted: BitSet::new(),
             mesh_modified: BitSet::new(),
             mesh_entity_bitsets: vec![BitSet::new(); num_meshes],
-            _pd: core::marker::PhantomData::<Backend>,
+            _pd: core::marker::PhantomData::<B>,
         }
     };
 
     let mut dispatcher = DispatcherBuilder::new()
         .with(systems::CameraInputSystem, "camera_input_system", &[])
+        .with(
+            systems::EnvironmentInputSystem,
+            "environment_input_system",
+            &[],
+        )
+        .with(systems::GltfDropSystem, "gltf_drop_system", &[])
         .with(
             systems::PbrAuxInputSystem {
                 helmet_mesh: 0 as asset::MeshHandle,
@@ -610,6 +423,11 @@ fn run<B: hal::Backend>(
             "helmet_array_size_update_system",
             &["pbr_aux_input_system"],
         )
+        .with(
+            animation::AnimationSystem::default(),
+            "animation_system",
+            &[],
+        )
         .with(
             hierarchy_system,
             "transform_hierarchy_system",
@@ -619,16 +437,45 @@ fn run<B: hal::Backend>(
                 "camera_input_system",
             ],
         )
+        .with(
+            systems::PreviousTransformSystem,
+            "previous_transform_system",
+            &[],
+        )
         .with(
             transform_system,
             "transform_system",
-            &["transform_hierarchy_system"],
+            &[
+                "transform_hierarchy_system",
+                "previous_transform_system",
+                "animation_system",
+            ],
+        )
+        .with(
+            systems::SkinningSystem,
+            "skinning_system",
+            &["transform_system"],
+        )
+        .with(
+            systems::MorphWeightSystem::default(),
+            "morph_weight_system",
+            &["animation_system"],
         )
         .with(
             instance_cache_update_system,
             "instance_cache_update_system",
             &["transform_system"],
         )
+        .with(
+            systems::ShadowMapUpdateSystem,
+            "shadow_map_update_system",
+            &["transform_system"],
+        )
+        .with(
+            systems::JitterUpdateSystem,
+            "jitter_update_system",
+            &["pbr_aux_input_system"],
+        )
         .with(
             systems::InputSystem,
             "input_system",
@@ -639,9 +486,415 @@ fn run<B: hal::Backend>(
     // Dispatch once to build all needed initial state before first frame render
     dispatcher.dispatch(&mut world.res);
 
+    Ok((world, dispatcher, environment_loader, gltf_loader))
+}
+
+/// Builds the main pbr render graph, rendering at the given resolution into `target`.
+/// Also adds the resources for temporal anti-aliasing at that resolution, and a slot for the
+/// buffers the mesh pass groups share, to the world.
+fn build_pbr_graph<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    families: &mut Families<B>,
+    world: &mut specs::World,
+    width: u32,
+    height: u32,
+    frames_in_flight: u32,
+    target: FrameTarget<B>,
+) -> Result<Graph<B, specs::World>, failure::Error> {
+    let taa_history =
+        node::pbr::taa::TaaHistory::new(factory, graphics_queue(families), width, height)?;
+    world.add_resource(taa_history);
+    world.add_resource(node::pbr::taa::Jitter::new(width, height));
+    let exposure_history =
+        node::pbr::exposure::ExposureHistory::new(factory, graphics_queue(families))?;
+    world.add_resource(exposure_history);
+    world.add_resource(node::pbr::mesh::MeshPassResources::<B>::default());
+    world.add_resource(node::pbr::shadow::ShadowPassResources::<B>::default());
+
+    let mut pbr_graph_builder = GraphBuilder::<B, specs::World>::new();
+
+    let color_format = match &target {
+        FrameTarget::Surface(surface) => factory.get_surface_format(surface),
+        FrameTarget::Offscreen(_) => hal::format::Format::Rgba8Srgb,
+    };
+
+    let msaa_samples = world.read_resource::<node::pbr::Aux>().msaa_samples;
+
+    // The images read by the passes after the mesh pass. Without MSAA the mesh pass renders
+    // into them directly, otherwise into multisampled images which are resolved into them.
+    let (hdr, velocity) = add_hdr_images(&mut pbr_graph_builder, width, height, 1);
+    let (mesh_hdr, mesh_velocity) = if msaa_samples > 1 {
+        add_hdr_images(&mut pbr_graph_builder, width, height, msaa_samples)
+    } else {
+        (hdr, velocity)
+    };
+
+    let resolved = pbr_graph_builder.create_image(
+        hal::image::Kind::D2(width, height, 1, 1),
+        1,
+        node::pbr::taa::RESOLVE_FORMAT,
+        Some(hal::command::ClearValue {
+            color: hal::command::ClearColor {
+                float32: [0.0, 0.0, 0.0, 1.0],
+            },
+        }),
+    );
+
+    let color = pbr_graph_builder.create_image(
+        hal::image::Kind::D2(width, height, 1, 1),
+        1,
+        color_format,
+        Some(hal::command::ClearValue {
+            color: hal::command::ClearColor {
+                float32: [0.1, 0.3, 0.4, 1.0],
+            },
+        }),
+    );
+
+    let depth = pbr_graph_builder.create_image(
+        hal::image::Kind::D2(width, height, 1, msaa_samples),
+        1,
+        hal::format::Format::D32Sfloat,
+        Some(hal::command::ClearValue {
+            depth_stencil: hal::command::ClearDepthStencil {
+                depth: 1.0,
+                stencil: 0,
+            },
+        }),
+    );
+
+    // One depth pass per shadow map, or a single empty one so the mesh pipeline always has
+    // a shadow map to bind
+    let shadow_slots = world
+        .read_resource::<node::pbr::shadow::ShadowMaps>()
+        .slots
+        .iter()
+        .map(|slot| Some(slot.resolution))
+        .collect::<Vec<_>>();
+    let shadow_slots = if shadow_slots.is_empty() {
+        vec![None]
+    } else {
+        shadow_slots
+    };
+
+    let mut shadow_maps = Vec::with_capacity(shadow_slots.len());
+    let mut shadow_passes = Vec::with_capacity(shadow_slots.len());
+    for (index, resolution) in shadow_slots.into_iter().enumerate() {
+        let slot = resolution.map(|_| index);
+        let resolution = resolution.unwrap_or(1);
+        let shadow_map = pbr_graph_builder.create_image(
+            hal::image::Kind::D2(resolution, resolution, 1, 1),
+            1,
+            node::pbr::shadow::SHADOW_MAP_FORMAT,
+            Some(hal::command::ClearValue {
+                depth_stencil: hal::command::ClearDepthStencil {
+                    depth: 1.0,
+                    stencil: 0,
+                },
+            }),
+        );
+        shadow_passes.push(
+            pbr_graph_builder.add_node(
+                node::pbr::shadow::PipelineDesc { slot }
+                    .builder()
+                    .into_subpass()
+                    .with_depth_stencil(shadow_map)
+                    .into_pass(),
+            ),
+        );
+        shadow_maps.push(shadow_map);
+    }
+
+    let pipeline_state = node::group::PipelineState::default().with_samples(msaa_samples);
+
+    let mut mesh_subpass = node::group::StateGroupDesc::new(
+        node::pbr::environment_map::PipelineDesc::default(),
+        pipeline_state.clone(),
+    )
+    .builder()
+    .into_subpass();
+    // Opaque triangles with and without back face culling, lines and points, then the blended
+    // ones. Blended draws don't write depth, so every opaque group has to be drawn before them.
+    // Blended triangles are sorted together, so their back faces are discarded by the shader
+    // instead.
+    let mesh_passes = [
+        (
+            node::pbr::mesh::MaterialPass::Opaque {
+                double_sided: Some(false),
+            },
+            asset::Topology::Triangles,
+            hal::pso::Face::BACK,
+        ),
+        (
+            node::pbr::mesh::MaterialPass::Opaque {
+                double_sided: Some(true),
+            },
+            asset::Topology::Triangles,
+            hal::pso::Face::NONE,
+        ),
+        (
+            node::pbr::mesh::MaterialPass::Opaque { double_sided: None },
+            asset::Topology::Lines,
+            hal::pso::Face::NONE,
+        ),
+        (
+            node::pbr::mesh::MaterialPass::Opaque { double_sided: None },
+            asset::Topology::Points,
+            hal::pso::Face::NONE,
+        ),
+        (
+            node::pbr::mesh::MaterialPass::Transparent,
+            asset::Topology::Triangles,
+            hal::pso::Face::NONE,
+        ),
+        (
+            node::pbr::mesh::MaterialPass::Transparent,
+            asset::Topology::Lines,
+            hal::pso::Face::NONE,
+        ),
+        (
+            node::pbr::mesh::MaterialPass::Transparent,
+            asset::Topology::Points,
+            hal::pso::Face::NONE,
+        ),
+    ];
+    for (pass, topology, cull_face) in mesh_passes.iter() {
+        // The projection doesn't flip y, so the counter-clockwise front faces of glTF wind
+        // clockwise in the framebuffer
+        let mut mesh_pipeline = node::group::StateGroupDesc::new(
+            node::pbr::mesh::PipelineDesc {
+                shadow_maps: shadow_maps.len(),
+                pass: *pass,
+                topology: *topology,
+            },
+            pipeline_state
+                .clone()
+                .with_primitive(topology.primitive())
+                .with_culling(*cull_face, hal::pso::FrontFace::Clockwise),
+        )
+        .builder();
+        for shadow_map in shadow_maps.iter() {
+            mesh_pipeline = mesh_pipeline.with_image(*shadow_map);
+        }
+        mesh_subpass = mesh_subpass.with_group(mesh_pipeline);
+    }
+    let mut mesh_subpass = mesh_subpass
+        .with_color(mesh_hdr)
+        .with_color(mesh_velocity)
+        .with_depth_stencil(depth);
+    for shadow_pass in shadow_passes {
+        mesh_subpass = mesh_subpass.with_dependency(shadow_pass);
+    }
+    let mesh_pass = pbr_graph_builder.add_node(mesh_subpass.into_pass());
+
+    // The pass after which `hdr` and `velocity` are complete
+    let hdr_pass = if msaa_samples > 1 {
+        pbr_graph_builder.add_node(
+            node::pbr::msaa::PipelineDesc
+                .builder()
+                .with_image(mesh_hdr)
+                .with_image(mesh_velocity)
+                .into_subpass()
+                .with_dependency(mesh_pass)
+                .with_color(hdr)
+                .with_color(velocity)
+                .into_pass(),
+        )
+    } else {
+        mesh_pass
+    };
+
+    let taa_pass = pbr_graph_builder.add_node(
+        node::pbr::taa::PipelineDesc
+            .builder()
+            .with_image(hdr)
+            .with_image(velocity)
+            .into_subpass()
+            .with_dependency(hdr_pass)
+            .with_color(resolved)
+            .into_pass(),
+    );
+    pbr_graph_builder.add_node(
+        node::history::HistoryCopy::<B>::builder::<node::pbr::taa::TaaHistory<B>>(resolved)
+            .with_dependency(taa_pass),
+    );
+
+    let (bloom, bloom_pass) = node::pbr::bloom::add_bloom_chain(
+        &mut pbr_graph_builder,
+        resolved,
+        taa_pass,
+        width,
+        height,
+    );
+
+    let (adapted_luminance, exposure_pass) = node::pbr::exposure::add_exposure_chain(
+        &mut pbr_graph_builder,
+        resolved,
+        taa_pass,
+        width,
+        height,
+    );
+
+    let tonemap_pass = pbr_graph_builder.add_node(
+        node::pbr::tonemap::Pipeline::builder()
+            .with_image(resolved)
+            .with_image(bloom)
+            .with_image(adapted_luminance)
+            .into_subpass()
+            .with_dependency(taa_pass)
+            .with_dependency(bloom_pass)
+            .with_dependency(exposure_pass)
+            .with_color(color)
+            .into_pass(),
+    );
+
+    match target {
+        FrameTarget::Surface(surface) => {
+            pbr_graph_builder.add_node(
+                PresentNode::builder(factory, surface, color).with_dependency(tonemap_pass),
+            );
+        }
+        FrameTarget::Offscreen(format) => {
+            let (image, pass) = match format {
+                headless::OutputFormat::Png => (color, tonemap_pass),
+                headless::OutputFormat::Exr => (resolved, taa_pass),
+            };
+            pbr_graph_builder.add_node(
+                node::readback::Readback::<B>::builder(image, format.readback_name())
+                    .with_dependency(pass),
+            );
+        }
+    }
+
     let pbr_graph = pbr_graph_builder
-        .with_frames_in_flight(FRAMES_IN_FLIGHT)
-        .build(&mut factory, &mut families, &mut world)?;
+        .with_frames_in_flight(frames_in_flight)
+        .build(factory, families, world)?;
+
+    Ok(pbr_graph)
+}
+
+/// Creates the HDR color and motion vector images rendered by the mesh pass
+fn add_hdr_images<B: hal::Backend>(
+    pbr_graph_builder: &mut GraphBuilder<B, specs::World>,
+    width: u32,
+    height: u32,
+    samples: hal::image::NumSamples,
+) -> (ImageId, ImageId) {
+    let hdr = pbr_graph_builder.create_image(
+        hal::image::Kind::D2(width, height, 1, samples),
+        1,
+        hal::format::Format::Rgba32Sfloat,
+        Some(hal::command::ClearValue {
+            color: hal::command::ClearColor {
+                float32: [0.1, 0.3, 0.4, 1.0],
+            },
+        }),
+    );
+
+    let velocity = pbr_graph_builder.create_image(
+        hal::image::Kind::D2(width, height, 1, samples),
+        1,
+        node::pbr::taa::VELOCITY_FORMAT,
+        Some(hal::command::ClearValue {
+            color: hal::command::ClearColor {
+                float32: [0.0, 0.0, 0.0, 0.0],
+            },
+        }),
+    );
+
+    (hdr, velocity)
+}
+
+fn run_headless<B: hal::Backend>(
+    mut factory: Factory<B>,
+    mut families: Families<B>,
+    config: &config::AppConfig,
+) -> Result<(), failure::Error> {
+    let headless = config
+        .headless
+        .as_ref()
+        .expect("Headless rendering requires a headless config");
+    let (width, height) = (config.width, config.height);
+
+    let (mut world, mut dispatcher, _, _) = init_world(&mut factory, &mut families, config)?;
+
+    let mut readback_storage = node::readback::ReadbackStorage::<B>::new();
+    readback_storage.add_buffer(
+        &factory,
+        headless.format.readback_name(),
+        width as u64 * height as u64 * headless.format.bytes_per_pixel(),
+    )?;
+    world.add_resource(readback_storage);
+
+    let mut pbr_graph = build_pbr_graph(
+        &mut factory,
+        &mut families,
+        &mut world,
+        width,
+        height,
+        config.frames_in_flight,
+        FrameTarget::Offscreen(headless.format),
+    )?;
+
+    for _ in 0..headless.frames {
+        world.maintain();
+        dispatcher.dispatch(&mut world.res);
+
+        factory.maintain(&mut families);
+        pbr_graph.run(&mut factory, &mut families, &world);
+    }
+
+    factory
+        .wait_idle()
+        .map_err(|e| failure::format_err!("Failed to wait for device idle: {:?}", e))?;
+
+    let data = world
+        .write_resource::<node::readback::ReadbackStorage<B>>()
+        .read(&factory, headless.format.readback_name())?;
+    headless.write_output(width, height, &data)?;
+    log::info!(
+        "Rendered {} frame(s) to {:?}",
+        headless.frames,
+        headless.output
+    );
+
+    pbr_graph.dispose(&mut factory, &world);
+    // world must be dropped before factory so that resources held in
+    // material/mesh/primitive storages can be sent back to the factory for
+    // disposal before it is destroyed.
+    std::mem::drop(world);
+
+    Ok(())
+}
+
+#[cfg(any(feature = "dx12", feature = "metal", feature = "vulkan"))]
+fn run<B: hal::Backend>(
+    event_loop: rendy::init::winit::event_loop::EventLoop<()>,
+    surface: rendy::wsi::Surface<B>,
+    window: Window,
+    mut factory: Factory<B>,
+    mut families: Families<B>,
+    config: &config::AppConfig,
+) -> Result<(), failure::Error> {
+    #[cfg(feature = "rd")]
+    rd.start_frame_capture(std::ptr::null(), std::ptr::null());
+
+    let size = window.inner_size().to_physical(window.hidpi_factor());
+    let (width, height) = (size.width as u32, size.height as u32);
+
+    let (mut world, mut dispatcher, mut environment_loader, mut gltf_loader) =
+        init_world(&mut factory, &mut families, config)?;
+    world.write_resource::<input::InputState>().window_size = window.inner_size();
+
+    let pbr_graph = build_pbr_graph(
+        &mut factory,
+        &mut families,
+        &mut world,
+        width,
+        height,
+        config.frames_in_flight,
+        FrameTarget::Surface(surface),
+    )?;
 
     let started = time::Instant::now();
 
@@ -670,6 +923,8 @@ fn run<B: hal::Backend>(
                 // Draw the app
                 match (world.as_mut(), pbr_graph.as_mut()) {
                     (Some(world), Some(pbr_graph)) => {
+                        environment_loader.maintain(&mut factory, &mut families, world);
+                        gltf_loader.maintain(&mut factory, world);
                         factory.maintain(&mut families);
 
                         pbr_graph.run(&mut factory, &mut families, world);
@@ -697,6 +952,23 @@ fn run<B: hal::Backend>(
                                 "Tonemapper Settings: {}",
                                 world.read_resource::<node::pbr::Aux>().tonemapper_args
                             );
+                            log::info!(
+                                "Auto Exposure Settings: {}",
+                                world.read_resource::<node::pbr::Aux>().auto_exposure
+                            );
+                            log::info!(
+                                "Bloom Settings: {}",
+                                world.read_resource::<node::pbr::Aux>().bloom_args
+                            );
+                            log::info!(
+                                "Color Grading Settings: {}, LUT: {}",
+                                world.read_resource::<node::pbr::Aux>().color_grading,
+                                world.read_resource::<node::pbr::Aux>().color_lut_enabled
+                            );
+                            log::info!(
+                                "TAA Settings: {}",
+                                world.read_resource::<node::pbr::Aux>().taa_args
+                            );
                             checkpoint += elapsed;
                             frames = 0;
                         }
@@ -710,6 +982,7 @@ fn run<B: hal::Backend>(
                 ..
             } => {
                 let mut world = world.take().unwrap();
+                environment_loader.dispose(&mut factory);
                 pbr_graph.take().unwrap().dispose(&mut factory, &mut world);
                 // world must be dropped before factory so that resources held in
                 // material/mesh/primitive storages can be sent back to the factory for
@@ -729,8 +1002,13 @@ fn run<B: hal::Backend>(
     });
 }
 
-#[cfg(not(any(feature = "dx12", feature = "metal", feature = "vulkan")))]
+#[cfg(not(any(
+    feature = "dx12",
+    feature = "metal",
+    feature = "vulkan",
+    feature = "empty"
+)))]
 fn main() -> Result<(), failure::Error> {
-    panic!("Specify feature: { dx12, metal, vulkan }");
+    panic!("Specify feature: { dx12, metal, vulkan, empty }");
     Ok(())
 }
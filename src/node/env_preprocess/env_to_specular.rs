@@ -243,8 +243,8 @@ where
                             .mip_level
                             .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
                             as f32
-                            / (crate::SPEC_CUBEMAP_MIP_LEVELS - 1) as f32,
-                        resolution: crate::ENV_CUBEMAP_RES as f32,
+                            / (aux.environment_config.spec_cubemap_mip_levels - 1) as f32,
+                        resolution: aux.environment_config.env_cubemap_res as f32,
                     }],
                 )
                 .unwrap()
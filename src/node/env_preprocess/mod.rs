@@ -12,6 +12,7 @@ pub mod integrate_spec_brdf;
 
 pub struct Aux<B: hal::Backend> {
     pub align: u64,
+    pub environment_config: crate::config::EnvironmentConfig,
     pub irradiance_theta_samples: u32,
     pub spec_samples: u32,
     pub equirectangular_texture: Texture<B>,
@@ -21,6 +22,7 @@ pub struct Aux<B: hal::Backend> {
     pub spec_brdf_map: Option<Texture<B>>,
     pub queue: QueueId,
     pub mip_level: std::sync::atomic::AtomicUsize,
+    pub readback: crate::node::readback::ReadbackStorage<B>,
 }
 
 impl<B> faces_to_cubemap::FacesToCubemapResource<B> for Aux<B>
@@ -66,3 +68,16 @@ where
         }
     }
 }
+
+impl<B> crate::node::readback::ReadbackResource<B> for Aux<B>
+where
+    B: hal::Backend,
+{
+    fn with_readback_buffer(&self, name: &str, f: &mut dyn FnMut(&B::Buffer)) {
+        f(self
+            .readback
+            .buffer(name)
+            .expect("Readback buffer missing from storage")
+            .raw());
+    }
+}
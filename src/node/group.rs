@@ -0,0 +1,296 @@
+//! A render group for `SimpleGraphicsPipelineDesc`s which need pipeline state that rendy's
+//! `SimpleRenderGroupDesc` always sets to its default, such as multisampling.
+use rendy::{
+    command::{QueueId, RenderPassEncoder},
+    factory::Factory,
+    graph::{
+        render::{
+            PrepareResult, RenderGroup, RenderGroupDesc, SimpleGraphicsPipeline,
+            SimpleGraphicsPipelineDesc,
+        },
+        BufferAccess, GraphContext, ImageAccess, NodeBuffer, NodeImage,
+    },
+    hal::device::Device,
+    resource::{DescriptorSetLayout, Handle},
+};
+
+use rendy::hal;
+
+/// Fixed function state used when building the pipeline
+#[derive(Debug, Clone)]
+pub struct PipelineState {
+    pub rasterizer: hal::pso::Rasterizer,
+    pub input_assembler: hal::pso::InputAssemblerDesc,
+    /// Number of samples per pixel of the subpass attachments
+    pub samples: hal::image::NumSamples,
+}
+
+impl Default for PipelineState {
+    fn default() -> Self {
+        PipelineState {
+            rasterizer: hal::pso::Rasterizer::FILL,
+            input_assembler: hal::pso::InputAssemblerDesc::new(hal::Primitive::TriangleList),
+            samples: 1,
+        }
+    }
+}
+
+impl PipelineState {
+    pub fn with_samples(mut self, samples: hal::image::NumSamples) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn with_primitive(mut self, primitive: hal::Primitive) -> Self {
+        self.input_assembler = hal::pso::InputAssemblerDesc::new(primitive);
+        self
+    }
+
+    /// Culls the faces in `cull_face`, treating faces whose vertices wind in `front_face`
+    /// order in the framebuffer as front faces
+    pub fn with_culling(
+        mut self,
+        cull_face: hal::pso::Face,
+        front_face: hal::pso::FrontFace,
+    ) -> Self {
+        self.rasterizer.cull_face = cull_face;
+        self.rasterizer.front_face = front_face;
+        self
+    }
+
+    fn multisampling(&self) -> Option<hal::pso::Multisampling> {
+        if self.samples > 1 {
+            Some(hal::pso::Multisampling {
+                rasterization_samples: self.samples,
+                sample_shading: None,
+                sample_mask: !0,
+                alpha_coverage: false,
+                alpha_to_one: false,
+            })
+        } else {
+            None
+        }
+    }
+}
+
+/// Builds the pipeline of `P` with the given `PipelineState`, otherwise behaving like
+/// `SimpleRenderGroupDesc`
+#[derive(Debug)]
+pub struct StateGroupDesc<P> {
+    inner: P,
+    state: PipelineState,
+}
+
+impl<P> StateGroupDesc<P> {
+    pub fn new(inner: P, state: PipelineState) -> Self {
+        StateGroupDesc { inner, state }
+    }
+}
+
+#[derive(Debug)]
+pub struct StateGroup<B: hal::Backend, P> {
+    set_layouts: Vec<Handle<DescriptorSetLayout<B>>>,
+    pipeline_layout: B::PipelineLayout,
+    graphics_pipeline: B::GraphicsPipeline,
+    pipeline: P,
+}
+
+impl<B, P> RenderGroupDesc<B, specs::World> for StateGroupDesc<P>
+where
+    B: hal::Backend,
+    P: SimpleGraphicsPipelineDesc<B, specs::World>,
+{
+    fn buffers(&self) -> Vec<BufferAccess> {
+        self.inner.buffers()
+    }
+
+    fn images(&self) -> Vec<ImageAccess> {
+        self.inner.images()
+    }
+
+    fn colors(&self) -> usize {
+        self.inner.colors().len()
+    }
+
+    fn depth(&self) -> bool {
+        self.inner.depth_stencil().is_some()
+    }
+
+    fn build<'a>(
+        self,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        queue: QueueId,
+        world: &specs::World,
+        framebuffer_width: u32,
+        framebuffer_height: u32,
+        subpass: hal::pass::Subpass<'_, B>,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn RenderGroup<B, specs::World>>, hal::pso::CreationError> {
+        let StateGroupDesc { inner, state } = self;
+        let layout = inner.layout();
+
+        let set_layouts = layout
+            .sets
+            .into_iter()
+            .map(|set| {
+                factory
+                    .create_descriptor_set_layout(set.bindings)
+                    .map(Handle::from)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let pipeline_layout = unsafe {
+            factory.device().create_pipeline_layout(
+                set_layouts.iter().map(|set_layout| set_layout.raw()),
+                layout.push_constants,
+            )
+        }?;
+
+        // Vertex buffers are bound in order, with attribute locations counting up across
+        // all of them
+        let mut vertex_buffers = Vec::new();
+        let mut attributes = Vec::new();
+        for (elements, stride, rate) in inner.vertices() {
+            let binding = vertex_buffers.len() as hal::pso::BufferIndex;
+            vertex_buffers.push(hal::pso::VertexBufferDesc {
+                binding,
+                stride,
+                rate,
+            });
+            for element in elements {
+                attributes.push(hal::pso::AttributeDesc {
+                    location: attributes.len() as hal::pso::Location,
+                    binding,
+                    element,
+                });
+            }
+        }
+
+        let rect = hal::pso::Rect {
+            x: 0,
+            y: 0,
+            w: framebuffer_width as i16,
+            h: framebuffer_height as i16,
+        };
+
+        let mut shader_set = inner.load_shader_set(factory, world);
+
+        let graphics_pipeline = {
+            let shaders = match shader_set.raw() {
+                Ok(shaders) => shaders,
+                Err(e) => {
+                    shader_set.dispose(factory);
+                    unsafe { factory.device().destroy_pipeline_layout(pipeline_layout) };
+                    return Err(e);
+                }
+            };
+
+            let graphics_pipeline = unsafe {
+                factory.device().create_graphics_pipeline(
+                    &hal::pso::GraphicsPipelineDesc {
+                        shaders,
+                        rasterizer: state.rasterizer.clone(),
+                        vertex_buffers,
+                        attributes,
+                        input_assembler: state.input_assembler.clone(),
+                        blender: hal::pso::BlendDesc {
+                            logic_op: None,
+                            targets: inner.colors(),
+                        },
+                        depth_stencil: inner.depth_stencil().unwrap_or_default(),
+                        multisampling: state.multisampling(),
+                        baked_states: hal::pso::BakedStates {
+                            viewport: Some(hal::pso::Viewport {
+                                rect,
+                                depth: 0.0..1.0,
+                            }),
+                            scissor: Some(rect),
+                            blend_color: None,
+                            depth_bounds: None,
+                        },
+                        layout: &pipeline_layout,
+                        subpass,
+                        flags: hal::pso::PipelineCreationFlags::empty(),
+                        parent: hal::pso::BasePipeline::None,
+                    },
+                    None,
+                )
+            };
+            shader_set.dispose(factory);
+
+            match graphics_pipeline {
+                Ok(graphics_pipeline) => graphics_pipeline,
+                Err(e) => {
+                    unsafe { factory.device().destroy_pipeline_layout(pipeline_layout) };
+                    return Err(e);
+                }
+            }
+        };
+
+        let pipeline = match inner.build(ctx, factory, queue, world, buffers, images, &set_layouts)
+        {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                unsafe {
+                    factory
+                        .device()
+                        .destroy_graphics_pipeline(graphics_pipeline);
+                    factory.device().destroy_pipeline_layout(pipeline_layout);
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Box::new(StateGroup::<B, P::Pipeline> {
+            set_layouts,
+            pipeline_layout,
+            graphics_pipeline,
+            pipeline,
+        }))
+    }
+}
+
+impl<B, P> RenderGroup<B, specs::World> for StateGroup<B, P>
+where
+    B: hal::Backend,
+    P: SimpleGraphicsPipeline<B, specs::World>,
+{
+    fn prepare(
+        &mut self,
+        factory: &Factory<B>,
+        queue: QueueId,
+        index: usize,
+        _subpass: hal::pass::Subpass<'_, B>,
+        world: &specs::World,
+    ) -> PrepareResult {
+        self.pipeline
+            .prepare(factory, queue, &self.set_layouts, index, world)
+    }
+
+    fn draw_inline(
+        &mut self,
+        mut encoder: RenderPassEncoder<'_, B>,
+        index: usize,
+        _subpass: hal::pass::Subpass<'_, B>,
+        world: &specs::World,
+    ) {
+        encoder.bind_graphics_pipeline(&self.graphics_pipeline);
+        self.pipeline
+            .draw(&self.pipeline_layout, encoder, index, world);
+    }
+
+    fn dispose(self: Box<Self>, factory: &mut Factory<B>, world: &specs::World) {
+        self.pipeline.dispose(factory, world);
+        unsafe {
+            factory
+                .device()
+                .destroy_graphics_pipeline(self.graphics_pipeline);
+            factory
+                .device()
+                .destroy_pipeline_layout(self.pipeline_layout);
+        }
+        drop(self.set_layouts);
+    }
+}
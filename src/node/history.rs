@@ -0,0 +1,286 @@
+//! Graph images don't keep their contents from one frame to the next, so passes which need
+//! the output of the previous frame read it from a texture stored in the world instead, which
+//! `HistoryCopy` updates at the end of every frame.
+use rendy::{
+    command::{
+        CommandBuffer, CommandPool, ExecutableState, Families, Family, FamilyId, Fence, Graphics,
+        MultiShot, PendingState, Queue, QueueId, SimultaneousUse, Submission, Submit, Supports,
+    },
+    factory::{Factory, ImageState},
+    frame::Frames,
+    graph::{
+        gfx_acquire_barriers, gfx_release_barriers, BufferAccess, BufferId, DynNode, GraphContext,
+        ImageAccess, ImageId, NodeBuffer, NodeBuildError, NodeBuilder, NodeId, NodeImage,
+    },
+    resource::ViewKind,
+    texture::{Texture, TextureBuilder},
+};
+
+use rendy::hal;
+
+use std::marker::PhantomData;
+
+/// A world resource holding the previous frame's contents of a graph image
+pub trait History<B: hal::Backend>: Send + Sync + 'static {
+    /// Must have the same size and format as the copied graph image, and be kept in the
+    /// `ShaderReadOnlyOptimal` layout between frames
+    fn texture(&self) -> &Texture<B>;
+}
+
+/// Creates a zeroed history texture in the layout `HistoryCopy` expects
+pub fn create_history_texture<B: hal::Backend>(
+    factory: &mut Factory<B>,
+    queue: QueueId,
+    width: u32,
+    height: u32,
+    format: hal::format::Format,
+) -> Result<Texture<B>, failure::Error> {
+    let texel_size = format.surface_desc().bits as usize / 8;
+    let texture = TextureBuilder::new()
+        .with_kind(rendy::resource::Kind::D2(width, height, 1, 1))
+        .with_view_kind(ViewKind::D2)
+        .with_data_width(width)
+        .with_data_height(height)
+        .with_raw_data(
+            vec![0u8; width as usize * height as usize * texel_size],
+            format,
+        )
+        .build(
+            ImageState {
+                queue,
+                stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+                access: hal::image::Access::SHADER_READ,
+                layout: hal::image::Layout::ShaderReadOnlyOptimal,
+            },
+            factory,
+        )?;
+    Ok(texture)
+}
+
+/// Copies a graph image into the `History` resource `H` after every frame
+#[derive(Debug)]
+pub struct HistoryCopy<B: hal::Backend> {
+    pool: CommandPool<B>,
+    submit: Submit<B, SimultaneousUse>,
+    buffer: CommandBuffer<
+        B,
+        hal::queue::QueueType,
+        PendingState<ExecutableState<MultiShot<SimultaneousUse>>>,
+    >,
+}
+
+impl<B: hal::Backend> HistoryCopy<B> {
+    pub fn builder<H: History<B>>(source: ImageId) -> HistoryCopyBuilder<H> {
+        HistoryCopyBuilder {
+            source,
+            dependencies: vec![],
+            marker: PhantomData,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct HistoryCopyBuilder<H> {
+    source: ImageId,
+    dependencies: Vec<NodeId>,
+    marker: PhantomData<fn() -> H>,
+}
+
+impl<H> HistoryCopyBuilder<H> {
+    /// Add dependency.
+    /// Node will be placed after its dependencies.
+    pub fn add_dependency(&mut self, dependency: NodeId) -> &mut Self {
+        self.dependencies.push(dependency);
+        self
+    }
+
+    /// Add dependency.
+    /// Node will be placed after its dependencies.
+    pub fn with_dependency(mut self, dependency: NodeId) -> Self {
+        self.add_dependency(dependency);
+        self
+    }
+}
+
+impl<B, H> NodeBuilder<B, specs::World> for HistoryCopyBuilder<H>
+where
+    B: hal::Backend,
+    H: History<B>,
+{
+    fn family(&self, _factory: &mut Factory<B>, families: &Families<B>) -> Option<FamilyId> {
+        // The history is synchronized with pipeline barriers only, so it has to be written on
+        // the same queue the passes reading it run on
+        families.find(|family| Supports::<Graphics>::supports(&family.capability()).is_some())
+    }
+
+    fn buffers(&self) -> Vec<(BufferId, BufferAccess)> {
+        Vec::new()
+    }
+
+    fn images(&self) -> Vec<(ImageId, ImageAccess)> {
+        vec![(
+            self.source,
+            ImageAccess {
+                access: hal::image::Access::TRANSFER_READ,
+                layout: hal::image::Layout::TransferSrcOptimal,
+                usage: hal::image::Usage::TRANSFER_SRC,
+                stages: hal::pso::PipelineStage::TRANSFER,
+            },
+        )]
+    }
+
+    fn dependencies(&self) -> Vec<NodeId> {
+        self.dependencies.clone()
+    }
+
+    fn build<'a>(
+        self: Box<Self>,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        family: &mut Family<B>,
+        _queue: usize,
+        world: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn DynNode<B, specs::World>>, NodeBuildError> {
+        assert_eq!(buffers.len(), 0);
+        assert_eq!(images.len(), 1);
+
+        let history = world.read_resource::<H>();
+        let target = history.texture().image().raw();
+
+        let mut pool = factory.create_command_pool(family).unwrap();
+
+        let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+        let mut buf_recording = buf_initial.begin(MultiShot(SimultaneousUse), ());
+        let mut encoder = buf_recording.encoder();
+
+        {
+            let (stages, barriers) = gfx_acquire_barriers(ctx, None, images.iter());
+            log::trace!("Acquire {:?} : {:#?}", stages, barriers);
+            if !barriers.is_empty() {
+                unsafe {
+                    encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers)
+                };
+            }
+        }
+
+        let image = ctx.get_image(images[0].id).unwrap();
+        let extent = image.kind().extent();
+        let range = hal::image::SubresourceRange {
+            aspects: hal::format::Aspects::COLOR,
+            levels: 0..1,
+            layers: 0..1,
+        };
+        let layers = hal::image::SubresourceLayers {
+            aspects: hal::format::Aspects::COLOR,
+            level: 0,
+            layers: 0..1,
+        };
+
+        unsafe {
+            // Wait for the passes of this frame to finish reading the history
+            encoder.pipeline_barrier(
+                hal::pso::PipelineStage::FRAGMENT_SHADER..hal::pso::PipelineStage::TRANSFER,
+                hal::memory::Dependencies::empty(),
+                Some(hal::memory::Barrier::Image {
+                    states: (
+                        hal::image::Access::SHADER_READ,
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )
+                        ..(
+                            hal::image::Access::TRANSFER_WRITE,
+                            hal::image::Layout::TransferDstOptimal,
+                        ),
+                    target,
+                    families: None,
+                    range: range.clone(),
+                }),
+            );
+
+            encoder.copy_image(
+                image.raw(),
+                images[0].layout,
+                target,
+                hal::image::Layout::TransferDstOptimal,
+                Some(hal::command::ImageCopy {
+                    src_subresource: layers.clone(),
+                    src_offset: hal::image::Offset::ZERO,
+                    dst_subresource: layers,
+                    dst_offset: hal::image::Offset::ZERO,
+                    extent,
+                }),
+            );
+
+            // Make the copy visible to the passes of the next frame
+            encoder.pipeline_barrier(
+                hal::pso::PipelineStage::TRANSFER..hal::pso::PipelineStage::FRAGMENT_SHADER,
+                hal::memory::Dependencies::empty(),
+                Some(hal::memory::Barrier::Image {
+                    states: (
+                        hal::image::Access::TRANSFER_WRITE,
+                        hal::image::Layout::TransferDstOptimal,
+                    )
+                        ..(
+                            hal::image::Access::SHADER_READ,
+                            hal::image::Layout::ShaderReadOnlyOptimal,
+                        ),
+                    target,
+                    families: None,
+                    range,
+                }),
+            );
+        }
+
+        {
+            let (stages, barriers) = gfx_release_barriers(ctx, None, images.iter());
+            log::trace!("Release {:?} : {:#?}", stages, barriers);
+            if !barriers.is_empty() {
+                unsafe {
+                    encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers)
+                };
+            }
+        }
+
+        let (submit, buffer) = buf_recording.finish().submit();
+
+        Ok(Box::new(HistoryCopy {
+            pool,
+            submit,
+            buffer,
+        }))
+    }
+}
+
+impl<B> DynNode<B, specs::World> for HistoryCopy<B>
+where
+    B: hal::Backend,
+{
+    unsafe fn run<'a>(
+        &mut self,
+        _ctx: &GraphContext<B>,
+        _factory: &Factory<B>,
+        queue: &mut Queue<B>,
+        _world: &specs::World,
+        _frames: &Frames<B>,
+        waits: &[(&'a B::Semaphore, hal::pso::PipelineStage)],
+        signals: &[&'a B::Semaphore],
+        fence: Option<&mut Fence<B>>,
+    ) {
+        queue.submit(
+            Some(
+                Submission::new()
+                    .submits(Some(&self.submit))
+                    .wait(waits.iter().cloned())
+                    .signal(signals.iter()),
+            ),
+            fence,
+        );
+    }
+
+    unsafe fn dispose(mut self: Box<Self>, factory: &mut Factory<B>, _world: &specs::World) {
+        drop(self.submit);
+        self.pool.free_buffers(Some(self.buffer.mark_complete()));
+        factory.destroy_command_pool(self.pool);
+    }
+}
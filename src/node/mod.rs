@@ -1,2 +1,5 @@
 pub mod env_preprocess;
+pub mod group;
+pub mod history;
 pub mod pbr;
+pub mod readback;
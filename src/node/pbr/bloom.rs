@@ -0,0 +1,399 @@
+//! Bloom, computed as a chain of fullscreen passes over progressively smaller images. The
+//! bright parts of the HDR image are extracted at half resolution, downsampled `BLOOM_LEVELS`
+//! times and then blurred back up, and the result is added to the HDR image by the tonemapper.
+use rendy::{
+    command::{QueueId, RenderPassEncoder},
+    factory::Factory,
+    graph::{
+        render::*, GraphBuilder, GraphContext, ImageAccess, ImageId, NodeBuffer, NodeId, NodeImage,
+    },
+    hal::{device::Device, pso::DescriptorPool},
+    resource::{
+        DescriptorSetLayout, Escape, Filter, Handle, ImageView, ImageViewInfo, Sampler,
+        SamplerDesc, ViewKind, WrapMode,
+    },
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+};
+
+use rendy::hal;
+
+use crate::node::pbr::Aux;
+
+lazy_static::lazy_static! {
+    static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/fullscreen_triangle.vert"),
+        ShaderKind::Vertex,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref PREFILTER: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/bloom_prefilter.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref DOWNSAMPLE: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/bloom_downsample.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref UPSAMPLE: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/bloom_upsample.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref PREFILTER_SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*PREFILTER).unwrap();
+
+    static ref DOWNSAMPLE_SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*DOWNSAMPLE).unwrap();
+
+    static ref UPSAMPLE_SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*UPSAMPLE).unwrap();
+}
+
+pub const BLOOM_FORMAT: hal::format::Format = hal::format::Format::Rgba16Sfloat;
+/// Number of images in the downsample chain, the first being half the HDR resolution
+pub const BLOOM_LEVELS: u32 = 5;
+
+#[derive(Debug, Clone, Copy)]
+pub struct BloomArgs {
+    /// How much of the bloom is added to the HDR image
+    pub intensity: f32,
+    /// Brightness above which pixels start to bloom, with a soft knee below it
+    pub threshold: f32,
+    /// Scales the blur filter of the upsample passes
+    pub radius: f32,
+}
+
+impl std::fmt::Display for BloomArgs {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Intensity: {}, Threshold: {}, Radius: {}",
+            self.intensity, self.threshold, self.radius
+        )
+    }
+}
+
+/// The passes making up the bloom chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Extracts the bright parts of the HDR image while downsampling it
+    Prefilter,
+    /// Downsamples the previous level
+    Downsample,
+    /// Blurs the smaller image up and adds the downsampled image of the same level
+    Upsample,
+}
+
+impl Stage {
+    fn num_images(self) -> usize {
+        match self {
+            Stage::Upsample => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PipelineDesc {
+    pub stage: Stage,
+}
+
+#[derive(Debug)]
+pub struct Pipeline<B: hal::Backend> {
+    descriptor_pool: B::DescriptorPool,
+    set: B::DescriptorSet,
+    image_sampler: Escape<Sampler<B>>,
+    image_views: Vec<Escape<ImageView<B>>>,
+}
+
+impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PipelineDesc
+where
+    B: hal::Backend,
+{
+    type Pipeline = Pipeline<B>;
+
+    fn images(&self) -> Vec<ImageAccess> {
+        vec![
+            ImageAccess {
+                access: hal::image::Access::SHADER_READ,
+                usage: hal::image::Usage::SAMPLED,
+                layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                stages: hal::pso::PipelineStage::FRAGMENT_SHADER,
+            };
+            self.stage.num_images()
+        ]
+    }
+
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        None
+    }
+
+    fn load_shader_set(
+        &self,
+        factory: &mut Factory<B>,
+        _world: &specs::World,
+    ) -> rendy::shader::ShaderSet<B> {
+        match self.stage {
+            Stage::Prefilter => PREFILTER_SHADERS.build(factory, Default::default()),
+            Stage::Downsample => DOWNSAMPLE_SHADERS.build(factory, Default::default()),
+            Stage::Upsample => UPSAMPLE_SHADERS.build(factory, Default::default()),
+        }
+        .unwrap()
+    }
+
+    fn layout(&self) -> Layout {
+        let mut bindings = vec![hal::pso::DescriptorSetLayoutBinding {
+            binding: 0,
+            ty: hal::pso::DescriptorType::Sampler,
+            count: 1,
+            stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+            immutable_samplers: false,
+        }];
+        for i in 0..self.stage.num_images() {
+            bindings.push(hal::pso::DescriptorSetLayoutBinding {
+                binding: i as u32 + 1,
+                ty: hal::pso::DescriptorType::SampledImage,
+                count: 1,
+                stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                immutable_samplers: false,
+            });
+        }
+        Layout {
+            sets: vec![SetLayout { bindings }],
+            // threshold and radius
+            push_constants: vec![(hal::pso::ShaderStageFlags::FRAGMENT, 0..8)],
+        }
+    }
+
+    fn build<'a>(
+        self,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        _queue: QueueId,
+        _world: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Pipeline<B>, hal::pso::CreationError> {
+        assert!(buffers.is_empty());
+        assert_eq!(images.len(), self.stage.num_images());
+        assert_eq!(set_layouts.len(), 1);
+
+        let mut descriptor_pool = unsafe {
+            factory.create_descriptor_pool(
+                1,
+                vec![
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::Sampler,
+                        count: 1,
+                    },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::SampledImage,
+                        count: images.len(),
+                    },
+                ],
+                hal::pso::DescriptorPoolCreateFlags::empty(),
+            )?
+        };
+
+        let image_sampler = factory
+            .create_sampler(SamplerDesc::new(Filter::Linear, WrapMode::Clamp))
+            .unwrap();
+
+        let image_views = images
+            .iter()
+            .enumerate()
+            .map(|(i, image)| {
+                let image_handle = ctx.get_image(image.id).expect("Bloom input image missing");
+                // Only the prefilter reads the full precision HDR image
+                let format = if self.stage == Stage::Prefilter && i == 0 {
+                    hal::format::Format::Rgba32Sfloat
+                } else {
+                    BLOOM_FORMAT
+                };
+                factory
+                    .create_image_view(
+                        image_handle.clone(),
+                        ImageViewInfo {
+                            view_kind: ViewKind::D2,
+                            format,
+                            swizzle: hal::format::Swizzle::NO,
+                            range: image.range.clone(),
+                        },
+                    )
+                    .expect("Could not create bloom input image view")
+            })
+            .collect::<Vec<_>>();
+
+        let set = unsafe {
+            let set = descriptor_pool.allocate_set(&set_layouts[0].raw()).unwrap();
+            factory.write_descriptor_sets(vec![hal::pso::DescriptorSetWrite {
+                set: &set,
+                binding: 0,
+                array_offset: 0,
+                descriptors: Some(hal::pso::Descriptor::Sampler(image_sampler.raw())),
+            }]);
+            for (i, image_view) in image_views.iter().enumerate() {
+                factory.write_descriptor_sets(vec![hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: i as u32 + 1,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        image_view.raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                }]);
+            }
+            set
+        };
+
+        Ok(Pipeline {
+            descriptor_pool,
+            set,
+            image_sampler,
+            image_views,
+        })
+    }
+}
+
+impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
+where
+    B: hal::Backend,
+{
+    type Desc = PipelineDesc;
+
+    fn prepare(
+        &mut self,
+        _factory: &Factory<B>,
+        _queue: QueueId,
+        _set_layouts: &[Handle<DescriptorSetLayout<B>>],
+        _index: usize,
+        _world: &specs::World,
+    ) -> PrepareResult {
+        // The bloom settings are recorded as push constants
+        PrepareResult::DrawRecord
+    }
+
+    fn draw(
+        &mut self,
+        layout: &B::PipelineLayout,
+        mut encoder: RenderPassEncoder<'_, B>,
+        _index: usize,
+        world: &specs::World,
+    ) {
+        let bloom_args = world.read_resource::<Aux>().bloom_args;
+        unsafe {
+            encoder.bind_graphics_descriptor_sets(layout, 0, Some(&self.set), std::iter::empty());
+            encoder.push_constants(
+                layout,
+                hal::pso::ShaderStageFlags::FRAGMENT,
+                0,
+                &[bloom_args.threshold.to_bits(), bloom_args.radius.to_bits()],
+            );
+            encoder.draw(0..3, 0..1);
+        }
+    }
+
+    fn dispose(mut self, factory: &mut Factory<B>, _world: &specs::World) {
+        unsafe {
+            self.descriptor_pool.reset();
+            factory.destroy_descriptor_pool(self.descriptor_pool);
+        }
+    }
+}
+
+/// Adds the bloom chain for a `width` by `height` HDR image to the graph. Returns the final
+/// bloom image and the node writing it.
+pub fn add_bloom_chain<B: hal::Backend>(
+    builder: &mut GraphBuilder<B, specs::World>,
+    hdr: ImageId,
+    hdr_pass: NodeId,
+    width: u32,
+    height: u32,
+) -> (ImageId, NodeId) {
+    let levels = (0..BLOOM_LEVELS)
+        .map(|level| {
+            (
+                (width >> (level + 1)).max(1),
+                (height >> (level + 1)).max(1),
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut create_image = |(width, height): (u32, u32)| {
+        builder.create_image(
+            hal::image::Kind::D2(width, height, 1, 1),
+            1,
+            BLOOM_FORMAT,
+            Some(hal::command::ClearValue {
+                color: hal::command::ClearColor {
+                    float32: [0.0, 0.0, 0.0, 1.0],
+                },
+            }),
+        )
+    };
+    let down_images = levels
+        .iter()
+        .map(|&size| create_image(size))
+        .collect::<Vec<_>>();
+    let up_images = levels[..levels.len() - 1]
+        .iter()
+        .map(|&size| create_image(size))
+        .collect::<Vec<_>>();
+
+    let mut pass = builder.add_node(
+        PipelineDesc {
+            stage: Stage::Prefilter,
+        }
+        .builder()
+        .with_image(hdr)
+        .into_subpass()
+        .with_dependency(hdr_pass)
+        .with_color(down_images[0])
+        .into_pass(),
+    );
+
+    for level in 1..down_images.len() {
+        pass = builder.add_node(
+            PipelineDesc {
+                stage: Stage::Downsample,
+            }
+            .builder()
+            .with_image(down_images[level - 1])
+            .into_subpass()
+            .with_dependency(pass)
+            .with_color(down_images[level])
+            .into_pass(),
+        );
+    }
+
+    let mut blurred = down_images[down_images.len() - 1];
+    for level in (0..up_images.len()).rev() {
+        pass = builder.add_node(
+            PipelineDesc {
+                stage: Stage::Upsample,
+            }
+            .builder()
+            .with_image(blurred)
+            .with_image(down_images[level])
+            .into_subpass()
+            .with_dependency(pass)
+            .with_color(up_images[level])
+            .into_pass(),
+        );
+        blurred = up_images[level];
+    }
+
+    (blurred, pass)
+}
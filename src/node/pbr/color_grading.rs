@@ -0,0 +1,341 @@
+//! Color grading applied by the tonemapper. White balance is applied to the HDR color before
+//! tonemapping, everything else to the tonemapped color in display (sRGB encoded) space,
+//! finishing with an optional 3D lookup table loaded from a `.cube` file.
+use rendy::{
+    command::QueueId,
+    factory::{Factory, ImageState},
+    resource::ViewKind,
+    texture::{pixel::Rgba32Sfloat, Texture, TextureBuilder},
+};
+
+use rendy::hal;
+use serde::Deserialize;
+
+use std::path::Path;
+
+/// Edge length of the lookup table used when the scene doesn't specify one. An identity
+/// table of any size leaves colors unchanged.
+const IDENTITY_LUT_SIZE: u32 = 2;
+
+/// Color grading settings, read from the scene file and adjustable at runtime
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ColorGrading {
+    /// White balance temperature shift from D65, from -1 (cooler) to 1 (warmer)
+    pub temperature: f32,
+    /// White balance shift from -1 (towards green) to 1 (towards magenta)
+    pub tint: f32,
+    pub contrast: f32,
+    pub saturation: f32,
+    /// Added to the shadows, per channel
+    pub lift: [f32; 3],
+    /// Exponent applied to the midtones, per channel
+    pub gamma: [f32; 3],
+    /// Multiplies the highlights, per channel
+    pub gain: [f32; 3],
+}
+
+impl Default for ColorGrading {
+    fn default() -> Self {
+        ColorGrading {
+            temperature: 0.0,
+            tint: 0.0,
+            contrast: 1.0,
+            saturation: 1.0,
+            lift: [0.0; 3],
+            gamma: [1.0; 3],
+            gain: [1.0; 3],
+        }
+    }
+}
+
+impl std::fmt::Display for ColorGrading {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Temperature: {}, Tint: {}, Contrast: {}, Saturation: {}, Lift: {:?}, Gamma: {:?}, Gain: {:?}",
+            self.temperature,
+            self.tint,
+            self.contrast,
+            self.saturation,
+            self.lift,
+            self.gamma,
+            self.gain
+        )
+    }
+}
+
+/// Layout of the color grading settings in the tonemapper uniform, following std140
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ColorGradingArgs {
+    lift: [f32; 3],
+    temperature: f32,
+    gamma: [f32; 3],
+    tint: f32,
+    gain: [f32; 3],
+    contrast: f32,
+    saturation: f32,
+    /// 1 to apply the lookup table, 0 to skip it
+    lut_contribution: f32,
+    _pad0: [f32; 2],
+    lut_domain_min: [f32; 3],
+    _pad1: f32,
+    lut_domain_max: [f32; 3],
+}
+
+impl ColorGradingArgs {
+    pub fn new<B: hal::Backend>(
+        grading: &ColorGrading,
+        lut: &ColorLut<B>,
+        lut_enabled: bool,
+    ) -> Self {
+        ColorGradingArgs {
+            lift: grading.lift,
+            temperature: grading.temperature,
+            gamma: grading.gamma,
+            tint: grading.tint,
+            gain: grading.gain,
+            contrast: grading.contrast,
+            saturation: grading.saturation,
+            lut_contribution: if lut_enabled { 1.0 } else { 0.0 },
+            _pad0: [0.0; 2],
+            lut_domain_min: lut.domain_min,
+            _pad1: 0.0,
+            lut_domain_max: lut.domain_max,
+        }
+    }
+}
+
+/// A 3D lookup table as read from an Adobe/Resolve `.cube` file
+#[derive(Debug, Clone, PartialEq)]
+pub struct CubeLut {
+    pub size: u32,
+    pub domain_min: [f32; 3],
+    pub domain_max: [f32; 3],
+    /// Output colors with red changing fastest, then green, then blue
+    pub data: Vec<[f32; 3]>,
+}
+
+impl CubeLut {
+    pub fn identity(size: u32) -> Self {
+        let scale = 1.0 / (size - 1) as f32;
+        let mut data = Vec::with_capacity((size * size * size) as usize);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    data.push([r as f32 * scale, g as f32 * scale, b as f32 * scale]);
+                }
+            }
+        }
+        CubeLut {
+            size,
+            domain_min: [0.0; 3],
+            domain_max: [1.0; 3],
+            data,
+        }
+    }
+
+    /// Reads a `.cube` file relative to the application root
+    pub fn from_path(path: &str) -> Result<Self, failure::Error> {
+        let full_path = Path::new(&crate::application_root_dir()).join(path);
+        let source = std::fs::read_to_string(&full_path)
+            .map_err(|e| failure::format_err!("Failed to read color LUT {:?}: {}", full_path, e))?;
+        Self::parse(&source)
+            .map_err(|e| failure::format_err!("Invalid color LUT {:?}: {}", full_path, e))
+    }
+
+    pub fn parse(source: &str) -> Result<Self, failure::Error> {
+        fn parse_floats(line: &str, values: &str) -> Result<[f32; 3], failure::Error> {
+            let values = values
+                .split_whitespace()
+                .map(|value| value.parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| failure::format_err!("Invalid numbers in line {:?}", line))?;
+            match values[..] {
+                [x, y, z] => Ok([x, y, z]),
+                _ => failure::bail!("Expected three numbers in line {:?}", line),
+            }
+        }
+
+        let mut size = None;
+        let mut domain_min = [0.0; 3];
+        let mut domain_max = [1.0; 3];
+        let mut data = Vec::new();
+
+        for line in source.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, rest) = match line.find(char::is_whitespace) {
+                Some(split) => (&line[..split], line[split..].trim()),
+                None => (line, ""),
+            };
+            match keyword {
+                "TITLE" => (),
+                "LUT_3D_SIZE" => {
+                    let parsed = rest
+                        .parse::<u32>()
+                        .map_err(|_| failure::format_err!("Invalid LUT size {:?}", rest))?;
+                    if parsed < 2 || parsed > 256 {
+                        failure::bail!("LUT size {} must be between 2 and 256", parsed);
+                    }
+                    size = Some(parsed);
+                }
+                "LUT_1D_SIZE" => failure::bail!("1D lookup tables are not supported"),
+                "DOMAIN_MIN" => domain_min = parse_floats(line, rest)?,
+                "DOMAIN_MAX" => domain_max = parse_floats(line, rest)?,
+                // Written by Resolve instead of the domain keywords
+                "LUT_3D_INPUT_RANGE" => {
+                    let range = rest
+                        .split_whitespace()
+                        .map(|value| value.parse::<f32>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| failure::format_err!("Invalid numbers in line {:?}", line))?;
+                    match range[..] {
+                        [min, max] => {
+                            domain_min = [min; 3];
+                            domain_max = [max; 3];
+                        }
+                        _ => failure::bail!("Expected two numbers in line {:?}", line),
+                    }
+                }
+                _ => data.push(parse_floats(line, line)?),
+            }
+        }
+
+        let size = size.ok_or(failure::format_err!("Missing LUT_3D_SIZE"))?;
+        let expected = (size * size * size) as usize;
+        if data.len() != expected {
+            failure::bail!(
+                "Expected {} entries for a LUT of size {}, found {}",
+                expected,
+                size,
+                data.len()
+            );
+        }
+        if (0..3).any(|i| domain_max[i] <= domain_min[i]) {
+            failure::bail!(
+                "DOMAIN_MAX {:?} must be greater than DOMAIN_MIN {:?}",
+                domain_max,
+                domain_min
+            );
+        }
+
+        Ok(CubeLut {
+            size,
+            domain_min,
+            domain_max,
+            data,
+        })
+    }
+}
+
+/// The lookup table sampled by the tonemapper, an identity table if the scene has none
+#[derive(Debug)]
+pub struct ColorLut<B: hal::Backend> {
+    pub texture: Texture<B>,
+    pub domain_min: [f32; 3],
+    pub domain_max: [f32; 3],
+}
+
+impl<B: hal::Backend> ColorLut<B> {
+    pub fn load(
+        factory: &mut Factory<B>,
+        queue: QueueId,
+        path: Option<&str>,
+    ) -> Result<Self, failure::Error> {
+        let lut = match path {
+            Some(path) => CubeLut::from_path(path)?,
+            None => CubeLut::identity(IDENTITY_LUT_SIZE),
+        };
+
+        let texture = TextureBuilder::new()
+            .with_kind(hal::image::Kind::D3(lut.size, lut.size, lut.size))
+            .with_view_kind(ViewKind::D3)
+            .with_data_width(lut.size)
+            .with_data_height(lut.size)
+            .with_data(
+                lut.data
+                    .iter()
+                    .map(|&[r, g, b]| Rgba32Sfloat {
+                        repr: [r, g, b, 1.0],
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .build(
+                ImageState {
+                    queue,
+                    stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+                    access: hal::image::Access::SHADER_READ,
+                    layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                },
+                factory,
+            )?;
+
+        Ok(ColorLut {
+            texture,
+            domain_min: lut.domain_min,
+            domain_max: lut.domain_max,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CubeLut;
+
+    #[test]
+    fn parses_red_fastest() {
+        let lut = CubeLut::parse(
+            "# Comment\n\
+             TITLE \"Test\"\n\
+             LUT_3D_SIZE 2\n\
+             \n\
+             0 0 0\n1 0 0\n0 1 0\n1 1 0\n\
+             0 0 1\n1 0 1\n0 1 1\n1 1 1\n",
+        )
+        .unwrap();
+        assert_eq!(lut.size, 2);
+        assert_eq!(lut.domain_min, [0.0; 3]);
+        assert_eq!(lut.domain_max, [1.0; 3]);
+        assert_eq!(lut.data, CubeLut::identity(2).data);
+    }
+
+    #[test]
+    fn parses_domain() {
+        let entries = "0 0 0\n".repeat(8);
+        let lut = CubeLut::parse(&format!(
+            "LUT_3D_SIZE 2\nDOMAIN_MIN 0 -1 0\nDOMAIN_MAX 1 2 4\n{}",
+            entries
+        ))
+        .unwrap();
+        assert_eq!(lut.domain_min, [0.0, -1.0, 0.0]);
+        assert_eq!(lut.domain_max, [1.0, 2.0, 4.0]);
+
+        let lut = CubeLut::parse(&format!(
+            "LUT_3D_SIZE 2\nLUT_3D_INPUT_RANGE 0 4\n{}",
+            entries
+        ))
+        .unwrap();
+        assert_eq!(lut.domain_min, [0.0; 3]);
+        assert_eq!(lut.domain_max, [4.0; 3]);
+    }
+
+    #[test]
+    fn rejects_invalid_luts() {
+        let entries = "0 0 0\n".repeat(8);
+        // Missing size, and sizes out of range
+        assert!(CubeLut::parse(&entries).is_err());
+        assert!(CubeLut::parse("LUT_3D_SIZE 1\n0 0 0\n").is_err());
+        assert!(CubeLut::parse(&format!("LUT_3D_SIZE 257\n{}", entries)).is_err());
+        assert!(CubeLut::parse("LUT_1D_SIZE 2\n0 0 0\n1 1 1\n").is_err());
+        // Wrong number of entries or values
+        assert!(CubeLut::parse(&format!("LUT_3D_SIZE 2\n{}0 0 0\n", entries)).is_err());
+        assert!(CubeLut::parse(&format!("LUT_3D_SIZE 2\n{}", "0 0\n".repeat(8))).is_err());
+        assert!(CubeLut::parse(&format!("LUT_3D_SIZE 2\n{}", "0 0 x\n".repeat(8))).is_err());
+        // Empty domain
+        assert!(CubeLut::parse(&format!("LUT_3D_SIZE 2\nDOMAIN_MAX 1 0 1\n{}", entries)).is_err());
+    }
+}
@@ -37,6 +37,7 @@ pub enum CubeDisplay {
 pub struct UniformArgs {
     proj: nalgebra::Matrix4<f32>,
     view: nalgebra::Matrix4<f32>,
+    prev_view: nalgebra::Matrix4<f32>,
     roughness: f32,
 }
 
@@ -85,9 +86,12 @@ pub struct PipelineDesc;
 pub struct Pipeline<B: hal::Backend> {
     cube: Mesh<B>,
     ubo_sets: Vec<B::DescriptorSet>,
-    env_cubemap_set: B::DescriptorSet,
-    irradiance_cubemap_set: B::DescriptorSet,
-    spec_cubemap_set: B::DescriptorSet,
+    /// The environment, irradiance and specular cube map sets of each frame. A frame's sets
+    /// are only rewritten once the frame is no longer in flight.
+    cube_sets: Vec<[B::DescriptorSet; 3]>,
+    /// The environment generation each frame's cube sets were written and its commands
+    /// recorded with
+    recorded_generations: Vec<u64>,
     settings: Settings,
     pool: B::DescriptorPool,
     #[allow(dead_code)]
@@ -106,6 +110,20 @@ where
 {
     type Pipeline = Pipeline<B>;
 
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        vec![
+            hal::pso::ColorBlendDesc {
+                mask: hal::pso::ColorMask::ALL,
+                blend: Some(hal::pso::BlendState::ALPHA),
+            },
+            // Motion vectors
+            hal::pso::ColorBlendDesc {
+                mask: hal::pso::ColorMask::RED | hal::pso::ColorMask::GREEN,
+                blend: None,
+            },
+        ]
+    }
+
     fn vertices(
         &self,
     ) -> Vec<(
@@ -197,7 +215,7 @@ where
         let mut pool = unsafe {
             factory
                 .create_descriptor_pool(
-                    frames + 3,
+                    frames * 4,
                     vec![
                         hal::pso::DescriptorRangeDesc {
                             ty: hal::pso::DescriptorType::UniformBuffer,
@@ -205,11 +223,11 @@ where
                         },
                         hal::pso::DescriptorRangeDesc {
                             ty: hal::pso::DescriptorType::Sampler,
-                            count: 3,
+                            count: frames * 3,
                         },
                         hal::pso::DescriptorRangeDesc {
                             ty: hal::pso::DescriptorType::SampledImage,
-                            count: 3,
+                            count: frames * 3,
                         },
                     ],
                     hal::pso::DescriptorPoolCreateFlags::empty(),
@@ -247,89 +265,21 @@ where
             });
         }
 
-        let env_cubemap_set = unsafe {
-            let set = pool.allocate_set(&set_layouts[1].raw()).unwrap();
-            factory.write_descriptor_sets(vec![
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 0,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Sampler(
-                        env_storage.env_cube.as_ref().unwrap().sampler().raw(),
-                    )),
-                },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 1,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Image(
-                        env_storage.env_cube.as_ref().unwrap().view().raw(),
-                        hal::image::Layout::ShaderReadOnlyOptimal,
-                    )),
-                },
-            ]);
-            set
-        };
-
-        let irradiance_cubemap_set = unsafe {
-            let set = pool.allocate_set(&set_layouts[1].raw()).unwrap();
-            factory.write_descriptor_sets(vec![
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 0,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Sampler(
-                        env_storage
-                            .irradiance_cube
-                            .as_ref()
-                            .unwrap()
-                            .sampler()
-                            .raw(),
-                    )),
-                },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 1,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Image(
-                        env_storage.irradiance_cube.as_ref().unwrap().view().raw(),
-                        hal::image::Layout::ShaderReadOnlyOptimal,
-                    )),
-                },
-            ]);
-            set
-        };
-
-        let spec_cubemap_set = unsafe {
-            let set = pool.allocate_set(&set_layouts[1].raw()).unwrap();
-            factory.write_descriptor_sets(vec![
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 0,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Sampler(
-                        env_storage.spec_cube.as_ref().unwrap().sampler().raw(),
-                    )),
-                },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 1,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Image(
-                        env_storage.spec_cube.as_ref().unwrap().view().raw(),
-                        hal::image::Layout::ShaderReadOnlyOptimal,
-                    )),
-                },
-            ]);
-            set
-        };
+        let mut cube_sets = Vec::with_capacity(frames);
+        for _ in 0..frames {
+            unsafe {
+                let mut allocate = || pool.allocate_set(&set_layouts[1].raw()).unwrap();
+                let sets = [allocate(), allocate(), allocate()];
+                write_cube_sets(factory, &sets, &env_storage);
+                cube_sets.push(sets);
+            }
+        }
 
         Ok(Pipeline {
             cube,
             ubo_sets,
-            env_cubemap_set,
-            irradiance_cubemap_set,
-            spec_cubemap_set,
+            cube_sets,
+            recorded_generations: vec![env_storage.generation; frames],
             settings,
             pool,
             buffer,
@@ -337,6 +287,39 @@ where
     }
 }
 
+/// Writes the environment, irradiance and specular cube maps of `env_storage`
+/// to their respective sets
+unsafe fn write_cube_sets<B: hal::Backend>(
+    factory: &Factory<B>,
+    sets: &[B::DescriptorSet; 3],
+    env_storage: &super::EnvironmentStorage<B>,
+) {
+    let cubes = [
+        env_storage.env_cube.as_ref().unwrap(),
+        env_storage.irradiance_cube.as_ref().unwrap(),
+        env_storage.spec_cube.as_ref().unwrap(),
+    ];
+    for (set, cube) in sets.iter().zip(cubes.iter()) {
+        factory.write_descriptor_sets(vec![
+            hal::pso::DescriptorSetWrite {
+                set,
+                binding: 0,
+                array_offset: 0,
+                descriptors: Some(hal::pso::Descriptor::Sampler(cube.sampler().raw())),
+            },
+            hal::pso::DescriptorSetWrite {
+                set,
+                binding: 1,
+                array_offset: 0,
+                descriptors: Some(hal::pso::Descriptor::Image(
+                    cube.view().raw(),
+                    hal::image::Layout::ShaderReadOnlyOptimal,
+                )),
+            },
+        ]);
+    }
+}
+
 impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
 where
     B: hal::Backend,
@@ -353,19 +336,39 @@ where
     ) -> PrepareResult {
         use specs::prelude::*;
 
+        // Updating the cube sets invalidates commands recorded with them, but this frame's
+        // previous commands have completed, so they are not in use
+        let env_storage = world.read_resource::<super::EnvironmentStorage<B>>();
+        let rerecord = self.recorded_generations[index] != env_storage.generation;
+        if rerecord {
+            unsafe {
+                write_cube_sets(factory, &self.cube_sets[index], &env_storage);
+            }
+            self.recorded_generations[index] = env_storage.generation;
+        }
+
         let aux = world.read_resource::<Aux>();
+        let jitter = world.read_resource::<super::taa::Jitter>();
         let transforms = world.read_storage::<components::GlobalTransform>();
+        let previous_transforms = world.read_storage::<components::PreviousGlobalTransform>();
         let cameras = world.read_storage::<components::Camera>();
         let active_cameras = world.read_storage::<components::ActiveCamera>();
-        let mut camera_args: CameraArgs = (&active_cameras, &cameras, &transforms)
+        let mut camera_args = (
+            &active_cameras,
+            &cameras,
+            &transforms,
+            previous_transforms.maybe(),
+        )
             .join()
-            .map(|(_, cam, trans)| (cam, trans).into())
+            .map(|(_, cam, trans, previous)| CameraArgs::new(cam, trans, previous, jitter.offset))
             .next()
             .expect("No active camera!");
 
-        camera_args.view.column_mut(3)[0] = 0.0;
-        camera_args.view.column_mut(3)[1] = 0.0;
-        camera_args.view.column_mut(3)[2] = 0.0;
+        for view in [&mut camera_args.view, &mut camera_args.prev_view].iter_mut() {
+            view.column_mut(3)[0] = 0.0;
+            view.column_mut(3)[1] = 0.0;
+            view.column_mut(3)[2] = 0.0;
+        }
 
         unsafe {
             factory
@@ -375,6 +378,7 @@ where
                     &[UniformArgs {
                         proj: camera_args.proj,
                         view: camera_args.view,
+                        prev_view: camera_args.prev_view,
                         roughness: match aux.cube_display {
                             CubeDisplay::Irradiance => 0.0,
                             CubeDisplay::Environment => 0.0,
@@ -385,7 +389,11 @@ where
                 .unwrap()
         };
 
-        PrepareResult::DrawReuse
+        if rerecord {
+            PrepareResult::DrawRecord
+        } else {
+            PrepareResult::DrawReuse
+        }
     }
 
     fn draw(
@@ -399,10 +407,11 @@ where
             .cube
             .bind(0, &[Position::vertex()], &mut encoder)
             .is_ok());
+        let [env_cubemap_set, irradiance_cubemap_set, spec_cubemap_set] = &self.cube_sets[index];
         let cube_set = match world.read_resource::<Aux>().cube_display {
-            CubeDisplay::Irradiance => &self.irradiance_cubemap_set,
-            CubeDisplay::Environment => &self.env_cubemap_set,
-            CubeDisplay::Specular => &self.spec_cubemap_set,
+            CubeDisplay::Irradiance => irradiance_cubemap_set,
+            CubeDisplay::Environment => env_cubemap_set,
+            CubeDisplay::Specular => spec_cubemap_set,
         };
         unsafe {
             encoder.bind_graphics_descriptor_sets(
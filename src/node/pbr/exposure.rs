@@ -0,0 +1,448 @@
+//! Automatic exposure. The luminance pass reduces the HDR image to a small image of average
+//! log luminance, which the adaptation pass bins into a histogram. The average of the middle
+//! of the histogram is the luminance the view should adapt to, and moving towards it from the
+//! previous frame's adapted luminance over time gives the eye adaptation effect. The
+//! tonemapper derives the exposure from the adapted luminance when enabled.
+use rendy::{
+    command::{QueueId, RenderPassEncoder},
+    factory::Factory,
+    graph::{
+        render::*, GraphBuilder, GraphContext, ImageAccess, ImageId, NodeBuffer, NodeId, NodeImage,
+    },
+    hal::{device::Device, pso::DescriptorPool},
+    resource::{
+        DescriptorSetLayout, Escape, Filter, Handle, ImageView, ImageViewInfo, Sampler,
+        SamplerDesc, ViewKind, WrapMode,
+    },
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+    texture::Texture,
+};
+
+use rendy::hal;
+use serde::Deserialize;
+
+use std::time::Instant;
+
+use crate::node::{
+    history::{create_history_texture, History, HistoryCopy},
+    pbr::Aux,
+};
+
+lazy_static::lazy_static! {
+    static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/fullscreen_triangle.vert"),
+        ShaderKind::Vertex,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref LUMINANCE: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/exposure_luminance.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref ADAPT: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/exposure_adapt.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref LUMINANCE_SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*LUMINANCE).unwrap();
+
+    static ref ADAPT_SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*ADAPT).unwrap();
+}
+
+/// Format of the log luminance image and the adapted luminance
+pub const LUMINANCE_FORMAT: hal::format::Format = hal::format::Format::R32Sfloat;
+/// Maximum width and height of the log luminance image
+pub const LUMINANCE_RES: u32 = 64;
+/// Time step used for the first frame, long enough to start out fully adapted
+const INITIAL_TIME_STEP: f32 = 1000.0;
+
+/// Eye adaptation settings, read from the scene file and adjustable at runtime
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct AutoExposure {
+    /// Whether the tonemapper uses the adapted exposure instead of the manual one
+    pub enabled: bool,
+    /// Rate at which the adapted luminance approaches the luminance of the current frame.
+    /// Higher is faster, 0 stops adaptation.
+    pub adaptation_speed: f32,
+    /// Lowest log2 luminance the view adapts to, limiting how much dark scenes are brightened
+    pub min_ev: f32,
+    /// Highest log2 luminance the view adapts to, limiting how much bright scenes are darkened
+    pub max_ev: f32,
+    /// Exposure adjustment in stops applied on top of the adapted exposure
+    pub compensation: f32,
+}
+
+impl Default for AutoExposure {
+    fn default() -> Self {
+        AutoExposure {
+            enabled: false,
+            adaptation_speed: 1.5,
+            min_ev: -8.0,
+            max_ev: 8.0,
+            compensation: 0.0,
+        }
+    }
+}
+
+impl std::fmt::Display for AutoExposure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "Enabled: {}, Speed: {}, EV range: {}..{}, Compensation: {}",
+            self.enabled, self.adaptation_speed, self.min_ev, self.max_ev, self.compensation
+        )
+    }
+}
+
+/// The adapted log luminance of the previous frame
+#[derive(Debug)]
+pub struct ExposureHistory<B: hal::Backend> {
+    pub texture: Texture<B>,
+}
+
+impl<B: hal::Backend> ExposureHistory<B> {
+    pub fn new(factory: &mut Factory<B>, queue: QueueId) -> Result<Self, failure::Error> {
+        let texture = create_history_texture(factory, queue, 1, 1, LUMINANCE_FORMAT)?;
+        Ok(ExposureHistory { texture })
+    }
+}
+
+impl<B: hal::Backend> History<B> for ExposureHistory<B> {
+    fn texture(&self) -> &Texture<B> {
+        &self.texture
+    }
+}
+
+/// The passes computing the adapted luminance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Averages the log luminance of blocks of the HDR image
+    Luminance,
+    /// Builds the histogram and moves the previous adapted luminance towards its average
+    Adapt,
+}
+
+#[derive(Debug)]
+pub struct PipelineDesc {
+    pub stage: Stage,
+}
+
+#[derive(Debug)]
+pub struct Pipeline<B: hal::Backend> {
+    stage: Stage,
+    descriptor_pool: B::DescriptorPool,
+    set: B::DescriptorSet,
+    image_sampler: Escape<Sampler<B>>,
+    image_view: Escape<ImageView<B>>,
+    last_frame: Option<Instant>,
+    time_step: f32,
+}
+
+impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PipelineDesc
+where
+    B: hal::Backend,
+{
+    type Pipeline = Pipeline<B>;
+
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        vec![hal::pso::ColorBlendDesc {
+            mask: hal::pso::ColorMask::RED,
+            blend: None,
+        }]
+    }
+
+    fn images(&self) -> Vec<ImageAccess> {
+        vec![ImageAccess {
+            access: hal::image::Access::SHADER_READ,
+            usage: hal::image::Usage::SAMPLED,
+            layout: hal::image::Layout::ShaderReadOnlyOptimal,
+            stages: hal::pso::PipelineStage::FRAGMENT_SHADER,
+        }]
+    }
+
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        None
+    }
+
+    fn load_shader_set(
+        &self,
+        factory: &mut Factory<B>,
+        _world: &specs::World,
+    ) -> rendy::shader::ShaderSet<B> {
+        match self.stage {
+            Stage::Luminance => LUMINANCE_SHADERS.build(factory, Default::default()),
+            Stage::Adapt => ADAPT_SHADERS.build(factory, Default::default()),
+        }
+        .unwrap()
+    }
+
+    fn layout(&self) -> Layout {
+        let mut bindings = vec![
+            hal::pso::DescriptorSetLayoutBinding {
+                binding: 0,
+                ty: hal::pso::DescriptorType::Sampler,
+                count: 1,
+                stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                immutable_samplers: false,
+            },
+            hal::pso::DescriptorSetLayoutBinding {
+                binding: 1,
+                ty: hal::pso::DescriptorType::SampledImage,
+                count: 1,
+                stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                immutable_samplers: false,
+            },
+        ];
+        match self.stage {
+            Stage::Luminance => Layout {
+                sets: vec![SetLayout { bindings }],
+                push_constants: Vec::new(),
+            },
+            Stage::Adapt => {
+                // The previous adapted luminance
+                bindings.push(hal::pso::DescriptorSetLayoutBinding {
+                    binding: 2,
+                    ty: hal::pso::DescriptorType::SampledImage,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                });
+                Layout {
+                    sets: vec![SetLayout { bindings }],
+                    // min and max EV, adaptation speed and time step
+                    push_constants: vec![(hal::pso::ShaderStageFlags::FRAGMENT, 0..16)],
+                }
+            }
+        }
+    }
+
+    fn build<'a>(
+        self,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        _queue: QueueId,
+        world: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Pipeline<B>, hal::pso::CreationError> {
+        assert!(buffers.is_empty());
+        assert_eq!(images.len(), 1);
+        assert_eq!(set_layouts.len(), 1);
+
+        let num_images = match self.stage {
+            Stage::Luminance => 1,
+            Stage::Adapt => 2,
+        };
+
+        let mut descriptor_pool = unsafe {
+            factory.create_descriptor_pool(
+                1,
+                vec![
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::Sampler,
+                        count: 1,
+                    },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::SampledImage,
+                        count: num_images,
+                    },
+                ],
+                hal::pso::DescriptorPoolCreateFlags::empty(),
+            )?
+        };
+
+        // Both passes read their inputs with texelFetch
+        let image_sampler = factory
+            .create_sampler(SamplerDesc::new(Filter::Nearest, WrapMode::Clamp))
+            .unwrap();
+
+        let image_handle = ctx
+            .get_image(images[0].id)
+            .expect("Exposure input image missing");
+        let format = match self.stage {
+            Stage::Luminance => hal::format::Format::Rgba32Sfloat,
+            Stage::Adapt => LUMINANCE_FORMAT,
+        };
+        let image_view = factory
+            .create_image_view(
+                image_handle.clone(),
+                ImageViewInfo {
+                    view_kind: ViewKind::D2,
+                    format,
+                    swizzle: hal::format::Swizzle::NO,
+                    range: images[0].range.clone(),
+                },
+            )
+            .expect("Could not create exposure input image view");
+
+        let set = unsafe {
+            let set = descriptor_pool.allocate_set(&set_layouts[0].raw()).unwrap();
+            factory.write_descriptor_sets(vec![
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 0,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Sampler(image_sampler.raw())),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 1,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        image_view.raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+            ]);
+            if self.stage == Stage::Adapt {
+                let history = world.read_resource::<ExposureHistory<B>>();
+                factory.write_descriptor_sets(vec![hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 2,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        history.texture.view().raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                }]);
+            }
+            set
+        };
+
+        Ok(Pipeline {
+            stage: self.stage,
+            descriptor_pool,
+            set,
+            image_sampler,
+            image_view,
+            last_frame: None,
+            time_step: INITIAL_TIME_STEP,
+        })
+    }
+}
+
+impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
+where
+    B: hal::Backend,
+{
+    type Desc = PipelineDesc;
+
+    fn prepare(
+        &mut self,
+        _factory: &Factory<B>,
+        _queue: QueueId,
+        _set_layouts: &[Handle<DescriptorSetLayout<B>>],
+        _index: usize,
+        _world: &specs::World,
+    ) -> PrepareResult {
+        match self.stage {
+            Stage::Luminance => PrepareResult::DrawReuse,
+            Stage::Adapt => {
+                let now = Instant::now();
+                if let Some(last_frame) = self.last_frame {
+                    let elapsed = now - last_frame;
+                    self.time_step =
+                        elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1_000_000_000.0;
+                }
+                self.last_frame = Some(now);
+                // The settings and time step are recorded as push constants
+                PrepareResult::DrawRecord
+            }
+        }
+    }
+
+    fn draw(
+        &mut self,
+        layout: &B::PipelineLayout,
+        mut encoder: RenderPassEncoder<'_, B>,
+        _index: usize,
+        world: &specs::World,
+    ) {
+        let auto_exposure = world.read_resource::<Aux>().auto_exposure;
+        unsafe {
+            encoder.bind_graphics_descriptor_sets(layout, 0, Some(&self.set), std::iter::empty());
+            if self.stage == Stage::Adapt {
+                encoder.push_constants(
+                    layout,
+                    hal::pso::ShaderStageFlags::FRAGMENT,
+                    0,
+                    &[
+                        auto_exposure.min_ev.to_bits(),
+                        auto_exposure.max_ev.to_bits(),
+                        auto_exposure.adaptation_speed.to_bits(),
+                        self.time_step.to_bits(),
+                    ],
+                );
+            }
+            encoder.draw(0..3, 0..1);
+        }
+    }
+
+    fn dispose(mut self, factory: &mut Factory<B>, _world: &specs::World) {
+        unsafe {
+            self.descriptor_pool.reset();
+            factory.destroy_descriptor_pool(self.descriptor_pool);
+        }
+    }
+}
+
+/// Adds the passes computing the adapted luminance of a `width` by `height` HDR image to the
+/// graph. Returns the 1x1 adapted luminance image and the node writing it. The
+/// `ExposureHistory` resource must exist when the graph is built.
+pub fn add_exposure_chain<B: hal::Backend>(
+    builder: &mut GraphBuilder<B, specs::World>,
+    hdr: ImageId,
+    hdr_pass: NodeId,
+    width: u32,
+    height: u32,
+) -> (ImageId, NodeId) {
+    let luminance = builder.create_image(
+        hal::image::Kind::D2(width.min(LUMINANCE_RES), height.min(LUMINANCE_RES), 1, 1),
+        1,
+        LUMINANCE_FORMAT,
+        None,
+    );
+    let adapted = builder.create_image(hal::image::Kind::D2(1, 1, 1, 1), 1, LUMINANCE_FORMAT, None);
+
+    let luminance_pass = builder.add_node(
+        PipelineDesc {
+            stage: Stage::Luminance,
+        }
+        .builder()
+        .with_image(hdr)
+        .into_subpass()
+        .with_dependency(hdr_pass)
+        .with_color(luminance)
+        .into_pass(),
+    );
+
+    let adapt_pass = builder.add_node(
+        PipelineDesc {
+            stage: Stage::Adapt,
+        }
+        .builder()
+        .with_image(luminance)
+        .into_subpass()
+        .with_dependency(luminance_pass)
+        .with_color(adapted)
+        .into_pass(),
+    );
+
+    builder.add_node(
+        HistoryCopy::<B>::builder::<ExposureHistory<B>>(adapted).with_dependency(adapt_pass),
+    );
+
+    (adapted, adapt_pass)
+}
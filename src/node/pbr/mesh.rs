@@ -1,13 +1,13 @@
 use rendy::{
     command::{DrawIndexedCommand, QueueId, RenderPassEncoder},
     factory::Factory,
-    graph::{render::*, GraphContext, NodeBuffer, NodeImage},
+    graph::{render::*, GraphContext, ImageAccess, NodeBuffer, NodeImage},
     hal::{device::Device, pso::DescriptorPool},
     memory::MemoryUsageValue,
-    mesh::{AsVertex, Model, PosNormTangTex},
+    mesh::{AsVertex, PosNormTangTex},
     resource::{
-        Buffer, BufferInfo, DescriptorSetLayout, Escape, Filter, Handle, Sampler, SamplerDesc,
-        WrapMode,
+        Buffer, BufferInfo, DescriptorSetLayout, Escape, Filter, Handle, ImageView, ImageViewInfo,
+        Sampler, SamplerDesc, ViewKind, WrapMode,
     },
     shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
 };
@@ -42,45 +42,203 @@ lazy_static::lazy_static! {
         .with_fragment(&*FRAGMENT).unwrap();
 }
 
+/// The largest `min_storage_buffer_offset_alignment` a device may have. Joint matrices and
+/// morph target weights are aligned to it rather than to the device's limit, which `Aux`
+/// doesn't carry.
+const STORAGE_ALIGN: u64 = 256;
+
+/// Per instance vertex data
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct InstanceData {
+    pub model: nalgebra::Matrix4<f32>,
+    /// Index of the first joint matrix of the instance's skin, or -1 if it isn't skinned
+    pub joint_offset: i32,
+    /// Index of the first morph target weight of the instance, or -1 if its mesh has no
+    /// morph targets
+    pub morph_weight_offset: i32,
+}
+
+impl InstanceData {
+    /// The vertex buffer layout, with the model matrix as four vec4s followed by the joint
+    /// and morph weight offsets as an ivec2, or with only the model matrix if `with_offsets`
+    /// is not set
+    pub fn vertex_input_desc(
+        with_offsets: bool,
+    ) -> (
+        Vec<hal::pso::Element<hal::format::Format>>,
+        hal::pso::ElemStride,
+        hal::pso::VertexInputRate,
+    ) {
+        let mut elements = (0..4)
+            .map(|column| hal::pso::Element {
+                format: hal::format::Format::Rgba32Sfloat,
+                offset: column * size_of::<[f32; 4]>() as u32,
+            })
+            .collect::<Vec<_>>();
+        if with_offsets {
+            elements.push(hal::pso::Element {
+                format: hal::format::Format::Rg32Sint,
+                offset: size_of::<nalgebra::Matrix4<f32>>() as u32,
+            });
+        }
+        (
+            elements,
+            size_of::<InstanceData>() as hal::pso::ElemStride,
+            hal::pso::VertexInputRate::Instance(1),
+        )
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C)]
 pub struct UniformArgs {
     camera: CameraArgs,
     num_lights: i32,
     lights: [super::LightData; crate::MAX_LIGHTS],
+    shadow_matrices: [nalgebra::Matrix4<f32>; crate::MAX_SHADOW_MAPS],
+    cascade_splits: [f32; 4],
+}
+
+/// The materials a mesh pipeline draws
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaterialPass {
+    /// Opaque and alpha masked materials, either the double sided ones or the ones whose back
+    /// faces the pipeline culls. Pipelines drawing primitives without faces draw both.
+    Opaque { double_sided: Option<bool> },
+    /// Alpha blended materials, drawn after the opaque ones from back to front without
+    /// writing depth or motion vectors
+    Transparent,
+}
+
+impl MaterialPass {
+    fn draws(&self, factors: &asset::MaterialFactors) -> bool {
+        match *self {
+            MaterialPass::Opaque { double_sided } => {
+                !factors.is_transparent()
+                    && double_sided.map_or(true, |double_sided| {
+                        factors.is_double_sided() == double_sided
+                    })
+            }
+            MaterialPass::Transparent => factors.is_transparent(),
+        }
+    }
 }
 
-#[derive(Debug, Default)]
-pub struct PipelineDesc;
+#[derive(Debug)]
+pub struct PipelineDesc {
+    /// The number of shadow map images passed to the pipeline builder
+    pub shadow_maps: usize,
+    pub pass: MaterialPass,
+    /// The primitives drawn, which must match the primitive type of the pipeline state
+    pub topology: asset::Topology,
+}
 
 #[derive(Debug)]
 pub struct Pipeline<B: hal::Backend> {
+    /// Whether this pipeline owns the `MeshPassResources`, which it then writes each frame
+    owner: bool,
+    pass: MaterialPass,
+    topology: asset::Topology,
+    /// The primitive instances of the transparent pass in the order they are drawn
+    transparent_draws: Vec<TransparentDraw>,
+    _pd: std::marker::PhantomData<B>,
+}
+
+/// The buffers and descriptor sets of the mesh pass, shared by the pipelines of all of its
+/// state groups. The first pipeline built owns them: it allocates them, reallocates them when
+/// the assets change and writes the data of each frame, which the others only draw.
+#[derive(Debug)]
+pub struct MeshPassResources<B: hal::Backend>(Option<SharedResources<B>>);
+
+impl<B: hal::Backend> Default for MeshPassResources<B> {
+    fn default() -> Self {
+        MeshPassResources(None)
+    }
+}
+
+impl<B: hal::Backend> MeshPassResources<B> {
+    /// The settings the current resources were allocated for
+    pub(super) fn settings(&self) -> Option<&Settings> {
+        self.0.as_ref().map(|shared| &shared.settings)
+    }
+}
+
+#[derive(Debug)]
+struct SharedResources<B: hal::Backend> {
+    resources: Resources<B>,
+    static_inputs: StaticInputs<B>,
+    /// The environment generation written to the static set of each frame
+    environment_generations: Vec<u64>,
+    /// Frames whose transforms and draw commands have to be written in full, because the
+    /// buffers were (re)allocated since that frame was last prepared
+    stale_frames: Vec<bool>,
+    settings: Settings,
+    /// Settings the buffers could not be reallocated for. Until the assets change again, the
+    /// frames recorded with the old buffers are reused.
+    failed_settings: Option<Settings>,
+}
+
+/// A single instance of a primitive with a blended material
+#[derive(Debug)]
+struct TransparentDraw {
+    /// View space depth of the instance's origin
+    depth: f32,
+    primitive: usize,
+    instance: systems::InstanceIndex,
+}
+
+/// Buffers and descriptor sets whose size depends on the contents of the asset storages.
+/// Recreated whenever a mesh, primitive or material is added.
+#[derive(Debug)]
+struct Resources<B: hal::Backend> {
     descriptor_pool: B::DescriptorPool,
     uniform_indirect_buffer: Escape<Buffer<B>>,
     transform_buffer: Escape<Buffer<B>>,
-    texture_sampler: Escape<Sampler<B>>,
-    static_set: B::DescriptorSet,
+    /// The instance transforms of the previous frame, for motion vectors
+    previous_transform_buffer: Escape<Buffer<B>>,
+    joint_buffer: Escape<Buffer<B>>,
+    morph_delta_buffer: Escape<Buffer<B>>,
+    morph_weight_buffer: Escape<Buffer<B>>,
+    /// The static set of each frame. Only the environment maps change, and a frame's set is
+    /// only rewritten once the frame is no longer in flight.
+    static_sets: Vec<B::DescriptorSet>,
     ubo_sets: Vec<B::DescriptorSet>,
     mat_sets: Vec<B::DescriptorSet>,
-    settings: Settings,
+}
+
+/// Samplers and views bound in the static set, which live as long as the pipeline
+#[derive(Debug)]
+struct StaticInputs<B: hal::Backend> {
+    texture_sampler: Escape<Sampler<B>>,
+    shadow_sampler: Escape<Sampler<B>>,
+    shadow_views: Vec<Escape<ImageView<B>>>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
-struct Settings {
+pub(super) struct Settings {
     align: u64,
+    num_materials: usize,
     num_primitives: usize,
     max_mesh_instances: Vec<u16>,
     total_max_mesh_instances: u64,
+    num_joints: usize,
+    num_morph_deltas: usize,
+    num_morph_weights: usize,
 }
 
 impl Settings {
     const UNIFORM_SIZE: u64 = size_of::<UniformArgs>() as u64;
 
-    fn from_world<B: hal::Backend>(world: &specs::World) -> Self {
+    pub(super) fn from_world<B: hal::Backend>(world: &specs::World) -> Self {
         let aux = world.read_resource::<Aux>();
 
         let mesh_storage = world.read_resource::<asset::MeshStorage>();
         let primitive_storage = world.read_resource::<asset::PrimitiveStorage<B>>();
+        let material_storage = world.read_resource::<asset::MaterialStorage<B>>();
+        let joint_matrices = world.read_resource::<systems::JointMatrices>();
+        let morph_target_storage = world.read_resource::<asset::MorphTargetStorage>();
+        let morph_weights = world.read_resource::<systems::InstanceMorphWeights>();
 
         let max_mesh_instances = mesh_storage
             .0
@@ -92,15 +250,53 @@ impl Settings {
 
         Settings {
             align: aux.align,
+            num_materials: material_storage.0.len(),
             num_primitives: primitive_storage.0.len(),
             max_mesh_instances,
             total_max_mesh_instances,
+            num_joints: joint_matrices.current.len(),
+            num_morph_deltas: morph_target_storage.0.len(),
+            num_morph_weights: morph_weights.current.len(),
         }
     }
 
     #[inline]
-    fn transform_size(&self) -> u64 {
-        size_of::<Model>() as u64 * self.total_max_mesh_instances
+    pub(super) fn transform_size(&self) -> u64 {
+        size_of::<InstanceData>() as u64 * self.total_max_mesh_instances
+    }
+
+    /// Size of the joint matrices of one frame, padded so that the matrices of the previous
+    /// frame can follow them
+    #[inline]
+    fn joints_size(&self) -> u64 {
+        storage_size(size_of::<nalgebra::Matrix4<f32>>() * self.num_joints)
+    }
+
+    /// Range of the joint matrices of frame `index` in the joint buffer, or of the matrices
+    /// of the frame before it if `previous` is set
+    #[inline]
+    pub(super) fn joints_range(&self, index: u64, previous: bool) -> std::ops::Range<u64> {
+        let start = self.joints_size() * (index * 2 + previous as u64);
+        start..start + self.joints_size()
+    }
+
+    /// Size of the morph target weights of one frame, padded like `joints_size`
+    #[inline]
+    fn morph_weights_size(&self) -> u64 {
+        storage_size(size_of::<f32>() * self.num_morph_weights)
+    }
+
+    /// Range of the morph target weights of frame `index` in the weight buffer, or of the
+    /// weights of the frame before it if `previous` is set
+    #[inline]
+    pub(super) fn morph_weights_range(&self, index: u64, previous: bool) -> std::ops::Range<u64> {
+        let start = self.morph_weights_size() * (index * 2 + previous as u64);
+        start..start + self.morph_weights_size()
+    }
+
+    #[inline]
+    fn morph_deltas_size(&self) -> u64 {
+        storage_size(size_of::<asset::MorphDelta>() * self.num_morph_deltas)
     }
 
     #[inline]
@@ -114,7 +310,7 @@ impl Settings {
     }
 
     #[inline]
-    fn transform_buffer_frame_size(&self) -> u64 {
+    pub(super) fn transform_buffer_frame_size(&self) -> u64 {
         ((self.transform_size() - 1) / self.align + 1) * self.align
     }
 
@@ -124,7 +320,7 @@ impl Settings {
     }
 
     #[inline]
-    fn transforms_offset(&self, index: u64) -> u64 {
+    pub(super) fn transforms_offset(&self, index: u64) -> u64 {
         self.transform_buffer_frame_size() * index as u64
     }
 
@@ -134,7 +330,7 @@ impl Settings {
     }
 
     #[inline]
-    fn mesh_transforms_index(&self, mesh_index: usize) -> usize {
+    pub(super) fn mesh_transforms_index(&self, mesh_index: usize) -> usize {
         self.max_mesh_instances[0..mesh_index]
             .iter()
             .map(|n| *n as usize)
@@ -152,17 +348,72 @@ impl Settings {
     }
 }
 
+/// Rounds `size` up to a multiple of `STORAGE_ALIGN`. Empty storage buffers are not allowed,
+/// so the result is never 0.
+#[inline]
+fn storage_size(size: usize) -> u64 {
+    let size = size.max(1) as u64;
+    ((size - 1) / STORAGE_ALIGN + 1) * STORAGE_ALIGN
+}
+
+/// The push constants of a primitive, locating its morph targets in the delta buffer
+pub(super) fn morph_push_constants(targets: &asset::MorphTargets) -> [u32; 3] {
+    [targets.first_delta, targets.count, targets.vertex_count]
+}
+
 impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PipelineDesc
 where
     B: hal::Backend,
 {
     type Pipeline = Pipeline<B>;
 
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        vec![
+            hal::pso::ColorBlendDesc {
+                mask: hal::pso::ColorMask::ALL,
+                blend: Some(hal::pso::BlendState::ALPHA),
+            },
+            // Motion vectors, which are those of the surfaces behind transparent ones
+            hal::pso::ColorBlendDesc {
+                mask: match self.pass {
+                    MaterialPass::Opaque { .. } => {
+                        hal::pso::ColorMask::RED | hal::pso::ColorMask::GREEN
+                    }
+                    MaterialPass::Transparent => hal::pso::ColorMask::empty(),
+                },
+                blend: None,
+            },
+        ]
+    }
+
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        Some(hal::pso::DepthStencilDesc {
+            depth: Some(hal::pso::DepthTest {
+                fun: hal::pso::Comparison::Less,
+                write: self.pass != MaterialPass::Transparent,
+            }),
+            depth_bounds: false,
+            stencil: None,
+        })
+    }
+
+    fn images(&self) -> Vec<ImageAccess> {
+        vec![
+            ImageAccess {
+                access: hal::image::Access::SHADER_READ,
+                usage: hal::image::Usage::SAMPLED,
+                layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                stages: hal::pso::PipelineStage::FRAGMENT_SHADER,
+            };
+            self.shadow_maps
+        ]
+    }
+
     fn layout(&self) -> Layout {
         // Layout to update only once at the beginning
         let static_layout = SetLayout {
             bindings: vec![
-                // Texture maps sampler
+                // Environment maps sampler
                 hal::pso::DescriptorSetLayoutBinding {
                     binding: 0,
                     ty: hal::pso::DescriptorType::Sampler,
@@ -194,17 +445,72 @@ where
                     stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
                     immutable_samplers: false,
                 },
+                // shadow map comparison sampler
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 4,
+                    ty: hal::pso::DescriptorType::Sampler,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                },
+                // shadow maps
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 5,
+                    ty: hal::pso::DescriptorType::SampledImage,
+                    count: crate::MAX_SHADOW_MAPS,
+                    stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                },
             ],
         };
         // Layout to update once per frame
         let ubo_layout = SetLayout {
-            bindings: vec![hal::pso::DescriptorSetLayoutBinding {
-                binding: 0,
-                ty: hal::pso::DescriptorType::UniformBuffer,
-                count: 1,
-                stage_flags: hal::pso::ShaderStageFlags::GRAPHICS,
-                immutable_samplers: false,
-            }],
+            bindings: vec![
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 0,
+                    ty: hal::pso::DescriptorType::UniformBuffer,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::GRAPHICS,
+                    immutable_samplers: false,
+                },
+                // joint matrices of this frame and the previous one
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 1,
+                    ty: hal::pso::DescriptorType::StorageBuffer,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::VERTEX,
+                    immutable_samplers: false,
+                },
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 2,
+                    ty: hal::pso::DescriptorType::StorageBuffer,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::VERTEX,
+                    immutable_samplers: false,
+                },
+                // morph target deltas, then the weights of this frame and the previous one
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 3,
+                    ty: hal::pso::DescriptorType::StorageBuffer,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::VERTEX,
+                    immutable_samplers: false,
+                },
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 4,
+                    ty: hal::pso::DescriptorType::StorageBuffer,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::VERTEX,
+                    immutable_samplers: false,
+                },
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 5,
+                    ty: hal::pso::DescriptorType::StorageBuffer,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::VERTEX,
+                    immutable_samplers: false,
+                },
+            ],
         };
         // SampledImage for each texture map, can reuse same sampler
         let mut bindings = Vec::with_capacity(4);
@@ -224,10 +530,21 @@ where
             stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
             immutable_samplers: false,
         });
+        // The sampler of each texture map, in the same order
+        for i in 6..11 {
+            bindings.push(hal::pso::DescriptorSetLayoutBinding {
+                binding: i,
+                ty: hal::pso::DescriptorType::Sampler,
+                count: 1,
+                stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                immutable_samplers: false,
+            });
+        }
         let material_layout = SetLayout { bindings };
         Layout {
             sets: vec![static_layout, ubo_layout, material_layout],
-            push_constants: Vec::new(),
+            // the morph targets of the primitive being drawn
+            push_constants: vec![(hal::pso::ShaderStageFlags::VERTEX, 0..12)],
         }
     }
 
@@ -240,7 +557,19 @@ where
     )> {
         vec![
             PosNormTangTex::vertex().gfx_vertex_input_desc(hal::pso::VertexInputRate::Vertex),
-            Model::vertex().gfx_vertex_input_desc(hal::pso::VertexInputRate::Instance(1)),
+            asset::SkinVertex::vertex_input_desc(),
+            InstanceData::vertex_input_desc(true),
+            // The previous frame's instances share the offsets of the current ones
+            InstanceData::vertex_input_desc(false),
+            // The second texture coordinate set
+            (
+                vec![hal::pso::Element {
+                    format: hal::format::Format::Rg32Sfloat,
+                    offset: 0,
+                }],
+                size_of::<[f32; 2]>() as hal::pso::ElemStride,
+                hal::pso::VertexInputRate::Vertex,
+            ),
         ]
     }
 
@@ -254,7 +583,7 @@ where
 
     fn build<'a>(
         self,
-        _ctx: &GraphContext<B>,
+        ctx: &GraphContext<B>,
         factory: &mut Factory<B>,
         _queue: QueueId,
         world: &specs::World,
@@ -263,11 +592,92 @@ where
         set_layouts: &[Handle<DescriptorSetLayout<B>>],
     ) -> Result<Pipeline<B>, hal::pso::CreationError> {
         assert!(buffers.is_empty());
-        assert!(images.is_empty());
+        assert!(!images.is_empty() && images.len() <= crate::MAX_SHADOW_MAPS);
         assert_eq!(set_layouts.len(), 3);
 
-        let aux = world.read_resource::<Aux>();
-        let frames = aux.frames;
+        // Every group of the pass has the same layout, so the sets allocated by the owner
+        // can be bound by all of them
+        let mut shared = world.write_resource::<MeshPassResources<B>>();
+        if shared.0.is_some() {
+            return Ok(Pipeline {
+                owner: false,
+                pass: self.pass,
+                topology: self.topology,
+                transparent_draws: Vec::new(),
+                _pd: std::marker::PhantomData,
+            });
+        }
+
+        let frames = world.read_resource::<Aux>().frames;
+        let env_storage = world.read_resource::<super::EnvironmentStorage<B>>();
+
+        let settings = Settings::from_world::<B>(world);
+
+        let texture_sampler = factory
+            .create_sampler(SamplerDesc::new(Filter::Linear, WrapMode::Clamp))
+            .unwrap();
+
+        let mut shadow_sampler_desc = SamplerDesc::new(Filter::Linear, WrapMode::Clamp);
+        shadow_sampler_desc.comparison = Some(hal::pso::Comparison::LessEqual);
+        let shadow_sampler = factory.create_sampler(shadow_sampler_desc).unwrap();
+
+        let shadow_views = images
+            .iter()
+            .map(|image| {
+                let image_handle = ctx.get_image(image.id).expect("Shadow map image missing");
+                factory
+                    .create_image_view(
+                        image_handle.clone(),
+                        ImageViewInfo {
+                            view_kind: ViewKind::D2,
+                            format: super::shadow::SHADOW_MAP_FORMAT,
+                            swizzle: hal::format::Swizzle::NO,
+                            range: image.range.clone(),
+                        },
+                    )
+                    .expect("Could not create shadow map image view")
+            })
+            .collect::<Vec<_>>();
+
+        let static_inputs = StaticInputs {
+            texture_sampler,
+            shadow_sampler,
+            shadow_views,
+        };
+
+        let resources = Resources::new(factory, world, &settings, &static_inputs, set_layouts)
+            .map_err(|e| {
+                log::error!("Failed to create mesh resources: {}", e);
+                hal::pso::CreationError::Other
+            })?;
+        shared.0 = Some(SharedResources {
+            resources,
+            static_inputs,
+            environment_generations: vec![env_storage.generation; frames],
+            stale_frames: vec![true; frames],
+            settings,
+            failed_settings: None,
+        });
+
+        Ok(Pipeline {
+            owner: true,
+            pass: self.pass,
+            topology: self.topology,
+            transparent_draws: Vec::new(),
+            _pd: std::marker::PhantomData,
+        })
+    }
+}
+
+impl<B: hal::Backend> Resources<B> {
+    fn new(
+        factory: &Factory<B>,
+        world: &specs::World,
+        settings: &Settings,
+        static_inputs: &StaticInputs<B>,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Self, failure::Error> {
+        let frames = world.read_resource::<Aux>().frames;
         let material_storage = world.read_resource::<asset::MaterialStorage<B>>();
         let env_storage = world.read_resource::<super::EnvironmentStorage<B>>();
 
@@ -275,27 +685,30 @@ where
         let num_env_maps = 3;
         let mut descriptor_pool = unsafe {
             factory.create_descriptor_pool(
-                // one per material, one per frame for ubo, and one for static set
-                frames + num_mats + 1,
+                // one per material, and one per frame for ubo and static set
+                frames * 2 + num_mats,
                 vec![
                     hal::pso::DescriptorRangeDesc {
                         ty: hal::pso::DescriptorType::UniformBuffer,
                         count: frames + num_mats,
                     },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::StorageBuffer,
+                        count: frames * 5,
+                    },
                     hal::pso::DescriptorRangeDesc {
                         ty: hal::pso::DescriptorType::Sampler,
-                        count: 1,
+                        count: (num_mats * 5) + frames * 2,
                     },
                     hal::pso::DescriptorRangeDesc {
                         ty: hal::pso::DescriptorType::SampledImage,
-                        count: (num_mats * 5) + (num_env_maps),
+                        count: (num_mats * 5) + (num_env_maps + crate::MAX_SHADOW_MAPS) * frames,
                     },
                 ],
                 hal::pso::DescriptorPoolCreateFlags::empty(),
-            )?
-        };
-
-        let settings = Settings::from_world::<B>(world);
+            )
+        }
+        .map_err(|e| failure::format_err!("Failed to create descriptor pool: {:?}", e))?;
 
         let uniform_indirect_buffer = factory
             .create_buffer(
@@ -305,84 +718,112 @@ where
                 },
                 MemoryUsageValue::Dynamic,
             )
-            .unwrap();
-        let transform_buffer = factory
-            .create_buffer(
-                BufferInfo {
-                    size: settings.transform_buffer_frame_size() * frames as u64,
-                    usage: hal::buffer::Usage::VERTEX,
-                },
-                MemoryUsageValue::Dynamic,
-            )
-            .unwrap();
+            .map_err(|e| failure::format_err!("Failed to create uniform buffer: {:?}", e))?;
+        let transform_buffer = create_transform_buffer(factory, settings, frames)?;
+        let previous_transform_buffer = create_transform_buffer(factory, settings, frames)?;
+        let joint_buffer = create_joint_buffer(factory, settings, frames)?;
+        let morph_delta_buffer = create_morph_delta_buffer(factory, settings, world)?;
+        let morph_weight_buffer = create_morph_weight_buffer(factory, settings, frames)?;
 
-        let texture_sampler = factory
-            .create_sampler(SamplerDesc::new(Filter::Linear, WrapMode::Clamp))
-            .unwrap();
-
-        let static_set = unsafe {
-            let set = descriptor_pool.allocate_set(&set_layouts[0].raw()).unwrap();
-            factory.write_descriptor_sets(vec![
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 0,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Sampler(texture_sampler.raw())),
-                },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 1,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Image(
-                        env_storage.spec_cube.as_ref().unwrap().view().raw(),
-                        hal::image::Layout::ShaderReadOnlyOptimal,
-                    )),
-                },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 2,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Image(
-                        env_storage.irradiance_cube.as_ref().unwrap().view().raw(),
-                        hal::image::Layout::ShaderReadOnlyOptimal,
-                    )),
-                },
-                hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 3,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Image(
-                        env_storage.spec_brdf_map.as_ref().unwrap().view().raw(),
-                        hal::image::Layout::ShaderReadOnlyOptimal,
-                    )),
-                },
-            ]);
-            set
-        };
+        let mut static_sets = Vec::with_capacity(frames);
+        for _ in 0..frames {
+            unsafe {
+                let set = allocate_set(&mut descriptor_pool, &set_layouts[0])?;
+                write_static_set(factory, &set, static_inputs, &env_storage);
+                static_sets.push(set);
+            }
+        }
 
         let mut ubo_sets = Vec::with_capacity(frames);
         for index in 0..frames {
             unsafe {
-                let set = descriptor_pool.allocate_set(&set_layouts[1].raw()).unwrap();
-                factory.write_descriptor_sets(vec![hal::pso::DescriptorSetWrite {
-                    set: &set,
-                    binding: 0,
-                    array_offset: 0,
-                    descriptors: Some(hal::pso::Descriptor::Buffer(
-                        uniform_indirect_buffer.raw(),
-                        Some(settings.uniform_offset(index as u64))
-                            ..Some(settings.uniform_offset(index as u64) + Settings::UNIFORM_SIZE),
-                    )),
-                }]);
+                let set = allocate_set(&mut descriptor_pool, &set_layouts[1])?;
+                let joints = settings.joints_range(index as u64, false);
+                let previous_joints = settings.joints_range(index as u64, true);
+                let weights = settings.morph_weights_range(index as u64, false);
+                let previous_weights = settings.morph_weights_range(index as u64, true);
+                factory.write_descriptor_sets(vec![
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 0,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(
+                            uniform_indirect_buffer.raw(),
+                            Some(settings.uniform_offset(index as u64))
+                                ..Some(
+                                    settings.uniform_offset(index as u64) + Settings::UNIFORM_SIZE,
+                                ),
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 1,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(
+                            joint_buffer.raw(),
+                            Some(joints.start)..Some(joints.end),
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 2,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(
+                            joint_buffer.raw(),
+                            Some(previous_joints.start)..Some(previous_joints.end),
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 3,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(
+                            morph_delta_buffer.raw(),
+                            None..None,
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 4,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(
+                            morph_weight_buffer.raw(),
+                            Some(weights.start)..Some(weights.end),
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 5,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(
+                            morph_weight_buffer.raw(),
+                            Some(previous_weights.start)..Some(previous_weights.end),
+                        )),
+                    },
+                ]);
                 ubo_sets.push(set);
             }
         }
 
         let mut mat_sets = Vec::new();
-
         for mat_data in material_storage.0.iter() {
             unsafe {
-                let set = descriptor_pool.allocate_set(&set_layouts[2].raw()).unwrap();
+                let set = allocate_set(&mut descriptor_pool, &set_layouts[2])?;
+                let textures = [
+                    &mat_data.albedo,
+                    &mat_data.normal,
+                    &mat_data.metallic_roughness,
+                    &mat_data.ao,
+                    &mat_data.emissive,
+                ];
+                factory.write_descriptor_sets(textures.iter().enumerate().map(|(i, texture)| {
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 6 + i as u32,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Sampler(texture.sampler().raw())),
+                    }
+                }));
                 factory.write_descriptor_sets(vec![
                     hal::pso::DescriptorSetWrite {
                         set: &set,
@@ -434,7 +875,7 @@ where
                         binding: 5,
                         array_offset: 0,
                         descriptors: Some(hal::pso::Descriptor::Buffer(
-                            mat_data.emissive_factor_buffer.raw(),
+                            mat_data.factors_buffer.raw(),
                             None..None,
                         )),
                     },
@@ -443,17 +884,419 @@ where
             }
         }
 
-        Ok(Pipeline {
+        Ok(Resources {
             descriptor_pool,
             uniform_indirect_buffer,
             transform_buffer,
-            texture_sampler,
-            static_set,
+            previous_transform_buffer,
+            joint_buffer,
+            morph_delta_buffer,
+            morph_weight_buffer,
+            static_sets,
             ubo_sets,
             mat_sets,
-            settings,
         })
     }
+
+    unsafe fn dispose(mut self, factory: &Factory<B>) {
+        self.descriptor_pool.reset();
+        factory.destroy_descriptor_pool(self.descriptor_pool);
+    }
+}
+
+/// Allocates a set with `layout` from `pool`
+pub(super) unsafe fn allocate_set<B: hal::Backend>(
+    pool: &mut B::DescriptorPool,
+    layout: &Handle<DescriptorSetLayout<B>>,
+) -> Result<B::DescriptorSet, failure::Error> {
+    pool.allocate_set(layout.raw())
+        .map_err(|e| failure::format_err!("Failed to allocate descriptor set: {:?}", e))
+}
+
+/// Writes the samplers, shadow maps and the environment maps of `env_storage` to the static set
+unsafe fn write_static_set<B: hal::Backend>(
+    factory: &Factory<B>,
+    set: &B::DescriptorSet,
+    static_inputs: &StaticInputs<B>,
+    env_storage: &super::EnvironmentStorage<B>,
+) {
+    // Every element of the shadow map array has to be valid, so the unused ones repeat the
+    // last shadow map
+    let shadow_views = &static_inputs.shadow_views;
+    let shadow_map_writes = (0..crate::MAX_SHADOW_MAPS).map(|i| hal::pso::DescriptorSetWrite {
+        set,
+        binding: 5,
+        array_offset: i,
+        descriptors: Some(hal::pso::Descriptor::Image(
+            shadow_views[i.min(shadow_views.len() - 1)].raw(),
+            hal::image::Layout::ShaderReadOnlyOptimal,
+        )),
+    });
+
+    factory.write_descriptor_sets(shadow_map_writes);
+    factory.write_descriptor_sets(vec![
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 0,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Sampler(
+                static_inputs.texture_sampler.raw(),
+            )),
+        },
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 4,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Sampler(
+                static_inputs.shadow_sampler.raw(),
+            )),
+        },
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 1,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Image(
+                env_storage.spec_cube.as_ref().unwrap().view().raw(),
+                hal::image::Layout::ShaderReadOnlyOptimal,
+            )),
+        },
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 2,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Image(
+                env_storage.irradiance_cube.as_ref().unwrap().view().raw(),
+                hal::image::Layout::ShaderReadOnlyOptimal,
+            )),
+        },
+        hal::pso::DescriptorSetWrite {
+            set,
+            binding: 3,
+            array_offset: 0,
+            descriptors: Some(hal::pso::Descriptor::Image(
+                env_storage.spec_brdf_map.as_ref().unwrap().view().raw(),
+                hal::image::Layout::ShaderReadOnlyOptimal,
+            )),
+        },
+    ]);
+}
+
+/// Creates a buffer holding the instance transforms of every mesh, for each frame in flight
+pub(super) fn create_transform_buffer<B: hal::Backend>(
+    factory: &Factory<B>,
+    settings: &Settings,
+    frames: usize,
+) -> Result<Escape<Buffer<B>>, failure::Error> {
+    factory
+        .create_buffer(
+            BufferInfo {
+                size: settings.transform_buffer_frame_size() * frames as u64,
+                usage: hal::buffer::Usage::VERTEX,
+            },
+            MemoryUsageValue::Dynamic,
+        )
+        .map_err(|e| failure::format_err!("Failed to create transform buffer: {:?}", e))
+}
+
+/// Creates a buffer holding the joint matrices of every skin for the current and the previous
+/// frame, for each frame in flight
+pub(super) fn create_joint_buffer<B: hal::Backend>(
+    factory: &Factory<B>,
+    settings: &Settings,
+    frames: usize,
+) -> Result<Escape<Buffer<B>>, failure::Error> {
+    factory
+        .create_buffer(
+            BufferInfo {
+                size: settings.joints_range(frames as u64, false).start,
+                usage: hal::buffer::Usage::STORAGE,
+            },
+            MemoryUsageValue::Dynamic,
+        )
+        .map_err(|e| failure::format_err!("Failed to create joint buffer: {:?}", e))
+}
+
+/// Writes the joint matrices of frame `index` to `buffer`, and those of the frame before it
+/// if `previous` is set
+pub(super) fn write_joints<B: hal::Backend>(
+    factory: &Factory<B>,
+    buffer: &mut Escape<Buffer<B>>,
+    settings: &Settings,
+    index: usize,
+    previous: bool,
+    world: &specs::World,
+) -> Result<(), failure::Error> {
+    let joint_matrices = world.read_resource::<systems::JointMatrices>();
+    if joint_matrices.current.is_empty() {
+        return Ok(());
+    }
+    let upload_error = |e| failure::format_err!("Failed to upload joint matrices: {:?}", e);
+    unsafe {
+        factory
+            .upload_visible_buffer(
+                buffer,
+                settings.joints_range(index as u64, false).start,
+                &joint_matrices.current,
+            )
+            .map_err(upload_error)?;
+        if previous {
+            factory
+                .upload_visible_buffer(
+                    buffer,
+                    settings.joints_range(index as u64, true).start,
+                    &joint_matrices.previous,
+                )
+                .map_err(upload_error)?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates a buffer holding the deltas of every morph target in `asset::MorphTargetStorage`
+pub(super) fn create_morph_delta_buffer<B: hal::Backend>(
+    factory: &Factory<B>,
+    settings: &Settings,
+    world: &specs::World,
+) -> Result<Escape<Buffer<B>>, failure::Error> {
+    let morph_target_storage = world.read_resource::<asset::MorphTargetStorage>();
+    let mut buffer = factory
+        .create_buffer(
+            BufferInfo {
+                size: settings.morph_deltas_size(),
+                usage: hal::buffer::Usage::STORAGE,
+            },
+            MemoryUsageValue::Dynamic,
+        )
+        .map_err(|e| failure::format_err!("Failed to create morph delta buffer: {:?}", e))?;
+    if !morph_target_storage.0.is_empty() {
+        unsafe {
+            factory
+                .upload_visible_buffer(&mut buffer, 0, &morph_target_storage.0)
+                .map_err(|e| failure::format_err!("Failed to upload morph deltas: {:?}", e))?;
+        }
+    }
+    Ok(buffer)
+}
+
+/// Creates a buffer holding the morph target weights of every mesh instance for the current
+/// and the previous frame, for each frame in flight
+pub(super) fn create_morph_weight_buffer<B: hal::Backend>(
+    factory: &Factory<B>,
+    settings: &Settings,
+    frames: usize,
+) -> Result<Escape<Buffer<B>>, failure::Error> {
+    factory
+        .create_buffer(
+            BufferInfo {
+                size: settings.morph_weights_range(frames as u64, false).start,
+                usage: hal::buffer::Usage::STORAGE,
+            },
+            MemoryUsageValue::Dynamic,
+        )
+        .map_err(|e| failure::format_err!("Failed to create morph weight buffer: {:?}", e))
+}
+
+/// Writes the morph target weights of frame `index` to `buffer`, and those of the frame
+/// before it if `previous` is set
+pub(super) fn write_morph_weights<B: hal::Backend>(
+    factory: &Factory<B>,
+    buffer: &mut Escape<Buffer<B>>,
+    settings: &Settings,
+    index: usize,
+    previous: bool,
+    world: &specs::World,
+) -> Result<(), failure::Error> {
+    let morph_weights = world.read_resource::<systems::InstanceMorphWeights>();
+    if morph_weights.current.is_empty() {
+        return Ok(());
+    }
+    let upload_error = |e| failure::format_err!("Failed to upload morph target weights: {:?}", e);
+    unsafe {
+        factory
+            .upload_visible_buffer(
+                buffer,
+                settings.morph_weights_range(index as u64, false).start,
+                &morph_weights.current,
+            )
+            .map_err(upload_error)?;
+        if previous {
+            factory
+                .upload_visible_buffer(
+                    buffer,
+                    settings.morph_weights_range(index as u64, true).start,
+                    &morph_weights.previous,
+                )
+                .map_err(upload_error)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the transforms of the mesh instances which changed since frame `index` was last
+/// prepared into `buffer`, or of all instances if `all` is set. If `previous_buffer` is given,
+/// the transforms of the previous frame are written to it as well.
+pub(super) fn write_transforms<B: hal::Backend>(
+    factory: &Factory<B>,
+    buffer: &mut Escape<Buffer<B>>,
+    previous_buffer: Option<&mut Escape<Buffer<B>>>,
+    settings: &Settings,
+    index: usize,
+    all: bool,
+    world: &specs::World,
+) {
+    use specs::prelude::*;
+
+    let instance_cache = world.read_resource::<systems::InstanceCache>();
+    let meshes = world.read_storage::<components::Mesh>();
+
+    let dirty_entities = if all {
+        meshes.mask().clone()
+    } else {
+        instance_cache.dirty_entities[index].clone()
+    };
+
+    let transforms = world.read_storage::<components::GlobalTransform>();
+    write_instance_matrices(
+        factory,
+        buffer,
+        settings,
+        index,
+        &dirty_entities,
+        world,
+        |entity| transforms.get(entity).map(|transform| transform.0),
+    );
+    if let Some(previous_buffer) = previous_buffer {
+        // Instances which just appeared don't have a previous transform yet, so they use
+        // their current one
+        let previous_transforms = world.read_storage::<components::PreviousGlobalTransform>();
+        write_instance_matrices(
+            factory,
+            previous_buffer,
+            settings,
+            index,
+            &dirty_entities,
+            world,
+            |entity| {
+                previous_transforms
+                    .get(entity)
+                    .map(|previous| previous.0)
+                    .or_else(|| transforms.get(entity).map(|transform| transform.0))
+            },
+        );
+    }
+}
+
+/// Writes the matrix returned by `matrix` for every entity in `dirty_entities` to the slot of
+/// its mesh instance in `buffer`
+fn write_instance_matrices<B: hal::Backend>(
+    factory: &Factory<B>,
+    buffer: &mut Escape<Buffer<B>>,
+    settings: &Settings,
+    index: usize,
+    dirty_entities: &specs::BitSet,
+    world: &specs::World,
+    matrix: impl Fn(specs::Entity) -> Option<nalgebra::Matrix4<f32>>,
+) {
+    use rendy::memory::Write;
+    use specs::{prelude::*, storage::UnprotectedStorage};
+
+    let mesh_instance_storage = world.read_resource::<systems::MeshInstanceStorage>();
+    let skins = world.read_storage::<components::Skin>();
+    let morph_weights = world.read_storage::<components::MorphWeights>();
+    let entities = world.entities();
+
+    let transforms_offset = settings.transforms_offset(index as u64);
+    let transforms_size = settings.transform_size();
+    let transforms_end = transforms_offset + transforms_size;
+
+    let mut transforms_mapped = buffer
+        .map(factory.device(), transforms_offset..transforms_end)
+        .unwrap();
+    let mut transforms_writer = unsafe {
+        transforms_mapped
+            .write(factory.device(), 0..transforms_size)
+            .unwrap()
+    };
+    let transforms_slice = unsafe { transforms_writer.slice() };
+
+    for (entity, _) in (&entities, dirty_entities).join() {
+        if let Some(matrix) = matrix(entity) {
+            let systems::MeshInstance { mesh, instance } =
+                unsafe { mesh_instance_storage.0.get(entity.id()) };
+            let idx = settings.instance_transform_index(*mesh, *instance);
+            transforms_slice[idx] = InstanceData {
+                model: matrix,
+                joint_offset: skins
+                    .get(entity)
+                    .map_or(-1, |skin| skin.joint_offset as i32),
+                morph_weight_offset: morph_weights
+                    .get(entity)
+                    .map_or(-1, |weights| weights.weight_offset as i32),
+            };
+        }
+    }
+}
+
+impl<B: hal::Backend> Pipeline<B> {
+    /// Collects the instances of primitives with blended materials into `transparent_draws`,
+    /// ordered from the farthest to the nearest
+    fn sort_transparent_draws(&mut self, world: &specs::World, view: &nalgebra::Matrix4<f32>) {
+        use specs::{prelude::*, storage::UnprotectedStorage};
+
+        let entities = world.entities();
+        let meshes = world.read_storage::<components::Mesh>();
+        let transforms = world.read_storage::<components::GlobalTransform>();
+        let mesh_instance_storage = world.read_resource::<systems::MeshInstanceStorage>();
+        let mesh_storage = world.read_resource::<asset::MeshStorage>();
+        let primitive_storage = world.read_resource::<asset::PrimitiveStorage<B>>();
+        let material_storage = world.read_resource::<asset::MaterialStorage<B>>();
+
+        self.transparent_draws.clear();
+        for (entity, _, transform) in (&entities, &meshes, &transforms).join() {
+            let systems::MeshInstance { mesh, instance } =
+                unsafe { mesh_instance_storage.0.get(entity.id()) };
+            let depth = (view * transform.0.column(3)).z;
+            for prim_index in mesh_storage.0[*mesh].primitives.iter() {
+                let primitive = &primitive_storage.0[*prim_index];
+                if primitive.topology == self.topology
+                    && material_storage.0[primitive.mat].factors.is_transparent()
+                {
+                    self.transparent_draws.push(TransparentDraw {
+                        depth,
+                        primitive: *prim_index,
+                        instance: *instance,
+                    });
+                }
+            }
+        }
+        // The view looks down -z, so the farthest instances have the lowest depth
+        self.transparent_draws.sort_by(|a, b| {
+            a.depth
+                .partial_cmp(&b.depth)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+    }
+}
+
+/// The camera arguments of the active camera
+fn active_camera_args(world: &specs::World) -> CameraArgs {
+    use specs::prelude::*;
+
+    let jitter = world.read_resource::<super::taa::Jitter>();
+    let transforms = world.read_storage::<components::GlobalTransform>();
+    let previous_transforms = world.read_storage::<components::PreviousGlobalTransform>();
+    let cameras = world.read_storage::<components::Camera>();
+    let active_cameras = world.read_storage::<components::ActiveCamera>();
+    (
+        &active_cameras,
+        &cameras,
+        &transforms,
+        previous_transforms.maybe(),
+    )
+        .join()
+        .map(|(_, cam, trans, previous)| CameraArgs::new(cam, trans, previous, jitter.offset))
+        .next()
+        .expect("No active camera!")
 }
 
 impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
@@ -466,66 +1309,149 @@ where
         &mut self,
         factory: &Factory<B>,
         _queue: QueueId,
-        _set_layouts: &[Handle<DescriptorSetLayout<B>>],
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
         index: usize,
         world: &specs::World,
     ) -> PrepareResult {
-        if self.settings != Settings::from_world::<B>(world) {
-            unimplemented!();
+        let settings = Settings::from_world::<B>(world);
+        let mut shared = world.write_resource::<MeshPassResources<B>>();
+        let shared = shared.0.as_mut().expect("Mesh pass resources missing");
+
+        if !self.owner {
+            // The owner is prepared first, so the resources are up to date unless they
+            // could not be reallocated
+            if shared.settings != settings {
+                return PrepareResult::DrawReuse;
+            }
+            if self.pass == MaterialPass::Transparent {
+                let camera_args = active_camera_args(world);
+                self.sort_transparent_draws(world, &camera_args.view);
+            }
+            return PrepareResult::DrawRecord;
+        }
+
+        if shared.settings != settings {
+            if shared.failed_settings.as_ref() == Some(&settings) {
+                return PrepareResult::DrawReuse;
+            }
+            // Assets were added since the buffers were allocated. Every frame in flight
+            // still references the old buffers and sets, so wait for them to finish before
+            // replacing them.
+            let resources = factory
+                .wait_idle()
+                .map_err(|e| failure::format_err!("Failed to wait for device idle: {:?}", e))
+                .and_then(|()| {
+                    Resources::new(
+                        factory,
+                        world,
+                        &settings,
+                        &shared.static_inputs,
+                        set_layouts,
+                    )
+                });
+            match resources {
+                Ok(resources) => {
+                    let old_resources = std::mem::replace(&mut shared.resources, resources);
+                    unsafe {
+                        old_resources.dispose(factory);
+                    }
+                    let generation = world
+                        .read_resource::<super::EnvironmentStorage<B>>()
+                        .generation;
+                    for frame_generation in shared.environment_generations.iter_mut() {
+                        *frame_generation = generation;
+                    }
+                    shared.settings = settings;
+                    shared.failed_settings = None;
+                    for stale in shared.stale_frames.iter_mut() {
+                        *stale = true;
+                    }
+                }
+                Err(e) => {
+                    // The old buffers are too small for the new assets, so nothing can be
+                    // written to them
+                    log::error!("Keeping the mesh pass resources of the old assets: {}", e);
+                    shared.failed_settings = Some(settings);
+                    return PrepareResult::DrawReuse;
+                }
+            }
+        }
+        let stale = std::mem::replace(&mut shared.stale_frames[index], false);
+
+        let env_storage = world.read_resource::<super::EnvironmentStorage<B>>();
+        if shared.environment_generations[index] != env_storage.generation {
+            // This frame's previous commands have completed, so its static set is not in use
+            unsafe {
+                write_static_set(
+                    factory,
+                    &shared.resources.static_sets[index],
+                    &shared.static_inputs,
+                    &env_storage,
+                );
+            }
+            shared.environment_generations[index] = env_storage.generation;
         }
 
         use rendy::memory::Write;
-        use specs::{prelude::*, storage::UnprotectedStorage};
+        use specs::prelude::*;
 
+        let entities = world.entities();
         let lights = world.read_storage::<components::Light>();
         let transforms = world.read_storage::<components::GlobalTransform>();
+        let shadow_maps = world.read_resource::<super::shadow::ShadowMaps>();
 
         let mut n_lights = 0;
         let mut lights_data = [Default::default(); crate::MAX_LIGHTS];
-        for (light, transform) in (&lights, &transforms).join() {
+        for (entity, light, transform) in (&entities, &lights, &transforms).join() {
             if n_lights >= crate::MAX_LIGHTS {
                 break;
             }
 
-            lights_data[n_lights] = super::LightData {
-                pos: nalgebra::Point3::from(transform.0.column(3).xyz()),
-                color: light.color,
-                intensity: light.intensity,
-                _pad: 0f32,
-            };
+            let mut light_data: super::LightData = (light, transform).into();
+            if let Some(slot) = shadow_maps.first_slot(entity) {
+                light_data.shadow_index = slot as i32;
+            }
+            lights_data[n_lights] = light_data;
 
             n_lights += 1;
         }
-        let cameras = world.read_storage::<components::Camera>();
-        let active_cameras = world.read_storage::<components::ActiveCamera>();
-        let camera_args: CameraArgs = (&active_cameras, &cameras, &transforms)
-            .join()
-            .map(|(_, cam, trans)| (cam, trans).into())
-            .next()
-            .expect("No active camera!");
+
+        let mut shadow_matrices = [nalgebra::Matrix4::identity(); crate::MAX_SHADOW_MAPS];
+        for (matrix, slot) in shadow_matrices.iter_mut().zip(shadow_maps.slots.iter()) {
+            *matrix = slot.view_proj;
+        }
+
+        let camera_args = active_camera_args(world);
         unsafe {
             factory
                 .upload_visible_buffer(
-                    &mut self.uniform_indirect_buffer,
-                    self.settings.uniform_offset(index as u64),
+                    &mut shared.resources.uniform_indirect_buffer,
+                    shared.settings.uniform_offset(index as u64),
                     &[UniformArgs {
                         camera: camera_args,
                         num_lights: n_lights as i32,
                         lights: lights_data,
+                        shadow_matrices,
+                        cascade_splits: shadow_maps.cascade_splits,
                     }],
                 )
                 .unwrap()
         };
 
+        if self.pass == MaterialPass::Transparent {
+            self.sort_transparent_draws(world, &camera_args.view);
+        }
+
         let instance_cache = world.read_resource::<systems::InstanceCache>();
         let mesh_storage = world.read_resource::<asset::MeshStorage>();
         let primitive_storage = world.read_resource::<asset::PrimitiveStorage<B>>();
 
-        let indirect_offset = self.settings.indirect_offset(index as u64);
-        let indirect_size = self.settings.indirect_size();
+        let indirect_offset = shared.settings.indirect_offset(index as u64);
+        let indirect_size = shared.settings.indirect_size();
         let indirect_end = indirect_offset + indirect_size;
         {
-            let mut indirects_mapped = self
+            let mut indirects_mapped = shared
+                .resources
                 .uniform_indirect_buffer
                 .map(factory.device(), indirect_offset..indirect_end)
                 .unwrap();
@@ -536,11 +1462,11 @@ where
             };
             let indirects_slice = unsafe { indirects_writer.slice() };
 
-            for dirty_mesh in instance_cache.dirty_mesh_indirects[index].iter() {
-                for prim_index in mesh_storage.0[*dirty_mesh].primitives.iter() {
+            let mut write_mesh_indirects = |mesh: asset::MeshHandle| {
+                for prim_index in mesh_storage.0[mesh].primitives.iter() {
                     let command = DrawIndexedCommand {
                         index_count: primitive_storage.0[*prim_index].mesh_data.len(),
-                        instance_count: instance_cache.mesh_instance_counts[*dirty_mesh],
+                        instance_count: instance_cache.mesh_instance_counts[mesh],
                         first_index: 0,
                         vertex_offset: 0,
                         first_instance: 0,
@@ -548,39 +1474,48 @@ where
 
                     indirects_slice[*prim_index] = command;
                 }
+            };
+
+            if stale {
+                (0..mesh_storage.0.len()).for_each(&mut write_mesh_indirects);
+            } else {
+                for dirty_mesh in instance_cache.dirty_mesh_indirects[index].iter() {
+                    write_mesh_indirects(*dirty_mesh);
+                }
             }
         }
 
-        let mesh_instance_storage = world.read_resource::<systems::MeshInstanceStorage>();
-        let entities = world.entities();
-
-        let transforms_offset = self.settings.transforms_offset(index as u64);
-        let transforms_size = self.settings.transform_size();
-        let transforms_end = transforms_offset + self.settings.transform_size();
-        {
-            let mut transforms_mapped = self
-                .transform_buffer
-                .map(factory.device(), transforms_offset..transforms_end)
-                .unwrap();
-            let mut transforms_writer = unsafe {
-                transforms_mapped
-                    .write(factory.device(), 0..transforms_size)
-                    .unwrap()
-            };
-            let transforms_slice = unsafe { transforms_writer.slice() };
-
-            for (entity, transform, _) in (
-                &entities,
-                &transforms,
-                &instance_cache.dirty_entities[index],
+        write_transforms(
+            factory,
+            &mut shared.resources.transform_buffer,
+            Some(&mut shared.resources.previous_transform_buffer),
+            &shared.settings,
+            index,
+            stale,
+            world,
+        );
+        // Skinned and morphed meshes keep the deformations of the frame's previous
+        // commands if they can't be uploaded
+        let deformations = write_joints(
+            factory,
+            &mut shared.resources.joint_buffer,
+            &shared.settings,
+            index,
+            true,
+            world,
+        )
+        .and_then(|()| {
+            write_morph_weights(
+                factory,
+                &mut shared.resources.morph_weight_buffer,
+                &shared.settings,
+                index,
+                true,
+                world,
             )
-                .join()
-            {
-                let systems::MeshInstance { mesh, instance } =
-                    unsafe { mesh_instance_storage.0.get(entity.id()) };
-                let idx = self.settings.instance_transform_index(*mesh, *instance);
-                transforms_slice[idx] = transform.0;
-            }
+        });
+        if let Err(e) = deformations {
+            log::error!("{}", e);
         }
 
         PrepareResult::DrawRecord
@@ -594,43 +1529,106 @@ where
         world: &specs::World,
     ) {
         let primitive_storage = world.read_resource::<asset::PrimitiveStorage<B>>();
+        let material_storage = world.read_resource::<asset::MaterialStorage<B>>();
+        let shared = world.read_resource::<MeshPassResources<B>>();
+        let shared = shared.0.as_ref().expect("Mesh pass resources missing");
         unsafe {
             encoder.bind_graphics_descriptor_sets(
                 layout,
                 0,
-                vec![&self.static_set, &self.ubo_sets[index]],
+                vec![
+                    &shared.resources.static_sets[index],
+                    &shared.resources.ubo_sets[index],
+                ],
                 std::iter::empty(),
             );
         }
-        let transforms_offset = self.settings.transforms_offset(index as u64);
-        let indirect_offset = self.settings.indirect_offset(index as u64);
-        for (mat_idx, set) in self.mat_sets.iter().enumerate() {
+        let transforms_offset = shared.settings.transforms_offset(index as u64);
+        let indirect_offset = shared.settings.indirect_offset(index as u64);
+
+        // Binds the vertex buffers and morph targets of a primitive, with the instance
+        // transforms starting at the first instance of its mesh
+        let bind_primitive = |primitive: &asset::Primitive<B>,
+                              encoder: &mut RenderPassEncoder<'_, B>| {
+            assert!(primitive
+                .mesh_data
+                .bind(0, &[PosNormTangTex::vertex()], encoder)
+                .is_ok());
+            let mesh_transforms_offset = transforms_offset
+                + shared.settings.mesh_transforms_index(primitive.mesh_handle) as u64
+                    * size_of::<InstanceData>() as u64;
+            unsafe {
+                encoder.push_constants(
+                    layout,
+                    hal::pso::ShaderStageFlags::VERTEX,
+                    0,
+                    &morph_push_constants(&primitive.morph_targets),
+                );
+                encoder.bind_vertex_buffers(
+                    1,
+                    vec![
+                        (primitive.skin_vertices.raw(), 0),
+                        (
+                            shared.resources.transform_buffer.raw(),
+                            mesh_transforms_offset,
+                        ),
+                        (
+                            shared.resources.previous_transform_buffer.raw(),
+                            mesh_transforms_offset,
+                        ),
+                        (primitive.tex_coords_1.raw(), 0),
+                    ],
+                );
+            }
+        };
+
+        if self.pass == MaterialPass::Transparent {
+            // Instances are drawn one at a time, as those of a mesh aren't sorted in the
+            // transform buffer
+            let mut bound_mat = None;
+            for draw in self.transparent_draws.iter() {
+                let primitive = &primitive_storage.0[draw.primitive];
+                if bound_mat != Some(primitive.mat) {
+                    unsafe {
+                        encoder.bind_graphics_descriptor_sets(
+                            layout,
+                            2,
+                            Some(&shared.resources.mat_sets[primitive.mat]),
+                            std::iter::empty(),
+                        );
+                    }
+                    bound_mat = Some(primitive.mat);
+                }
+                bind_primitive(primitive, &mut encoder);
+                let instance = draw.instance as u32;
+                unsafe {
+                    encoder.draw_indexed(0..primitive.mesh_data.len(), 0, instance..instance + 1);
+                }
+            }
+            return;
+        }
+
+        for (mat_idx, set) in shared.resources.mat_sets.iter().enumerate() {
+            if !self.pass.draws(&material_storage.0[mat_idx].factors) {
+                continue;
+            }
             unsafe {
                 encoder.bind_graphics_descriptor_sets(layout, 2, Some(set), std::iter::empty());
             }
-            for (prim_idx, primitive) in primitive_storage
-                .0
-                .iter()
-                .enumerate()
-                .filter(|(_, primitive)| primitive.mat == mat_idx)
+            for (prim_idx, primitive) in
+                primitive_storage
+                    .0
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, primitive)| {
+                        primitive.mat == mat_idx && primitive.topology == self.topology
+                    })
             {
-                assert!(primitive
-                    .mesh_data
-                    .bind(0, &[PosNormTangTex::vertex()], &mut encoder)
-                    .is_ok());
+                bind_primitive(primitive, &mut encoder);
                 unsafe {
-                    encoder.bind_vertex_buffers(
-                        1,
-                        std::iter::once((
-                            self.transform_buffer.raw(),
-                            transforms_offset
-                                + self.settings.mesh_transforms_index(primitive.mesh_handle) as u64
-                                    * size_of::<Model>() as u64,
-                        )),
-                    );
                     encoder.draw_indexed_indirect(
-                        self.uniform_indirect_buffer.raw(),
-                        indirect_offset + self.settings.primitive_indirect_offset(prim_idx),
+                        shared.resources.uniform_indirect_buffer.raw(),
+                        indirect_offset + shared.settings.primitive_indirect_offset(prim_idx),
                         1,
                         size_of::<DrawIndexedCommand>() as u32,
                     );
@@ -639,10 +1637,14 @@ where
         }
     }
 
-    fn dispose(mut self, factory: &mut Factory<B>, _world: &specs::World) {
-        unsafe {
-            self.descriptor_pool.reset();
-            factory.destroy_descriptor_pool(self.descriptor_pool);
+    fn dispose(self, factory: &mut Factory<B>, world: &specs::World) {
+        if self.owner {
+            let shared = world.write_resource::<MeshPassResources<B>>().0.take();
+            if let Some(shared) = shared {
+                unsafe {
+                    shared.resources.dispose(factory);
+                }
+            }
         }
     }
 }
@@ -2,8 +2,14 @@ use crate::components;
 use derivative::Derivative;
 use rendy::hal;
 
+pub mod bloom;
+pub mod color_grading;
 pub mod environment_map;
+pub mod exposure;
 pub mod mesh;
+pub mod msaa;
+pub mod shadow;
+pub mod taa;
 pub mod tonemap;
 
 #[derive(Debug, Clone, Copy)]
@@ -11,19 +17,38 @@ pub mod tonemap;
 pub struct CameraArgs {
     pub proj: nalgebra::Matrix4<f32>,
     pub view: nalgebra::Matrix4<f32>,
+    /// The view matrix of the previous frame, used to compute motion vectors
+    pub prev_view: nalgebra::Matrix4<f32>,
     pub camera_pos: nalgebra::Point3<f32>,
 }
 
-impl From<(&components::Camera, &components::GlobalTransform)> for CameraArgs {
-    fn from((cam, trans): (&components::Camera, &components::GlobalTransform)) -> Self {
+impl CameraArgs {
+    /// `jitter` offsets the projection by a sub-pixel amount in normalized device coordinates.
+    /// `previous` is the camera's transform in the last frame, if it existed then.
+    pub fn new(
+        cam: &components::Camera,
+        trans: &components::GlobalTransform,
+        previous: Option<&components::PreviousGlobalTransform>,
+        jitter: [f32; 2],
+    ) -> Self {
+        let jitter =
+            nalgebra::Matrix4::new_translation(&nalgebra::Vector3::new(jitter[0], jitter[1], 0.0));
         CameraArgs {
-            proj: cam.proj.to_homogeneous(),
+            proj: jitter * cam.proj.to_homogeneous(),
             view: trans.0.try_inverse().unwrap(),
+            prev_view: previous
+                .map_or(trans.0, |previous| previous.0)
+                .try_inverse()
+                .unwrap(),
             camera_pos: nalgebra::Point3::from(trans.0.column(3).xyz()),
         }
     }
 }
 
+pub const LIGHT_KIND_POINT: i32 = 0;
+pub const LIGHT_KIND_DIRECTIONAL: i32 = 1;
+pub const LIGHT_KIND_SPOT: i32 = 2;
+
 #[derive(Debug, Derivative, Clone, Copy)]
 #[derivative(Default)]
 #[repr(C)]
@@ -32,7 +57,41 @@ pub struct LightData {
     pub pos: nalgebra::Point3<f32>,
     pub intensity: f32,
     pub color: [f32; 3],
-    pub _pad: f32,
+    pub kind: i32,
+    #[derivative(Default(value = "nalgebra::Vector3::<f32>::zeros()"))]
+    pub direction: nalgebra::Vector3<f32>,
+    /// Cosines of the spot cone angles, unused for other kinds of lights
+    pub spot_cos_inner: f32,
+    pub spot_cos_outer: f32,
+    /// Index of the light's first shadow map, or -1 if it has none. Directional lights use
+    /// one consecutive map per cascade.
+    #[derivative(Default(value = "-1"))]
+    pub shadow_index: i32,
+    pub _pad: [f32; 2],
+}
+
+impl From<(&components::Light, &components::GlobalTransform)> for LightData {
+    fn from((light, trans): (&components::Light, &components::GlobalTransform)) -> Self {
+        let (kind, spot_cos_inner, spot_cos_outer) = match light.kind {
+            components::LightKind::Point => (LIGHT_KIND_POINT, 0.0, 0.0),
+            components::LightKind::Directional => (LIGHT_KIND_DIRECTIONAL, 0.0, 0.0),
+            components::LightKind::Spot {
+                inner_angle,
+                outer_angle,
+            } => (LIGHT_KIND_SPOT, inner_angle.cos(), outer_angle.cos()),
+        };
+        LightData {
+            pos: nalgebra::Point3::from(trans.0.column(3).xyz()),
+            intensity: light.intensity,
+            color: light.color,
+            kind,
+            direction: -trans.0.column(2).xyz().normalize(),
+            spot_cos_inner,
+            spot_cos_outer,
+            shadow_index: -1,
+            _pad: [0.0; 2],
+        }
+    }
 }
 
 #[derive(Derivative)]
@@ -42,13 +101,25 @@ pub struct EnvironmentStorage<B: hal::Backend> {
     pub irradiance_cube: Option<rendy::texture::Texture<B>>,
     pub spec_cube: Option<rendy::texture::Texture<B>>,
     pub spec_brdf_map: Option<rendy::texture::Texture<B>>,
+    /// Incremented whenever the environment is swapped out at runtime, so that
+    /// pipelines know to rebind it
+    pub generation: u64,
 }
 
 #[derive(Default)]
 pub struct Aux {
     pub frames: usize,
     pub align: u64,
+    pub spec_cubemap_mip_levels: u8,
     pub tonemapper_args: tonemap::TonemapperArgs,
+    pub auto_exposure: exposure::AutoExposure,
+    pub bloom_args: bloom::BloomArgs,
+    pub color_grading: color_grading::ColorGrading,
+    /// Whether the scene's color grading lookup table is applied
+    pub color_lut_enabled: bool,
+    pub taa_args: taa::TaaArgs,
+    /// Samples per pixel of the images rendered by the mesh pass, 1 if MSAA is disabled
+    pub msaa_samples: hal::image::NumSamples,
     pub cube_display: environment_map::CubeDisplay,
     pub cube_roughness: f32,
 }
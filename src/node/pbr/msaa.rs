@@ -0,0 +1,296 @@
+//! Multisample anti-aliasing. When enabled, the mesh pass renders into multisampled HDR,
+//! motion vector and depth images, and the resolve pass averages the samples into the
+//! single sampled images read by the rest of the graph.
+use rendy::{
+    command::{QueueId, RenderPassEncoder},
+    factory::Factory,
+    graph::{render::*, GraphContext, ImageAccess, NodeBuffer, NodeImage},
+    hal::{device::Device, pso::DescriptorPool},
+    resource::{
+        DescriptorSetLayout, Escape, Filter, Handle, ImageView, ImageViewInfo, Sampler,
+        SamplerDesc, ViewKind, WrapMode,
+    },
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+};
+
+use rendy::hal;
+
+use crate::node::pbr::{taa, Aux};
+
+lazy_static::lazy_static! {
+    static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/fullscreen_triangle.vert"),
+        ShaderKind::Vertex,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref FRAGMENT: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/msaa_resolve.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*FRAGMENT).unwrap();
+}
+
+/// Sample counts which may be requested in the scene file or on the command line
+pub const SAMPLE_COUNTS: [hal::image::NumSamples; 4] = [1, 2, 4, 8];
+
+/// Checks that `requested` is one of `SAMPLE_COUNTS`. If the device can't render or sample
+/// the HDR and depth images with that many samples, warns and falls back to the highest
+/// count below it which it can.
+pub fn supported_samples<B: hal::Backend>(
+    factory: &Factory<B>,
+    requested: hal::image::NumSamples,
+) -> Result<hal::image::NumSamples, failure::Error> {
+    if !SAMPLE_COUNTS.contains(&requested) {
+        failure::bail!(
+            "Invalid MSAA sample count {}, expected one of {:?}",
+            requested,
+            SAMPLE_COUNTS
+        );
+    }
+
+    // Each limit is a mask with a bit set for every supported sample count
+    let limits = hal::adapter::PhysicalDevice::limits(factory.physical());
+    let supported = limits.framebuffer_color_samples_count
+        & limits.framebuffer_depth_samples_count
+        & limits.sampled_image_color_samples_count;
+
+    let mut samples = requested;
+    while samples > 1 && supported & samples == 0 {
+        samples /= 2;
+    }
+    if samples != requested {
+        log::warn!(
+            "{}x MSAA is not supported by the device, using {}x instead",
+            requested,
+            samples
+        );
+    }
+    Ok(samples)
+}
+
+#[derive(Debug, Default)]
+pub struct PipelineDesc;
+
+#[derive(Debug)]
+pub struct Pipeline<B: hal::Backend> {
+    descriptor_pool: B::DescriptorPool,
+    set: B::DescriptorSet,
+    image_sampler: Escape<Sampler<B>>,
+    image_views: Vec<Escape<ImageView<B>>>,
+}
+
+impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PipelineDesc
+where
+    B: hal::Backend,
+{
+    type Pipeline = Pipeline<B>;
+
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        // The resolved HDR image and motion vectors
+        vec![
+            hal::pso::ColorBlendDesc {
+                mask: hal::pso::ColorMask::ALL,
+                blend: None,
+            },
+            hal::pso::ColorBlendDesc {
+                mask: hal::pso::ColorMask::RED | hal::pso::ColorMask::GREEN,
+                blend: None,
+            },
+        ]
+    }
+
+    fn images(&self) -> Vec<ImageAccess> {
+        // The multisampled HDR image and motion vectors
+        vec![
+            ImageAccess {
+                access: hal::image::Access::SHADER_READ,
+                usage: hal::image::Usage::SAMPLED,
+                layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                stages: hal::pso::PipelineStage::FRAGMENT_SHADER,
+            };
+            2
+        ]
+    }
+
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        None
+    }
+
+    fn load_shader_set(
+        &self,
+        factory: &mut Factory<B>,
+        _world: &specs::World,
+    ) -> rendy::shader::ShaderSet<B> {
+        SHADERS.build(factory, Default::default()).unwrap()
+    }
+
+    fn layout(&self) -> Layout {
+        let mut bindings = vec![hal::pso::DescriptorSetLayoutBinding {
+            binding: 0,
+            ty: hal::pso::DescriptorType::Sampler,
+            count: 1,
+            stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+            immutable_samplers: false,
+        }];
+        // HDR image and motion vectors
+        for i in 1..3 {
+            bindings.push(hal::pso::DescriptorSetLayoutBinding {
+                binding: i,
+                ty: hal::pso::DescriptorType::SampledImage,
+                count: 1,
+                stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                immutable_samplers: false,
+            });
+        }
+        Layout {
+            sets: vec![SetLayout { bindings }],
+            // sample count
+            push_constants: vec![(hal::pso::ShaderStageFlags::FRAGMENT, 0..4)],
+        }
+    }
+
+    fn build<'a>(
+        self,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        _queue: QueueId,
+        _world: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Pipeline<B>, hal::pso::CreationError> {
+        assert!(buffers.is_empty());
+        assert_eq!(images.len(), 2);
+        assert_eq!(set_layouts.len(), 1);
+
+        let mut descriptor_pool = unsafe {
+            factory.create_descriptor_pool(
+                1,
+                vec![
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::Sampler,
+                        count: 1,
+                    },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::SampledImage,
+                        count: 2,
+                    },
+                ],
+                hal::pso::DescriptorPoolCreateFlags::empty(),
+            )?
+        };
+
+        // Samples are read with texelFetch, the sampler only completes the combined
+        // image sampler in the shader
+        let image_sampler = factory
+            .create_sampler(SamplerDesc::new(Filter::Nearest, WrapMode::Clamp))
+            .unwrap();
+
+        let image_views = images
+            .iter()
+            .zip([taa::RESOLVE_FORMAT, taa::VELOCITY_FORMAT].iter())
+            .map(|(image, format)| {
+                let image_handle = ctx
+                    .get_image(image.id)
+                    .expect("MSAA resolve input image missing");
+                factory
+                    .create_image_view(
+                        image_handle.clone(),
+                        ImageViewInfo {
+                            view_kind: ViewKind::D2,
+                            format: *format,
+                            swizzle: hal::format::Swizzle::NO,
+                            range: image.range.clone(),
+                        },
+                    )
+                    .expect("Could not create MSAA resolve input image view")
+            })
+            .collect::<Vec<_>>();
+
+        let set = unsafe {
+            let set = descriptor_pool.allocate_set(&set_layouts[0].raw()).unwrap();
+            factory.write_descriptor_sets(vec![
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 0,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Sampler(image_sampler.raw())),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 1,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        image_views[0].raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 2,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        image_views[1].raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+            ]);
+            set
+        };
+
+        Ok(Pipeline {
+            descriptor_pool,
+            set,
+            image_sampler,
+            image_views,
+        })
+    }
+}
+
+impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
+where
+    B: hal::Backend,
+{
+    type Desc = PipelineDesc;
+
+    fn prepare(
+        &mut self,
+        _factory: &Factory<B>,
+        _queue: QueueId,
+        _set_layouts: &[Handle<DescriptorSetLayout<B>>],
+        _index: usize,
+        _world: &specs::World,
+    ) -> PrepareResult {
+        // The sample count is recorded as a push constant
+        PrepareResult::DrawRecord
+    }
+
+    fn draw(
+        &mut self,
+        layout: &B::PipelineLayout,
+        mut encoder: RenderPassEncoder<'_, B>,
+        _index: usize,
+        world: &specs::World,
+    ) {
+        let samples = world.read_resource::<Aux>().msaa_samples as u32;
+        unsafe {
+            encoder.bind_graphics_descriptor_sets(layout, 0, Some(&self.set), std::iter::empty());
+            encoder.push_constants(layout, hal::pso::ShaderStageFlags::FRAGMENT, 0, &[samples]);
+            encoder.draw(0..3, 0..1);
+        }
+    }
+
+    fn dispose(mut self, factory: &mut Factory<B>, _world: &specs::World) {
+        unsafe {
+            self.descriptor_pool.reset();
+            factory.destroy_descriptor_pool(self.descriptor_pool);
+        }
+    }
+}
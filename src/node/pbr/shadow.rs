@@ -0,0 +1,815 @@
+//! Depth-only pass rendering the scene's meshes from a shadow casting light. One node is
+//! added to the graph per shadow map, see `ShadowMaps`.
+use rendy::{
+    command::{QueueId, RenderPassEncoder},
+    factory::Factory,
+    graph::{render::*, GraphContext, NodeBuffer, NodeImage},
+    hal::pso::DescriptorPool,
+    mesh::{AsVertex, PosNormTangTex},
+    resource::{Buffer, DescriptorSetLayout, Escape, Handle},
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+};
+
+use nalgebra::{Matrix4, Orthographic3, Perspective3, Point3, Vector3};
+use specs::prelude::*;
+
+use std::mem::size_of;
+
+use rendy::hal;
+
+use crate::{
+    asset, components,
+    node::pbr::{mesh, Aux},
+    systems,
+};
+
+lazy_static::lazy_static! {
+    static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/shadow.vert"),
+        ShaderKind::Vertex,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref FRAGMENT: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/shadow.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*FRAGMENT).unwrap();
+}
+
+pub const SHADOW_MAP_FORMAT: hal::format::Format = hal::format::Format::D32Sfloat;
+
+/// Must match `NUM_CASCADES` in `pbr.frag`, and be at most 4
+pub const NUM_CASCADES: usize = 3;
+/// Directional light shadows are only rendered up to this distance from the camera
+const MAX_SHADOW_DISTANCE: f32 = 40.0;
+/// Blend between logarithmic (1.0) and uniform (0.0) cascade splits
+const CASCADE_SPLIT_LAMBDA: f32 = 0.75;
+/// How far behind a cascade's bounds meshes still cast shadows into it
+const SHADOW_CASTER_MARGIN: f32 = 20.0;
+const SPOT_SHADOW_ZNEAR: f32 = 0.1;
+const SPOT_SHADOW_ZFAR: f32 = 100.0;
+
+/// One shadow map, rendered from `light`
+#[derive(Debug, Clone)]
+pub struct ShadowSlot {
+    pub light: Entity,
+    /// The cascade this map covers, always 0 for spot lights
+    pub cascade: usize,
+    pub resolution: u32,
+    /// Transforms world space to the light's clip space, with depth in `0..1`
+    pub view_proj: Matrix4<f32>,
+}
+
+/// The shadow maps of every shadow casting light. The slots are assigned once, before the
+/// render graph is built, and only their matrices are updated afterwards. Shadow casting
+/// lights which don't get a slot, including any added later, are rendered without shadows.
+#[derive(Debug, Default)]
+pub struct ShadowMaps {
+    pub slots: Vec<ShadowSlot>,
+    /// View space depth at which each directional light cascade ends
+    pub cascade_splits: [f32; 4],
+    /// Shadow casting lights without a slot which have already been warned about
+    unassigned: Vec<Entity>,
+}
+
+impl ShadowMaps {
+    /// Assigns shadow maps to the shadow casting lights in `world`, up to `MAX_SHADOW_MAPS`
+    pub fn allocate(world: &World) -> Self {
+        let entities = world.entities();
+        let lights = world.read_storage::<components::Light>();
+
+        let mut slots = Vec::new();
+        let mut unassigned = Vec::new();
+        for (entity, light) in (&entities, &lights).join() {
+            if !light.casts_shadows {
+                continue;
+            }
+            let cascades = match light.kind {
+                components::LightKind::Directional => NUM_CASCADES,
+                components::LightKind::Spot { .. } => 1,
+                components::LightKind::Point => {
+                    log::warn!("Point lights cannot cast shadows, ignoring casts_shadows");
+                    continue;
+                }
+            };
+            if slots.len() + cascades > crate::MAX_SHADOW_MAPS {
+                log::warn!(
+                    "Out of shadow maps for light {:?}, which needs {} of the {} left, \
+                     rendering it without shadows",
+                    entity,
+                    cascades,
+                    crate::MAX_SHADOW_MAPS - slots.len()
+                );
+                unassigned.push(entity);
+                continue;
+            }
+            for cascade in 0..cascades {
+                slots.push(ShadowSlot {
+                    light: entity,
+                    cascade,
+                    resolution: light.shadow_resolution,
+                    view_proj: Matrix4::identity(),
+                });
+            }
+        }
+
+        ShadowMaps {
+            slots,
+            cascade_splits: [0.0; 4],
+            unassigned,
+        }
+    }
+
+    /// Warns once about each shadow casting light which has no shadow map, such as lights
+    /// added or made to cast shadows after the slots were assigned
+    pub fn warn_unassigned(
+        &mut self,
+        entities: &Entities<'_>,
+        lights: &ReadStorage<'_, components::Light>,
+    ) {
+        for (entity, light) in (entities, lights).join() {
+            if !light.casts_shadows
+                || light.kind == components::LightKind::Point
+                || self.first_slot(entity).is_some()
+                || self.unassigned.contains(&entity)
+            {
+                continue;
+            }
+            log::warn!(
+                "Light {:?} casts shadows but has no shadow map, shadow maps are only assigned \
+                 when the scene is loaded",
+                entity
+            );
+            self.unassigned.push(entity);
+        }
+    }
+
+    /// The index of the first shadow map of `light`
+    pub fn first_slot(&self, light: Entity) -> Option<usize> {
+        self.slots.iter().position(|slot| slot.light == light)
+    }
+
+    /// Recomputes the light matrices, fitting the directional light cascades to the camera
+    pub fn update(
+        &mut self,
+        lights: &ReadStorage<'_, components::Light>,
+        transforms: &ReadStorage<'_, components::GlobalTransform>,
+        camera: &components::Camera,
+        camera_transform: &components::GlobalTransform,
+    ) {
+        // Orthographic cameras may put their near plane at 0, which the logarithmic splits
+        // can't start from
+        let znear = camera.proj.znear().max(0.01);
+        let zfar = camera.proj.zfar().min(MAX_SHADOW_DISTANCE);
+        self.cascade_splits = cascade_splits(znear, zfar);
+
+        for slot in self.slots.iter_mut() {
+            let (light, transform) = match (lights.get(slot.light), transforms.get(slot.light)) {
+                (Some(light), Some(transform)) => (light, transform),
+                _ => continue,
+            };
+            let direction = -transform.0.column(2).xyz().normalize();
+            slot.view_proj = match light.kind {
+                components::LightKind::Directional => {
+                    let near = if slot.cascade == 0 {
+                        znear
+                    } else {
+                        self.cascade_splits[slot.cascade - 1]
+                    };
+                    let far = self.cascade_splits[slot.cascade];
+                    directional_view_proj(
+                        &direction,
+                        camera,
+                        camera_transform,
+                        near..far,
+                        slot.resolution,
+                    )
+                }
+                components::LightKind::Spot { outer_angle, .. } => {
+                    let position = Point3::from(transform.0.column(3).xyz());
+                    let view =
+                        Matrix4::look_at_rh(&position, &(position + direction), &up(&direction));
+                    let fov = (2.0 * outer_angle).min(std::f32::consts::PI - 0.01);
+                    let proj = Perspective3::new(1.0, fov, SPOT_SHADOW_ZNEAR, SPOT_SHADOW_ZFAR);
+                    depth_zero_to_one() * proj.to_homogeneous() * view
+                }
+                components::LightKind::Point => continue,
+            };
+        }
+    }
+}
+
+/// Practical split scheme, blending logarithmic and uniform splits of `znear..zfar`
+fn cascade_splits(znear: f32, zfar: f32) -> [f32; 4] {
+    let mut splits = [zfar; 4];
+    for (i, split) in splits.iter_mut().enumerate().take(NUM_CASCADES) {
+        let t = (i + 1) as f32 / NUM_CASCADES as f32;
+        let logarithmic = znear * (zfar / znear).powf(t);
+        let uniform = znear + (zfar - znear) * t;
+        *split = CASCADE_SPLIT_LAMBDA * logarithmic + (1.0 - CASCADE_SPLIT_LAMBDA) * uniform;
+    }
+    splits
+}
+
+fn directional_view_proj(
+    direction: &Vector3<f32>,
+    camera: &components::Camera,
+    camera_transform: &components::GlobalTransform,
+    depths: std::ops::Range<f32>,
+    resolution: u32,
+) -> Matrix4<f32> {
+    // Fit a sphere around the part of the view frustum covered by the cascade, so that the
+    // size of the projection doesn't change as the camera rotates
+    let mut corners = Vec::with_capacity(8);
+    for &depth in [depths.start, depths.end].iter() {
+        for view_corner in camera.proj.frustum_corners(depth).iter() {
+            let world_corner = camera_transform.0 * view_corner.to_homogeneous();
+            corners.push(Point3::from_homogeneous(world_corner).unwrap());
+        }
+    }
+    let center = Point3::from(
+        corners
+            .iter()
+            .fold(Vector3::zeros(), |sum, corner| sum + corner.coords)
+            / corners.len() as f32,
+    );
+    let radius = corners
+        .iter()
+        .map(|corner| (corner - center).norm())
+        .fold(0.0, f32::max);
+    let radius = (radius * 16.0).ceil() / 16.0;
+
+    let eye = center - direction * (radius + SHADOW_CASTER_MARGIN);
+    let view = Matrix4::look_at_rh(&eye, &center, &up(direction));
+
+    // Move the projection in whole texels, otherwise shadow edges shimmer as the camera moves
+    let texel = 2.0 * radius / resolution as f32;
+    let origin = view * Point3::<f32>::origin().to_homogeneous();
+    let snap = Vector3::new(
+        (origin.x / texel).round() * texel - origin.x,
+        (origin.y / texel).round() * texel - origin.y,
+        0.0,
+    );
+    let view = Matrix4::new_translation(&snap) * view;
+
+    let proj = Orthographic3::new(
+        -radius,
+        radius,
+        -radius,
+        radius,
+        0.0,
+        2.0 * radius + SHADOW_CASTER_MARGIN,
+    );
+    depth_zero_to_one() * proj.to_homogeneous() * view
+}
+
+/// An up vector for a view looking along `direction`
+fn up(direction: &Vector3<f32>) -> Vector3<f32> {
+    if direction.y.abs() > 0.99 {
+        Vector3::z()
+    } else {
+        Vector3::y()
+    }
+}
+
+/// Maps the `-1..1` depth range of nalgebra's projections to the `0..1` range of the
+/// depth buffer
+fn depth_zero_to_one() -> Matrix4<f32> {
+    Matrix4::new(
+        1.0, 0.0, 0.0, 0.0, //
+        0.0, 1.0, 0.0, 0.0, //
+        0.0, 0.0, 0.5, 0.5, //
+        0.0, 0.0, 0.0, 1.0,
+    )
+}
+
+#[derive(Debug)]
+pub struct PipelineDesc {
+    /// The shadow map rendered by this node. `None` only clears the image, which is used
+    /// when there are no shadow casting lights so the mesh pipeline has an image to bind.
+    pub slot: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct Pipeline<B: hal::Backend> {
+    slot: Option<usize>,
+    /// Whether this pipeline owns the `ShadowPassResources`, which it then writes each frame
+    owner: bool,
+    _pd: std::marker::PhantomData<B>,
+}
+
+/// The buffers and descriptor sets of the shadow passes, shared by the nodes of every shadow
+/// map. Like `mesh::MeshPassResources`, they are owned by the first pipeline built, which
+/// reallocates them when the assets change and writes the data of each frame.
+#[derive(Debug)]
+pub struct ShadowPassResources<B: hal::Backend>(Option<SharedResources<B>>);
+
+impl<B: hal::Backend> Default for ShadowPassResources<B> {
+    fn default() -> Self {
+        ShadowPassResources(None)
+    }
+}
+
+#[derive(Debug)]
+struct SharedResources<B: hal::Backend> {
+    resources: Resources<B>,
+    /// Frames whose transforms have to be written in full, because the buffers were
+    /// (re)allocated since that frame was last prepared
+    stale_frames: Vec<bool>,
+    settings: mesh::Settings,
+    /// Settings the buffers could not be reallocated for
+    failed_settings: Option<mesh::Settings>,
+}
+
+#[derive(Debug)]
+struct Resources<B: hal::Backend> {
+    transform_buffer: Escape<Buffer<B>>,
+    joint_buffer: Escape<Buffer<B>>,
+    morph_delta_buffer: Escape<Buffer<B>>,
+    morph_weight_buffer: Escape<Buffer<B>>,
+    descriptor_pool: B::DescriptorPool,
+    /// One per frame in flight, holding the joint matrices and morph target weights of the
+    /// frame
+    deformation_sets: Vec<B::DescriptorSet>,
+    /// One per material, holding what the alpha test of masked materials reads
+    mat_sets: Vec<B::DescriptorSet>,
+}
+
+impl<B: hal::Backend> Resources<B> {
+    fn new(
+        factory: &Factory<B>,
+        world: &specs::World,
+        settings: &mesh::Settings,
+        frames: usize,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Self, failure::Error> {
+        let transform_buffer = mesh::create_transform_buffer(factory, settings, frames)?;
+        let joint_buffer = mesh::create_joint_buffer(factory, settings, frames)?;
+        let morph_delta_buffer = mesh::create_morph_delta_buffer(factory, settings, world)?;
+        let morph_weight_buffer = mesh::create_morph_weight_buffer(factory, settings, frames)?;
+
+        let material_storage = world.read_resource::<asset::MaterialStorage<B>>();
+        let num_mats = material_storage.0.len();
+        let mut descriptor_pool = unsafe {
+            factory.create_descriptor_pool(
+                // one per frame for the deformations, and one per material
+                frames + num_mats,
+                vec![
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::StorageBuffer,
+                        count: frames * 3,
+                    },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::SampledImage,
+                        count: num_mats,
+                    },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::Sampler,
+                        count: num_mats,
+                    },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::UniformBuffer,
+                        count: num_mats,
+                    },
+                ]
+                .into_iter()
+                .filter(|range| range.count > 0),
+                hal::pso::DescriptorPoolCreateFlags::empty(),
+            )
+        }
+        .map_err(|e| failure::format_err!("Failed to create descriptor pool: {:?}", e))?;
+        let deformation_sets = (0..frames)
+            .map(|_| unsafe { mesh::allocate_set(&mut descriptor_pool, &set_layouts[0]) })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut mat_sets = Vec::with_capacity(num_mats);
+        for mat_data in material_storage.0.iter() {
+            unsafe {
+                let set = mesh::allocate_set(&mut descriptor_pool, &set_layouts[1])?;
+                factory.write_descriptor_sets(vec![
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 0,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Image(
+                            mat_data.albedo.view().raw(),
+                            hal::image::Layout::ShaderReadOnlyOptimal,
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 1,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Sampler(
+                            mat_data.albedo.sampler().raw(),
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 2,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(
+                            mat_data.factors_buffer.raw(),
+                            None..None,
+                        )),
+                    },
+                ]);
+                mat_sets.push(set);
+            }
+        }
+
+        let resources = Resources {
+            transform_buffer,
+            joint_buffer,
+            morph_delta_buffer,
+            morph_weight_buffer,
+            descriptor_pool,
+            deformation_sets,
+            mat_sets,
+        };
+        resources.write_deformation_sets(factory, settings);
+        Ok(resources)
+    }
+
+    /// Points the set of each frame in flight at the joint matrices and morph target weights
+    /// of that frame, and at the morph target deltas
+    fn write_deformation_sets(&self, factory: &Factory<B>, settings: &mesh::Settings) {
+        for (index, set) in self.deformation_sets.iter().enumerate() {
+            let joints = settings.joints_range(index as u64, false);
+            let weights = settings.morph_weights_range(index as u64, false);
+            unsafe {
+                factory.write_descriptor_sets(vec![
+                    hal::pso::DescriptorSetWrite {
+                        set,
+                        binding: 0,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(
+                            self.joint_buffer.raw(),
+                            Some(joints.start)..Some(joints.end),
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set,
+                        binding: 1,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(
+                            self.morph_delta_buffer.raw(),
+                            None..None,
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set,
+                        binding: 2,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Buffer(
+                            self.morph_weight_buffer.raw(),
+                            Some(weights.start)..Some(weights.end),
+                        )),
+                    },
+                ]);
+            }
+        }
+    }
+
+    unsafe fn dispose(mut self, factory: &Factory<B>) {
+        self.descriptor_pool.reset();
+        factory.destroy_descriptor_pool(self.descriptor_pool);
+    }
+}
+
+impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PipelineDesc
+where
+    B: hal::Backend,
+{
+    type Pipeline = Pipeline<B>;
+
+    fn colors(&self) -> Vec<hal::pso::ColorBlendDesc> {
+        Vec::new()
+    }
+
+    fn vertices(
+        &self,
+    ) -> Vec<(
+        Vec<hal::pso::Element<hal::format::Format>>,
+        hal::pso::ElemStride,
+        hal::pso::VertexInputRate,
+    )> {
+        vec![
+            PosNormTangTex::vertex().gfx_vertex_input_desc(hal::pso::VertexInputRate::Vertex),
+            asset::SkinVertex::vertex_input_desc(),
+            mesh::InstanceData::vertex_input_desc(true),
+            // The second texture coordinate set
+            (
+                vec![hal::pso::Element {
+                    format: hal::format::Format::Rg32Sfloat,
+                    offset: 0,
+                }],
+                size_of::<[f32; 2]>() as hal::pso::ElemStride,
+                hal::pso::VertexInputRate::Vertex,
+            ),
+        ]
+    }
+
+    fn load_shader_set(
+        &self,
+        factory: &mut Factory<B>,
+        _world: &specs::World,
+    ) -> rendy::shader::ShaderSet<B> {
+        SHADERS.build(factory, Default::default()).unwrap()
+    }
+
+    fn layout(&self) -> Layout {
+        // The joint matrices, morph target deltas and morph target weights
+        let bindings = (0..3)
+            .map(|binding| hal::pso::DescriptorSetLayoutBinding {
+                binding,
+                ty: hal::pso::DescriptorType::StorageBuffer,
+                count: 1,
+                stage_flags: hal::pso::ShaderStageFlags::VERTEX,
+                immutable_samplers: false,
+            })
+            .collect();
+        let deformation_layout = SetLayout { bindings };
+        // The albedo map, its sampler and the material factors
+        let material_layout = SetLayout {
+            bindings: vec![
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 0,
+                    ty: hal::pso::DescriptorType::SampledImage,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                },
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 1,
+                    ty: hal::pso::DescriptorType::Sampler,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                },
+                hal::pso::DescriptorSetLayoutBinding {
+                    binding: 2,
+                    ty: hal::pso::DescriptorType::UniformBuffer,
+                    count: 1,
+                    stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                    immutable_samplers: false,
+                },
+            ],
+        };
+        Layout {
+            sets: vec![deformation_layout, material_layout],
+            // The light's view projection matrix, then the morph targets of the primitive
+            push_constants: vec![(hal::pso::ShaderStageFlags::VERTEX, 0..76)],
+        }
+    }
+
+    fn build<'a>(
+        self,
+        _ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        _queue: QueueId,
+        world: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Pipeline<B>, hal::pso::CreationError> {
+        assert!(buffers.is_empty());
+        assert!(images.is_empty());
+        assert_eq!(set_layouts.len(), 2);
+
+        // Every shadow pass has the same layout, so the sets allocated by the owner can be
+        // bound by all of them
+        let mut shared = world.write_resource::<ShadowPassResources<B>>();
+        if self.slot.is_none() || shared.0.is_some() {
+            return Ok(Pipeline {
+                slot: self.slot,
+                owner: false,
+                _pd: std::marker::PhantomData,
+            });
+        }
+
+        let frames = world.read_resource::<Aux>().frames;
+        let settings = mesh::Settings::from_world::<B>(world);
+        let resources =
+            Resources::new(factory, world, &settings, frames, set_layouts).map_err(|e| {
+                log::error!("Failed to create shadow pass resources: {}", e);
+                hal::pso::CreationError::Other
+            })?;
+        shared.0 = Some(SharedResources {
+            resources,
+            stale_frames: vec![true; frames],
+            settings,
+            failed_settings: None,
+        });
+
+        Ok(Pipeline {
+            slot: self.slot,
+            owner: true,
+            _pd: std::marker::PhantomData,
+        })
+    }
+}
+
+impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
+where
+    B: hal::Backend,
+{
+    type Desc = PipelineDesc;
+
+    fn prepare(
+        &mut self,
+        factory: &Factory<B>,
+        _queue: QueueId,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+        index: usize,
+        world: &specs::World,
+    ) -> PrepareResult {
+        if self.slot.is_none() {
+            return PrepareResult::DrawReuse;
+        }
+
+        let settings = mesh::Settings::from_world::<B>(world);
+        let mut shared = world.write_resource::<ShadowPassResources<B>>();
+        let shared = shared.0.as_mut().expect("Shadow pass resources missing");
+
+        if !self.owner {
+            // The owner is prepared first, so the resources are up to date unless they
+            // could not be reallocated
+            if shared.settings != settings {
+                return PrepareResult::DrawReuse;
+            }
+            return PrepareResult::DrawRecord;
+        }
+
+        if shared.settings != settings {
+            // The mesh pass is prepared after the shadow passes. Only switch to the new
+            // assets once it has reallocated its resources for them, so that the shadows
+            // keep matching the meshes drawn if it fails to.
+            let mesh_adopted = world
+                .read_resource::<mesh::MeshPassResources<B>>()
+                .settings()
+                == Some(&settings);
+            if !mesh_adopted || shared.failed_settings.as_ref() == Some(&settings) {
+                return PrepareResult::DrawReuse;
+            }
+            // Frames in flight may still be reading the old buffers
+            let frames = shared.stale_frames.len();
+            let resources = factory
+                .wait_idle()
+                .map_err(|e| failure::format_err!("Failed to wait for device idle: {:?}", e))
+                .and_then(|()| Resources::new(factory, world, &settings, frames, set_layouts));
+            match resources {
+                Ok(resources) => {
+                    let old_resources = std::mem::replace(&mut shared.resources, resources);
+                    unsafe {
+                        old_resources.dispose(factory);
+                    }
+                    shared.settings = settings;
+                    shared.failed_settings = None;
+                    for stale in shared.stale_frames.iter_mut() {
+                        *stale = true;
+                    }
+                }
+                Err(e) => {
+                    log::error!("Keeping the shadow pass resources of the old assets: {}", e);
+                    shared.failed_settings = Some(settings);
+                    return PrepareResult::DrawReuse;
+                }
+            }
+        }
+        let stale = std::mem::replace(&mut shared.stale_frames[index], false);
+
+        mesh::write_transforms(
+            factory,
+            &mut shared.resources.transform_buffer,
+            None,
+            &shared.settings,
+            index,
+            stale,
+            world,
+        );
+        // Skinned and morphed meshes keep the deformations of the frame's previous commands
+        // if they can't be uploaded
+        let deformations = mesh::write_joints(
+            factory,
+            &mut shared.resources.joint_buffer,
+            &shared.settings,
+            index,
+            false,
+            world,
+        )
+        .and_then(|()| {
+            mesh::write_morph_weights(
+                factory,
+                &mut shared.resources.morph_weight_buffer,
+                &shared.settings,
+                index,
+                false,
+                world,
+            )
+        });
+        if let Err(e) = deformations {
+            log::error!("{}", e);
+        }
+
+        // The light matrix and instance counts are recorded into the command buffer
+        PrepareResult::DrawRecord
+    }
+
+    fn draw(
+        &mut self,
+        layout: &B::PipelineLayout,
+        mut encoder: RenderPassEncoder<'_, B>,
+        index: usize,
+        world: &specs::World,
+    ) {
+        let slot = match self.slot {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        let shared = world.read_resource::<ShadowPassResources<B>>();
+        let shared = shared.0.as_ref().expect("Shadow pass resources missing");
+        let shadow_maps = world.read_resource::<ShadowMaps>();
+        let instance_cache = world.read_resource::<systems::InstanceCache>();
+        let primitive_storage = world.read_resource::<asset::PrimitiveStorage<B>>();
+        let material_storage = world.read_resource::<asset::MaterialStorage<B>>();
+
+        let view_proj = shadow_maps.slots[slot].view_proj;
+        let view_proj: &[u32] =
+            unsafe { std::slice::from_raw_parts(view_proj.as_slice().as_ptr() as *const u32, 16) };
+        unsafe {
+            encoder.bind_graphics_descriptor_sets(
+                layout,
+                0,
+                Some(&shared.resources.deformation_sets[index]),
+                std::iter::empty(),
+            );
+            encoder.push_constants(layout, hal::pso::ShaderStageFlags::VERTEX, 0, view_proj);
+        }
+
+        let transforms_offset = shared.settings.transforms_offset(index as u64);
+        // Points and lines don't cast shadows, and neither do blended materials, which have
+        // no single alpha value to cut off at
+        for primitive in primitive_storage.0.iter().filter(|primitive| {
+            primitive.topology == asset::Topology::Triangles
+                && !material_storage.0[primitive.mat].factors.is_transparent()
+        }) {
+            let instance_count = instance_cache.mesh_instance_counts[primitive.mesh_handle];
+            if instance_count == 0 {
+                continue;
+            }
+            assert!(primitive
+                .mesh_data
+                .bind(0, &[PosNormTangTex::vertex()], &mut encoder)
+                .is_ok());
+            let mesh_transforms_offset = transforms_offset
+                + shared.settings.mesh_transforms_index(primitive.mesh_handle) as u64
+                    * size_of::<mesh::InstanceData>() as u64;
+            unsafe {
+                encoder.bind_graphics_descriptor_sets(
+                    layout,
+                    1,
+                    Some(&shared.resources.mat_sets[primitive.mat]),
+                    std::iter::empty(),
+                );
+                encoder.push_constants(
+                    layout,
+                    hal::pso::ShaderStageFlags::VERTEX,
+                    64,
+                    &mesh::morph_push_constants(&primitive.morph_targets),
+                );
+                encoder.bind_vertex_buffers(
+                    1,
+                    vec![
+                        (primitive.skin_vertices.raw(), 0),
+                        (
+                            shared.resources.transform_buffer.raw(),
+                            mesh_transforms_offset,
+                        ),
+                        (primitive.tex_coords_1.raw(), 0),
+                    ],
+                );
+                encoder.draw_indexed(0..primitive.mesh_data.len(), 0, 0..instance_count);
+            }
+        }
+    }
+
+    fn dispose(self, factory: &mut Factory<B>, world: &specs::World) {
+        if self.owner {
+            let shared = world.write_resource::<ShadowPassResources<B>>().0.take();
+            if let Some(shared) = shared {
+                unsafe {
+                    shared.resources.dispose(factory);
+                }
+            }
+        }
+    }
+}
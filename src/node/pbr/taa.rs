@@ -0,0 +1,359 @@
+//! Temporal anti-aliasing. The projection is offset by a different sub-pixel amount every
+//! frame, and the resolve pass blends the HDR image with the resolved image of the previous
+//! frame, reprojected with the motion vectors written by the mesh pass. The history is clamped
+//! to the colors around each pixel so that disoccluded and changed areas don't ghost.
+use rendy::{
+    command::{QueueId, RenderPassEncoder},
+    factory::Factory,
+    graph::{render::*, GraphContext, ImageAccess, NodeBuffer, NodeImage},
+    hal::{device::Device, pso::DescriptorPool},
+    resource::{
+        DescriptorSetLayout, Escape, Filter, Handle, ImageView, ImageViewInfo, Sampler,
+        SamplerDesc, ViewKind, WrapMode,
+    },
+    shader::{PathBufShaderInfo, ShaderKind, SourceLanguage},
+    texture::Texture,
+};
+
+use rendy::hal;
+
+use crate::node::{
+    history::{create_history_texture, History},
+    pbr::Aux,
+};
+
+lazy_static::lazy_static! {
+    static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/fullscreen_triangle.vert"),
+        ShaderKind::Vertex,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref FRAGMENT: PathBufShaderInfo = PathBufShaderInfo::new(
+        std::path::PathBuf::from(crate::application_root_dir()).join("assets/shaders/taa_resolve.frag"),
+        ShaderKind::Fragment,
+        SourceLanguage::GLSL,
+        "main",
+    );
+
+    static ref SHADERS: rendy::shader::ShaderSetBuilder = rendy::shader::ShaderSetBuilder::default()
+        .with_vertex(&*VERTEX).unwrap()
+        .with_fragment(&*FRAGMENT).unwrap();
+}
+
+/// Format of the motion vectors written by the mesh pass, in texture coordinates per frame
+pub const VELOCITY_FORMAT: hal::format::Format = hal::format::Format::Rg16Sfloat;
+/// Format of the resolved image and the history, the same as the HDR image
+pub const RESOLVE_FORMAT: hal::format::Format = hal::format::Format::Rgba32Sfloat;
+/// Number of jitter offsets cycled through before repeating
+pub const JITTER_SAMPLES: u32 = 8;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaaArgs {
+    pub enabled: bool,
+    /// Weight of the reprojected history in the resolved image
+    pub feedback: f32,
+}
+
+impl std::fmt::Display for TaaArgs {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Enabled: {}, Feedback: {}", self.enabled, self.feedback)
+    }
+}
+
+/// The sub-pixel offset applied to the camera projection in the current frame
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Jitter {
+    pub width: u32,
+    pub height: u32,
+    pub frame: u32,
+    /// Offset in normalized device coordinates
+    pub offset: [f32; 2],
+}
+
+impl Jitter {
+    pub fn new(width: u32, height: u32) -> Self {
+        Jitter {
+            width,
+            height,
+            frame: 0,
+            offset: [0.0; 2],
+        }
+    }
+
+    /// Moves on to the next point of a Halton(2, 3) sequence, or centers the projection again
+    /// if `enabled` is unset
+    pub fn advance(&mut self, enabled: bool) {
+        self.frame = (self.frame + 1) % JITTER_SAMPLES;
+        self.offset = if enabled && self.width > 0 && self.height > 0 {
+            // Skip the first point of the sequence, which is 0 in both dimensions
+            let x = halton(self.frame + 1, 2) - 0.5;
+            let y = halton(self.frame + 1, 3) - 0.5;
+            [2.0 * x / self.width as f32, 2.0 * y / self.height as f32]
+        } else {
+            [0.0; 2]
+        };
+    }
+}
+
+fn halton(mut index: u32, base: u32) -> f32 {
+    let mut fraction = 1.0;
+    let mut result = 0.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+/// The resolved image of the previous frame
+#[derive(Debug)]
+pub struct TaaHistory<B: hal::Backend> {
+    pub texture: Texture<B>,
+}
+
+impl<B: hal::Backend> TaaHistory<B> {
+    pub fn new(
+        factory: &mut Factory<B>,
+        queue: QueueId,
+        width: u32,
+        height: u32,
+    ) -> Result<Self, failure::Error> {
+        let texture = create_history_texture(factory, queue, width, height, RESOLVE_FORMAT)?;
+        Ok(TaaHistory { texture })
+    }
+}
+
+impl<B: hal::Backend> History<B> for TaaHistory<B> {
+    fn texture(&self) -> &Texture<B> {
+        &self.texture
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PipelineDesc;
+
+#[derive(Debug)]
+pub struct Pipeline<B: hal::Backend> {
+    descriptor_pool: B::DescriptorPool,
+    set: B::DescriptorSet,
+    image_sampler: Escape<Sampler<B>>,
+    image_views: Vec<Escape<ImageView<B>>>,
+}
+
+impl<B> SimpleGraphicsPipelineDesc<B, specs::World> for PipelineDesc
+where
+    B: hal::Backend,
+{
+    type Pipeline = Pipeline<B>;
+
+    fn images(&self) -> Vec<ImageAccess> {
+        // The HDR image and the motion vectors
+        vec![
+            ImageAccess {
+                access: hal::image::Access::SHADER_READ,
+                usage: hal::image::Usage::SAMPLED,
+                layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                stages: hal::pso::PipelineStage::FRAGMENT_SHADER,
+            };
+            2
+        ]
+    }
+
+    fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
+        None
+    }
+
+    fn load_shader_set(
+        &self,
+        factory: &mut Factory<B>,
+        _world: &specs::World,
+    ) -> rendy::shader::ShaderSet<B> {
+        SHADERS.build(factory, Default::default()).unwrap()
+    }
+
+    fn layout(&self) -> Layout {
+        let mut bindings = vec![hal::pso::DescriptorSetLayoutBinding {
+            binding: 0,
+            ty: hal::pso::DescriptorType::Sampler,
+            count: 1,
+            stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+            immutable_samplers: false,
+        }];
+        // HDR image, motion vectors and history
+        for i in 1..4 {
+            bindings.push(hal::pso::DescriptorSetLayoutBinding {
+                binding: i,
+                ty: hal::pso::DescriptorType::SampledImage,
+                count: 1,
+                stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                immutable_samplers: false,
+            });
+        }
+        Layout {
+            sets: vec![SetLayout { bindings }],
+            // feedback
+            push_constants: vec![(hal::pso::ShaderStageFlags::FRAGMENT, 0..4)],
+        }
+    }
+
+    fn build<'a>(
+        self,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        _queue: QueueId,
+        world: &specs::World,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+        set_layouts: &[Handle<DescriptorSetLayout<B>>],
+    ) -> Result<Pipeline<B>, hal::pso::CreationError> {
+        assert!(buffers.is_empty());
+        assert_eq!(images.len(), 2);
+        assert_eq!(set_layouts.len(), 1);
+
+        let history = world.read_resource::<TaaHistory<B>>();
+
+        let mut descriptor_pool = unsafe {
+            factory.create_descriptor_pool(
+                1,
+                vec![
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::Sampler,
+                        count: 1,
+                    },
+                    hal::pso::DescriptorRangeDesc {
+                        ty: hal::pso::DescriptorType::SampledImage,
+                        count: 3,
+                    },
+                ],
+                hal::pso::DescriptorPoolCreateFlags::empty(),
+            )?
+        };
+
+        // Linear filtering is needed for the history, the current frame is read with
+        // texelFetch
+        let image_sampler = factory
+            .create_sampler(SamplerDesc::new(Filter::Linear, WrapMode::Clamp))
+            .unwrap();
+
+        let image_views = images
+            .iter()
+            .zip([RESOLVE_FORMAT, VELOCITY_FORMAT].iter())
+            .map(|(image, format)| {
+                let image_handle = ctx.get_image(image.id).expect("TAA input image missing");
+                factory
+                    .create_image_view(
+                        image_handle.clone(),
+                        ImageViewInfo {
+                            view_kind: ViewKind::D2,
+                            format: *format,
+                            swizzle: hal::format::Swizzle::NO,
+                            range: image.range.clone(),
+                        },
+                    )
+                    .expect("Could not create TAA input image view")
+            })
+            .collect::<Vec<_>>();
+
+        let set = unsafe {
+            let set = descriptor_pool.allocate_set(&set_layouts[0].raw()).unwrap();
+            factory.write_descriptor_sets(vec![
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 0,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Sampler(image_sampler.raw())),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 1,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        image_views[0].raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 2,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        image_views[1].raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+                hal::pso::DescriptorSetWrite {
+                    set: &set,
+                    binding: 3,
+                    array_offset: 0,
+                    descriptors: Some(hal::pso::Descriptor::Image(
+                        history.texture.view().raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                    )),
+                },
+            ]);
+            set
+        };
+
+        Ok(Pipeline {
+            descriptor_pool,
+            set,
+            image_sampler,
+            image_views,
+        })
+    }
+}
+
+impl<B> SimpleGraphicsPipeline<B, specs::World> for Pipeline<B>
+where
+    B: hal::Backend,
+{
+    type Desc = PipelineDesc;
+
+    fn prepare(
+        &mut self,
+        _factory: &Factory<B>,
+        _queue: QueueId,
+        _set_layouts: &[Handle<DescriptorSetLayout<B>>],
+        _index: usize,
+        _world: &specs::World,
+    ) -> PrepareResult {
+        // The feedback is recorded as a push constant
+        PrepareResult::DrawRecord
+    }
+
+    fn draw(
+        &mut self,
+        layout: &B::PipelineLayout,
+        mut encoder: RenderPassEncoder<'_, B>,
+        _index: usize,
+        world: &specs::World,
+    ) {
+        let taa_args = world.read_resource::<Aux>().taa_args;
+        // Without jitter there is nothing to accumulate, so the HDR image passes through
+        let feedback = if taa_args.enabled {
+            taa_args.feedback
+        } else {
+            0.0
+        };
+        unsafe {
+            encoder.bind_graphics_descriptor_sets(layout, 0, Some(&self.set), std::iter::empty());
+            encoder.push_constants(
+                layout,
+                hal::pso::ShaderStageFlags::FRAGMENT,
+                0,
+                &[feedback.to_bits()],
+            );
+            encoder.draw(0..3, 0..1);
+        }
+    }
+
+    fn dispose(mut self, factory: &mut Factory<B>, _world: &specs::World) {
+        unsafe {
+            self.descriptor_pool.reset();
+            factory.destroy_descriptor_pool(self.descriptor_pool);
+        }
+    }
+}
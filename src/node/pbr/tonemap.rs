@@ -11,10 +11,14 @@ use rendy::{
 };
 
 use rendy::hal;
+use serde::Deserialize;
 
 use std::mem::size_of;
 
-use crate::node::pbr::Aux;
+use crate::node::pbr::{
+    color_grading::{ColorGradingArgs, ColorLut},
+    Aux,
+};
 
 lazy_static::lazy_static! {
     static ref VERTEX: PathBufShaderInfo = PathBufShaderInfo::new(
@@ -36,24 +40,176 @@ lazy_static::lazy_static! {
         .with_fragment(&*FRAGMENT).unwrap();
 }
 
-#[derive(Debug, Clone, Copy, Default)]
-#[repr(C)]
+/// The curves mapping HDR colors to the display range. The discriminants are the operator
+/// indices used by the tonemapping shader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum TonemapOperator {
+    Reinhard = 0,
+    /// Reinhard with a configurable luminance mapped to white
+    ReinhardExtended = 1,
+    /// John Hable's filmic curve from Uncharted 2
+    Hable = 2,
+    /// Stephen Hill's fit of the ACES reference rendering and output transforms
+    AcesFitted = 3,
+    AgX = 4,
+    /// The Khronos PBR Neutral operator, which keeps base colors close to their sRGB values
+    PbrNeutral = 5,
+}
+
+impl TonemapOperator {
+    pub const ALL: [TonemapOperator; 6] = [
+        TonemapOperator::Reinhard,
+        TonemapOperator::ReinhardExtended,
+        TonemapOperator::Hable,
+        TonemapOperator::AcesFitted,
+        TonemapOperator::AgX,
+        TonemapOperator::PbrNeutral,
+    ];
+
+    pub fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+
+    pub fn previous(self) -> Self {
+        Self::ALL[(self as usize + Self::ALL.len() - 1) % Self::ALL.len()]
+    }
+}
+
+impl std::fmt::Display for TonemapOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            TonemapOperator::Reinhard => "Reinhard",
+            TonemapOperator::ReinhardExtended => "Extended Reinhard",
+            TonemapOperator::Hable => "Hable",
+            TonemapOperator::AcesFitted => "ACES (fitted)",
+            TonemapOperator::AgX => "AgX",
+            TonemapOperator::PbrNeutral => "Khronos PBR Neutral",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Parameters of the Hable curve, named as in the original presentation
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct HableParams {
+    pub shoulder_strength: f32,
+    pub linear_strength: f32,
+    pub linear_angle: f32,
+    pub toe_strength: f32,
+    pub toe_numerator: f32,
+    pub toe_denominator: f32,
+    /// Linear color mapped to white
+    pub white_point: f32,
+}
+
+impl Default for HableParams {
+    fn default() -> Self {
+        HableParams {
+            shoulder_strength: 0.15,
+            linear_strength: 0.5,
+            linear_angle: 0.1,
+            toe_strength: 0.2,
+            toe_numerator: 0.02,
+            toe_denominator: 0.3,
+            white_point: 11.2,
+        }
+    }
+}
+
+/// Tonemapper settings, read from the scene file and adjustable at runtime
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
 pub struct TonemapperArgs {
     pub exposure: f32,
-    pub curve: i32,
+    pub operator: TonemapOperator,
+    /// Whether to show `comparison_operator` left of the split and `operator` right of it
+    pub compare: bool,
+    pub comparison_operator: TonemapOperator,
+    /// Horizontal position of the split, from 0 to 1
     pub comparison_factor: f32,
+    /// Luminance mapped to white by the extended Reinhard operator
+    pub reinhard_white_point: f32,
+    pub hable: HableParams,
+}
+
+impl Default for TonemapperArgs {
+    fn default() -> Self {
+        TonemapperArgs {
+            exposure: 1.7,
+            operator: TonemapOperator::AcesFitted,
+            compare: false,
+            comparison_operator: TonemapOperator::Hable,
+            comparison_factor: 0.5,
+            reinhard_white_point: 4.0,
+            hable: HableParams::default(),
+        }
+    }
 }
 
 impl std::fmt::Display for TonemapperArgs {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Exposure: {}, Curve: {}", self.exposure, self.curve)
+        write!(
+            f,
+            "Exposure: {}, Operator: {}",
+            self.exposure, self.operator
+        )?;
+        if self.compare {
+            write!(f, ", Comparing with: {}", self.comparison_operator)?;
+        }
+        Ok(())
+    }
+}
+
+/// Layout of the tonemapper settings in the uniform, following std140
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct TonemapperUniform {
+    exposure: f32,
+    operator: i32,
+    comparison_operator: i32,
+    comparison_factor: f32,
+    reinhard_white_point: f32,
+    hable: [f32; 7],
+}
+
+impl From<&TonemapperArgs> for TonemapperUniform {
+    fn from(args: &TonemapperArgs) -> Self {
+        let hable = &args.hable;
+        TonemapperUniform {
+            exposure: args.exposure,
+            operator: args.operator as i32,
+            comparison_operator: args.comparison_operator as i32,
+            // With the split at the left edge every pixel uses `operator`
+            comparison_factor: if args.compare {
+                args.comparison_factor
+            } else {
+                0.0
+            },
+            reinhard_white_point: args.reinhard_white_point,
+            hable: [
+                hable.shoulder_strength,
+                hable.linear_strength,
+                hable.linear_angle,
+                hable.toe_strength,
+                hable.toe_numerator,
+                hable.toe_denominator,
+                hable.white_point,
+            ],
+        }
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
 pub struct UniformArgs {
-    tonemapper: TonemapperArgs,
+    tonemapper: TonemapperUniform,
+    bloom_intensity: f32,
+    _pad: [f32; 3],
+    color_grading: ColorGradingArgs,
+    /// 1 to derive the exposure from the adapted luminance, 0 to use the manual exposure
+    auto_exposure: i32,
+    exposure_compensation: f32,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -100,7 +256,10 @@ pub struct Pipeline<B: hal::Backend> {
     sets: Vec<B::DescriptorSet>,
     descriptor_pool: B::DescriptorPool,
     image_sampler: Escape<Sampler<B>>,
+    lut_sampler: Escape<Sampler<B>>,
     image_view: Escape<ImageView<B>>,
+    bloom_view: Escape<ImageView<B>>,
+    adapted_view: Escape<ImageView<B>>,
     settings: Settings,
 }
 
@@ -111,12 +270,16 @@ where
     type Pipeline = Pipeline<B>;
 
     fn images(&self) -> Vec<ImageAccess> {
-        vec![ImageAccess {
-            access: hal::image::Access::SHADER_READ,
-            usage: hal::image::Usage::SAMPLED,
-            layout: hal::image::Layout::ShaderReadOnlyOptimal,
-            stages: hal::pso::PipelineStage::FRAGMENT_SHADER,
-        }]
+        // The HDR image, the bloom and the adapted luminance
+        vec![
+            ImageAccess {
+                access: hal::image::Access::SHADER_READ,
+                usage: hal::image::Usage::SAMPLED,
+                layout: hal::image::Layout::ShaderReadOnlyOptimal,
+                stages: hal::pso::PipelineStage::FRAGMENT_SHADER,
+            };
+            3
+        ]
     }
 
     fn depth_stencil(&self) -> Option<hal::pso::DepthStencilDesc> {
@@ -156,6 +319,35 @@ where
                         stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
                         immutable_samplers: false,
                     },
+                    hal::pso::DescriptorSetLayoutBinding {
+                        binding: 3,
+                        ty: hal::pso::DescriptorType::SampledImage,
+                        count: 1,
+                        stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
+                    },
+                    // Color grading lookup table and its sampler
+                    hal::pso::DescriptorSetLayoutBinding {
+                        binding: 4,
+                        ty: hal::pso::DescriptorType::SampledImage,
+                        count: 1,
+                        stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
+                    },
+                    hal::pso::DescriptorSetLayoutBinding {
+                        binding: 5,
+                        ty: hal::pso::DescriptorType::Sampler,
+                        count: 1,
+                        stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
+                    },
+                    hal::pso::DescriptorSetLayoutBinding {
+                        binding: 6,
+                        ty: hal::pso::DescriptorType::SampledImage,
+                        count: 1,
+                        stage_flags: hal::pso::ShaderStageFlags::FRAGMENT,
+                        immutable_samplers: false,
+                    },
                 ],
             }],
             push_constants: Vec::new(),
@@ -173,10 +365,11 @@ where
         set_layouts: &[Handle<DescriptorSetLayout<B>>],
     ) -> Result<Pipeline<B>, hal::pso::CreationError> {
         assert!(buffers.is_empty());
-        assert!(images.len() == 1);
+        assert!(images.len() == 3);
         assert!(set_layouts.len() == 1);
 
         let aux = world.read_resource::<Aux>();
+        let lut = world.read_resource::<ColorLut<B>>();
 
         let settings: Settings = (&*aux).into();
 
@@ -188,11 +381,11 @@ where
                 vec![
                     hal::pso::DescriptorRangeDesc {
                         ty: hal::pso::DescriptorType::Sampler,
-                        count: frames,
+                        count: frames * 2,
                     },
                     hal::pso::DescriptorRangeDesc {
                         ty: hal::pso::DescriptorType::SampledImage,
-                        count: frames,
+                        count: frames * 4,
                     },
                     hal::pso::DescriptorRangeDesc {
                         ty: hal::pso::DescriptorType::UniformBuffer,
@@ -207,6 +400,11 @@ where
             .create_sampler(SamplerDesc::new(Filter::Nearest, WrapMode::Clamp))
             .unwrap();
 
+        // The lookup table is interpolated between its entries
+        let lut_sampler = factory
+            .create_sampler(SamplerDesc::new(Filter::Linear, WrapMode::Clamp))
+            .unwrap();
+
         let image_handle = ctx
             .get_image(images[0].id)
             .expect("Tonemapper HDR image missing");
@@ -223,6 +421,38 @@ where
             )
             .expect("Could not create tonemapper input image view");
 
+        let bloom_handle = ctx
+            .get_image(images[1].id)
+            .expect("Tonemapper bloom image missing");
+
+        let bloom_view = factory
+            .create_image_view(
+                bloom_handle.clone(),
+                ImageViewInfo {
+                    view_kind: ViewKind::D2,
+                    format: super::bloom::BLOOM_FORMAT,
+                    swizzle: hal::format::Swizzle::NO,
+                    range: images[1].range.clone(),
+                },
+            )
+            .expect("Could not create tonemapper bloom image view");
+
+        let adapted_handle = ctx
+            .get_image(images[2].id)
+            .expect("Tonemapper adapted luminance image missing");
+
+        let adapted_view = factory
+            .create_image_view(
+                adapted_handle.clone(),
+                ImageViewInfo {
+                    view_kind: ViewKind::D2,
+                    format: super::exposure::LUMINANCE_FORMAT,
+                    swizzle: hal::format::Swizzle::NO,
+                    range: images[2].range.clone(),
+                },
+            )
+            .expect("Could not create tonemapper adapted luminance image view");
+
         let buffer = factory
             .create_buffer(
                 BufferInfo {
@@ -265,6 +495,39 @@ where
                                 ),
                         )),
                     },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 3,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Image(
+                            bloom_view.raw(),
+                            hal::image::Layout::ShaderReadOnlyOptimal,
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 4,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Image(
+                            lut.texture.view().raw(),
+                            hal::image::Layout::ShaderReadOnlyOptimal,
+                        )),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 5,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Sampler(lut_sampler.raw())),
+                    },
+                    hal::pso::DescriptorSetWrite {
+                        set: &set,
+                        binding: 6,
+                        array_offset: 0,
+                        descriptors: Some(hal::pso::Descriptor::Image(
+                            adapted_view.raw(),
+                            hal::image::Layout::ShaderReadOnlyOptimal,
+                        )),
+                    },
                 ]);
                 sets.push(set);
             }
@@ -273,7 +536,10 @@ where
             buffer,
             sets,
             image_view,
+            bloom_view,
+            adapted_view,
             image_sampler,
+            lut_sampler,
             descriptor_pool,
             settings,
         })
@@ -295,13 +561,23 @@ where
         world: &specs::World,
     ) -> PrepareResult {
         let aux = world.read_resource::<Aux>();
+        let lut = world.read_resource::<ColorLut<B>>();
         unsafe {
             factory
                 .upload_visible_buffer(
                     &mut self.buffer,
                     self.settings.uniform_offset(index as u64),
                     &[UniformArgs {
-                        tonemapper: aux.tonemapper_args,
+                        tonemapper: TonemapperUniform::from(&aux.tonemapper_args),
+                        bloom_intensity: aux.bloom_args.intensity,
+                        _pad: [0.0; 3],
+                        color_grading: ColorGradingArgs::new(
+                            &aux.color_grading,
+                            &lut,
+                            aux.color_lut_enabled,
+                        ),
+                        auto_exposure: aux.auto_exposure.enabled as i32,
+                        exposure_compensation: aux.auto_exposure.compensation,
                     }],
                 )
                 .unwrap()
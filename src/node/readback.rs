@@ -0,0 +1,285 @@
+use rendy::{
+    command::{
+        CommandBuffer, CommandPool, ExecutableState, Families, Family, FamilyId, Fence, MultiShot,
+        PendingState, Queue, SimultaneousUse, Submission, Submit, Supports, Transfer,
+    },
+    factory::Factory,
+    frame::Frames,
+    graph::{
+        gfx_acquire_barriers, gfx_release_barriers, BufferAccess, BufferId, DynNode, GraphContext,
+        ImageAccess, ImageId, NodeBuffer, NodeBuildError, NodeBuilder, NodeId, NodeImage,
+    },
+    memory::MemoryUsageValue,
+    resource::{Buffer, BufferInfo, Escape},
+};
+
+use rendy::hal;
+
+use std::collections::HashMap;
+
+/// Copies a graph image into a host-visible buffer every time the graph is run,
+/// so that its contents can be read back on the CPU once the frame has completed.
+#[derive(Debug)]
+pub struct Readback<B: hal::Backend> {
+    pool: CommandPool<B>,
+    submit: Submit<B, SimultaneousUse>,
+    buffer: CommandBuffer<
+        B,
+        hal::queue::QueueType,
+        PendingState<ExecutableState<MultiShot<SimultaneousUse>>>,
+    >,
+}
+
+impl<B: hal::Backend> Readback<B> {
+    pub fn builder(input: ImageId, output_buffer_name: &str) -> ReadbackBuilder {
+        ReadbackBuilder {
+            input,
+            output_buffer_name: String::from(output_buffer_name),
+            dependencies: vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ReadbackBuilder {
+    input: ImageId,
+    output_buffer_name: String,
+    dependencies: Vec<NodeId>,
+}
+
+impl ReadbackBuilder {
+    /// Add dependency.
+    /// Node will be placed after its dependencies.
+    pub fn add_dependency(&mut self, dependency: NodeId) -> &mut Self {
+        self.dependencies.push(dependency);
+        self
+    }
+
+    /// Add dependency.
+    /// Node will be placed after its dependencies.
+    pub fn with_dependency(mut self, dependency: NodeId) -> Self {
+        self.add_dependency(dependency);
+        self
+    }
+}
+
+pub trait ReadbackResource<B: hal::Backend> {
+    /// Calls `f` with the raw buffer that the image read back under `name` should be copied into.
+    fn with_readback_buffer(&self, name: &str, f: &mut dyn FnMut(&B::Buffer));
+}
+
+/// Host-visible buffers which readback nodes copy images into, keyed by name.
+#[derive(Debug)]
+pub struct ReadbackStorage<B: hal::Backend> {
+    buffers: HashMap<String, Escape<Buffer<B>>>,
+}
+
+impl<B: hal::Backend> ReadbackStorage<B> {
+    pub fn new() -> Self {
+        ReadbackStorage {
+            buffers: HashMap::new(),
+        }
+    }
+
+    /// Create a buffer of `size` bytes to hold the readback of an image
+    pub fn add_buffer(
+        &mut self,
+        factory: &Factory<B>,
+        name: &str,
+        size: u64,
+    ) -> Result<(), failure::Error> {
+        let buffer = factory.create_buffer(
+            BufferInfo {
+                size,
+                usage: hal::buffer::Usage::TRANSFER_DST,
+            },
+            MemoryUsageValue::Download,
+        )?;
+        self.buffers.insert(String::from(name), buffer);
+        Ok(())
+    }
+
+    pub fn buffer(&self, name: &str) -> Option<&Escape<Buffer<B>>> {
+        self.buffers.get(name)
+    }
+
+    /// Copy the current contents of a readback buffer out to host memory.
+    /// The device must be idle, or at least done with the frame which wrote to the buffer.
+    pub fn read(&mut self, factory: &Factory<B>, name: &str) -> Result<Vec<u8>, failure::Error> {
+        let buffer = self
+            .buffers
+            .get_mut(name)
+            .ok_or(failure::format_err!("No readback buffer named {}", name))?;
+        let size = buffer.size();
+        let mut mapped = buffer
+            .map(factory.device(), 0..size)
+            .map_err(|e| failure::format_err!("Failed to map readback buffer: {:?}", e))?;
+        let data: &[u8] = unsafe {
+            mapped
+                .read(factory.device(), 0..size)
+                .map_err(|e| failure::format_err!("Failed to read readback buffer: {:?}", e))?
+        };
+        Ok(data.to_vec())
+    }
+}
+
+impl<B: hal::Backend> ReadbackResource<B> for specs::World {
+    fn with_readback_buffer(&self, name: &str, f: &mut dyn FnMut(&B::Buffer)) {
+        let storage = self.read_resource::<ReadbackStorage<B>>();
+        f(storage
+            .buffer(name)
+            .expect("Readback buffer missing from storage")
+            .raw());
+    }
+}
+
+impl<B, RR> NodeBuilder<B, RR> for ReadbackBuilder
+where
+    B: hal::Backend,
+    RR: ReadbackResource<B>,
+{
+    fn family(&self, _factory: &mut Factory<B>, families: &Families<B>) -> Option<FamilyId> {
+        families.find(|family| Supports::<Transfer>::supports(&family.capability()).is_some())
+    }
+
+    fn buffers(&self) -> Vec<(BufferId, BufferAccess)> {
+        Vec::new()
+    }
+
+    fn images(&self) -> Vec<(ImageId, ImageAccess)> {
+        vec![(
+            self.input,
+            ImageAccess {
+                access: hal::image::Access::TRANSFER_READ,
+                layout: hal::image::Layout::TransferSrcOptimal,
+                usage: hal::image::Usage::TRANSFER_SRC,
+                stages: hal::pso::PipelineStage::TRANSFER,
+            },
+        )]
+    }
+
+    fn dependencies(&self) -> Vec<NodeId> {
+        self.dependencies.clone()
+    }
+
+    fn build<'a>(
+        self: Box<Self>,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        family: &mut Family<B>,
+        _queue: usize,
+        aux: &RR,
+        buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn DynNode<B, RR>>, NodeBuildError> {
+        assert_eq!(buffers.len(), 0);
+        assert_eq!(images.len(), 1);
+
+        let mut pool = factory.create_command_pool(family).unwrap();
+
+        let buf_initial = pool.allocate_buffers(1).pop().unwrap();
+        let mut buf_recording = buf_initial.begin(MultiShot(SimultaneousUse), ());
+        let mut encoder = buf_recording.encoder();
+
+        {
+            let (stages, barriers) = gfx_acquire_barriers(ctx, None, images.iter());
+            log::trace!("Acquire {:?} : {:#?}", stages, barriers);
+            if !barriers.is_empty() {
+                unsafe {
+                    encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers)
+                };
+            }
+        }
+
+        let image = ctx.get_image(images[0].id).unwrap();
+        let extent = image.kind().extent();
+
+        aux.with_readback_buffer(&self.output_buffer_name, &mut |target| unsafe {
+            encoder.copy_image_to_buffer(
+                image.raw(),
+                images[0].layout,
+                target,
+                Some(hal::command::BufferImageCopy {
+                    buffer_offset: 0,
+                    buffer_width: extent.width,
+                    buffer_height: extent.height,
+                    image_layers: hal::image::SubresourceLayers {
+                        aspects: hal::format::Aspects::COLOR,
+                        level: 0,
+                        layers: 0..1,
+                    },
+                    image_offset: hal::image::Offset::ZERO,
+                    image_extent: hal::image::Extent {
+                        width: extent.width,
+                        height: extent.height,
+                        depth: 1,
+                    },
+                }),
+            );
+
+            // Make the copied data visible to the host once the submission completes
+            encoder.pipeline_barrier(
+                hal::pso::PipelineStage::TRANSFER..hal::pso::PipelineStage::HOST,
+                hal::memory::Dependencies::empty(),
+                Some(hal::memory::Barrier::Buffer {
+                    states: hal::buffer::Access::TRANSFER_WRITE..hal::buffer::Access::HOST_READ,
+                    families: None,
+                    target,
+                    range: None..None,
+                }),
+            );
+        });
+
+        {
+            let (stages, barriers) = gfx_release_barriers(ctx, None, images.iter());
+            log::trace!("Release {:?} : {:#?}", stages, barriers);
+            if !barriers.is_empty() {
+                unsafe {
+                    encoder.pipeline_barrier(stages, hal::memory::Dependencies::empty(), barriers)
+                };
+            }
+        }
+
+        let (submit, buffer) = buf_recording.finish().submit();
+
+        Ok(Box::new(Readback {
+            pool,
+            submit,
+            buffer,
+        }))
+    }
+}
+
+impl<B, RR> DynNode<B, RR> for Readback<B>
+where
+    B: hal::Backend,
+    RR: ReadbackResource<B>,
+{
+    unsafe fn run<'a>(
+        &mut self,
+        _ctx: &GraphContext<B>,
+        _factory: &Factory<B>,
+        queue: &mut Queue<B>,
+        _aux: &RR,
+        _frames: &Frames<B>,
+        waits: &[(&'a B::Semaphore, hal::pso::PipelineStage)],
+        signals: &[&'a B::Semaphore],
+        fence: Option<&mut Fence<B>>,
+    ) {
+        queue.submit(
+            Some(
+                Submission::new()
+                    .submits(Some(&self.submit))
+                    .wait(waits.iter().cloned())
+                    .signal(signals.iter()),
+            ),
+            fence,
+        );
+    }
+
+    unsafe fn dispose(mut self: Box<Self>, factory: &mut Factory<B>, _aux: &RR) {
+        drop(self.submit);
+        self.pool.free_buffers(Some(self.buffer.mark_complete()));
+        factory.destroy_command_pool(self.pool);
+    }
+}
@@ -1,6 +1,6 @@
 //! A simple scene description format which allows loading models (meshes) and transforms
 //! from multiple glTF files, as well as to define a scene graph hierarchy and cameras and lights.
-use crate::{asset, components};
+use crate::{animation, asset, components, node};
 
 use rendy::hal;
 use serde::Deserialize;
@@ -25,29 +25,83 @@ pub type GltfFileIndex = usize;
 #[derive(Debug, Deserialize)]
 pub struct SceneConfig {
     pub environment_map: String,
+    /// Additional environment maps which can be switched to at runtime
+    #[serde(default)]
+    pub environment_maps: Vec<String>,
     pub environment_filter_quality: Quality,
+    /// Samples per pixel for multisample anti-aliasing of the main pass, 1 to disable it
+    #[serde(default = "default_msaa_samples")]
+    pub msaa_samples: u8,
+    /// Tonemapping operator and its parameters
+    #[serde(default)]
+    pub tonemapper: node::pbr::tonemap::TonemapperArgs,
+    /// Eye adaptation, replacing the manual exposure when enabled
+    #[serde(default)]
+    pub auto_exposure: node::pbr::exposure::AutoExposure,
+    /// Color grading applied after tonemapping
+    #[serde(default)]
+    pub color_grading: node::pbr::color_grading::ColorGrading,
+    /// A `.cube` 3D lookup table applied after the rest of the color grading
+    #[serde(default)]
+    pub color_lut: Option<String>,
     pub mipmap_model_textures: bool,
+    /// Anisotropic filtering level of model textures, 1 to disable it
+    #[serde(default = "default_texture_anisotropy")]
+    pub texture_anisotropy: u8,
+    /// Generate smooth normals for primitives without normals, instead of the flat normals the
+    /// glTF spec asks for
+    #[serde(default)]
+    pub smooth_generated_normals: bool,
     pub gltf_sources: Vec<(BasePath, Filename)>,
     pub entities: Vec<SceneEntity>,
+    /// Animations from the glTF source files to play, looping. They animate the entities
+    /// whose transforms come from the animated nodes, and the skeletons of skinned meshes.
+    #[serde(default)]
+    pub animations: Vec<GltfAnimation>,
+}
+
+fn default_msaa_samples() -> u8 {
+    1
+}
+
+fn default_texture_anisotropy() -> u8 {
+    1
 }
 
 /// Determines the quality of some part of the render
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
 pub enum Quality {
     Low,
     Medium,
     High,
 }
 
+impl std::str::FromStr for Quality {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "low" => Ok(Quality::Low),
+            "medium" => Ok(Quality::Medium),
+            "high" => Ok(Quality::High),
+            _ => Err(failure::format_err!(
+                "Unknown quality {:?}, expected one of low, medium, high",
+                s
+            )),
+        }
+    }
+}
+
 /// The index of an entity in the SceneEntity list of the scene config
 pub type SceneEntityIndex = usize;
 
-/// An entity in the scene. Must have a transform, and can optionally have
+/// An entity in the scene. Has a transform, and can optionally have
 /// a parent, mesh, light, and camera components.
 #[derive(Debug, Deserialize)]
 pub struct SceneEntity {
     /// The transform of this entity. Can either be specified manually in the scene config file
-    /// or inherited from a node in one of the glTF source files.
+    /// or inherited from a node in one of the glTF source files. Defaults to the identity.
+    #[serde(default)]
     transform: TransformSource,
     /// The parent of this entity. This entity's transform will be relative to the parent,
     /// if there is one.
@@ -55,12 +109,21 @@ pub struct SceneEntity {
     /// The mesh of this entity. Mesh is used in the glTF sense, which means a mesh contains multiple
     /// 'primitives', which are each a set of vertex data and an associated material. A mesh can either
     /// be loaded from the index of the mesh in the glTF source file, or from the index of a node in the
-    /// glTF file.
+    /// glTF file. If the node has a skin, entities are created for the nodes of its skeleton,
+    /// placed relative to this entity.
     mesh: Option<MeshSource>,
-    /// Designates this entity as a light, with an intensity and color
-    light: Option<components::Light>,
-    /// Designates this entity as a camera, with associated camera parameters
-    camera: Option<CameraData>,
+    /// Designates this entity as a light, either defined manually or loaded from a
+    /// `KHR_lights_punctual` light in one of the glTF source files
+    light: Option<LightSource>,
+    /// Designates this entity as a camera, either defined manually or loaded from a camera in
+    /// one of the glTF source files
+    camera: Option<CameraSource>,
+    /// Instantiates the node tree of a scene in a glTF source file below this entity, with the
+    /// meshes, skins, cameras and `KHR_lights_punctual` lights of the nodes.
+    gltf_scene: Option<GltfScene>,
+    /// A node of `gltf_scene` whose camera becomes the active camera. It orbits in the space
+    /// of its parent node, around the point in front of it which is as far away as the origin.
+    active_camera: Option<GltfNode>,
 }
 
 /// The source of the transform.
@@ -72,6 +135,12 @@ pub enum TransformSource {
     Manual(components::Transform),
 }
 
+impl Default for TransformSource {
+    fn default() -> Self {
+        TransformSource::Manual(components::Transform::default())
+    }
+}
+
 /// The source of the mesh data
 #[derive(Debug, Deserialize)]
 pub enum MeshSource {
@@ -81,6 +150,35 @@ pub enum MeshSource {
     Mesh(GltfMesh),
 }
 
+/// The source of the light
+#[derive(Debug, Deserialize)]
+pub enum LightSource {
+    /// The light of a node in a glTF source file (must have a `KHR_lights_punctual` light).
+    /// The light casts shadows if `casts_shadows` is set.
+    Gltf {
+        node: GltfNode,
+        #[serde(default)]
+        casts_shadows: bool,
+    },
+    /// Define the light manually, with an intensity and color. When left out, `kind` is a
+    /// point light, `casts_shadows` is false and `shadow_resolution` is 1024.
+    Manual(components::Light),
+}
+
+/// The source of the camera
+#[derive(Debug, Deserialize)]
+pub enum CameraSource {
+    /// The camera of a node in a glTF source file (must have a camera). The camera orbits
+    /// around a point in front of it when it's active.
+    Gltf {
+        node: GltfNode,
+        #[serde(default)]
+        active: bool,
+    },
+    /// Define the camera manually
+    Manual(CameraData),
+}
+
 /// Data for the camera. This is an orbiting camera which orbits at a distance
 /// around a focus point.
 #[derive(Debug, Deserialize)]
@@ -114,6 +212,24 @@ pub enum GltfMesh {
     Name(GltfFileIndex, String),
 }
 
+/// A glTF scene in one of the source files
+#[derive(Debug, Deserialize)]
+pub enum GltfScene {
+    /// Fetch the scene by its index in the source file
+    Index(GltfFileIndex, usize),
+    /// Fetch the scene by its name in the source file
+    Name(GltfFileIndex, String),
+}
+
+/// A glTF animation in one of the source files
+#[derive(Debug, Deserialize)]
+pub enum GltfAnimation {
+    /// Fetch the animation by its index in the source file
+    Index(GltfFileIndex, usize),
+    /// Fetch the animation by its name in the source file
+    Name(GltfFileIndex, String),
+}
+
 impl SceneConfig {
     pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, failure::Error> {
         let path = Path::new(&crate::application_root_dir()).join(path.as_ref());
@@ -133,6 +249,8 @@ impl SceneConfig {
             asset::MaterialStorage<B>,
             asset::PrimitiveStorage<B>,
             asset::MeshStorage,
+            asset::MorphTargetStorage,
+            animation::Animations,
             Vec<specs::Entity>,
         ),
         failure::Error,
@@ -140,23 +258,29 @@ impl SceneConfig {
         let mut mesh_storage = Vec::new();
         let mut primitive_storage = Vec::new();
         let mut material_storage = Vec::new();
+        let mut morph_target_storage = Vec::new();
         let mut scene_entities = Vec::new();
-        // (node, mesh, material)
+        let mut gltf_buffers = Vec::new();
+        // The entities created from the nodes of each source file
+        let mut node_entities = vec![animation::NodeEntities::new(); self.gltf_sources.len()];
+        let mut num_joints = 0;
+        let mut num_morph_weights = 0;
+        // (mesh, material, node)
         let mut gltf_file_offsets = vec![(0, 0, 0)];
 
-        let (gltfs, basepaths): (Vec<_>, Vec<_>) = self
-            .gltf_sources
-            .drain(..)
-            .map(|path| {
-                let base_path = Path::new(&crate::application_root_dir()).join(path.0);
-                let file = File::open(base_path.join(path.1)).unwrap();
-                let reader = std::io::BufReader::new(file);
-                (gltf::Gltf::from_reader(reader).unwrap(), base_path.clone())
-            })
-            .unzip();
+        let mut gltfs = Vec::new();
+        let mut gltf_extensions = Vec::new();
+        let mut basepaths = Vec::new();
+        for (base_path, filename) in self.gltf_sources.drain(..) {
+            let base_path = Path::new(&crate::application_root_dir()).join(base_path);
+            let (gltf, extensions) = crate::gltf_extensions::read_gltf(&base_path.join(filename))?;
+            gltfs.push(gltf);
+            gltf_extensions.push(extensions);
+            basepaths.push(base_path);
+        }
 
         for (source_index, (gltf, base_path)) in gltfs.iter().zip(basepaths.iter()).enumerate() {
-            let gltf_buffers = asset::GltfBuffers::load_from_gltf(base_path, gltf)?;
+            let buffers = asset::GltfBuffers::load_from_gltf(base_path, gltf)?;
 
             let offsets = gltf_file_offsets[source_index];
             let base_mesh_index = offsets.0;
@@ -168,44 +292,70 @@ impl SceneConfig {
                 material_storage.push(None);
             }
 
+            let mut default_material = None;
             for mesh in gltf.meshes() {
                 asset::load_gltf_mesh(
                     &mesh,
                     256,
                     self.mipmap_model_textures,
+                    self.texture_anisotropy,
+                    self.smooth_generated_normals,
                     base_path,
-                    &gltf_buffers,
+                    &buffers,
+                    &gltf_extensions[source_index],
                     base_mesh_index,
                     base_material_index,
+                    &mut default_material,
                     &mut material_storage,
                     &mut primitive_storage,
                     &mut mesh_storage,
+                    &mut morph_target_storage,
                     factory,
                     queue,
                 )?;
             }
+            asset::load_unused_gltf_materials(
+                gltf,
+                base_path,
+                &buffers,
+                &gltf_extensions[source_index],
+                self.mipmap_model_textures,
+                self.texture_anisotropy,
+                base_material_index,
+                &mut material_storage,
+                factory,
+                queue,
+            )?;
 
             gltf_file_offsets.push((
                 mesh_storage.len(),
                 material_storage.len(),
                 offsets.2 + gltf.nodes().len(),
-            ))
+            ));
+            gltf_buffers.push(buffers);
         }
 
         let mut active_camera_de = false;
         for (i, scene_entity) in self.entities.iter().enumerate() {
             let mut entity_builder = world.create_entity();
 
+            let mut transform_node = None;
             let transform = match &scene_entity.transform {
                 TransformSource::Gltf(gltf_node) => {
                     let src: GltfFileIndex = gltf_node.into();
                     let node: gltf::Node =
                         GltfNodeWrapper::from((&gltfs[src], gltf_node)).try_into()?;
+                    transform_node = Some((src, node.index()));
                     components::Transform::from(node.transform())
                 }
                 TransformSource::Manual(transform) => transform.clone(),
             };
-            entity_builder = entity_builder.with(transform);
+            entity_builder = entity_builder.with(transform.clone());
+
+            let mut skin = None;
+            // The mesh of the entity, and the morph target weights of its node if it has any
+            let mut mesh_handle = None;
+            let mut node_weights = None;
 
             match &scene_entity.mesh {
                 Some(MeshSource::Node(gltf_node)) => {
@@ -219,9 +369,9 @@ impl SceneConfig {
                         "Entity with Combined data refers to node with no Mesh: {:?}",
                         gltf_node
                     ))?;
-                    entity_builder = entity_builder.with(components::Mesh(
-                        gltf_file_offsets[src].0 + node_mesh.index(),
-                    ));
+                    mesh_handle = Some(gltf_file_offsets[src].0 + node_mesh.index());
+                    skin = node.skin().map(|node_skin| (src, node_skin));
+                    node_weights = node.weights();
                 }
                 Some(MeshSource::Mesh(mesh)) => {
                     let mesh = match mesh {
@@ -234,7 +384,7 @@ impl SceneConfig {
                                     mesh
                                 ))?
                                 .index()
-                                + gltf_file_offsets[*src].1,
+                                + gltf_file_offsets[*src].0,
                         ),
                         GltfMesh::Name(src, name) => components::Mesh(
                             gltfs[*src]
@@ -255,32 +405,87 @@ impl SceneConfig {
                                     mesh
                                 ))?
                                 .index()
-                                + gltf_file_offsets[*src].1,
+                                + gltf_file_offsets[*src].0,
                         ),
                     };
-                    entity_builder = entity_builder.with(mesh);
+                    mesh_handle = Some(mesh.0);
                 }
                 None => (),
             }
+            if let Some(mesh_handle) = mesh_handle {
+                entity_builder = entity_builder.with(components::Mesh(mesh_handle));
+                if let Some(weights) = morph_weights(
+                    mesh_storage[mesh_handle].as_ref().unwrap(),
+                    node_weights,
+                    &mut num_morph_weights,
+                ) {
+                    entity_builder = entity_builder.with(weights);
+                }
+            }
 
-            if let Some(light) = &scene_entity.light {
-                entity_builder = entity_builder.with(*light);
+            match &scene_entity.light {
+                Some(LightSource::Gltf {
+                    node: gltf_node,
+                    casts_shadows,
+                }) => {
+                    let src: GltfFileIndex = gltf_node.into();
+                    let node: gltf::Node =
+                        GltfNodeWrapper::from((&gltfs[src], gltf_node)).try_into()?;
+                    let mut light = gltf_extensions[src].node_light(node.index()).ok_or(
+                        failure::format_err!(
+                            "Entity with light refers to node with no light: {:?}",
+                            gltf_node
+                        ),
+                    )?;
+                    light.casts_shadows = *casts_shadows;
+                    entity_builder = entity_builder.with(light);
+                }
+                Some(LightSource::Manual(light)) => {
+                    entity_builder = entity_builder.with(*light);
+                }
+                None => (),
             }
 
-            if let Some(camera_data) = &scene_entity.camera {
-                entity_builder = entity_builder.with(components::Camera {
-                    yaw: camera_data.yaw,
-                    pitch: camera_data.pitch,
-                    dist: camera_data.distance,
-                    focus: nalgebra::Point3::from(camera_data.focus_point),
-                    proj: nalgebra::Perspective3::new(
-                        aspect,
-                        camera_data.fov,
-                        camera_data.znear,
-                        camera_data.zfar,
-                    ),
-                });
-                if camera_data.active {
+            let camera = match &scene_entity.camera {
+                Some(CameraSource::Gltf {
+                    node: gltf_node,
+                    active,
+                }) => {
+                    let src: GltfFileIndex = gltf_node.into();
+                    let node: gltf::Node =
+                        GltfNodeWrapper::from((&gltfs[src], gltf_node)).try_into()?;
+                    let camera = node.camera().ok_or(failure::format_err!(
+                        "Entity with camera refers to node with no camera: {:?}",
+                        gltf_node
+                    ))?;
+                    // A transform taken from a node is relative to the node's parent in the glTF
+                    // file, but the camera is placed where the node is in the file's world
+                    let matrix = match transform_node {
+                        Some((src, node)) => node_world_matrix(&gltfs[src], node),
+                        None => transform.matrix(),
+                    };
+                    Some((orbit_camera(gltf_camera(&camera, aspect), &matrix), *active))
+                }
+                Some(CameraSource::Manual(camera_data)) => Some((
+                    components::Camera {
+                        yaw: camera_data.yaw,
+                        pitch: camera_data.pitch,
+                        dist: camera_data.distance,
+                        focus: nalgebra::Point3::from(camera_data.focus_point),
+                        proj: components::Projection::Perspective(nalgebra::Perspective3::new(
+                            aspect,
+                            camera_data.fov,
+                            camera_data.znear,
+                            camera_data.zfar,
+                        )),
+                    },
+                    camera_data.active,
+                )),
+                None => None,
+            };
+            if let Some((camera, active)) = camera {
+                entity_builder = entity_builder.with(camera);
+                if active {
                     if !active_camera_de {
                         active_camera_de = true;
                         entity_builder = entity_builder.with(components::ActiveCamera);
@@ -290,7 +495,90 @@ impl SceneConfig {
                 }
             }
 
-            scene_entities.push(entity_builder.build());
+            let entity = entity_builder.build();
+            if let Some((src, node)) = transform_node {
+                node_entities[src].entry(node).or_default().push(entity);
+            }
+            if let Some((src, node_skin)) = skin {
+                let skin = load_skin(
+                    world,
+                    &gltfs[src],
+                    &gltf_buffers[src],
+                    &node_skin,
+                    entity,
+                    num_joints,
+                    &mut node_entities[src],
+                )?;
+                num_joints += skin.joints.len();
+                world
+                    .write_storage::<components::Skin>()
+                    .insert(entity, skin)?;
+            }
+            if let Some(gltf_scene) = &scene_entity.gltf_scene {
+                let (src, scene) = match gltf_scene {
+                    GltfScene::Index(src, idx) => (
+                        *src,
+                        gltfs.get(*src).and_then(|gltf| gltf.scenes().nth(*idx)),
+                    ),
+                    GltfScene::Name(src, name) => (
+                        *src,
+                        gltfs.get(*src).and_then(|gltf| {
+                            gltf.scenes()
+                                .find(|scene| scene.name() == Some(name.as_str()))
+                        }),
+                    ),
+                };
+                let scene = scene.ok_or(failure::format_err!(
+                    "GltfScene refers to scene that does not exist: {:?}",
+                    gltf_scene
+                ))?;
+                let active_camera = match &scene_entity.active_camera {
+                    Some(gltf_node) => {
+                        if GltfFileIndex::from(gltf_node) != src {
+                            failure::bail!(
+                                "Active camera {:?} is not in the file of scene {:?}",
+                                gltf_node,
+                                gltf_scene
+                            );
+                        }
+                        let node: gltf::Node =
+                            GltfNodeWrapper::from((&gltfs[src], gltf_node)).try_into()?;
+                        if node.camera().is_none() {
+                            failure::bail!(
+                                "Active camera refers to node with no camera: {:?}",
+                                gltf_node
+                            );
+                        }
+                        if active_camera_de {
+                            failure::bail!("Attempted to load multiple active cameras");
+                        }
+                        active_camera_de = true;
+                        Some(node.index())
+                    }
+                    None => None,
+                };
+                load_gltf_scene(
+                    world,
+                    &scene,
+                    &gltf_buffers[src],
+                    &gltf_extensions[src],
+                    gltf_file_offsets[src].0,
+                    &mesh_storage,
+                    aspect,
+                    entity,
+                    active_camera,
+                    &mut num_joints,
+                    &mut num_morph_weights,
+                    &mut node_entities[src],
+                )?;
+            } else if let Some(gltf_node) = &scene_entity.active_camera {
+                failure::bail!(
+                    "Entity {} has an active camera {:?} but no gltf_scene",
+                    i,
+                    gltf_node
+                );
+            }
+            scene_entities.push(entity);
         }
 
         for (i, scene_entity) in self.entities.iter().enumerate() {
@@ -303,6 +591,28 @@ impl SceneConfig {
             }
         }
 
+        let mut animations = animation::Animations::default();
+        for gltf_animation in self.animations.iter() {
+            let (src, animation) = match gltf_animation {
+                GltfAnimation::Index(src, idx) => (*src, gltfs[*src].animations().nth(*idx)),
+                GltfAnimation::Name(src, name) => (
+                    *src,
+                    gltfs[*src]
+                        .animations()
+                        .find(|animation| animation.name() == Some(name.as_str())),
+                ),
+            };
+            let animation = animation.ok_or(failure::format_err!(
+                "GltfAnimation refers to animation that does not exist: {:?}",
+                gltf_animation
+            ))?;
+            animations.clips.push(animation::AnimationClip::load(
+                &animation,
+                &gltf_buffers[src],
+                &node_entities[src],
+            )?);
+        }
+
         let material_storage = asset::MaterialStorage(
             material_storage
                 .into_iter()
@@ -326,11 +636,291 @@ impl SceneConfig {
             material_storage,
             primitive_storage,
             mesh_storage,
+            asset::MorphTargetStorage(morph_target_storage),
+            animations,
             scene_entities,
         ))
     }
 }
 
+/// The morph target weights of an instance of `mesh`, starting from the weights of its node if
+/// it has them, or `None` if the mesh has no morph targets. The weights are placed after the
+/// `num_morph_weights` already in use.
+fn morph_weights(
+    mesh: &asset::Mesh,
+    node_weights: Option<&[f32]>,
+    num_morph_weights: &mut usize,
+) -> Option<components::MorphWeights> {
+    if mesh.default_weights.is_empty() {
+        return None;
+    }
+    let mut weights = mesh.default_weights.clone();
+    if let Some(node_weights) = node_weights {
+        for (weight, node_weight) in weights.iter_mut().zip(node_weights) {
+            *weight = *node_weight;
+        }
+    }
+    let weight_offset = *num_morph_weights;
+    *num_morph_weights += weights.len();
+    Some(components::MorphWeights {
+        weights,
+        weight_offset,
+    })
+}
+
+/// A camera component for a glTF camera. The projection uses the aspect ratio of the window
+/// rather than that of the camera, and the camera doesn't orbit, see `orbit_camera`.
+fn gltf_camera(camera: &gltf::Camera<'_>, aspect: f32) -> components::Camera {
+    let proj = match camera.projection() {
+        gltf::camera::Projection::Perspective(perspective) => {
+            components::Projection::Perspective(nalgebra::Perspective3::new(
+                aspect,
+                perspective.yfov(),
+                perspective.znear(),
+                // Infinite projections aren't supported, so fall back to a far plane
+                perspective.zfar().unwrap_or(perspective.znear() * 1.0e5),
+            ))
+        }
+        gltf::camera::Projection::Orthographic(orthographic) => {
+            let ymag = orthographic.ymag();
+            components::Projection::Orthographic(nalgebra::Orthographic3::new(
+                -ymag * aspect,
+                ymag * aspect,
+                -ymag,
+                ymag,
+                orthographic.znear(),
+                orthographic.zfar(),
+            ))
+        }
+    };
+    components::Camera {
+        yaw: 0.0,
+        pitch: 0.0,
+        dist: 0.0,
+        focus: nalgebra::Point3::origin(),
+        proj,
+    }
+}
+
+/// Sets up `camera` to orbit around the point in front of it which is as far away as the
+/// origin, so that it starts out where `matrix` places it, apart from any roll and scale
+fn orbit_camera(
+    mut camera: components::Camera,
+    matrix: &nalgebra::Matrix4<f32>,
+) -> components::Camera {
+    let eye = nalgebra::Point3::from(matrix.column(3).xyz());
+    let forward = -matrix.column(2).xyz().normalize();
+    camera.dist = eye.coords.norm().max(0.1);
+    camera.focus = eye + forward * camera.dist;
+    camera.pitch = (-forward.y).max(-1.0).min(1.0).asin();
+    camera.yaw = (-forward.x).atan2(-forward.z);
+    camera
+}
+
+/// The parent of each node of `gltf`, by node index
+fn node_parents(gltf: &gltf::Gltf) -> Vec<Option<usize>> {
+    let mut parents = vec![None; gltf.nodes().len()];
+    for node in gltf.nodes() {
+        for child in node.children() {
+            parents[child.index()] = Some(node.index());
+        }
+    }
+    parents
+}
+
+/// The matrix placing `node` in the world of `gltf`, which is the product of the matrices of
+/// the node and its ancestors
+fn node_world_matrix(gltf: &gltf::Gltf, node: usize) -> nalgebra::Matrix4<f32> {
+    let parents = node_parents(gltf);
+    let mut matrix = nalgebra::Matrix4::identity();
+    let mut current = Some(node);
+    while let Some(index) = current {
+        let transform = gltf.nodes().nth(index).unwrap().transform();
+        matrix = nalgebra::Matrix4::from(transform.matrix()) * matrix;
+        current = parents[index];
+    }
+    matrix
+}
+
+/// Creates an entity for every node of `scene`, with the root nodes parented to `root` and the
+/// rest to their parent nodes. The skins of the nodes use the joint entities of the scene. The
+/// camera of the `active_camera` node, which must be in the scene, becomes the active camera.
+fn load_gltf_scene(
+    world: &mut specs::World,
+    scene: &gltf::Scene<'_>,
+    buffers: &asset::GltfBuffers,
+    extensions: &crate::gltf_extensions::GltfExtensions,
+    base_mesh_index: usize,
+    mesh_storage: &[Option<asset::Mesh>],
+    aspect: f32,
+    root: specs::Entity,
+    active_camera: Option<usize>,
+    num_joints: &mut usize,
+    num_morph_weights: &mut usize,
+    node_entities: &mut animation::NodeEntities,
+) -> Result<(), failure::Error> {
+    // The entity of each node of this instance of the scene
+    let mut scene_nodes = std::collections::HashMap::new();
+    let mut skinned = Vec::new();
+    let mut nodes = scene.nodes().map(|node| (node, root)).collect::<Vec<_>>();
+    while let Some((node, parent)) = nodes.pop() {
+        let transform = components::Transform::from(node.transform());
+        let mut entity_builder = world
+            .create_entity()
+            .with(transform.clone())
+            .with(components::Parent::new(parent));
+        if let Some(mesh) = node.mesh() {
+            let mesh_handle = base_mesh_index + mesh.index();
+            entity_builder = entity_builder.with(components::Mesh(mesh_handle));
+            if let Some(weights) = morph_weights(
+                mesh_storage[mesh_handle].as_ref().unwrap(),
+                node.weights(),
+                num_morph_weights,
+            ) {
+                entity_builder = entity_builder.with(weights);
+            }
+        }
+        if let Some(camera) = node.camera() {
+            // The camera's transform is relative to its parent, so it orbits in the parent's
+            // space, starting from where the node is placed
+            entity_builder = entity_builder.with(orbit_camera(
+                gltf_camera(&camera, aspect),
+                &transform.matrix(),
+            ));
+            if active_camera == Some(node.index()) {
+                entity_builder = entity_builder.with(components::ActiveCamera);
+            }
+        }
+        if let Some(light) = extensions.node_light(node.index()) {
+            entity_builder = entity_builder.with(light);
+        }
+        let entity = entity_builder.build();
+
+        scene_nodes.insert(node.index(), entity);
+        node_entities.entry(node.index()).or_default().push(entity);
+        if let Some(skin) = node.skin() {
+            skinned.push((entity, skin));
+        }
+        nodes.extend(node.children().map(|child| (child, entity)));
+    }
+    if let Some(active_camera) = active_camera {
+        if !scene_nodes.contains_key(&active_camera) {
+            failure::bail!(
+                "Active camera node {} is not in scene {}",
+                active_camera,
+                scene.index()
+            );
+        }
+    }
+
+    // The joints are nodes of the scene, so skins are loaded once all of them exist
+    for (entity, skin) in skinned {
+        let joints = skin
+            .joints()
+            .map(|joint| {
+                scene_nodes
+                    .get(&joint.index())
+                    .cloned()
+                    .ok_or(failure::format_err!(
+                        "Joint {} of skin {} is not in scene {}",
+                        joint.index(),
+                        skin.index(),
+                        scene.index()
+                    ))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let inverse_bind_matrices = inverse_bind_matrices(&skin, buffers, joints.len())?;
+        let joint_offset = *num_joints;
+        *num_joints += joints.len();
+        world.write_storage::<components::Skin>().insert(
+            entity,
+            components::Skin {
+                joints,
+                inverse_bind_matrices,
+                joint_offset,
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// Creates entities for the joints of `skin` and the nodes above them, with the root nodes
+/// parented to `entity`. Returns the skin component of `entity`, whose joint matrices start at
+/// `joint_offset`.
+fn load_skin(
+    world: &mut specs::World,
+    gltf: &gltf::Gltf,
+    buffers: &asset::GltfBuffers,
+    skin: &gltf::Skin<'_>,
+    entity: specs::Entity,
+    joint_offset: usize,
+    node_entities: &mut animation::NodeEntities,
+) -> Result<components::Skin, failure::Error> {
+    let parents = node_parents(gltf);
+
+    let mut skeleton = std::collections::HashMap::new();
+    let mut joints = Vec::new();
+    for joint in skin.joints() {
+        // Create the missing nodes on the path from the root to the joint, top down
+        let mut path = vec![joint.index()];
+        while let Some(parent) = parents[path[path.len() - 1]] {
+            if skeleton.contains_key(&parent) {
+                break;
+            }
+            path.push(parent);
+        }
+        for &node_index in path.iter().rev() {
+            if skeleton.contains_key(&node_index) {
+                continue;
+            }
+            let node = gltf.nodes().nth(node_index).unwrap();
+            let parent = parents[node_index].map_or(entity, |parent| skeleton[&parent]);
+            let node_entity = world
+                .create_entity()
+                .with(components::Transform::from(node.transform()))
+                .with(components::Parent::new(parent))
+                .build();
+            skeleton.insert(node_index, node_entity);
+            node_entities
+                .entry(node_index)
+                .or_default()
+                .push(node_entity);
+        }
+        joints.push(skeleton[&joint.index()]);
+    }
+
+    let inverse_bind_matrices = inverse_bind_matrices(skin, buffers, joints.len())?;
+
+    Ok(components::Skin {
+        joints,
+        inverse_bind_matrices,
+        joint_offset,
+    })
+}
+
+/// The inverse bind matrices of the `num_joints` joints of `skin`
+fn inverse_bind_matrices(
+    skin: &gltf::Skin<'_>,
+    buffers: &asset::GltfBuffers,
+    num_joints: usize,
+) -> Result<Vec<nalgebra::Matrix4<f32>>, failure::Error> {
+    let inverse_bind_matrices = match skin
+        .reader(|buffer| buffers.buffer(&buffer))
+        .read_inverse_bind_matrices()
+    {
+        Some(matrices) => matrices.map(nalgebra::Matrix4::from).collect::<Vec<_>>(),
+        None => vec![nalgebra::Matrix4::identity(); num_joints],
+    };
+    if inverse_bind_matrices.len() != num_joints {
+        failure::bail!(
+            "Skin has {} joints but {} inverse bind matrices",
+            num_joints,
+            inverse_bind_matrices.len()
+        );
+    }
+    Ok(inverse_bind_matrices)
+}
+
 impl From<&GltfNode> for GltfFileIndex {
     fn from(node: &GltfNode) -> Self {
         match node {
@@ -1,5 +1,5 @@
-use crate::{asset, components, input, node};
-use nalgebra::Similarity3;
+use crate::{asset, components, environment, input, node};
+use nalgebra::Isometry3;
 use rendy::{hal, init::winit};
 use specs::{prelude::*, storage::UnprotectedStorage};
 
@@ -119,32 +119,187 @@ impl<'a> System<'a> for PbrAuxInputSystem {
                                         ElementState::Pressed,
                                         ModifiersState { shift: false, .. },
                                     ) => {
-                                        aux.tonemapper_args.exposure +=
-                                            input::EXPOSURE_ADJUST_SENSITIVITY;
+                                        if aux.auto_exposure.enabled {
+                                            aux.auto_exposure.compensation +=
+                                                input::EXPOSURE_ADJUST_SENSITIVITY;
+                                        } else {
+                                            aux.tonemapper_args.exposure +=
+                                                input::EXPOSURE_ADJUST_SENSITIVITY;
+                                        }
                                     }
                                     (
                                         VirtualKeyCode::E,
                                         ElementState::Pressed,
                                         ModifiersState { shift: true, .. },
                                     ) => {
-                                        aux.tonemapper_args.exposure -=
-                                            input::EXPOSURE_ADJUST_SENSITIVITY;
+                                        if aux.auto_exposure.enabled {
+                                            aux.auto_exposure.compensation -=
+                                                input::EXPOSURE_ADJUST_SENSITIVITY;
+                                        } else {
+                                            aux.tonemapper_args.exposure -=
+                                                input::EXPOSURE_ADJUST_SENSITIVITY;
+                                        }
                                     }
                                     (
-                                        VirtualKeyCode::A,
+                                        VirtualKeyCode::H,
                                         ElementState::Pressed,
                                         ModifiersState { .. },
-                                    ) => aux.tonemapper_args.curve = 0,
+                                    ) => aux.auto_exposure.enabled = !aux.auto_exposure.enabled,
+                                    (
+                                        VirtualKeyCode::A,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: false, .. },
+                                    ) => {
+                                        aux.tonemapper_args.operator =
+                                            aux.tonemapper_args.operator.next();
+                                    }
+                                    (
+                                        VirtualKeyCode::A,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => {
+                                        aux.tonemapper_args.operator =
+                                            aux.tonemapper_args.operator.previous();
+                                    }
                                     (
                                         VirtualKeyCode::U,
                                         ElementState::Pressed,
-                                        ModifiersState { .. },
-                                    ) => aux.tonemapper_args.curve = 1,
+                                        ModifiersState { shift: false, .. },
+                                    ) => {
+                                        aux.tonemapper_args.comparison_operator =
+                                            aux.tonemapper_args.comparison_operator.next();
+                                    }
+                                    (
+                                        VirtualKeyCode::U,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => {
+                                        aux.tonemapper_args.comparison_operator =
+                                            aux.tonemapper_args.comparison_operator.previous();
+                                    }
                                     (
                                         VirtualKeyCode::C,
                                         ElementState::Pressed,
                                         ModifiersState { .. },
-                                    ) => aux.tonemapper_args.curve = 2,
+                                    ) => aux.tonemapper_args.compare = !aux.tonemapper_args.compare,
+                                    // Bloom controls
+                                    (
+                                        VirtualKeyCode::B,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: false, .. },
+                                    ) => {
+                                        aux.bloom_args.intensity +=
+                                            input::BLOOM_INTENSITY_SENSITIVITY;
+                                    }
+                                    (
+                                        VirtualKeyCode::B,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => {
+                                        aux.bloom_args.intensity -=
+                                            input::BLOOM_INTENSITY_SENSITIVITY;
+                                        aux.bloom_args.intensity =
+                                            aux.bloom_args.intensity.max(0.0);
+                                    }
+                                    (
+                                        VirtualKeyCode::T,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: false, .. },
+                                    ) => {
+                                        aux.bloom_args.threshold +=
+                                            input::BLOOM_THRESHOLD_SENSITIVITY;
+                                    }
+                                    (
+                                        VirtualKeyCode::T,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => {
+                                        aux.bloom_args.threshold -=
+                                            input::BLOOM_THRESHOLD_SENSITIVITY;
+                                        aux.bloom_args.threshold =
+                                            aux.bloom_args.threshold.max(0.0);
+                                    }
+                                    (
+                                        VirtualKeyCode::R,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: false, .. },
+                                    ) => {
+                                        aux.bloom_args.radius += input::BLOOM_RADIUS_SENSITIVITY;
+                                    }
+                                    (
+                                        VirtualKeyCode::R,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => {
+                                        aux.bloom_args.radius -= input::BLOOM_RADIUS_SENSITIVITY;
+                                        aux.bloom_args.radius = aux.bloom_args.radius.max(0.1);
+                                    }
+                                    // Color grading controls
+                                    (
+                                        VirtualKeyCode::W,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: false, .. },
+                                    ) => {
+                                        aux.color_grading.temperature +=
+                                            input::COLOR_TEMPERATURE_SENSITIVITY;
+                                        aux.color_grading.temperature =
+                                            aux.color_grading.temperature.min(1.0);
+                                    }
+                                    (
+                                        VirtualKeyCode::W,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => {
+                                        aux.color_grading.temperature -=
+                                            input::COLOR_TEMPERATURE_SENSITIVITY;
+                                        aux.color_grading.temperature =
+                                            aux.color_grading.temperature.max(-1.0);
+                                    }
+                                    (
+                                        VirtualKeyCode::O,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: false, .. },
+                                    ) => {
+                                        aux.color_grading.contrast += input::CONTRAST_SENSITIVITY;
+                                    }
+                                    (
+                                        VirtualKeyCode::O,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => {
+                                        aux.color_grading.contrast -= input::CONTRAST_SENSITIVITY;
+                                        aux.color_grading.contrast =
+                                            aux.color_grading.contrast.max(0.0);
+                                    }
+                                    (
+                                        VirtualKeyCode::P,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: false, .. },
+                                    ) => {
+                                        aux.color_grading.saturation +=
+                                            input::SATURATION_SENSITIVITY;
+                                    }
+                                    (
+                                        VirtualKeyCode::P,
+                                        ElementState::Pressed,
+                                        ModifiersState { shift: true, .. },
+                                    ) => {
+                                        aux.color_grading.saturation -=
+                                            input::SATURATION_SENSITIVITY;
+                                        aux.color_grading.saturation =
+                                            aux.color_grading.saturation.max(0.0);
+                                    }
+                                    (
+                                        VirtualKeyCode::L,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => aux.color_lut_enabled = !aux.color_lut_enabled,
+                                    // Anti-aliasing controls
+                                    (
+                                        VirtualKeyCode::J,
+                                        ElementState::Pressed,
+                                        ModifiersState { .. },
+                                    ) => aux.taa_args.enabled = !aux.taa_args.enabled,
                                     // Environment Cube map display
                                     (
                                         VirtualKeyCode::M,
@@ -182,7 +337,7 @@ impl<'a> System<'a> for PbrAuxInputSystem {
                                         aux.cube_roughness += input::CUBE_ROUGHNESS_SENSITIVITY;
                                         aux.cube_roughness = aux
                                             .cube_roughness
-                                            .min(crate::SPEC_CUBEMAP_MIP_LEVELS as f32 - 1.0);
+                                            .min(aux.spec_cubemap_mip_levels as f32 - 1.0);
                                     }
                                     _ => (),
                                 }
@@ -197,6 +352,71 @@ impl<'a> System<'a> for PbrAuxInputSystem {
     }
 }
 
+/// Cycles through the environment maps of the scene with N (hold shift to go backwards)
+pub struct EnvironmentInputSystem;
+
+impl<'a> System<'a> for EnvironmentInputSystem {
+    type SystemData = (
+        Read<'a, input::EventBucket>,
+        Write<'a, environment::EnvironmentList>,
+    );
+
+    fn run(&mut self, (events, mut environments): Self::SystemData) {
+        use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+
+        for event in events.0.iter() {
+            if let Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                virtual_keycode: Some(VirtualKeyCode::N),
+                                state: ElementState::Pressed,
+                                modifiers,
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } = event
+            {
+                if modifiers.shift {
+                    environments.request_previous();
+                } else {
+                    environments.request_next();
+                }
+            }
+        }
+    }
+}
+
+/// Requests loading the glTF files dropped onto the window
+pub struct GltfDropSystem;
+
+impl<'a> System<'a> for GltfDropSystem {
+    type SystemData = (
+        Read<'a, input::EventBucket>,
+        Write<'a, asset::GltfLoadRequests>,
+    );
+
+    fn run(&mut self, (events, mut requests): Self::SystemData) {
+        use winit::event::{Event, WindowEvent};
+
+        for event in events.0.iter() {
+            if let Event::WindowEvent {
+                event: WindowEvent::DroppedFile(path),
+                ..
+            } = event
+            {
+                match path.extension().and_then(|extension| extension.to_str()) {
+                    Some("gltf") | Some("glb") => requests.0.push(path.clone()),
+                    _ => log::warn!("Ignoring dropped file {:?}, which isn't glTF", path),
+                }
+            }
+        }
+    }
+}
+
 pub struct CameraInputSystem;
 
 impl<'a> System<'a> for CameraInputSystem {
@@ -256,7 +476,7 @@ impl<'a> System<'a> for CameraInputSystem {
                                         delta.1 as f32,
                                         0.0,
                                     ) * TRANSLATE_SENSITIVITY;
-                                    let rot = transform.0.isometry.rotation;
+                                    let rot = transform.isometry.rotation;
                                     let m_vec = rot * m_vec;
                                     camera.focus = camera.focus + m_vec;
                                 }
@@ -300,19 +520,148 @@ impl<'a> System<'a> for CameraInputSystem {
                         camera.yaw.cos() * camera.pitch.cos(),
                     ));
 
-            transform.0 = Similarity3::from_parts(
+            transform.isometry = Isometry3::from_parts(
                 nalgebra::Translation::from(eye.coords.clone()),
                 // Invert direction for right handed
                 nalgebra::UnitQuaternion::face_towards(
                     &(eye - camera.focus),
                     &nalgebra::Vector3::y(),
                 ),
-                1.0,
             );
         }
     }
 }
 
+/// Fits the shadow maps of the shadow casting lights to the active camera
+pub struct ShadowMapUpdateSystem;
+
+impl<'a> System<'a> for ShadowMapUpdateSystem {
+    type SystemData = (
+        Entities<'a>,
+        Write<'a, node::pbr::shadow::ShadowMaps>,
+        ReadStorage<'a, components::Light>,
+        ReadStorage<'a, components::GlobalTransform>,
+        ReadStorage<'a, components::ActiveCamera>,
+        ReadStorage<'a, components::Camera>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mut shadow_maps, lights, transforms, active_cameras, cameras): Self::SystemData,
+    ) {
+        shadow_maps.warn_unassigned(&entities, &lights);
+        if let Some((_, camera, camera_transform)) =
+            (&active_cameras, &cameras, &transforms).join().next()
+        {
+            shadow_maps.update(&lights, &transforms, camera, camera_transform);
+        }
+    }
+}
+
+/// Moves the projection jitter on to the next sample
+pub struct JitterUpdateSystem;
+
+impl<'a> System<'a> for JitterUpdateSystem {
+    type SystemData = (Read<'a, node::pbr::Aux>, Write<'a, node::pbr::taa::Jitter>);
+
+    fn run(&mut self, (aux, mut jitter): Self::SystemData) {
+        jitter.advance(aux.taa_args.enabled);
+    }
+}
+
+/// The joint matrices of every skin in the scene, for this frame and the previous one. Each
+/// `components::Skin` owns the range starting at its `joint_offset`.
+#[derive(Debug, Default)]
+pub struct JointMatrices {
+    pub current: Vec<nalgebra::Matrix4<f32>>,
+    pub previous: Vec<nalgebra::Matrix4<f32>>,
+}
+
+impl JointMatrices {
+    pub fn new(num_joints: usize) -> Self {
+        JointMatrices {
+            current: vec![nalgebra::Matrix4::identity(); num_joints],
+            previous: vec![nalgebra::Matrix4::identity(); num_joints],
+        }
+    }
+}
+
+/// Computes the joint matrices of the skins from the transforms of their joints
+pub struct SkinningSystem;
+
+impl<'a> System<'a> for SkinningSystem {
+    type SystemData = (
+        Write<'a, JointMatrices>,
+        ReadStorage<'a, components::Skin>,
+        ReadStorage<'a, components::GlobalTransform>,
+        ReadStorage<'a, components::PreviousGlobalTransform>,
+    );
+
+    fn run(
+        &mut self,
+        (mut joint_matrices, skins, transforms, previous_transforms): Self::SystemData,
+    ) {
+        let JointMatrices { current, previous } = &mut *joint_matrices;
+        for skin in skins.join() {
+            let joints = skin.joints.iter().zip(skin.inverse_bind_matrices.iter());
+            for (i, (joint, inverse_bind_matrix)) in joints.enumerate() {
+                let transform = transforms
+                    .get(*joint)
+                    .map_or(nalgebra::Matrix4::identity(), |transform| transform.0);
+                let previous_transform = previous_transforms
+                    .get(*joint)
+                    .map_or(transform, |previous| previous.0);
+                current[skin.joint_offset + i] = transform * inverse_bind_matrix;
+                previous[skin.joint_offset + i] = previous_transform * inverse_bind_matrix;
+            }
+        }
+    }
+}
+
+/// The morph target weights of every morphed mesh instance, for this frame and the previous
+/// one. Each `components::MorphWeights` owns the range starting at its `weight_offset`.
+#[derive(Debug, Default)]
+pub struct InstanceMorphWeights {
+    pub current: Vec<f32>,
+    pub previous: Vec<f32>,
+}
+
+impl InstanceMorphWeights {
+    pub fn new(num_weights: usize) -> Self {
+        InstanceMorphWeights {
+            current: vec![0.0; num_weights],
+            previous: vec![0.0; num_weights],
+        }
+    }
+}
+
+/// Gathers the morph target weights of the mesh instances into `InstanceMorphWeights`
+#[derive(Default)]
+pub struct MorphWeightSystem {
+    initialized: bool,
+}
+
+impl<'a> System<'a> for MorphWeightSystem {
+    type SystemData = (
+        Write<'a, InstanceMorphWeights>,
+        ReadStorage<'a, components::MorphWeights>,
+    );
+
+    fn run(&mut self, (mut instance_weights, morph_weights): Self::SystemData) {
+        let InstanceMorphWeights { current, previous } = &mut *instance_weights;
+        previous.copy_from_slice(current);
+        for weights in morph_weights.join() {
+            let offset = weights.weight_offset;
+            current[offset..offset + weights.weights.len()].copy_from_slice(&weights.weights);
+        }
+        // There is no previous frame to move from on the first one
+        if !self.initialized {
+            previous.copy_from_slice(current);
+            self.initialized = true;
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct HelmetArrayEntities(pub Vec<Entity>);
 
@@ -328,7 +677,7 @@ impl HelmetArraySize {
         self.x as usize * self.y as usize * self.z as usize
     }
 
-    pub fn generate_transforms(&self) -> Vec<nalgebra::Similarity3<f32>> {
+    pub fn generate_transforms(&self) -> Vec<Isometry3<f32>> {
         let x_size = 3.0;
         let y_size = 4.0;
         let z_size = 4.0;
@@ -336,14 +685,13 @@ impl HelmetArraySize {
         for x in 0..self.x {
             for y in 0..self.y {
                 for z in 0..self.z {
-                    transforms.push(nalgebra::Similarity3::from_parts(
+                    transforms.push(Isometry3::from_parts(
                         nalgebra::Translation3::new(
                             (x as f32 * x_size) - (x_size * (self.x - 1) as f32 * 0.5),
                             (y as f32 * y_size) - (y_size * (self.y - 1) as f32 * 0.5),
                             (z as f32 * z_size) - (z_size * (self.z - 1) as f32 * 0.5),
                         ),
                         nalgebra::UnitQuaternion::identity(),
-                        1.0,
                     ));
                 }
             }
@@ -429,7 +777,7 @@ impl<'a> System<'a> for HelmetArraySizeUpdateSystem {
             {
                 if let Ok(entry) = transforms.entry(*entity) {
                     let entity_transform = entry.or_insert(Default::default());
-                    entity_transform.0 = transform
+                    entity_transform.isometry = transform
                 }
                 if let Ok(entry) = meshes.entry(*entity) {
                     entry.or_insert(components::Mesh(self.helmet_mesh));
@@ -461,6 +809,7 @@ pub struct InstanceCacheUpdateSystem<B> {
     pub previous_frame: usize,
     pub mesh_reader_id: ReaderId<ComponentEvent>,
     pub transform_reader_id: ReaderId<ComponentEvent>,
+    pub previous_transform_reader_id: ReaderId<ComponentEvent>,
     pub dirty_entities_scratch: BitSet,
     pub dirty_mesh_indirects_scratch: HashSet<asset::MeshHandle>,
     pub mesh_inserted: BitSet,
@@ -477,8 +826,10 @@ impl<'a, B: hal::Backend> System<'a> for InstanceCacheUpdateSystem<B> {
         Read<'a, asset::MeshStorage>,
         Write<'a, MeshInstanceStorage>,
         Read<'a, asset::PrimitiveStorage<B>>,
+        Read<'a, asset::MaterialStorage<B>>,
         ReadStorage<'a, components::Mesh>,
         ReadStorage<'a, components::GlobalTransform>,
+        ReadStorage<'a, components::PreviousGlobalTransform>,
     );
 
     fn run(
@@ -489,10 +840,20 @@ impl<'a, B: hal::Backend> System<'a> for InstanceCacheUpdateSystem<B> {
             mesh_storage,
             mut mesh_instance_storage,
             primitive_storage,
+            material_storage,
             meshes,
             transforms,
+            previous_transforms,
         ): Self::SystemData,
     ) {
+        // Meshes and materials may have been loaded since the last run
+        cache.mesh_instance_counts.resize(mesh_storage.0.len(), 0);
+        cache
+            .material_bitsets
+            .resize(material_storage.0.len(), BitSet::new());
+        self.mesh_entity_bitsets
+            .resize(mesh_storage.0.len(), BitSet::new());
+
         cache.dirty_entities[self.previous_frame].clear();
         cache.dirty_mesh_indirects[self.previous_frame].clear();
         self.dirty_entities_scratch.clear();
@@ -524,6 +885,19 @@ impl<'a, B: hal::Backend> System<'a> for InstanceCacheUpdateSystem<B> {
                     _ => (),
                 };
             }
+            let events = previous_transforms
+                .channel()
+                .read(&mut self.previous_transform_reader_id);
+            for event in events {
+                match event {
+                    ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) => {
+                        if mesh_mask.contains(*id) {
+                            self.dirty_entities_scratch.add(*id);
+                        }
+                    }
+                    _ => (),
+                };
+            }
         }
         for (entity, _) in (&entities, &self.mesh_deleted).join() {
             let MeshInstance { mesh, instance } =
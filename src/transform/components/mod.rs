@@ -2,7 +2,7 @@
 
 pub use self::{
     parent::{HierarchyEvent, Parent, ParentHierarchy},
-    transform::{Transform, GlobalTransform},
+    transform::{GlobalTransform, PreviousGlobalTransform, Transform},
 };
 
 mod parent;
@@ -2,7 +2,8 @@
 use std::fmt;
 
 use nalgebra::{
-    self as na, Matrix4, Quaternion, Similarity3, Translation3, Unit, UnitQuaternion, Vector3,
+    self as na, Isometry3, Matrix3, Matrix4, Quaternion, Rotation3, Translation3, Unit,
+    UnitQuaternion, Vector3,
 };
 use serde::{
     de::{self, Deserializer, MapAccess, SeqAccess, Visitor},
@@ -30,12 +31,66 @@ impl Default for GlobalTransform {
     }
 }
 
+/// The `GlobalTransform` of an entity in the previous frame
+#[derive(Debug, Copy, Clone)]
+pub struct PreviousGlobalTransform(pub Matrix4<f32>);
+
+impl Component for PreviousGlobalTransform {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+/// A translation, rotation and scale, applied to a point in the reverse order
 #[derive(Clone, Debug, PartialEq)]
-pub struct Transform(pub Similarity3<f32>);
+pub struct Transform {
+    pub isometry: Isometry3<f32>,
+    /// Scale along each local axis, which may be negative to mirror along it
+    pub scale: Vector3<f32>,
+}
 
 impl Transform {
-    pub fn new(position: Translation3<f32>, rotation: UnitQuaternion<f32>, scale: f32) -> Self {
-        Transform(Similarity3::from_parts(position, rotation, scale))
+    pub fn new(
+        position: Translation3<f32>,
+        rotation: UnitQuaternion<f32>,
+        scale: Vector3<f32>,
+    ) -> Self {
+        Transform {
+            isometry: Isometry3::from_parts(position, rotation),
+            scale,
+        }
+    }
+
+    /// Decomposes an affine matrix without shear into a transform. A mirroring matrix is
+    /// decomposed with a negative x scale.
+    pub fn from_matrix(matrix: &Matrix4<f32>) -> Self {
+        let linear = matrix.fixed_slice::<na::U3, na::U3>(0, 0).into_owned();
+        let mut scale = Vector3::new(
+            linear.column(0).norm(),
+            linear.column(1).norm(),
+            linear.column(2).norm(),
+        );
+        if linear.determinant() < 0.0 {
+            scale.x = -scale.x;
+        }
+        let rotation = if scale.iter().all(|s| s.abs() > std::f32::EPSILON) {
+            let rotation = Matrix3::from_columns(&[
+                linear.column(0) / scale.x,
+                linear.column(1) / scale.y,
+                linear.column(2) / scale.z,
+            ]);
+            UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(rotation))
+        } else {
+            UnitQuaternion::identity()
+        };
+        Transform::new(
+            Translation3::new(matrix[(0, 3)], matrix[(1, 3)], matrix[(2, 3)]),
+            rotation,
+            scale,
+        )
+    }
+
+    /// The matrix mapping local coordinates to the coordinates of the parent
+    pub fn matrix(&self) -> Matrix4<f32> {
+        self.isometry.to_homogeneous() * Matrix4::new_nonuniform_scaling(&self.scale)
     }
 }
 
@@ -43,20 +98,20 @@ impl From<gltf::scene::Transform> for Transform {
     fn from(transform: gltf::scene::Transform) -> Self {
         use gltf::scene::Transform as GltfTransform;
         match transform {
-            GltfTransform::Matrix { .. } => unimplemented!(),
+            GltfTransform::Matrix { matrix } => Transform::from_matrix(&Matrix4::from(matrix)),
             GltfTransform::Decomposed {
                 translation,
                 rotation,
                 scale,
             } => Transform::new(
-                nalgebra::Translation3::new(translation[0], translation[1], translation[2]),
-                nalgebra::UnitQuaternion::from_quaternion(nalgebra::Quaternion::new(
+                Translation3::new(translation[0], translation[1], translation[2]),
+                UnitQuaternion::from_quaternion(Quaternion::new(
                     rotation[3],
                     rotation[0],
                     rotation[1],
                     rotation[2],
                 )),
-                scale.iter().sum::<f32>() / 3.0,
+                Vector3::from(scale),
             ),
         }
     }
@@ -64,7 +119,10 @@ impl From<gltf::scene::Transform> for Transform {
 
 impl Default for Transform {
     fn default() -> Self {
-        Transform(Similarity3::identity())
+        Transform {
+            isometry: Isometry3::identity(),
+            scale: Vector3::repeat(1.0),
+        }
     }
 }
 
@@ -74,7 +132,27 @@ impl Component for Transform {
 
 impl From<Vector3<f32>> for Transform {
     fn from(translation: Vector3<f32>) -> Self {
-        Transform(Similarity3::new(translation, na::zero(), 0.0))
+        Transform {
+            isometry: Isometry3::new(translation, na::zero()),
+            scale: Vector3::repeat(1.0),
+        }
+    }
+}
+
+/// A scale in a scene file, either a single factor for all axes or one per axis
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Scale {
+    Uniform(f32),
+    NonUniform([f32; 3]),
+}
+
+impl From<Scale> for Vector3<f32> {
+    fn from(scale: Scale) -> Self {
+        match scale {
+            Scale::Uniform(scale) => Vector3::repeat(scale),
+            Scale::NonUniform(scale) => Vector3::from(scale),
+        }
     }
 }
 
@@ -111,11 +189,11 @@ impl<'de> Deserialize<'de> for Transform {
                 let rotation: [f32; 4] = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(1, &self))?;
-                let scale: f32 = seq
+                let scale: Scale = seq
                     .next_element()?
                     .ok_or_else(|| de::Error::invalid_length(2, &self))?;
 
-                Ok(Transform(Similarity3::from_parts(
+                Ok(Transform::new(
                     Translation3::new(translation[0], translation[1], translation[2]),
                     Unit::new_normalize(Quaternion::new(
                         rotation[0],
@@ -123,8 +201,8 @@ impl<'de> Deserialize<'de> for Transform {
                         rotation[2],
                         rotation[3],
                     )),
-                    scale,
-                )))
+                    scale.into(),
+                ))
             }
 
             fn visit_map<V>(self, mut map: V) -> Result<Self::Value, V::Error>
@@ -168,21 +246,19 @@ impl<'de> Deserialize<'de> for Transform {
                             if scale.is_some() {
                                 return Err(de::Error::duplicate_field("scale"));
                             }
-                            scale = Some(map.next_value()?);
+                            scale = Some(map.next_value::<Scale>()?.into());
                         }
                     }
                 }
                 let translation: [f32; 3] = translation.unwrap_or([0.0; 3]);
                 let rotation: UnitQuaternion<f32> = rotation.unwrap_or(UnitQuaternion::identity());
-                let scale: f32 = scale.unwrap_or(1.0);
+                let scale: Vector3<f32> = scale.unwrap_or(Vector3::repeat(1.0));
 
-                let sim = Similarity3::from_parts(
+                Ok(Transform::new(
                     Translation3::new(translation[0], translation[1], translation[2]),
                     rotation,
                     scale,
-                );
-
-                Ok(Transform(sim))
+                ))
             }
         }
 
@@ -200,16 +276,81 @@ impl Serialize for Transform {
         struct TransformValues {
             translation: [f32; 3],
             rotation: [f32; 4],
-            scale: f32,
+            scale: [f32; 3],
         }
 
         Serialize::serialize(
             &TransformValues {
-                translation: self.0.isometry.translation.vector.into(),
-                rotation: self.0.isometry.rotation.as_ref().coords.into(),
-                scale: self.0.scaling(),
+                translation: self.isometry.translation.vector.into(),
+                rotation: self.isometry.rotation.as_ref().coords.into(),
+                scale: self.scale.into(),
             },
             serializer,
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_matrix_eq(a: &Matrix4<f32>, b: &Matrix4<f32>) {
+        assert!((a - b).norm() < 1.0e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn from_matrix_recovers_decomposed_transform() {
+        let rotation = UnitQuaternion::from_euler_angles(0.3, -1.2, 2.0);
+        let transform = Transform::new(
+            Translation3::new(1.0, -2.0, 3.0),
+            rotation,
+            Vector3::new(2.0, 0.5, 4.0),
+        );
+        let decomposed = Transform::from_matrix(&transform.matrix());
+        assert!(
+            (decomposed.isometry.translation.vector - Vector3::new(1.0, -2.0, 3.0)).norm() < 1.0e-5
+        );
+        assert!(decomposed.isometry.rotation.angle_to(&rotation) < 1.0e-3);
+        assert!((decomposed.scale - Vector3::new(2.0, 0.5, 4.0)).norm() < 1.0e-5);
+    }
+
+    #[test]
+    fn from_matrix_mirrors_along_x() {
+        let matrix = Matrix4::new_nonuniform_scaling(&Vector3::new(1.0, -1.0, 1.0));
+        let decomposed = Transform::from_matrix(&matrix);
+        assert!(decomposed.scale.x < 0.0);
+        assert!(decomposed.scale.y > 0.0 && decomposed.scale.z > 0.0);
+        assert_matrix_eq(&decomposed.matrix(), &matrix);
+    }
+
+    #[test]
+    fn from_matrix_handles_zero_scale() {
+        let matrix = Matrix4::new_translation(&Vector3::new(1.0, 2.0, 3.0))
+            * Matrix4::new_nonuniform_scaling(&Vector3::new(0.0, 1.0, 1.0));
+        let decomposed = Transform::from_matrix(&matrix);
+        assert_eq!(decomposed.isometry.rotation, UnitQuaternion::identity());
+        assert_eq!(decomposed.scale, Vector3::new(0.0, 1.0, 1.0));
+        assert_matrix_eq(&decomposed.matrix(), &matrix);
+    }
+
+    #[test]
+    fn gltf_matrix_and_decomposed_transforms_agree() {
+        let decomposed = gltf::scene::Transform::Decomposed {
+            translation: [1.0, 2.0, 3.0],
+            rotation: [
+                0.0,
+                0.0,
+                std::f32::consts::FRAC_1_SQRT_2,
+                std::f32::consts::FRAC_1_SQRT_2,
+            ],
+            scale: [2.0, 2.0, 2.0],
+        };
+        let matrix = gltf::scene::Transform::Matrix {
+            matrix: decomposed.clone().matrix(),
+        };
+        assert_matrix_eq(
+            &Transform::from(decomposed).matrix(),
+            &Transform::from(matrix).matrix(),
+        );
+    }
+}
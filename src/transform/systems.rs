@@ -1,6 +1,8 @@
 //! Scene graph system and types
 
-use crate::transform::{GlobalTransform, HierarchyEvent, Parent, ParentHierarchy, Transform};
+use crate::transform::{
+    GlobalTransform, HierarchyEvent, Parent, ParentHierarchy, PreviousGlobalTransform, Transform,
+};
 
 use specs::prelude::{
     ComponentEvent, Entities, Entity, Join, ReadExpect, ReadStorage, ReaderId, Resources, System,
@@ -98,7 +100,7 @@ impl<'a> System<'a> for TransformSystem {
             .join()
         {
             self.global_modified.add(entity.id());
-            global.0 = local.0.to_homogeneous();
+            global.0 = local.matrix();
             debug_assert!(
                 global.is_finite(),
                 format!("Entity {:?} had a non-finite `Transform`", entity)
@@ -113,9 +115,9 @@ impl<'a> System<'a> for TransformSystem {
                 if parent_dirty || self_dirty {
                     let combined_transform = if let Some(parent_global) = globals.get(parent.entity)
                     {
-                        (parent_global.0 * local.0.to_homogeneous())
+                        (parent_global.0 * local.matrix())
                     } else {
-                        local.0.to_homogeneous()
+                        local.matrix()
                     };
 
                     if let Some(global) = globals.get_mut(*entity) {
@@ -136,3 +138,29 @@ impl<'a> System<'a> for TransformSystem {
         self.locals_events_id = Some(locals.register_reader());
     }
 }
+
+/// Stores the global transform of every entity before the `TransformSystem` updates it, so
+/// that the transforms of the previous frame are available for computing motion vectors.
+pub struct PreviousTransformSystem;
+
+impl<'a> System<'a> for PreviousTransformSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, GlobalTransform>,
+        WriteStorage<'a, PreviousGlobalTransform>,
+    );
+
+    fn run(&mut self, (entities, globals, mut previous): Self::SystemData) {
+        for (entity, global) in (&*entities, &globals).join() {
+            // Only touch changed transforms so that static entities aren't flagged every frame
+            let unchanged = previous
+                .get(entity)
+                .map_or(false, |previous| previous.0 == global.0);
+            if !unchanged {
+                previous
+                    .insert(entity, PreviousGlobalTransform(global.0))
+                    .expect("unreachable");
+            }
+        }
+    }
+}